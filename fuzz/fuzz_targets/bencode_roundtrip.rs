@@ -0,0 +1,13 @@
+#![no_main]
+
+use bittorrent_starter_rust::bencode::{self, Value};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|value: Value| {
+    // Values nested past the decoder's depth limit are rejected on the way back.
+    if let Ok(encoded) = bencode::encode(&value) {
+        if let Ok(decoded) = bencode::decode(&encoded) {
+            assert_eq!(decoded, value);
+        }
+    }
+});
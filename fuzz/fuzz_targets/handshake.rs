@@ -0,0 +1,15 @@
+#![no_main]
+
+use bittorrent_starter_rust::peer::Handshake;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (Handshake, &[u8])| {
+    let (handshake, data) = input;
+    if let Ok(parsed) = Handshake::from_bytes(data) {
+        assert_eq!(parsed.to_bytes().unwrap(), data);
+    }
+    let bytes = handshake.to_bytes().unwrap();
+    if let Ok(parsed) = Handshake::from_bytes(&bytes) {
+        assert_eq!(parsed, handshake);
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use bittorrent_starter_rust::magnet::Magnet;
+use libfuzzer_sys::fuzz_target;
+use url::Url;
+
+fuzz_target!(|data: &str| {
+    if let Ok(url) = Url::parse(data) {
+        let _ = Magnet::new(url);
+    }
+});
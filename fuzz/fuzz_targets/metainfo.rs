@@ -0,0 +1,13 @@
+#![no_main]
+
+use bittorrent_starter_rust::torrent::Torrent;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(torrent) = Torrent::from_bytes(data) {
+        for (index, _, length) in torrent.piece_infos() {
+            assert_eq!(torrent.info.piece_len(index), length);
+        }
+        let _ = torrent.info_hash();
+    }
+});
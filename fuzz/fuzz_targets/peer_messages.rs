@@ -0,0 +1,47 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bittorrent_starter_rust::{
+    peer::{Handshake, Peer},
+    record::ReplayStream,
+};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+enum Operation {
+    Replay,
+    Bitfield,
+    ExtensionHandshake,
+    Metadata,
+    Unchoke,
+}
+
+// Feeds arbitrary frames, after a valid handshake, to the receive path
+// behind each peer operation.
+fuzz_target!(|input: (Operation, Vec<u8>)| {
+    let (operation, frames) = input;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let info_hash = [0u8; 20];
+        let mut inbound = Handshake::new(info_hash, [1; 20]).to_bytes().unwrap();
+        inbound.extend(frames);
+        let address = "127.0.0.1:6881".parse().unwrap();
+        let stream = ReplayStream::from(inbound);
+        let mut peer = Peer::connect_stream(stream, address, info_hash)
+            .await
+            .unwrap();
+        let _ = match operation {
+            Operation::Replay => peer.replay().await.map(drop),
+            Operation::Bitfield => peer.get_pieces().await.map(drop),
+            Operation::ExtensionHandshake => peer.extension_handshake().await,
+            Operation::Metadata => match peer.extension_handshake().await {
+                Ok(()) => peer.extension_metadata().await.map(drop),
+                Err(e) => Err(e),
+            },
+            Operation::Unchoke => peer.prepare_download().await,
+        };
+    });
+});
@@ -0,0 +1,95 @@
+#ifndef BITTORRENT_H
+#define BITTORRENT_H
+
+/* Generated with cbindgen. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum BtDownloadState {
+  BtDownloadState_Running = 0,
+  BtDownloadState_Paused = 1,
+  BtDownloadState_Completed = 2,
+  BtDownloadState_Failed = 3,
+  BtDownloadState_Cancelled = 4,
+} BtDownloadState;
+
+typedef struct BtSession BtSession;
+
+typedef struct BtProgress {
+  enum BtDownloadState state;
+  uint64_t bytes_done;
+  uint64_t total_bytes;
+  uint64_t pieces_done;
+  uint64_t total_pieces;
+  uint64_t download_rate;
+  uint64_t upload_rate;
+} BtProgress;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a session with its own runtime. Returns null on failure.
+ */
+struct BtSession *bt_session_new(void);
+
+/**
+ * # Safety
+ *
+ * `session` must come from `bt_session_new` and not be used afterwards.
+ */
+void bt_session_free(struct BtSession *session);
+
+/**
+ * Starts downloading a magnet link into `output_path`. Returns the download
+ * id, or -1 if the arguments are invalid.
+ *
+ * # Safety
+ *
+ * `session` must be a live session and both strings valid NUL-terminated
+ * UTF-8.
+ */
+int64_t bt_session_add_magnet(const struct BtSession *session,
+                              const char *magnet_uri,
+                              const char *output_path);
+
+/**
+ * Fills `out` with the current progress. Returns 0 on success, -1 for an
+ * unknown id.
+ *
+ * # Safety
+ *
+ * `session` must be a live session and `out` a valid, writable pointer.
+ */
+int32_t bt_download_progress(const struct BtSession *session, uint64_t id, struct BtProgress *out);
+
+/**
+ * # Safety
+ *
+ * `session` must be a live session.
+ */
+int32_t bt_download_cancel(const struct BtSession *session, uint64_t id);
+
+/**
+ * # Safety
+ *
+ * `session` must be a live session.
+ */
+int32_t bt_download_pause(const struct BtSession *session, uint64_t id);
+
+/**
+ * # Safety
+ *
+ * `session` must be a live session.
+ */
+int32_t bt_download_resume(const struct BtSession *session, uint64_t id);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BITTORRENT_H */
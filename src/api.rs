@@ -0,0 +1,204 @@
+// An HTTP API over a session's control requests, for web dashboards and
+// remote management. Bodies are JSON; each connection carries one request.
+//
+//   GET    /torrents             every torrent's status
+//   POST   /torrents             add {"source": ..., "output": ...}
+//   GET    /torrents/<id>        one torrent's status
+//   GET    /torrents/<id>/peers  its connected peers and their countries
+//   POST   /torrents/<id>/pause
+//   POST   /torrents/<id>/resume
+//   DELETE /torrents/<id>
+//   GET    /stats                totals over every torrent
+//   GET    /metrics              Prometheus metrics for every torrent
+//
+// `<id>` is the info hash, or enough of its start to name one torrent.
+use serde::Deserialize;
+use std::path::PathBuf;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::debug;
+
+use crate::{
+    control::{ControlRequest, ControlResponse, Registry},
+    error::{Error, Result},
+    metrics,
+};
+
+const MAX_BODY: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct AddBody {
+    source: String,
+    output: Option<PathBuf>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: &'static str, body: impl serde::Serialize) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_vec(&body).unwrap_or_default(),
+        }
+    }
+
+    fn error(status: &'static str, message: impl ToString) -> Self {
+        Self::json(status, serde_json::json!({ "error": message.to_string() }))
+    }
+}
+
+// Answers requests until the task is dropped.
+pub async fn serve(listener: TcpListener, registry: Registry) -> Result<()> {
+    accept(listener, registry, false).await
+}
+
+// Like `serve`, answering only `GET /metrics`, for a scraper that should not
+// be able to manage the session.
+pub async fn serve_metrics(listener: TcpListener, registry: Registry) -> Result<()> {
+    accept(listener, registry, true).await
+}
+
+async fn accept(listener: TcpListener, registry: Registry, metrics_only: bool) -> Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, registry, metrics_only).await {
+                debug!(client = %address, "{}", e);
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream, registry: Registry, metrics_only: bool) -> Result<()> {
+    let response = match read_request(&mut stream).await {
+        Ok((method, path, _)) if method == "GET" && path.trim_matches('/') == "metrics" => {
+            match registry.handle(ControlRequest::Status { id: None }).await {
+                ControlResponse::Status { torrents } => Response {
+                    status: "200 OK",
+                    content_type: metrics::CONTENT_TYPE,
+                    body: metrics::render(&torrents).into_bytes(),
+                },
+                response => respond(response, false),
+            }
+        }
+        Ok(_) if metrics_only => Response::error("404 Not Found", "no such route"),
+        Ok((method, path, body)) => match route(&method, &path, &body) {
+            Ok((request, one)) => respond(registry.handle(request).await, one),
+            Err(response) => response,
+        },
+        Err(e) => Response::error("400 Bad Request", e),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream
+        .write_all(&[head.as_bytes(), &response.body].concat())
+        .await?;
+    Ok(())
+}
+
+// The method, path without its query, and body.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, String, Vec<u8>)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Error::Protocol("malformed request line".to_string()));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| Error::Protocol("bad Content-Length".to_string()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(Error::Protocol("request body too large".to_string()));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    Ok((method, path, body))
+}
+
+// The control request a route stands for, and whether it asks for one
+// torrent rather than a list.
+fn route(
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> std::result::Result<(ControlRequest, bool), Response> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let id = |id: &str| id.to_string();
+    let request = match (method, segments.as_slice()) {
+        ("GET", ["torrents"]) => ControlRequest::Status { id: None },
+        ("POST", ["torrents"]) => {
+            let body: AddBody =
+                serde_json::from_slice(body).map_err(|e| Response::error("400 Bad Request", e))?;
+            ControlRequest::Add {
+                source: body.source,
+                output: body.output,
+            }
+        }
+        ("GET", ["torrents", torrent]) => {
+            return Ok((
+                ControlRequest::Status {
+                    id: Some(id(torrent)),
+                },
+                true,
+            ))
+        }
+        ("DELETE", ["torrents", torrent]) => ControlRequest::Remove { id: id(torrent) },
+        ("GET", ["torrents", torrent, "peers"]) => ControlRequest::Peers { id: id(torrent) },
+        ("POST", ["torrents", torrent, "pause"]) => ControlRequest::Pause { id: id(torrent) },
+        ("POST", ["torrents", torrent, "resume"]) => ControlRequest::Resume { id: id(torrent) },
+        ("GET", ["stats"]) => ControlRequest::Stats,
+        _ => return Err(Response::error("404 Not Found", "no such route")),
+    };
+    Ok((request, false))
+}
+
+fn respond(response: ControlResponse, one: bool) -> Response {
+    match response {
+        ControlResponse::Status { mut torrents } if one => match torrents.len() {
+            1 => Response::json("200 OK", torrents.remove(0)),
+            _ => Response::error("400 Bad Request", "id matches more than one torrent"),
+        },
+        ControlResponse::Status { torrents } => Response::json("200 OK", torrents),
+        ControlResponse::Peers { peers } => Response::json("200 OK", peers),
+        ControlResponse::Added { id } => {
+            Response::json("201 Created", serde_json::json!({ "id": id }))
+        }
+        ControlResponse::Done => Response {
+            status: "204 No Content",
+            content_type: "application/json",
+            body: Vec::new(),
+        },
+        ControlResponse::Stats { stats } => Response::json("200 OK", stats),
+        ControlResponse::Error { message } if message.contains("no torrent matches") => {
+            Response::error("404 Not Found", message)
+        }
+        ControlResponse::Error { message } => Response::error("400 Bad Request", message),
+    }
+}
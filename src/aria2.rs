@@ -0,0 +1,295 @@
+// aria2 `.aria2` control files, so a single-file download can move between
+// this client and aria2 without starting over. Layout (version 1, integers
+// big endian; version 0 used host order):
+//
+//   version u16, extension u32, info hash length u32, info hash,
+//   piece length u32, total length u64, upload length u64,
+//   bitfield length u32, bitfield, in-flight piece count u32,
+//   then per in-flight piece: index u32, length u32, bitfield length u32,
+//   bitfield.
+//
+// In-flight pieces are partially downloaded; they are dropped on read and
+// never written.
+use bitvec::prelude::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    download::{fetch_pieces, DownloadHandle},
+    error::{Error, Result},
+    torrent::{Info, Torrent},
+};
+
+const VERSION: u16 = 1;
+const EXT_INFO_HASH_CHECK: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFile {
+    pub info_hash: Option<[u8; 20]>,
+    pub piece_length: u32,
+    pub total_length: u64,
+    pub upload_length: u64,
+    bitfield: BitVec<u8, Msb0>,
+}
+
+impl ControlFile {
+    pub fn new(info: &Info) -> Result<Self> {
+        Ok(Self {
+            info_hash: Some(info.info_hash()?),
+            // The control file only has room for 32-bit piece lengths.
+            piece_length: u32::try_from(info.piece_length)
+                .map_err(|_| Error::Metadata("piece length is too large".to_string()))?,
+            total_length: info.file_len(),
+            upload_length: 0,
+            bitfield: bitvec![u8, Msb0; 0; info.piece_count()],
+        })
+    }
+
+    // aria2 keeps the control file next to the download.
+    pub fn path_for(download: &Path) -> PathBuf {
+        let mut path = download.as_os_str().to_owned();
+        path.push(".aria2");
+        PathBuf::from(path)
+    }
+
+    pub fn have(&self) -> Vec<usize> {
+        self.bitfield.iter_ones().collect()
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        self.bitfield.get(piece).is_some_and(|bit| *bit)
+    }
+
+    pub fn set_have(&mut self, piece: usize, have: bool) {
+        if piece < self.bitfield.len() {
+            self.bitfield.set(piece, have);
+        }
+    }
+
+    pub fn matches(&self, info: &Info) -> bool {
+        self.info_hash
+            .is_none_or(|hash| info.info_hash().is_ok_and(|info_hash| info_hash == hash))
+            && self.piece_length as u64 == info.piece_length
+            && self.total_length == info.file_len()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        let version = u16::from_be_bytes(reader.array()?);
+        let big_endian = match version {
+            1 => true,
+            // Version 0 was written in host byte order.
+            0 => cfg!(target_endian = "big"),
+            _ => return Err(corrupt(format!("unsupported version {}", version))),
+        };
+        let u32 = |reader: &mut Reader| -> Result<u32> {
+            let bytes = reader.array()?;
+            Ok(match big_endian {
+                true => u32::from_be_bytes(bytes),
+                false => u32::from_le_bytes(bytes),
+            })
+        };
+        let u64 = |reader: &mut Reader| -> Result<u64> {
+            let bytes = reader.array()?;
+            Ok(match big_endian {
+                true => u64::from_be_bytes(bytes),
+                false => u64::from_le_bytes(bytes),
+            })
+        };
+
+        let extension = u32(&mut reader)?;
+        let hash_len = u32(&mut reader)? as usize;
+        let hash = reader.take(hash_len)?;
+        // aria2 only compares the info hash when the extension bit asks it to.
+        let info_hash = match hash_len {
+            20 if extension & EXT_INFO_HASH_CHECK != 0 => Some(hash.try_into().unwrap()),
+            0 | 20 => None,
+            _ => return Err(corrupt(format!("bad info hash length {}", hash_len))),
+        };
+        let piece_length = u32(&mut reader)?;
+        let total_length = u64(&mut reader)?;
+        let upload_length = u64(&mut reader)?;
+        if piece_length == 0 {
+            return Err(corrupt("piece length is zero".to_string()));
+        }
+        let piece_count = total_length.div_ceil(piece_length as u64) as usize;
+        let bitfield_len = u32(&mut reader)? as usize;
+        if bitfield_len != piece_count.div_ceil(8) {
+            return Err(corrupt(format!("bad bitfield length {}", bitfield_len)));
+        }
+        let mut bitfield = BitVec::from_vec(reader.take(bitfield_len)?.to_vec());
+        bitfield.truncate(piece_count);
+
+        let in_flight = u32(&mut reader)?;
+        for _ in 0..in_flight {
+            let _index = u32(&mut reader)?;
+            let _length = u32(&mut reader)?;
+            let block_bitfield_len = u32(&mut reader)? as usize;
+            reader.take(block_bitfield_len)?;
+        }
+
+        Ok(Self {
+            info_hash,
+            piece_length,
+            total_length,
+            upload_length,
+            bitfield,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(VERSION.to_be_bytes());
+        match &self.info_hash {
+            Some(hash) => {
+                bytes.extend(EXT_INFO_HASH_CHECK.to_be_bytes());
+                bytes.extend((hash.len() as u32).to_be_bytes());
+                bytes.extend(hash);
+            }
+            None => {
+                bytes.extend(0u32.to_be_bytes());
+                bytes.extend(0u32.to_be_bytes());
+            }
+        }
+        bytes.extend(self.piece_length.to_be_bytes());
+        bytes.extend(self.total_length.to_be_bytes());
+        bytes.extend(self.upload_length.to_be_bytes());
+        let bitfield = self.bitfield.as_raw_slice();
+        bytes.extend((bitfield.len() as u32).to_be_bytes());
+        bytes.extend(bitfield);
+        bytes.extend(0u32.to_be_bytes());
+        bytes
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::from_bytes(&fs::read(path).map_err(Error::Storage)?)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        // Write then rename, as aria2 does, so a crash keeps the old file.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push("__temp");
+        fs::write(&tmp, self.to_bytes()).map_err(Error::Storage)?;
+        fs::rename(tmp, path).map_err(Error::Storage)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| corrupt("truncated".to_string()))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+}
+
+fn corrupt(reason: String) -> Error {
+    Error::Metadata(format!("invalid aria2 control file: {}", reason))
+}
+
+// Pieces the control file claims, re-read from the download and verified.
+fn verified_pieces(info: &Info, path: &Path, control: &ControlFile) -> BTreeMap<usize, Vec<u8>> {
+    let mut known = BTreeMap::new();
+    let Ok(mut file) = File::open(path) else {
+        return known;
+    };
+    for piece in control.have() {
+        let mut data = vec![0u8; info.piece_len(piece) as usize];
+        let offset = info.piece_offset(piece);
+        let read = file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut data));
+        if read.is_ok() && info.verify_piece(piece, &data) {
+            known.insert(piece, data);
+        }
+    }
+    known
+}
+
+// Downloads a single-file torrent into `path`, resuming from an aria2
+// control file next to it and keeping that file up to date as pieces land.
+// The control file is removed once the download completes.
+pub fn download_to(torrent: &Torrent, path: PathBuf) -> DownloadHandle<()> {
+    let torrent = torrent.clone();
+    DownloadHandle::spawn(|ctx| async move {
+        let info = &torrent.info;
+        if !info.is_single_file() {
+            return Err(Error::Metadata(
+                "aria2 control files only support single-file torrents".to_string(),
+            ));
+        }
+        let control_path = ControlFile::path_for(&path);
+        let mut control = match ControlFile::read(&control_path) {
+            Ok(control) if control.matches(info) => control,
+            Ok(_) => {
+                return Err(Error::Metadata(format!(
+                    "{} belongs to a different download",
+                    control_path.display()
+                )))
+            }
+            Err(Error::Storage(e)) if e.kind() == io::ErrorKind::NotFound => {
+                ControlFile::new(info)?
+            }
+            Err(e) => return Err(e),
+        };
+        let known = verified_pieces(info, &path, &control);
+        for piece in control.have() {
+            control.set_have(piece, known.contains_key(&piece));
+        }
+
+        let peer_piece_map = if known.len() < info.piece_count() {
+            torrent.connect_peers(&ctx).await?
+        } else {
+            HashMap::new()
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .map_err(Error::Storage)?;
+        file.set_len(info.file_len()).map_err(Error::Storage)?;
+        control.write(&control_path)?;
+
+        let mut write_error = None;
+        fetch_pieces(info, peer_piece_map, known, &ctx, |piece, data| {
+            if control.has(piece) || write_error.is_some() {
+                return;
+            }
+            let offset = info.piece_offset(piece);
+            let written = file
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| file.write_all(&data))
+                .map_err(Error::Storage);
+            write_error = written
+                .and_then(|_| {
+                    control.set_have(piece, true);
+                    control.write(&control_path)
+                })
+                .err();
+        })
+        .await?;
+        if let Some(e) = write_error {
+            return Err(e);
+        }
+        file.sync_all().map_err(Error::Storage)?;
+        fs::remove_file(&control_path).map_err(Error::Storage)
+    })
+}
@@ -0,0 +1,145 @@
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::{borrow::Cow, collections::BTreeMap};
+
+use crate::error::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Value {
+    Bytes(Vec<u8>),
+    Int(i64),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+// Deeper nesting than any real metainfo uses; the decoder recurses per level.
+const MAX_DEPTH: usize = 64;
+
+pub fn decode(bytes: &[u8]) -> Result<Value> {
+    from_bytes(bytes)
+}
+
+// Decodes untrusted input, refusing nesting deep enough to exhaust the stack.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    check_depth(bytes)?;
+    Ok(serde_bencode::from_bytes(bytes)?)
+}
+
+fn check_depth(bytes: &[u8]) -> Result<()> {
+    let mut depth = 0usize;
+    let mut i = 0;
+    while let Some(&byte) = bytes.get(i) {
+        match byte {
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(
+                        serde_bencode::Error::Custom("nested too deeply".to_string()).into(),
+                    );
+                }
+                i += 1;
+            }
+            b'e' => {
+                depth = depth.saturating_sub(1);
+                i += 1;
+            }
+            b'i' => {
+                let end = bytes[i..].iter().position(|&b| b == b'e');
+                i = end.map_or(bytes.len(), |end| i + end + 1);
+            }
+            b'0'..=b'9' => {
+                let Some(colon) = bytes[i..].iter().position(|&b| b == b':') else {
+                    break;
+                };
+                let len = std::str::from_utf8(&bytes[i..i + colon])
+                    .ok()
+                    .and_then(|len| len.parse::<usize>().ok());
+                let Some(len) = len else {
+                    break;
+                };
+                i = (i + colon + 1).saturating_add(len);
+            }
+            // Anything else is malformed; leave the error to the decoder.
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+pub fn encode(value: &Value) -> Result<Vec<u8>> {
+    Ok(serde_bencode::to_bytes(value)?)
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_str_lossy(&self) -> Option<Cow<'_, str>> {
+        self.as_bytes().map(String::from_utf8_lossy)
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_bencode::value::Value> for Value {
+    fn from(value: serde_bencode::value::Value) -> Self {
+        use serde_bencode::value::Value as Raw;
+        match value {
+            Raw::Bytes(b) => Value::Bytes(b),
+            Raw::Int(i) => Value::Int(i),
+            Raw::List(l) => Value::List(l.into_iter().map(Value::from).collect()),
+            Raw::Dict(d) => Value::Dict(d.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+impl From<Value> for serde_bencode::value::Value {
+    fn from(value: Value) -> Self {
+        use serde_bencode::value::Value as Raw;
+        match value {
+            Value::Bytes(b) => Raw::Bytes(b),
+            Value::Int(i) => Raw::Int(i),
+            Value::List(l) => Raw::List(l.into_iter().map(Raw::from).collect()),
+            Value::Dict(d) => Raw::Dict(d.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serde_bencode::value::Value::from(self.clone()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        serde_bencode::value::Value::deserialize(deserializer).map(Value::from)
+    }
+}
@@ -0,0 +1,36 @@
+// Synchronous wrappers for callers without an async runtime. Each call spins
+// up its own runtime, so these must not be called from within async code.
+use std::{net::SocketAddr, path::Path};
+use tokio::runtime::Runtime;
+
+use crate::{
+    error::{Error, Result},
+    source::Source,
+    torrent::Torrent,
+};
+
+fn runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(Error::Io)
+}
+
+pub fn info(source: &Source) -> Result<Torrent> {
+    runtime()?.block_on(source.resolve())
+}
+
+pub fn peers(torrent: &Torrent) -> Result<Vec<SocketAddr>> {
+    runtime()?.block_on(torrent.get_peer_addrs())
+}
+
+pub fn download_piece(torrent: &Torrent, piece: usize) -> Result<Vec<u8>> {
+    runtime()?
+        .block_on(torrent.download_piece(piece))
+        .map(Vec::from)
+}
+
+pub fn download(torrent: &Torrent, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref().to_path_buf();
+    runtime()?.block_on(torrent.download_to(path).join())
+}
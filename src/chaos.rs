@@ -0,0 +1,80 @@
+// Fault injection for resilience testing. Once a config is installed, peer
+// connections and tracker announces consult it and misbehave at the given
+// probabilities. Rolls come from a single seeded RNG so a run is repeatable.
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{io, sync::Mutex, time::Duration};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Default)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    pub disconnect: f64,
+    pub delay: f64,
+    pub max_delay: Duration,
+    pub corrupt_block: f64,
+    pub tracker_failure: f64,
+}
+
+struct Chaos {
+    config: ChaosConfig,
+    rng: StdRng,
+}
+
+static CHAOS: Mutex<Option<Chaos>> = Mutex::new(None);
+
+pub fn install(config: ChaosConfig) {
+    let rng = StdRng::seed_from_u64(config.seed);
+    *CHAOS.lock().unwrap() = Some(Chaos { config, rng });
+}
+
+pub fn clear() {
+    *CHAOS.lock().unwrap() = None;
+}
+
+fn roll<T>(f: impl FnOnce(&ChaosConfig, &mut StdRng) -> Option<T>) -> Option<T> {
+    let mut chaos = CHAOS.lock().unwrap();
+    let Chaos { config, rng } = chaos.as_mut()?;
+    f(config, rng)
+}
+
+fn hit(rng: &mut StdRng, probability: f64) -> bool {
+    probability > 0.0 && rng.gen_bool(probability.min(1.0))
+}
+
+pub(crate) fn disconnect() -> Result<()> {
+    match roll(|config, rng| hit(rng, config.disconnect).then_some(())) {
+        Some(()) => Err(Error::Io(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "chaos: injected disconnect",
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub(crate) async fn delay() {
+    let delay = roll(|config, rng| {
+        let max_millis = config.max_delay.as_millis() as u64;
+        (hit(rng, config.delay) && max_millis > 0)
+            .then(|| Duration::from_millis(rng.gen_range(0..=max_millis)))
+    });
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+pub(crate) fn corrupt_block(block: &mut [u8]) {
+    let index = roll(|config, rng| {
+        (hit(rng, config.corrupt_block) && !block.is_empty()).then(|| rng.gen_range(0..block.len()))
+    });
+    if let Some(index) = index {
+        block[index] = !block[index];
+    }
+}
+
+pub(crate) fn tracker_failure() -> Result<()> {
+    match roll(|config, rng| hit(rng, config.tracker_failure).then_some(())) {
+        Some(()) => Err(Error::Tracker("chaos: injected failure".to_string())),
+        None => Ok(()),
+    }
+}
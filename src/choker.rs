@@ -0,0 +1,129 @@
+// Deciding whom we upload to. Every round the peers that sent us the most
+// since the last round (or, when seeding, took the most from us) are
+// unchoked, tit-for-tat, plus one optimistic unchoke that rotates every few
+// rounds so new peers get a chance to prove themselves.
+use rand::seq::SliceRandom;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Duration,
+};
+use tracing::debug;
+
+use crate::peer::Peer;
+
+pub const ROUND_INTERVAL: Duration = Duration::from_secs(10);
+// Rounds between optimistic unchoke rotations, 30 seconds.
+const OPTIMISTIC_ROUNDS: u64 = 3;
+const DEFAULT_SLOTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub address: SocketAddr,
+    // Bytes transferred with the peer so far, in whichever direction the
+    // round ranks by.
+    pub transferred: u64,
+    pub interested: bool,
+}
+
+pub struct Choker {
+    slots: usize,
+    last: HashMap<SocketAddr, u64>,
+    optimistic: Option<SocketAddr>,
+    round: u64,
+}
+
+impl Default for Choker {
+    fn default() -> Self {
+        Self::new(DEFAULT_SLOTS)
+    }
+}
+
+impl Choker {
+    // Unchokes up to `slots` peers by rate, plus the optimistic one.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            last: HashMap::new(),
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    // Runs one round and returns the peers to unchoke; everyone else is
+    // choked.
+    pub fn select(&mut self, candidates: &[Candidate]) -> HashSet<SocketAddr> {
+        let mut rated: Vec<(u64, SocketAddr)> = candidates
+            .iter()
+            .filter(|candidate| candidate.interested)
+            .map(|candidate| {
+                let last = self.last.get(&candidate.address).copied().unwrap_or(0);
+                (
+                    candidate.transferred.saturating_sub(last),
+                    candidate.address,
+                )
+            })
+            .collect();
+        self.last = candidates
+            .iter()
+            .map(|candidate| (candidate.address, candidate.transferred))
+            .collect();
+        rated.sort_by_key(|&(rate, _)| Reverse(rate));
+        let mut unchoked: HashSet<SocketAddr> = rated
+            .iter()
+            .take(self.slots)
+            .map(|&(_, address)| address)
+            .collect();
+
+        let choked: Vec<SocketAddr> = rated
+            .iter()
+            .map(|&(_, address)| address)
+            .filter(|address| !unchoked.contains(address))
+            .collect();
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS)
+            || !self
+                .optimistic
+                .is_some_and(|optimistic| choked.contains(&optimistic));
+        if rotate {
+            self.optimistic = choked.choose(&mut rand::thread_rng()).copied();
+        }
+        self.round += 1;
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+
+    // Runs a round over connected peers and tells each one whether it is
+    // unchoked. Sends happen in the background, since a connection busy
+    // with a download can take a while to get to them.
+    pub(crate) fn rechoke(&mut self, peers: &[Peer], seeding: bool) {
+        let candidates: Vec<Candidate> = peers
+            .iter()
+            .map(|peer| Candidate {
+                address: peer.address,
+                transferred: match seeding {
+                    true => peer.uploaded(),
+                    false => peer.downloaded(),
+                },
+                interested: peer.is_interested(),
+            })
+            .collect();
+        let unchoked = self.select(&candidates);
+        for peer in peers {
+            let unchoke = unchoked.contains(&peer.address);
+            if unchoke != peer.is_choking() {
+                continue; // already where it should be
+            }
+            let mut peer = peer.clone();
+            tokio::spawn(async move {
+                let sent = match unchoke {
+                    true => peer.unchoke().await,
+                    false => peer.choke().await,
+                };
+                if let Err(e) = sent {
+                    debug!(peer = %peer.address, "{}", e);
+                }
+            });
+        }
+    }
+}
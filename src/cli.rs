@@ -0,0 +1,1200 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::{
+    ffi::OsStr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{fs::File, io::AsyncWriteExt, net::TcpListener};
+use tokio_stream::{Stream, StreamExt};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use url::Url;
+
+#[cfg(unix)]
+use crate::api;
+use crate::aria2;
+use crate::config::Config;
+#[cfg(unix)]
+use crate::config::DEFAULT_FEED_INTERVAL;
+#[cfg(unix)]
+use crate::control::{self, ControlRequest, ControlResponse, Registry, TorrentStatus};
+use crate::create::TorrentCreator;
+use crate::decode::{decode_bencoded_bytes, encode_json_value, BytesFormat};
+use crate::dht;
+use crate::download::{DownloadEvent, DownloadHandle, DownloadMonitor, Progress, Sequential};
+use crate::error::{Error, Result};
+use crate::i2p;
+use crate::import::import_qbittorrent;
+use crate::listener;
+use crate::magnet::Magnet;
+use crate::nat;
+use crate::peer::{self, Peer};
+use crate::record::{self, Direction, ReplayStream};
+#[cfg(unix)]
+use crate::rss::{FeedConfig, FeedItem, FeedWatcher};
+#[cfg(unix)]
+use crate::session::{Session, SessionConfig};
+use crate::source::Source;
+use crate::storage::{self, PieceReader};
+use crate::store::SessionStore;
+use crate::testing::{MockPeer, MockTracker};
+use crate::tor;
+use crate::torrent::{Info, Torrent};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+    /// Record all peer wire traffic to this file
+    #[arg(long, global = true)]
+    record: Option<PathBuf>,
+    /// Route all traffic through a Tor SOCKS proxy
+    #[arg(long, global = true, num_args = 0..=1, default_missing_value = tor::DEFAULT_PROXY)]
+    tor: Option<SocketAddr>,
+    /// Also look peers up in the mainline DHT, for torrents without a
+    /// reachable tracker
+    #[arg(long, global = true)]
+    dht: bool,
+    /// Accept connections from peers on this port
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    /// Don't ask the router (UPnP or NAT-PMP) to forward the port
+    #[arg(long, global = true)]
+    no_nat: bool,
+    /// Most bytes per second to download, over every torrent
+    #[arg(long, global = true)]
+    download_limit: Option<u64>,
+    /// Most bytes per second to upload, over every torrent
+    #[arg(long, global = true)]
+    upload_limit: Option<u64>,
+    /// Peers each download stays connected to at once
+    #[arg(long, global = true)]
+    max_peers: Option<usize>,
+    /// Block requests to keep outstanding per peer
+    #[arg(long, global = true, default_value_t = peer::DEFAULT_QUEUE_DEPTH)]
+    queue_depth: usize,
+    /// Seconds to wait for a peer to connect and handshake
+    #[arg(long, global = true, default_value_t = peer::DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    connect_timeout: u64,
+    /// Print results as JSON, and download progress as one JSON object per
+    /// line
+    #[arg(long, global = true)]
+    json: bool,
+    /// Diagnostics to print to stderr: off, error, warn, info, debug or
+    /// trace. RUST_LOG takes precedence when set.
+    #[arg(long, global = true, default_value_t = LevelFilter::WARN)]
+    log_level: LevelFilter,
+    /// Settings file; ~/.config/bittorrent-rust/config.toml or the platform's
+    /// equivalent when not given
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ImportFrom {
+    Qbittorrent,
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum ControlAction {
+    /// Start downloading a torrent file, URL, magnet link or info hash
+    Add {
+        source: String,
+        /// Where to save it; the daemon's download directory when not given
+        #[arg(short)]
+        output: Option<PathBuf>,
+    },
+    Pause {
+        id: String,
+    },
+    Resume {
+        id: String,
+    },
+    /// Stop downloading a torrent, keeping what was saved
+    Remove {
+        id: String,
+    },
+    /// Totals over all the daemon's torrents
+    Stats,
+}
+
+#[derive(Subcommand)]
+#[clap(rename_all = "snake_case")]
+enum Command {
+    Decode {
+        /// Bencoded value, or - to read it from stdin
+        value: String,
+        /// How to show byte strings that aren't UTF-8
+        #[arg(long, value_enum, default_value_t = BytesFormat::Hex)]
+        bytes: BytesFormat,
+    },
+    /// Bencode a JSON value
+    Encode {
+        json: String,
+    },
+    #[command(alias = "magnet_info")]
+    Info {
+        source: Source,
+    },
+    Peers {
+        source: Source,
+        /// GeoLite2 country database to annotate peers with
+        #[arg(long)]
+        geoip: Option<PathBuf>,
+    },
+    Scrape {
+        source: Source,
+    },
+    Handshake {
+        torrent: PathBuf,
+        peer_address: SocketAddr,
+    },
+    #[command(alias = "magnet_download_piece")]
+    DownloadPiece {
+        #[arg(short)]
+        output: PathBuf,
+        source: Source,
+        piece: usize,
+    },
+    /// Build a .torrent file sharing a file or directory
+    Create {
+        path: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        #[arg(long)]
+        announce: String,
+        /// Bytes per piece; picked from the total size when not given
+        #[arg(long)]
+        piece_length: Option<u64>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Mark the torrent private, so clients only use its tracker
+        #[arg(long)]
+        private: bool,
+    },
+    /// Hash-check a downloaded file or directory against the torrent
+    Verify {
+        source: Source,
+        path: PathBuf,
+    },
+    #[command(alias = "magnet_download")]
+    Download {
+        /// Where to save the download; the torrent's name in the configured
+        /// download directory when not given
+        #[arg(short)]
+        output: Option<PathBuf>,
+        source: Source,
+        /// Resume from and keep an aria2 control file (<output>.aria2)
+        #[arg(long)]
+        aria2: bool,
+        /// Download over I2P through this SAM bridge
+        #[arg(long, num_args = 0..=1, default_missing_value = i2p::DEFAULT_SAM)]
+        i2p: Option<SocketAddr>,
+        /// Fetch pieces in order, a few at a time, so media can play early
+        #[arg(long, conflicts_with_all = ["aria2", "i2p"])]
+        sequential: bool,
+        /// Keep a fast-resume file (<output>.resume) so a restart skips the
+        /// hash check
+        #[arg(long, conflicts_with_all = ["aria2", "i2p", "sequential"])]
+        fast_resume: bool,
+    },
+    MagnetParse {
+        magnet_link: Url,
+    },
+    MagnetHandshake {
+        magnet_link: Url,
+    },
+    /// Print a magnet link for a torrent, to share it without the file
+    MagnetCreate {
+        source: Source,
+    },
+    Selftest,
+    Replay {
+        log: PathBuf,
+    },
+    Import {
+        #[arg(long)]
+        from: ImportFrom,
+        dir: PathBuf,
+        /// Session directory to import into
+        #[arg(long)]
+        state_dir: Option<PathBuf>,
+    },
+    /// Show what a running client is doing
+    #[cfg(unix)]
+    Status {
+        /// Only show the torrent whose info hash starts with this
+        id: Option<String>,
+        /// Control socket of the client to ask
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Ask a client listening on this loopback TCP address instead
+        #[arg(long, conflicts_with = "socket")]
+        tcp: Option<SocketAddr>,
+    },
+    /// Keep running, downloading whatever is added over the control socket
+    #[cfg(unix)]
+    Daemon {
+        /// Control socket to listen on
+        #[arg(long)]
+        socket: Option<PathBuf>,
+        /// Also listen for control requests on this loopback TCP address
+        #[arg(long)]
+        tcp: Option<SocketAddr>,
+        /// Serve Prometheus metrics at /metrics on this address
+        #[arg(long)]
+        metrics: Option<SocketAddr>,
+        /// Downloads to run at once; the rest wait their turn
+        #[arg(long)]
+        max_active: Option<usize>,
+    },
+    /// Run a session managed over an HTTP JSON API
+    #[cfg(unix)]
+    #[command(alias = "serve-api")]
+    ServeApi {
+        /// Address to take API requests on. There is no authentication, so
+        /// only expose it to networks you trust.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+        /// Downloads to run at once; the rest wait their turn
+        #[arg(long)]
+        max_active: Option<usize>,
+    },
+    /// Tell a running daemon what to do
+    #[cfg(unix)]
+    Control {
+        #[command(subcommand)]
+        action: ControlAction,
+        /// Control socket of the daemon
+        #[arg(long, global = true)]
+        socket: Option<PathBuf>,
+        /// Reach the daemon on this loopback TCP address instead
+        #[arg(long, global = true, conflicts_with = "socket")]
+        tcp: Option<SocketAddr>,
+    },
+    Testpeer {
+        #[arg(short, long, default_value_t = 6881)]
+        port: u16,
+        torrent: PathBuf,
+        file: PathBuf,
+        /// Serve pieces without re-hashing them on first read
+        #[arg(long)]
+        no_verify: bool,
+    },
+}
+
+pub async fn run() -> Result<()> {
+    let args = Args::parse();
+    // Other crates' chatter stays at warnings unless RUST_LOG asks for it.
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(directives).map_err(log_filter_error)?,
+        Err(_) => EnvFilter::try_new(format!(
+            "{},{}={}",
+            args.log_level.min(LevelFilter::WARN),
+            env!("CARGO_CRATE_NAME"),
+            args.log_level
+        ))
+        .map_err(log_filter_error)?,
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    let config = match &args.config {
+        Some(path) if !path.exists() => {
+            return Err(Error::Config(format!("{} does not exist", path.display())))
+        }
+        Some(path) => Config::load(path)?,
+        None => Config::load(&Config::default_path())?,
+    };
+    let config = config.with_env()?.merge(Config {
+        listen_port: args.port,
+        download_rate_limit: args.download_limit,
+        upload_rate_limit: args.upload_limit,
+        max_peers: args.max_peers,
+        ..Default::default()
+    });
+    config.apply();
+    if let Some(path) = &args.record {
+        record::start(path)?;
+    }
+    peer::set_queue_depth(args.queue_depth);
+    peer::set_connect_timeout(Duration::from_secs(args.connect_timeout));
+    if let Some(proxy) = args.tor {
+        tor::enable(proxy);
+    }
+    if args.dht {
+        dht::enable(
+            dht::BOOTSTRAP_NODES
+                .iter()
+                .map(|node| node.to_string())
+                .collect(),
+        );
+    }
+    if let Some(port) = config.listen_port.filter(|_| !tor::enabled()) {
+        let address = listener::listen((Ipv6Addr::UNSPECIFIED, port).into()).await?;
+        if !args.json {
+            println!("Accepting peers on {}", address);
+        }
+        if !args.no_nat {
+            nat::keep_mapped(address.port());
+        }
+    }
+    let result = execute(args.command, args.json, &config).await;
+    record::stop()?;
+    result
+}
+
+async fn execute(command: Command, json: bool, config: &Config) -> Result<()> {
+    match command {
+        Command::Decode { value, bytes } => {
+            let encoded = match value.as_str() {
+                "-" => {
+                    let mut encoded = Vec::new();
+                    std::io::Read::read_to_end(&mut std::io::stdin(), &mut encoded)?;
+                    encoded
+                }
+                _ => value.into_bytes(),
+            };
+            let decoded = decode_bencoded_bytes(&encoded, bytes)?;
+            println!("{}", decoded);
+        }
+        Command::Encode { json } => {
+            let encoded = encode_json_value(&json)?;
+            std::io::Write::write_all(&mut std::io::stdout(), &encoded)?;
+        }
+        Command::Info { source } => {
+            let torrent = source.resolve().await?;
+            if json {
+                return print_json(&info_json(&torrent)?);
+            }
+            if !torrent.announce.is_empty() {
+                println!("Tracker URL: {}", torrent.announce);
+            }
+            println!("Length: {}", torrent.len());
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+            if torrent.info.is_v2() {
+                println!(
+                    "Info Hash v2: {}",
+                    hex::encode(torrent.info.info_hash_v2()?)
+                );
+            }
+            println!("Piece Length: {}", torrent.info.piece_length);
+            if let Some(comment) = &torrent.comment {
+                println!("Comment: {}", comment);
+            }
+            if let Some(created_by) = &torrent.created_by {
+                println!("Created By: {}", created_by);
+            }
+            if let Some(creation_date) = torrent.creation_date {
+                println!("Creation Date: {}", creation_date);
+            }
+            if let Some(encoding) = &torrent.encoding {
+                println!("Encoding: {}", encoding);
+            }
+            if torrent.info.is_private() {
+                println!("Private: yes");
+            }
+            println!("Piece Hashes:");
+            for piece_hash in torrent.pieces() {
+                println!("{}", hex::encode(piece_hash));
+            }
+        }
+        Command::Peers { source, geoip } => {
+            let country = match geoip {
+                Some(path) => country_lookup(&path)?,
+                None => Box::new(|_| None),
+            };
+            let peer_addrs = source.resolve().await?.get_peer_addrs().await?;
+            if json {
+                let peers: Vec<_> = peer_addrs
+                    .iter()
+                    .map(|addr| serde_json::json!({ "address": addr, "country": country(addr.ip()) }))
+                    .collect();
+                return print_json(&serde_json::json!({ "peers": peers }));
+            }
+            for addr in peer_addrs {
+                match country(addr.ip()) {
+                    Some(code) => println!("{} {}", addr, code),
+                    None => println!("{}", addr),
+                }
+            }
+        }
+        Command::Scrape { source } => {
+            let torrent = source.resolve().await?;
+            let info_hash = torrent.info_hash()?;
+            let stats = torrent.scrape().await?;
+            if json {
+                return print_json(&serde_json::json!({
+                    "info_hash": hex::encode(info_hash),
+                    "seeders": stats.seeders,
+                    "leechers": stats.leechers,
+                    "completed": stats.completed,
+                }));
+            }
+            println!("Info Hash: {}", hex::encode(info_hash));
+            println!("Seeders: {}", stats.seeders);
+            println!("Leechers: {}", stats.leechers);
+            println!("Completed: {}", stats.completed);
+        }
+        Command::Handshake {
+            torrent,
+            peer_address,
+        } => {
+            let peer = Torrent::new(torrent)?.handshake(peer_address).await?;
+            match json {
+                true => print_json(&serde_json::json!({ "peer_id": hex::encode(peer.id) }))?,
+                false => println!("Peer ID: {}", hex::encode(peer.id)),
+            }
+        }
+        Command::DownloadPiece {
+            output,
+            source,
+            piece,
+        } => {
+            let torrent = source.resolve().await?;
+            let piece_bytes = torrent.download_piece(piece).await?;
+            let mut file = File::create(output).await.map_err(Error::Storage)?;
+            file.write_all(&piece_bytes).await.map_err(Error::Storage)?;
+        }
+        Command::Download {
+            output,
+            source,
+            i2p: Some(sam),
+            ..
+        } => {
+            let torrent = source.resolve().await?;
+            let output = output_path(output, config, &torrent)?;
+            let handle = i2p::download_to(&torrent, sam, output);
+            monitored(handle, json).await?;
+        }
+        Command::Download {
+            output,
+            source,
+            aria2: true,
+            ..
+        } => {
+            let torrent = source.resolve().await?;
+            let output = output_path(output, config, &torrent)?;
+            let handle = aria2::download_to(&torrent, output);
+            monitored(handle, json).await?;
+        }
+        Command::Download {
+            output,
+            source,
+            fast_resume: true,
+            ..
+        } => {
+            let torrent = source.resolve().await?;
+            let output = output_path(output, config, &torrent)?;
+            let handle = torrent.download_with_resume(output);
+            monitored(handle, json).await?;
+        }
+        Command::Download {
+            output,
+            source,
+            sequential,
+            ..
+        } => {
+            let torrent = source.resolve().await?;
+            let output = output_path(output, config, &torrent)?;
+            // WebSocket trackers only lead to WebRTC peers, which Tor can't reach.
+            #[cfg(feature = "webrtc")]
+            if !tor::enabled()
+                && Url::parse(&torrent.announce)
+                    .is_ok_and(|tracker| matches!(tracker.scheme(), "ws" | "wss"))
+            {
+                let config = crate::webtorrent::WebRtcConfig::default();
+                let handle = crate::webtorrent::download_to(&torrent, config, output);
+                monitored(handle, json).await?;
+                return Ok(());
+            }
+            let handle = match sequential {
+                true => torrent.download_ordered_to(Sequential::default(), output),
+                false => torrent.download_to(output),
+            };
+            monitored(handle, json).await?;
+        }
+        Command::Create {
+            path,
+            output,
+            announce,
+            piece_length,
+            comment,
+            private,
+        } => {
+            let mut creator = TorrentCreator::new(path, &announce).with_private(private);
+            if let Some(piece_length) = piece_length {
+                creator = creator.with_piece_length(piece_length);
+            }
+            if let Some(comment) = comment {
+                creator = creator.with_comment(&comment);
+            }
+            let metainfo = tokio::task::spawn_blocking(move || creator.build()).await??;
+            tokio::fs::write(&output, &metainfo)
+                .await
+                .map_err(Error::Storage)?;
+            let torrent = Torrent::from_bytes(&metainfo)?;
+            if json {
+                return print_json(&serde_json::json!({
+                    "path": output,
+                    "info_hash": hex::encode(torrent.info_hash()?),
+                }));
+            }
+            println!("Created {}", output.display());
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
+        }
+        Command::Verify { source, path } => {
+            let torrent = source.resolve().await?;
+            let intact = storage::verify_pieces(&torrent.info, &path).await?;
+            let (good, bad): (Vec<usize>, Vec<usize>) =
+                (0..intact.len()).partition(|&index| intact[index]);
+            if json {
+                return print_json(&serde_json::json!({ "good": good, "bad": bad }));
+            }
+            println!("Good pieces: {}", piece_ranges(&good));
+            println!("Bad pieces: {}", piece_ranges(&bad));
+            let percent = match intact.len() {
+                0 => 100.0,
+                total => good.len() as f64 * 100.0 / total as f64,
+            };
+            println!(
+                "Verified: {}/{} pieces ({:.1}%)",
+                good.len(),
+                intact.len(),
+                percent
+            );
+        }
+        Command::MagnetParse { magnet_link } => {
+            let magnet = Magnet::new(magnet_link)?;
+            if json {
+                let trackers: Vec<_> = magnet.trackers.iter().map(Url::as_str).collect();
+                return print_json(&serde_json::json!({
+                    "trackers": trackers,
+                    "info_hash": hex::encode(magnet.info_hash),
+                    "info_hash_v2": magnet.info_hash_v2.map(hex::encode),
+                }));
+            }
+            for tracker in &magnet.trackers {
+                println!("Tracker URL: {}", tracker);
+            }
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            if let Some(info_hash_v2) = magnet.info_hash_v2 {
+                println!("Info Hash v2: {}", hex::encode(info_hash_v2));
+            }
+        }
+        Command::MagnetHandshake { magnet_link } => {
+            let magnet = Magnet::new(magnet_link)?;
+            let peer = magnet.handshake().await?;
+            if json {
+                return print_json(&serde_json::json!({
+                    "peer_id": hex::encode(peer.id),
+                    "metadata_extension_id": peer.metadata_extension_id,
+                }));
+            }
+            println!("Peer ID: {}", hex::encode(peer.id));
+            println!(
+                "Peer Metadata Extension ID: {}",
+                peer.metadata_extension_id.unwrap()
+            );
+        }
+        Command::MagnetCreate { source } => {
+            let magnet = source.resolve().await?.magnet()?.to_url();
+            if json {
+                return print_json(&serde_json::json!({ "magnet": magnet.as_str() }));
+            }
+            println!("{}", magnet);
+        }
+        Command::Selftest => selftest().await?,
+        Command::Replay { log } => replay(log).await?,
+        Command::Import {
+            from,
+            dir,
+            state_dir,
+        } => {
+            let state_dir = state_dir.unwrap_or_else(SessionStore::default_dir);
+            let mut store = SessionStore::open(&state_dir)?;
+            let imported = match from {
+                ImportFrom::Qbittorrent => import_qbittorrent(&dir, &mut store)?,
+            };
+            for torrent in &imported {
+                println!(
+                    "Imported {} ({} pieces done) -> {}",
+                    torrent.name,
+                    torrent.have().len(),
+                    torrent.save_path.display()
+                );
+            }
+            println!(
+                "{} torrents imported into {}",
+                imported.len(),
+                state_dir.display()
+            );
+        }
+        #[cfg(unix)]
+        Command::Status { id, socket, tcp } => {
+            match ask(socket, tcp, &ControlRequest::Status { id }).await? {
+                ControlResponse::Status { torrents } if json => {
+                    println!("{}", serde_json::to_string_pretty(&torrents)?)
+                }
+                ControlResponse::Status { torrents } => print_status(&torrents),
+                response => {
+                    return Err(Error::Protocol(format!(
+                        "unexpected response {:?}",
+                        response
+                    )))
+                }
+            }
+        }
+        #[cfg(unix)]
+        Command::Daemon {
+            socket,
+            tcp,
+            metrics,
+            max_active,
+        } => {
+            let socket = socket.unwrap_or_else(control::default_socket);
+            daemon(config, socket, tcp, metrics, max_active).await?
+        }
+        #[cfg(unix)]
+        Command::ServeApi { listen, max_active } => serve_api(config, listen, max_active).await?,
+        #[cfg(unix)]
+        Command::Control {
+            action,
+            socket,
+            tcp,
+        } => {
+            let request = match action {
+                ControlAction::Add { source, output } => {
+                    // The daemon may not share our working directory.
+                    let source = match std::fs::canonicalize(&source) {
+                        Ok(path) => path.display().to_string(),
+                        Err(_) => source,
+                    };
+                    let output = output
+                        .map(std::path::absolute)
+                        .transpose()
+                        .map_err(Error::Storage)?;
+                    ControlRequest::Add { source, output }
+                }
+                ControlAction::Pause { id } => ControlRequest::Pause { id },
+                ControlAction::Resume { id } => ControlRequest::Resume { id },
+                ControlAction::Remove { id } => ControlRequest::Remove { id },
+                ControlAction::Stats => ControlRequest::Stats,
+            };
+            match ask(socket, tcp, &request).await? {
+                response if json => print_json(&response)?,
+                ControlResponse::Added { id } => println!("Added {}", id),
+                ControlResponse::Stats { stats } => {
+                    println!("Torrents: {} ({} paused)", stats.torrents, stats.paused);
+                    println!("Peers: {}", stats.peers);
+                    println!("Download: {}", rate(stats.download_rate));
+                    println!("Upload: {}", rate(stats.upload_rate));
+                    println!("Downloaded: {} bytes", stats.bytes_downloaded);
+                    println!("Uploaded: {} bytes", stats.bytes_uploaded);
+                }
+                _ => {}
+            }
+        }
+        Command::Testpeer {
+            port,
+            torrent,
+            file,
+            no_verify,
+        } => testpeer(port, torrent, file, !no_verify).await?,
+    }
+
+    Ok(())
+}
+
+// Sorted piece indexes as runs, e.g. "0-4, 7".
+fn piece_ranges(pieces: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &piece in pieces {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == piece => *end = piece,
+            _ => ranges.push((piece, piece)),
+        }
+    }
+    if ranges.is_empty() {
+        return "none".to_string();
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}-{}", start, end),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Waits for a download while showing its progress. Only the daemon answers
+// on the control socket; a one-shot download would take it over.
+async fn monitored<T: Send + 'static>(handle: DownloadHandle<T>, json: bool) -> Result<T> {
+    let bar = tokio::spawn(show_progress(handle.monitor(), handle.events(), json));
+    let result = until_interrupted(handle).await;
+    let _ = bar.await;
+    result
+}
+
+// Joins the download. Ctrl-C cancels it, which still saves its progress and
+// tells its trackers; a second Ctrl-C quits without waiting for that.
+async fn until_interrupted<T: Send + 'static>(handle: DownloadHandle<T>) -> Result<T> {
+    let cancel = handle.cancellation_token();
+    let join = handle.join();
+    tokio::pin!(join);
+    tokio::select! {
+        result = &mut join => return result,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    eprintln!("Stopping, press Ctrl-C again to quit now");
+    cancel.cancel();
+    tokio::select! {
+        result = &mut join => result,
+        _ = tokio::signal::ctrl_c() => Err(Error::Cancelled),
+    }
+}
+
+// Redraws on every event and at least once a second, until the download's
+// events end. With `json` each redraw is a line of JSON instead.
+async fn show_progress(
+    monitor: DownloadMonitor,
+    events: impl Stream<Item = DownloadEvent> + Send + 'static,
+    json: bool,
+) {
+    let bar = match json {
+        true => ProgressBar::hidden(),
+        false => ProgressBar::new(0),
+    };
+    let bar = bar.with_style(
+        ProgressStyle::with_template("{bar:40} {percent:>3}% {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    let mut events = std::pin::pin!(events);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(DownloadEvent::Completed) | None => break,
+                Some(_) => {}
+            },
+            _ = tick.tick() => {}
+        }
+        let progress = monitor.progress();
+        if json {
+            if let Ok(line) = serde_json::to_string(&progress) {
+                println!("{}", line);
+            }
+            continue;
+        }
+        bar.set_length(progress.total_bytes);
+        bar.set_position(progress.bytes_done);
+        bar.set_message(format!(
+            "{}  {} peers  ETA {}",
+            rate(progress.download_rate),
+            progress.peers,
+            eta(&progress)
+        ));
+    }
+    bar.finish_and_clear();
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+fn info_json(torrent: &Torrent) -> Result<serde_json::Value> {
+    let info_hash_v2 = match torrent.info.is_v2() {
+        true => Some(hex::encode(torrent.info.info_hash_v2()?)),
+        false => None,
+    };
+    let piece_hashes: Vec<_> = torrent.pieces().iter().map(hex::encode).collect();
+    Ok(serde_json::json!({
+        "tracker": torrent.announce,
+        "length": torrent.len(),
+        "info_hash": hex::encode(torrent.info_hash()?),
+        "info_hash_v2": info_hash_v2,
+        "piece_length": torrent.info.piece_length,
+        "comment": torrent.comment,
+        "created_by": torrent.created_by,
+        "creation_date": torrent.creation_date,
+        "encoding": torrent.encoding,
+        "private": torrent.info.is_private(),
+        "piece_hashes": piece_hashes,
+    }))
+}
+
+fn eta(progress: &Progress) -> String {
+    let left = progress.total_bytes.saturating_sub(progress.bytes_done);
+    match progress.download_rate {
+        0 => "-".to_string(),
+        rate => {
+            let secs = left.div_ceil(rate);
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        }
+    }
+}
+
+// Sends `request` to the client on `tcp` when given, otherwise on the
+// control socket. Error responses become errors.
+#[cfg(unix)]
+async fn ask(
+    socket: Option<PathBuf>,
+    tcp: Option<SocketAddr>,
+    request: &ControlRequest,
+) -> Result<ControlResponse> {
+    let response = match tcp {
+        Some(address) => control::request_tcp(address, request).await?,
+        None => {
+            let socket = socket.unwrap_or_else(control::default_socket);
+            control::request(&socket, request).await?
+        }
+    };
+    match response {
+        ControlResponse::Error { message } => Err(Error::Protocol(message)),
+        response => Ok(response),
+    }
+}
+
+// A session for the daemon and API server. `run` already accepts peers on
+// the configured port for every download.
+#[cfg(unix)]
+async fn session(config: &Config, max_active: Option<usize>) -> Result<Session> {
+    let session = Session::new(SessionConfig {
+        listen: None,
+        max_active,
+        ..config.session_config()
+    })
+    .await?;
+    Ok(session)
+}
+
+// Runs a session until Ctrl-C, taking requests on the control socket and,
+// when given, on `tcp`, and serving metrics on `metrics`.
+#[cfg(unix)]
+async fn daemon(
+    config: &Config,
+    socket: PathBuf,
+    tcp: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+    max_active: Option<usize>,
+) -> Result<()> {
+    let session = session(config, max_active).await?;
+    let feeds = config.feed_configs()?;
+    let interval = Duration::from_secs(config.feed_interval.unwrap_or(DEFAULT_FEED_INTERVAL));
+    let registry = registry(config, &session)?;
+    let tcp_server = async {
+        match tcp {
+            Some(address) => control::serve_tcp(address, registry.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let metrics_server = async {
+        match metrics {
+            Some(address) => {
+                let listener = TcpListener::bind(address).await?;
+                println!("Metrics at http://{}/metrics", listener.local_addr()?);
+                api::serve_metrics(listener, registry.clone()).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    println!("Daemon listening on {}", socket.display());
+    let result = tokio::select! {
+        result = control::serve(&socket, registry.clone()) => result,
+        result = tcp_server => result,
+        result = metrics_server => result,
+        () = watch_feeds(&session, feeds, interval) => Ok(()),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    stop(&session).await;
+    let _ = std::fs::remove_file(&socket);
+    result
+}
+
+// Adds the feeds' matching items to the session, each in its feed's
+// directory. Never returns.
+#[cfg(unix)]
+async fn watch_feeds(session: &Session, feeds: Vec<FeedConfig>, interval: Duration) {
+    if feeds.is_empty() {
+        return std::future::pending().await;
+    }
+    for feed in &feeds {
+        println!("Watching {}", feed.url);
+    }
+    FeedWatcher::new(feeds, interval)
+        .run(|item, download_dir| {
+            let session = session.clone();
+            tokio::spawn(async move {
+                if let Err(e) = add_item(&session, &item, &download_dir).await {
+                    tracing::warn!(item = %item.title, "{}", e);
+                }
+            });
+        })
+        .await
+}
+
+#[cfg(unix)]
+async fn add_item(session: &Session, item: &FeedItem, download_dir: &Path) -> Result<()> {
+    let source = item.link.parse::<Source>()?;
+    tokio::fs::create_dir_all(download_dir)
+        .await
+        .map_err(Error::Storage)?;
+    let (info_hash, _) = session.add_source_in(&source, download_dir).await?;
+    println!("Added {} ({})", item.title, &hex::encode(info_hash)[..8]);
+    Ok(())
+}
+
+// Reports on `session`, with peers' countries when a GeoIP database is
+// configured.
+#[cfg(unix)]
+fn registry(config: &Config, session: &Session) -> Result<Registry> {
+    let registry = Registry::for_session(session.clone());
+    match &config.geoip {
+        #[cfg(feature = "geoip")]
+        Some(path) => Ok(registry.with_geoip(crate::geoip::GeoIp::open(path)?)),
+        #[cfg(not(feature = "geoip"))]
+        Some(_) => Err(without_geoip()),
+        None => Ok(registry),
+    }
+}
+
+// Closes the session, unless a second Ctrl-C says not to wait for it.
+#[cfg(unix)]
+async fn stop(session: &Session) {
+    eprintln!("Stopping, press Ctrl-C again to quit now");
+    tokio::select! {
+        () = session.close() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(unix)]
+async fn serve_api(config: &Config, address: SocketAddr, max_active: Option<usize>) -> Result<()> {
+    let session = session(config, max_active).await?;
+    let listener = TcpListener::bind(address).await?;
+    if !address.ip().is_loopback() {
+        tracing::warn!(
+            "the API on {} takes requests from anyone who can reach it",
+            address
+        );
+    }
+    println!("API listening on http://{}", listener.local_addr()?);
+    let result = tokio::select! {
+        result = api::serve(listener, registry(config, &session)?) => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    stop(&session).await;
+    result
+}
+
+#[cfg(unix)]
+fn print_status(torrents: &[TorrentStatus]) {
+    println!(
+        "{:<8}  {:<24}  {:<11}  {:>6}  {:>10}  {:>10}  {:>5}  {:>5}  TRACKER",
+        "ID", "NAME", "STATE", "DONE", "DOWN", "UP", "PEERS", "RATIO"
+    );
+    for torrent in torrents {
+        let progress = &torrent.progress;
+        let done = match progress.total_bytes {
+            0 => 0.0,
+            total => progress.bytes_done as f64 * 100.0 / total as f64,
+        };
+        let name: String = torrent.name.chars().take(24).collect();
+        println!(
+            "{:<8}  {:<24}  {:<11}  {:>5.1}%  {:>10}  {:>10}  {:>5}  {:>5.2}  {}",
+            &torrent.id[..8],
+            name,
+            format!("{:?}", torrent.state).to_lowercase(),
+            done,
+            rate(progress.download_rate),
+            rate(progress.upload_rate),
+            progress.peers,
+            torrent.ratio,
+            torrent.tracker.as_deref().unwrap_or("-"),
+        );
+    }
+}
+
+fn rate(bytes_per_sec: u64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut rate = bytes_per_sec as f64;
+    let mut unit = 0;
+    while rate >= 1024.0 && unit < UNITS.len() - 1 {
+        rate /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", rate, UNITS[unit])
+}
+
+// Seeds a random file from an in-process peer and downloads it back over
+// localhost, exercising the tracker, wire protocol and storage end to end.
+async fn selftest() -> Result<()> {
+    const PIECE_LENGTH: u64 = 256 * 1024;
+    // The swarm is all on localhost.
+    dht::disable();
+    let dir = tempfile::tempdir().map_err(Error::Storage)?;
+
+    let data: Vec<u8> = (0..4 * PIECE_LENGTH + 1234)
+        .map(|_| rand::random())
+        .collect();
+    let source_path = dir.path().join("selftest.bin");
+    tokio::fs::write(&source_path, &data)
+        .await
+        .map_err(Error::Storage)?;
+    println!("Created {} ({} bytes)", source_path.display(), data.len());
+
+    let info = Info::single_file("selftest.bin", PIECE_LENGTH, &data);
+    let seeder = MockPeer::new(info, data.clone());
+    let seeder_address = seeder.listen().await?;
+    let tracker = MockTracker::start(vec![seeder_address]).await?;
+    println!(
+        "Seeding from {} via {}",
+        seeder_address,
+        tracker.announce_url()
+    );
+
+    let torrent_path = dir.path().join("selftest.torrent");
+    let torrent = seeder.torrent(&tracker.announce_url());
+    tokio::fs::write(&torrent_path, serde_bencode::to_bytes(&torrent)?)
+        .await
+        .map_err(Error::Storage)?;
+    let torrent = Torrent::new(torrent_path)?;
+
+    let output_path = dir.path().join("selftest.out");
+    torrent.download_to(output_path.clone()).join().await?;
+    if tokio::fs::read(&output_path)
+        .await
+        .map_err(Error::Storage)?
+        != data
+    {
+        return Err(Error::Protocol(
+            "downloaded file does not match the original".to_string(),
+        ));
+    }
+    println!("Selftest passed");
+    Ok(())
+}
+
+// Prints a recorded session, then feeds each peer's frames back through the
+// wire protocol parser.
+async fn replay(log: PathBuf) -> Result<()> {
+    let records = record::read_log(&log)?;
+    for record in &records {
+        let arrow = match record.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        println!(
+            "{:>12.6}s {} {} {} ({} bytes)",
+            record.micros as f64 / 1e6,
+            arrow,
+            record.peer,
+            record.describe(),
+            record.frame.len()
+        );
+    }
+
+    for peer_address in record::peers(&records) {
+        let info_hash = records
+            .iter()
+            .filter(|record| record.peer == peer_address)
+            .find_map(|record| record.info_hash())
+            .unwrap_or_default();
+        let stream = ReplayStream::new(&records, peer_address);
+        let result = match Peer::connect_stream(stream, peer_address, info_hash).await {
+            Ok(mut peer) => peer.replay().await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(count) => println!("{}: replayed {} messages", peer_address, count),
+            Err(e) => println!("{}: {}", peer_address, e),
+        }
+    }
+    Ok(())
+}
+
+// Serves `file` as a strict reference peer, reporting whether each incoming
+// session kept to the wire protocol.
+async fn testpeer(port: u16, torrent: PathBuf, file: PathBuf, verify: bool) -> Result<()> {
+    let torrent = Torrent::new(torrent)?;
+    if tokio::fs::metadata(&file)
+        .await
+        .map_err(Error::Storage)?
+        .len()
+        != torrent.len()
+    {
+        return Err(Error::Metadata(format!(
+            "{} does not match the torrent length",
+            file.display()
+        )));
+    }
+    let storage = PieceReader::new(torrent.info, file).verify(verify);
+    let peer = MockPeer::from_storage(storage).strict();
+
+    // `[::]` takes IPv4 connections too, unless the host has no IPv6 at all.
+    let listener = match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(listener) => listener,
+        Err(_) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await?,
+    };
+    println!("Reference peer listening on {}", listener.local_addr()?);
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let peer = peer.clone();
+        tokio::spawn(async move {
+            match peer.serve(stream).await {
+                Ok(()) => println!("{}: session conformed", address),
+                Err(e) => println!("{}: {}", address, e),
+            }
+        });
+    }
+}
+
+// The name comes from the torrent; never let it leave the directory.
+fn safe_name(torrent: &Torrent) -> Result<&OsStr> {
+    Path::new(torrent.info.name())
+        .file_name()
+        .ok_or_else(|| Error::Metadata(format!("invalid torrent name {:?}", torrent.info.name())))
+}
+
+// `output` when given, otherwise the torrent's name in the configured
+// download directory.
+fn output_path(output: Option<PathBuf>, config: &Config, torrent: &Torrent) -> Result<PathBuf> {
+    match (output, &config.download_dir) {
+        (Some(output), _) => Ok(output),
+        (None, Some(dir)) => Ok(dir.join(safe_name(torrent)?)),
+        (None, None) => Err(Error::Config(
+            "no output path given or download directory configured".to_string(),
+        )),
+    }
+}
+
+type CountryLookup = Box<dyn Fn(IpAddr) -> Option<String>>;
+
+#[cfg(feature = "geoip")]
+fn country_lookup(path: &Path) -> Result<CountryLookup> {
+    let geoip = crate::geoip::GeoIp::open(path)?;
+    Ok(Box::new(move |ip| geoip.country(ip)))
+}
+
+#[cfg(not(feature = "geoip"))]
+fn country_lookup(_path: &Path) -> Result<CountryLookup> {
+    Err(without_geoip())
+}
+
+#[cfg(not(feature = "geoip"))]
+fn without_geoip() -> Error {
+    Error::Config("built without the geoip feature".to_string())
+}
+
+fn log_filter_error(e: tracing_subscriber::filter::ParseError) -> Error {
+    Error::Config(format!("log filter: {}", e))
+}
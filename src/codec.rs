@@ -0,0 +1,173 @@
+// Framing for the peer wire protocol once the handshake is done: a 4-byte
+// length, then a message id and its payload, or nothing at all for a
+// keep-alive.
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::{Error, Result};
+
+// Enough for any bitfield we accept.
+pub const MAX_MESSAGE_LENGTH: u32 = 1 << 21; // 2 MiB
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    KeepAlive,
+    Message(Message),
+}
+
+impl Frame {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BytesMut::new();
+        // Encoding never fails; the limit only applies to what peers send.
+        PeerCodec.encode(self.clone(), &mut bytes).unwrap();
+        bytes.to_vec()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub id: MessageId,
+    // Shares the buffer the frame was read into, so blocks reach the piece
+    // they belong to without a copy on the way.
+    pub payload: Bytes,
+}
+
+impl Message {
+    pub fn new(id: MessageId, payload: impl Into<Bytes>) -> Self {
+        Self {
+            id,
+            payload: payload.into(),
+        }
+    }
+
+    // A Request or Cancel for one block.
+    pub fn block(id: MessageId, index: u32, begin: u32, length: u32) -> Self {
+        let payload = [
+            index.to_be_bytes(),
+            begin.to_be_bytes(),
+            length.to_be_bytes(),
+        ]
+        .concat();
+        Self::new(id, payload)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MessageId {
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    Port,
+    SuggestPiece,
+    HaveAll,
+    HaveNone,
+    RejectRequest,
+    AllowedFast,
+    Extension,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageId {
+    fn from(id: u8) -> Self {
+        match id {
+            0 => MessageId::Choke,
+            1 => MessageId::Unchoke,
+            2 => MessageId::Interested,
+            3 => MessageId::NotInterested,
+            4 => MessageId::Have,
+            5 => MessageId::Bitfield,
+            6 => MessageId::Request,
+            7 => MessageId::Piece,
+            8 => MessageId::Cancel,
+            9 => MessageId::Port,
+            13 => MessageId::SuggestPiece,
+            14 => MessageId::HaveAll,
+            15 => MessageId::HaveNone,
+            16 => MessageId::RejectRequest,
+            17 => MessageId::AllowedFast,
+            20 => MessageId::Extension,
+            id => MessageId::Unknown(id),
+        }
+    }
+}
+
+impl From<MessageId> for u8 {
+    fn from(id: MessageId) -> Self {
+        match id {
+            MessageId::Choke => 0,
+            MessageId::Unchoke => 1,
+            MessageId::Interested => 2,
+            MessageId::NotInterested => 3,
+            MessageId::Have => 4,
+            MessageId::Bitfield => 5,
+            MessageId::Request => 6,
+            MessageId::Piece => 7,
+            MessageId::Cancel => 8,
+            MessageId::Port => 9,
+            MessageId::SuggestPiece => 13,
+            MessageId::HaveAll => 14,
+            MessageId::HaveNone => 15,
+            MessageId::RejectRequest => 16,
+            MessageId::AllowedFast => 17,
+            MessageId::Extension => 20,
+            MessageId::Unknown(id) => id,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PeerCodec;
+
+impl Decoder for PeerCodec {
+    type Item = Frame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>> {
+        let Some(length) = src.get(..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(length.try_into().unwrap());
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(Error::Protocol(format!(
+                "{}-byte message exceeds the limit",
+                length
+            )));
+        }
+        let frame_len = 4 + length as usize;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Frame::KeepAlive));
+        }
+        let id = MessageId::from(src.get_u8());
+        let payload = src.split_to(length as usize - 1).freeze();
+        Ok(Some(Frame::Message(Message { id, payload })))
+    }
+}
+
+impl Encoder<Frame> for PeerCodec {
+    type Error = Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<()> {
+        match frame {
+            Frame::KeepAlive => dst.put_u32(0),
+            Frame::Message(message) => {
+                let length = 1 + message.payload.len();
+                dst.reserve(4 + length);
+                dst.put_u32(length as u32);
+                dst.put_u8(message.id.into());
+                dst.put_slice(&message.payload);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+// Settings that hold across runs, from a TOML file and BITTORRENT_*
+// environment variables. Every setting is optional; the environment wins
+// over the file, and whatever the caller merges on top (command-line flags)
+// wins over both.
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use url::Url;
+
+#[cfg(feature = "rss")]
+use crate::rss::FeedConfig;
+use crate::{
+    connections,
+    error::{Error, Result},
+    peer, ratelimit,
+    session::SessionConfig,
+};
+
+const ENV_PREFIX: &str = "BITTORRENT_";
+pub const DEFAULT_FEED_INTERVAL: u64 = 900;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_port: Option<u16>,
+    // Where downloads go when no output path is given.
+    pub download_dir: Option<PathBuf>,
+    // Bytes per second.
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
+    // Connections per download.
+    pub max_peers: Option<usize>,
+    // Starts every peer ID we make, e.g. an Azureus-style "-BR0001-".
+    pub peer_id_prefix: Option<String>,
+    // RSS or Atom feeds the daemon downloads matching items from.
+    pub feeds: Vec<Feed>,
+    // Seconds between polls of the feeds.
+    pub feed_interval: Option<u64>,
+    // A GeoLite2 country database for the peers the daemon and API report.
+    pub geoip: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Feed {
+    pub url: String,
+    // Regular expressions; items whose title matches one are downloaded.
+    // Without any, every item is.
+    pub filters: Vec<String>,
+    // Where the feed's items go, in place of `download_dir`.
+    pub download_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        dirs::config_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("bittorrent-rust")
+            .join("config.toml")
+    }
+
+    // A missing file is an empty config.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => text.parse(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::Storage(e)),
+        }
+    }
+
+    pub fn with_env(self) -> Result<Self> {
+        self.with_env_vars(std::env::vars())
+    }
+
+    // Like `with_env`, reading the variables from `vars`.
+    pub fn with_env_vars(self, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut overrides = Self::default();
+        for (name, value) in vars {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match key {
+                "LISTEN_PORT" => overrides.listen_port = Some(parse_var(&name, &value)?),
+                "DOWNLOAD_DIR" => overrides.download_dir = Some(PathBuf::from(value)),
+                "DOWNLOAD_RATE_LIMIT" => {
+                    overrides.download_rate_limit = Some(parse_var(&name, &value)?)
+                }
+                "UPLOAD_RATE_LIMIT" => {
+                    overrides.upload_rate_limit = Some(parse_var(&name, &value)?)
+                }
+                "MAX_PEERS" => overrides.max_peers = Some(parse_var(&name, &value)?),
+                "PEER_ID_PREFIX" => overrides.peer_id_prefix = Some(value),
+                "GEOIP" => overrides.geoip = Some(PathBuf::from(value)),
+                _ => {}
+            }
+        }
+        let merged = self.merge(overrides);
+        merged.validate()?;
+        Ok(merged)
+    }
+
+    // Settings in `overrides` replace ours; the rest are kept.
+    pub fn merge(self, overrides: Config) -> Self {
+        Self {
+            listen_port: overrides.listen_port.or(self.listen_port),
+            download_dir: overrides.download_dir.or(self.download_dir),
+            download_rate_limit: overrides.download_rate_limit.or(self.download_rate_limit),
+            upload_rate_limit: overrides.upload_rate_limit.or(self.upload_rate_limit),
+            max_peers: overrides.max_peers.or(self.max_peers),
+            peer_id_prefix: overrides.peer_id_prefix.or(self.peer_id_prefix),
+            feeds: match overrides.feeds.is_empty() {
+                true => self.feeds,
+                false => overrides.feeds,
+            },
+            feed_interval: overrides.feed_interval.or(self.feed_interval),
+            geoip: overrides.geoip.or(self.geoip),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(prefix) = &self.peer_id_prefix {
+            if !prefix.is_ascii() || prefix.len() > 20 {
+                return Err(Error::Config(format!(
+                    "peer ID prefix {:?} must be at most 20 ASCII characters",
+                    prefix
+                )));
+            }
+        }
+        for feed in &self.feeds {
+            Url::parse(&feed.url)
+                .map_err(|e| Error::Config(format!("feed {:?}: {}", feed.url, e)))?;
+            for filter in &feed.filters {
+                Regex::new(filter)
+                    .map_err(|e| Error::Config(format!("feed {:?}: {}", feed.url, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    // The feeds to watch, each with the directory its items go to.
+    #[cfg(feature = "rss")]
+    pub fn feed_configs(&self) -> Result<Vec<FeedConfig>> {
+        self.feeds
+            .iter()
+            .map(|feed| {
+                let download_dir = feed
+                    .download_dir
+                    .clone()
+                    .or_else(|| self.download_dir.clone())
+                    .ok_or_else(|| {
+                        Error::Config(format!("feed {:?} has no download directory", feed.url))
+                    })?;
+                let url = Url::parse(&feed.url).map_err(|e| Error::Config(e.to_string()))?;
+                let filters = feed
+                    .filters
+                    .iter()
+                    .map(|filter| Regex::new(filter).map_err(|e| Error::Config(e.to_string())))
+                    .collect::<Result<_>>()?;
+                Ok(FeedConfig {
+                    url,
+                    filters,
+                    download_dir,
+                })
+            })
+            .collect()
+    }
+
+    // Applies the settings that hold for the whole process. Call it before
+    // starting downloads.
+    pub fn apply(&self) {
+        peer::set_peer_id_prefix(self.peer_id_prefix.as_deref().unwrap_or_default());
+        ratelimit::set_download_limit(self.download_rate_limit);
+        ratelimit::set_upload_limit(self.upload_rate_limit);
+        connections::set_max_peers(self.max_peers.unwrap_or(connections::DEFAULT_MAX_PEERS));
+    }
+
+    pub fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            listen: self
+                .listen_port
+                .map(|port| SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))),
+            download_dir: self.download_dir.clone(),
+            max_peers: self.max_peers,
+            download_rate_limit: self.download_rate_limit,
+            upload_rate_limit: self.upload_rate_limit,
+            ..Default::default()
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text).map_err(|e| Error::Config(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::Config(format!("{}={:?} is not valid", name, value)))
+}
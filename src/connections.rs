@@ -0,0 +1,78 @@
+// How many peers a download keeps connected at once. Every connection holds
+// one of the download's slots from the moment we dial (or accept) it until
+// it is given up on; addresses found while every slot is taken wait in a
+// backlog until one frees up.
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_MAX_PEERS: usize = 50;
+
+// Applies to downloads started afterwards.
+static MAX_PEERS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_PEERS);
+
+pub fn set_max_peers(max_peers: usize) {
+    MAX_PEERS.store(max_peers.max(1), Ordering::Relaxed);
+}
+
+pub(crate) struct Connections {
+    slots: Arc<Semaphore>,
+    held: Mutex<HashMap<SocketAddr, OwnedSemaphorePermit>>,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self::new(MAX_PEERS.load(Ordering::Relaxed))
+    }
+}
+
+impl Connections {
+    pub(crate) fn new(max_peers: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max_peers.max(1))),
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Takes a slot for `address` if one is free and it holds none yet.
+    pub(crate) fn try_reserve(&self, address: SocketAddr) -> bool {
+        let mut held = self.held.lock().unwrap();
+        if held.contains_key(&address) {
+            return false;
+        }
+        match self.slots.clone().try_acquire_owned() {
+            Ok(permit) => {
+                held.insert(address, permit);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    // Waits for a free slot and gives it to `address`. Returns false if the
+    // address already holds one.
+    pub(crate) async fn reserve(&self, address: SocketAddr) -> bool {
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("connection slots are never closed");
+        let mut held = self.held.lock().unwrap();
+        if held.contains_key(&address) {
+            return false;
+        }
+        held.insert(address, permit);
+        true
+    }
+
+    pub(crate) fn release(&self, address: &SocketAddr) {
+        self.held.lock().unwrap().remove(address);
+    }
+}
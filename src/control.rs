@@ -0,0 +1,405 @@
+// The control socket a running client listens on so other processes can ask
+// what it is doing. Each request and response is one line of JSON over a
+// Unix socket, or a TCP socket on the loopback interface; a connection may
+// carry any number of requests. Clients running a session (the daemon) also
+// take requests to add, pause, resume and remove torrents.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+use tracing::warn;
+
+#[cfg(feature = "geoip")]
+use crate::geoip::GeoIp;
+use crate::{
+    download::{DownloadMonitor, Progress},
+    error::{Error, Result},
+    session::{Session, SessionStats},
+    source::Source,
+    store::SessionStore,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    // All torrents, or those whose info hash starts with `id`.
+    Status {
+        id: Option<String>,
+    },
+    // Addresses of the peers connected for one torrent.
+    Peers {
+        id: String,
+    },
+    // A torrent file, URL, magnet link or info hash, as on the command line.
+    // Paths are read by the client, so should be absolute.
+    Add {
+        source: String,
+        output: Option<PathBuf>,
+    },
+    // `id` is the info hash, or enough of its start to name one torrent.
+    Pause {
+        id: String,
+    },
+    Resume {
+        id: String,
+    },
+    Remove {
+        id: String,
+    },
+    Stats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Status { torrents: Vec<TorrentStatus> },
+    Peers { peers: Vec<PeerStatus> },
+    Added { id: String },
+    Done,
+    Stats { stats: SessionStats },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    Downloading,
+    Paused,
+    Seeding,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub id: String, // hex info hash
+    pub name: String,
+    pub state: TorrentState,
+    pub progress: Progress,
+    pub ratio: f64,
+    pub tracker: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub address: SocketAddr,
+    // ISO 3166-1 alpha-2 code, when the registry has a GeoIP database that
+    // knows the address.
+    #[cfg(feature = "geoip")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+}
+
+pub fn default_socket() -> PathBuf {
+    SessionStore::default_dir().join("control.sock")
+}
+
+struct Entry {
+    name: String,
+    monitor: DownloadMonitor,
+}
+
+// The downloads a control socket reports on: those inserted by hand, or a
+// session's.
+#[derive(Clone, Default)]
+pub struct Registry {
+    torrents: Arc<Mutex<BTreeMap<String, Entry>>>,
+    session: Option<Session>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<GeoIp>>,
+}
+
+impl Registry {
+    pub fn for_session(session: Session) -> Self {
+        Self {
+            session: Some(session),
+            ..Self::default()
+        }
+    }
+
+    // Looks up the country of each peer it reports in `geoip`.
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(Arc::new(geoip));
+        self
+    }
+
+    pub fn insert(&self, info_hash: [u8; 20], name: &str, monitor: DownloadMonitor) {
+        let entry = Entry {
+            name: name.to_string(),
+            monitor,
+        };
+        self.torrents
+            .lock()
+            .unwrap()
+            .insert(hex::encode(info_hash), entry);
+    }
+
+    pub fn remove(&self, info_hash: [u8; 20]) {
+        self.torrents
+            .lock()
+            .unwrap()
+            .remove(&hex::encode(info_hash));
+    }
+
+    // The torrents, brought up to date with the session's if there is one.
+    fn torrents(&self) -> MutexGuard<'_, BTreeMap<String, Entry>> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(session) = &self.session {
+            *torrents = session
+                .torrents()
+                .into_iter()
+                .map(|torrent| {
+                    let entry = Entry {
+                        name: torrent.name,
+                        monitor: torrent.monitor,
+                    };
+                    (hex::encode(torrent.info_hash), entry)
+                })
+                .collect();
+        }
+        torrents
+    }
+
+    pub fn status(&self, id: Option<&str>) -> Result<Vec<TorrentStatus>> {
+        let torrents = self.torrents();
+        let id = id.map(str::to_ascii_lowercase);
+        let statuses: Vec<_> = torrents
+            .iter()
+            .filter(|(hash, _)| id.as_ref().is_none_or(|id| hash.starts_with(id)))
+            .map(|(hash, entry)| status(hash, entry))
+            .collect();
+        match id {
+            Some(id) if statuses.is_empty() => {
+                Err(Error::Protocol(format!("no torrent matches {}", id)))
+            }
+            _ => Ok(statuses),
+        }
+    }
+
+    // The peers connected for the one torrent whose info hash starts with
+    // `id`.
+    pub fn peers(&self, id: &str) -> Result<Vec<PeerStatus>> {
+        let id = id.to_ascii_lowercase();
+        let torrents = self.torrents();
+        let mut matches = torrents.iter().filter(|(hash, _)| hash.starts_with(&id));
+        match (matches.next(), matches.next()) {
+            (Some((_, entry)), None) => Ok(entry
+                .monitor
+                .peers()
+                .into_iter()
+                .map(|address| self.peer_status(address))
+                .collect()),
+            (None, _) => Err(Error::Protocol(format!("no torrent matches {}", id))),
+            (Some(_), Some(_)) => Err(Error::Protocol(format!(
+                "{} matches more than one torrent",
+                id
+            ))),
+        }
+    }
+
+    fn peer_status(&self, address: SocketAddr) -> PeerStatus {
+        PeerStatus {
+            address,
+            #[cfg(feature = "geoip")]
+            country: self
+                .geoip
+                .as_ref()
+                .and_then(|geoip| geoip.country(address.ip())),
+        }
+    }
+
+    pub(crate) async fn handle(&self, request: ControlRequest) -> ControlResponse {
+        let response = match request {
+            ControlRequest::Status { id } => self
+                .status(id.as_deref())
+                .map(|torrents| ControlResponse::Status { torrents }),
+            ControlRequest::Peers { id } => self
+                .peers(&id)
+                .map(|peers| ControlResponse::Peers { peers }),
+            request => self.manage(request).await,
+        };
+        response.unwrap_or_else(|e| ControlResponse::Error {
+            message: e.to_string(),
+        })
+    }
+
+    async fn manage(&self, request: ControlRequest) -> Result<ControlResponse> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or_else(|| Error::Protocol("this client only reports status".to_string()))?;
+        match request {
+            ControlRequest::Status { .. } | ControlRequest::Peers { .. } => {
+                unreachable!("answered by `handle`")
+            }
+            ControlRequest::Add { source, output } => {
+                let source: Source = source.parse()?;
+                // The download runs on in the session once its handle is
+                // dropped.
+                let (info_hash, _) = session.add_source(&source, output).await?;
+                Ok(ControlResponse::Added {
+                    id: hex::encode(info_hash),
+                })
+            }
+            ControlRequest::Pause { id } => {
+                session.pause(&session.find(&id)?);
+                Ok(ControlResponse::Done)
+            }
+            ControlRequest::Resume { id } => {
+                session.resume(&session.find(&id)?);
+                Ok(ControlResponse::Done)
+            }
+            ControlRequest::Remove { id } => {
+                session.remove(&session.find(&id)?);
+                Ok(ControlResponse::Done)
+            }
+            ControlRequest::Stats => Ok(ControlResponse::Stats {
+                stats: session.stats(),
+            }),
+        }
+    }
+}
+
+fn status(hash: &str, entry: &Entry) -> TorrentStatus {
+    let progress = entry.monitor.progress();
+    let state = if entry.monitor.is_paused() {
+        TorrentState::Paused
+    } else if progress.total_pieces > 0 && progress.pieces_done == progress.total_pieces {
+        TorrentState::Seeding
+    } else {
+        TorrentState::Downloading
+    };
+    let ratio = match progress.bytes_done {
+        0 => 0.0,
+        done => progress.bytes_uploaded as f64 / done as f64,
+    };
+    TorrentStatus {
+        id: hash.to_string(),
+        name: entry.name.clone(),
+        state,
+        progress,
+        ratio,
+        tracker: entry.monitor.tracker_status(),
+    }
+}
+
+// Binds the socket and answers requests until the task is dropped. A socket
+// file left behind by a client that exited uncleanly is replaced; one that
+// still has a listener is an error.
+pub async fn serve(path: &Path, registry: Registry) -> Result<()> {
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another client", path.display()),
+        )));
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::Storage(e)),
+        _ => {}
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(Error::Storage)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(answering(reader, writer, registry.clone()));
+    }
+}
+
+// Like `serve`, on a TCP socket. Anyone who can connect can add and remove
+// torrents, so only loopback addresses are allowed.
+pub async fn serve_tcp(address: SocketAddr, registry: Registry) -> Result<()> {
+    if !address.ip().is_loopback() {
+        return Err(Error::Protocol(format!(
+            "control socket {} is not on the loopback interface",
+            address
+        )));
+    }
+    let listener = TcpListener::bind(address).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+        tokio::spawn(answering(reader, writer, registry.clone()));
+    }
+}
+
+async fn answering(
+    reader: impl AsyncRead + Unpin,
+    writer: impl AsyncWrite + Unpin,
+    registry: Registry,
+) {
+    if let Err(e) = answer(reader, writer, registry).await {
+        warn!("control: {}", e);
+    }
+}
+
+async fn answer(
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    registry: Registry,
+) -> Result<()> {
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => registry.handle(request).await,
+            Err(e) => ControlResponse::Error {
+                message: format!("bad request: {}", e),
+            },
+        };
+        writer.write_all(&to_line(&response)?).await?;
+    }
+    Ok(())
+}
+
+// Sends one request to the client listening on `path`.
+pub async fn request(path: &Path, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = UnixStream::connect(path).await.map_err(|e| {
+        Error::Io(io::Error::new(
+            e.kind(),
+            format!("no client listening on {}: {}", path.display(), e),
+        ))
+    })?;
+    let (reader, writer) = stream.into_split();
+    exchange(reader, writer, request).await
+}
+
+// Sends one request to the client listening on `address`.
+pub async fn request_tcp(address: SocketAddr, request: &ControlRequest) -> Result<ControlResponse> {
+    let stream = TcpStream::connect(address).await.map_err(|e| {
+        Error::Io(io::Error::new(
+            e.kind(),
+            format!("no client listening on {}: {}", address, e),
+        ))
+    })?;
+    let (reader, writer) = stream.into_split();
+    exchange(reader, writer, request).await
+}
+
+async fn exchange(
+    reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    request: &ControlRequest,
+) -> Result<ControlResponse> {
+    writer.write_all(&to_line(request)?).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| Error::Protocol("control socket closed".to_string()))?;
+    serde_json::from_str(&line).map_err(|e| Error::Protocol(e.to_string()))
+}
+
+fn to_line(message: &impl Serialize) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(message).map_err(|e| Error::Protocol(e.to_string()))?;
+    line.push(b'\n');
+    Ok(line)
+}
@@ -0,0 +1,180 @@
+// Building .torrent files from a file or a directory. Pieces are hashed
+// straight through the files in order, spanning file boundaries the way
+// `storage::Layout` maps them back.
+use sha1::{Digest, Sha1};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Error, Result},
+    torrent::{Info, Torrent},
+};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+// About how many pieces the automatic piece length aims for.
+const TARGET_PIECES: u64 = 1500;
+
+// A power of two giving about `TARGET_PIECES` pieces, between 16 KiB and
+// 16 MiB.
+pub fn auto_piece_length(total_length: u64) -> u64 {
+    (total_length / TARGET_PIECES)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+pub struct TorrentCreator {
+    path: PathBuf,
+    announce: String,
+    piece_length: Option<u64>,
+    comment: Option<String>,
+    private: bool,
+}
+
+impl TorrentCreator {
+    pub fn new(path: PathBuf, announce: &str) -> Self {
+        Self {
+            path,
+            announce: announce.to_string(),
+            piece_length: None,
+            comment: None,
+            private: false,
+        }
+    }
+
+    pub fn with_piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn with_comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    // Hashes the files, which is most of the work.
+    pub fn info(&self) -> Result<Info> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| Error::Metadata(format!("{} has no name", self.path.display())))?;
+        let paths = match self.path.is_dir() {
+            true => files_under(&self.path)?,
+            false => vec![self.path.clone()],
+        };
+        if paths.is_empty() {
+            return Err(Error::Metadata(format!(
+                "{} has no files",
+                self.path.display()
+            )));
+        }
+        let total_length = paths
+            .iter()
+            .map(|path| Ok(fs::metadata(path).map_err(Error::Storage)?.len()))
+            .sum::<Result<u64>>()?;
+        let piece_length = self
+            .piece_length
+            .unwrap_or_else(|| auto_piece_length(total_length));
+        if !(MIN_PIECE_LENGTH..=u32::MAX as u64).contains(&piece_length) {
+            return Err(Error::Metadata(format!(
+                "piece length {} is out of range",
+                piece_length
+            )));
+        }
+
+        let mut pieces = Vec::new();
+        let mut piece = Vec::with_capacity(piece_length as usize);
+        let mut files = Vec::new();
+        for path in &paths {
+            let mut file = File::open(path).map_err(Error::Storage)?;
+            let mut length = 0;
+            loop {
+                let remaining = piece_length - piece.len() as u64;
+                length += (&mut file)
+                    .take(remaining)
+                    .read_to_end(&mut piece)
+                    .map_err(Error::Storage)? as u64;
+                if piece.len() as u64 != piece_length {
+                    break;
+                }
+                pieces.push(Sha1::digest(&piece).into());
+                piece.clear();
+            }
+            files.push((file_path(&self.path, path)?, length));
+        }
+        if !piece.is_empty() {
+            pieces.push(Sha1::digest(&piece).into());
+        }
+        Ok(Info::new(name, piece_length, pieces, files).with_private(self.private))
+    }
+
+    pub fn torrent(&self) -> Result<Torrent> {
+        let creation_date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+        Ok(Torrent {
+            announce: self.announce.clone(),
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+            comment: self.comment.clone(),
+            created_by: Some(
+                concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_string(),
+            ),
+            creation_date: Some(creation_date),
+            encoding: None,
+            info: self.info()?,
+            piece_layers: BTreeMap::new(),
+        })
+    }
+
+    // The bencoded metainfo.
+    pub fn build(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(&self.torrent()?)?)
+    }
+}
+
+// Regular files below `dir`, in a stable order.
+fn files_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(Error::Storage)?
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(Error::Storage)?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut files = Vec::new();
+    for entry in entries {
+        let file_type = entry.file_type().map_err(Error::Storage)?;
+        if file_type.is_dir() {
+            files.extend(files_under(&entry.path())?);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+// The path of `file` below `root` as metainfo lists it; empty for a
+// single-file torrent.
+fn file_path(root: &Path, file: &Path) -> Result<Vec<String>> {
+    let relative = file.strip_prefix(root).unwrap_or(Path::new(""));
+    relative
+        .iter()
+        .map(|component| {
+            component
+                .to_str()
+                .map(str::to_string)
+                .ok_or_else(|| Error::Metadata(format!("{} is not valid UTF-8", file.display())))
+        })
+        .collect()
+}
@@ -1,30 +1,81 @@
-pub fn decode_bencoded_value(encoded_value: &str) -> anyhow::Result<serde_json::Value> {
-    let value = serde_bencode::from_str(encoded_value)?;
-    let decoded = bencode_to_json(value)?;
-    Ok(decoded)
+use data_encoding::{BASE64, HEXLOWER};
+
+use crate::{
+    bencode::{self, Value},
+    error::Result,
+};
+
+// How byte strings that aren't UTF-8, like piece hashes or compact peers,
+// are rendered in JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum BytesFormat {
+    #[default]
+    Hex,
+    Base64,
 }
 
-fn bencode_to_json(value: serde_bencode::value::Value) -> anyhow::Result<serde_json::Value> {
-    match value {
-        serde_bencode::value::Value::Bytes(b) => {
-            Ok(serde_json::Value::String(String::from_utf8(b)?))
-        }
-        serde_bencode::value::Value::Int(i) => {
-            Ok(serde_json::Value::Number(serde_json::Number::from(i)))
-        }
-        serde_bencode::value::Value::List(l) => {
-            let json_list = l
-                .into_iter()
-                .map(|v| bencode_to_json(v))
-                .collect::<anyhow::Result<Vec<serde_json::Value>>>()?;
-            Ok(serde_json::Value::Array(json_list))
+impl BytesFormat {
+    fn render(self, bytes: Vec<u8>) -> String {
+        match String::from_utf8(bytes) {
+            Ok(s) => s,
+            Err(e) => match self {
+                BytesFormat::Hex => HEXLOWER.encode(e.as_bytes()),
+                BytesFormat::Base64 => BASE64.encode(e.as_bytes()),
+            },
         }
-        serde_bencode::value::Value::Dict(d) => {
-            let json_map = d
-                .into_iter()
-                .map(|(k, v)| Ok((String::from_utf8(k)?, bencode_to_json(v)?)))
-                .collect::<anyhow::Result<serde_json::Map<String, serde_json::Value>>>()?;
-            Ok(serde_json::Value::Object(json_map))
+    }
+}
+
+pub fn decode_bencoded_value(encoded_value: &str) -> Result<serde_json::Value> {
+    decode_bencoded_bytes(encoded_value.as_bytes(), BytesFormat::default())
+}
+
+pub fn decode_bencoded_bytes(encoded: &[u8], format: BytesFormat) -> Result<serde_json::Value> {
+    let value = bencode::decode(encoded)?;
+    Ok(bencode_to_json(value, format))
+}
+
+pub fn encode_json_value(json: &str) -> Result<Vec<u8>> {
+    let value = json_to_bencode(serde_json::from_str(json)?)?;
+    bencode::encode(&value)
+}
+
+// Bencode has no booleans, floats or null to map JSON's onto; strings become
+// their UTF-8 bytes.
+fn json_to_bencode(value: serde_json::Value) -> Result<Value> {
+    let unsupported =
+        |kind: &str| serde_bencode::Error::Custom(format!("bencode has no {}", kind)).into();
+    match value {
+        serde_json::Value::String(s) => Ok(Value::Bytes(s.into_bytes())),
+        serde_json::Value::Number(n) => n
+            .as_i64()
+            .map(Value::Int)
+            .ok_or_else(|| unsupported("floats")),
+        serde_json::Value::Array(a) => Ok(Value::List(
+            a.into_iter().map(json_to_bencode).collect::<Result<_>>()?,
+        )),
+        serde_json::Value::Object(o) => Ok(Value::Dict(
+            o.into_iter()
+                .map(|(k, v)| Ok((k.into_bytes(), json_to_bencode(v)?)))
+                .collect::<Result<_>>()?,
+        )),
+        serde_json::Value::Bool(_) => Err(unsupported("booleans")),
+        serde_json::Value::Null => Err(unsupported("null")),
+    }
+}
+
+fn bencode_to_json(value: Value, format: BytesFormat) -> serde_json::Value {
+    match value {
+        Value::Bytes(b) => serde_json::Value::String(format.render(b)),
+        Value::Int(i) => serde_json::Value::Number(serde_json::Number::from(i)),
+        Value::List(l) => {
+            serde_json::Value::Array(l.into_iter().map(|v| bencode_to_json(v, format)).collect())
         }
+        Value::Dict(d) => serde_json::Value::Object(
+            d.into_iter()
+                .map(|(k, v)| (format.render(k), bencode_to_json(v, format)))
+                .collect(),
+        ),
     }
 }
@@ -0,0 +1,163 @@
+// Cross-torrent reuse of identical content (BEP 38). Completed downloads are
+// registered with a `Library`; later downloads link whole files that match
+// and copy any piece whose hash is already on disk instead of fetching it.
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{
+    download::{download_pieces_to, DownloadHandle},
+    error::{Error, Result},
+    torrent::{Info, Torrent},
+};
+
+#[derive(Clone)]
+struct Completed {
+    info_hash: [u8; 20],
+    info: Info,
+    path: PathBuf,
+}
+
+#[derive(Clone, Default)]
+pub struct Library {
+    completed: Arc<Mutex<Vec<Completed>>>,
+}
+
+impl Library {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, info: Info, path: PathBuf) -> Result<()> {
+        let info_hash = info.info_hash()?;
+        let mut completed = self.completed.lock().unwrap();
+        completed.retain(|c| c.info_hash != info_hash);
+        completed.push(Completed {
+            info_hash,
+            info,
+            path,
+        });
+        Ok(())
+    }
+
+    pub fn contains(&self, info_hash: &[u8; 20]) -> bool {
+        let completed = self.completed.lock().unwrap();
+        completed.iter().any(|c| &c.info_hash == info_hash)
+    }
+
+    // Completed torrents ordered by how likely they are to share content
+    // with `info`: ones it names as similar, then shared collections.
+    fn candidates(&self, info: &Info) -> Vec<Completed> {
+        let similar: HashSet<_> = info.similar().collect();
+        let collections: HashSet<_> = info.collections.iter().flatten().collect();
+        let mut candidates = self.completed.lock().unwrap().clone();
+        candidates.sort_by_key(|c| {
+            if similar.contains(&c.info_hash) {
+                0
+            } else if c
+                .info
+                .collections
+                .iter()
+                .flatten()
+                .any(|name| collections.contains(name))
+            {
+                1
+            } else {
+                2
+            }
+        });
+        candidates
+    }
+
+    fn identical(&self, info: &Info) -> Option<PathBuf> {
+        self.candidates(info)
+            .into_iter()
+            .find(|c| {
+                c.info.piece_length == info.piece_length
+                    && c.info.file_len() == info.file_len()
+                    && c.info.pieces == info.pieces
+                    // v2-only torrents have no v1 hashes to tell them apart.
+                    && c.info.v2_files() == info.v2_files()
+            })
+            .map(|c| c.path)
+    }
+
+    // Reads every piece of `info` whose hash matches a piece of a completed
+    // torrent with the same piece length, verifying it on the way.
+    pub async fn reuse_pieces(&self, info: &Info) -> BTreeMap<usize, Vec<u8>> {
+        let mut known = BTreeMap::new();
+        for candidate in self.candidates(info) {
+            if candidate.info.piece_length != info.piece_length {
+                continue;
+            }
+            let Ok(mut file) = File::open(&candidate.path).await else {
+                continue;
+            };
+            let sources: HashMap<[u8; 20], usize> = candidate
+                .info
+                .piece_infos()
+                .map(|(index, hash, _)| (hash, index))
+                .collect();
+            for (index, hash, length) in info.piece_infos() {
+                let Some(&source) = sources.get(&hash) else {
+                    continue;
+                };
+                if known.contains_key(&index) || candidate.info.piece_len(source) != length {
+                    continue;
+                }
+                let offset = source as u64 * info.piece_length;
+                if let Ok(data) = read_piece(&mut file, offset, length).await {
+                    if <[u8; 20]>::from(Sha1::digest(&data)) == hash {
+                        known.insert(index, data);
+                    }
+                }
+            }
+        }
+        known
+    }
+
+    // Downloads `torrent` into `path`, taking whatever the library already
+    // has locally, and adds the result to the library once complete.
+    pub fn download_to(&self, torrent: &Torrent, path: PathBuf) -> DownloadHandle<()> {
+        let torrent = torrent.clone();
+        let library = self.clone();
+        DownloadHandle::spawn(|ctx| async move {
+            if let Some(existing) = library.identical(&torrent.info) {
+                link_or_copy(&existing, &path).await?;
+                ctx.mark_complete(&torrent.info);
+            } else {
+                let known = library.reuse_pieces(&torrent.info).await;
+                let peer_piece_map = if known.len() < torrent.info.piece_count() {
+                    torrent.connect_peers(&ctx).await?
+                } else {
+                    HashMap::new()
+                };
+                download_pieces_to(&torrent.info, peer_piece_map, known, &ctx, path.clone())
+                    .await?;
+            }
+            library.add(torrent.info, path)
+        })
+    }
+}
+
+async fn read_piece(file: &mut File, offset: u64, length: u32) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; length as usize];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+async fn link_or_copy(existing: &Path, path: &Path) -> Result<()> {
+    if fs::hard_link(existing, path).await.is_err() {
+        fs::copy(existing, path).await.map_err(Error::Storage)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,706 @@
+// Mainline DHT (BEP 5): finding peers without a tracker. Nodes form a
+// Kademlia network keyed by 160-bit ids; a lookup walks towards the nodes
+// whose ids are closest to an info hash, which are the ones peers announce
+// themselves to. Messages are bencoded KRPC dictionaries over UDP. Only IPv4
+// nodes are used, and like every other UDP transport the DHT stays off while
+// Tor is enabled.
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    task::{JoinHandle, JoinSet},
+    time,
+};
+use tracing::debug;
+
+use crate::{
+    bencode::{self, Value},
+    error::{Error, Result},
+    tor,
+    tracker::Announce,
+};
+
+pub const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+// Nodes per routing table bucket, and how many of the closest nodes a lookup
+// has to hear from before it stops.
+const K: usize = 8;
+// Queries a lookup keeps in flight.
+const ALPHA: usize = 3;
+// Gives up on lookups that keep finding new nodes without converging.
+const MAX_QUERIES: usize = 256;
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// A whole search, bootstrap included.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(20);
+const MAX_PACKET: usize = 2048;
+// Bootstrap nodes given plus ones peers told us about.
+const MAX_BOOTSTRAP_NODES: usize = 64;
+
+pub type NodeId = [u8; 20];
+
+static BOOTSTRAP: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+// Searches join every announce from now on, starting from `bootstrap`
+// (`host:port` strings).
+pub fn enable(bootstrap: Vec<String>) {
+    *BOOTSTRAP.lock().unwrap() = Some(bootstrap);
+}
+
+pub fn disable() {
+    *BOOTSTRAP.lock().unwrap() = None;
+}
+
+pub fn enabled() -> bool {
+    BOOTSTRAP.lock().unwrap().is_some() && !tor::enabled()
+}
+
+// Adds a node a peer told us about (BEP 5 PORT message) to where searches
+// start, while the DHT is enabled.
+pub fn add_node(address: SocketAddr) {
+    if let Some(bootstrap) = BOOTSTRAP.lock().unwrap().as_mut() {
+        let node = address.to_string();
+        if bootstrap.len() < MAX_BOOTSTRAP_NODES && !bootstrap.contains(&node) {
+            bootstrap.push(node);
+        }
+    }
+}
+
+pub fn bootstrap_nodes() -> Vec<String> {
+    BOOTSTRAP.lock().unwrap().clone().unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+// Nodes sorted into buckets by how many leading bits their id shares with
+// ours, so we know many nodes near us and a few far away.
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own, id);
+        let byte = distance.iter().position(|&byte| byte != 0)?;
+        Some(byte * 8 + distance[byte].leading_zeros() as usize)
+    }
+
+    // A node seen again moves to the back of its bucket. Full buckets keep
+    // the nodes they have, since nodes that have been up long tend to stay up.
+    pub fn insert(&mut self, node: Node) {
+        let Some(index) = self.bucket(&node.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|known| known.id == node.id) {
+            bucket.remove(position);
+            bucket.push(node);
+        } else if bucket.len() < K {
+            bucket.push(node);
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket(id) {
+            self.buckets[index].retain(|node| node.id != *id);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// A node's answer to `get_peers`: peers if it knows any, otherwise nodes
+// closer to the info hash, and a token for announcing to it.
+#[derive(Debug, Clone, Default)]
+pub struct GetPeers {
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddr>,
+    pub nodes: Vec<Node>,
+}
+
+type Pending = HashMap<Vec<u8>, oneshot::Sender<Result<Value>>>;
+
+struct Inner {
+    id: NodeId,
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<Pending>,
+    next_transaction: AtomicU16,
+    // Peers announced to us, by info hash.
+    announced: Mutex<HashMap<[u8; 20], Vec<SocketAddr>>>,
+    // Tokens are a hash of this and the asker's address, so they can be
+    // checked without remembering who was given one.
+    secret: [u8; 20],
+}
+
+// A DHT node. It answers other nodes' queries for as long as it is alive.
+pub struct Dht {
+    inner: Arc<Inner>,
+    task: JoinHandle<()>,
+}
+
+impl Dht {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        let socket = UdpSocket::bind(address).await?;
+        let id: NodeId = rand::thread_rng().gen();
+        let inner = Arc::new(Inner {
+            id,
+            socket,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+            announced: Mutex::new(HashMap::new()),
+            secret: rand::thread_rng().gen(),
+        });
+        let task = tokio::spawn(inner.clone().receive());
+        Ok(Self { inner, task })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.inner.id
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.inner.socket.local_addr()?)
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.inner.table.lock().unwrap().len()
+    }
+
+    pub async fn ping(&self, address: SocketAddr) -> Result<NodeId> {
+        self.inner.ping(address).await
+    }
+
+    pub async fn find_node(&self, address: SocketAddr, target: NodeId) -> Result<Vec<Node>> {
+        self.inner.find_node(address, target).await
+    }
+
+    pub async fn get_peers(&self, address: SocketAddr, info_hash: [u8; 20]) -> Result<GetPeers> {
+        self.inner.get_peers(address, info_hash).await
+    }
+
+    pub async fn announce_peer(
+        &self,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        self.inner
+            .announce_peer(address, info_hash, port, token)
+            .await
+    }
+
+    // Fills the routing table from the given `host:port` nodes and whatever
+    // nodes near us they know.
+    pub async fn bootstrap(&self, nodes: &[String]) -> Result<()> {
+        let mut addresses = Vec::new();
+        for node in nodes {
+            match tokio::net::lookup_host(node.as_str()).await {
+                Ok(resolved) => addresses.extend(resolved.filter(SocketAddr::is_ipv4)),
+                Err(e) => debug!(%node, "DHT: {}", e),
+            }
+        }
+        let mut join_set = JoinSet::new();
+        for address in addresses {
+            let inner = self.inner.clone();
+            join_set.spawn(async move { inner.find_node(address, inner.id).await });
+        }
+        while join_set.join_next().await.is_some() {}
+        match self.nodes() {
+            0 => Err(Error::Protocol(
+                "DHT: no bootstrap node answered".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Walks towards `info_hash`, collecting the peers nodes hand out on the
+    // way.
+    pub async fn find_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash).await.0
+    }
+
+    // Finds the nodes closest to `info_hash` and tells them we are a peer
+    // listening on `port`. Returns how many accepted the announce.
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> usize {
+        let (_, responders) = self.lookup(info_hash).await;
+        let mut accepted = 0;
+        for (node, token) in responders.into_iter().take(K) {
+            match self
+                .announce_peer(node.address, info_hash, port, token)
+                .await
+            {
+                Ok(()) => accepted += 1,
+                Err(e) => debug!(node = %node.address, "DHT: {}", e),
+            }
+        }
+        accepted
+    }
+
+    // An iterative `get_peers` lookup. Returns the peers found and the
+    // nodes that answered with a token, closest first.
+    async fn lookup(&self, info_hash: [u8; 20]) -> (Vec<SocketAddr>, Vec<(Node, Vec<u8>)>) {
+        let mut candidates: BTreeMap<NodeId, Node> = self
+            .inner
+            .table
+            .lock()
+            .unwrap()
+            .closest(&info_hash, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, &info_hash), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut peers = Vec::new();
+        let mut responders = BTreeMap::new();
+        let mut join_set = JoinSet::new();
+        loop {
+            while join_set.len() < ALPHA && queried.len() < MAX_QUERIES {
+                let next = candidates
+                    .values()
+                    .take(K)
+                    .find(|node| !queried.contains(&node.address))
+                    .copied();
+                let Some(node) = next else {
+                    break;
+                };
+                queried.insert(node.address);
+                let inner = self.inner.clone();
+                join_set
+                    .spawn(async move { (node, inner.get_peers(node.address, info_hash).await) });
+            }
+            let Some(joined) = join_set.join_next().await else {
+                break;
+            };
+            let Ok((node, reply)) = joined else {
+                continue;
+            };
+            let key = distance(&node.id, &info_hash);
+            match reply {
+                Ok(reply) => {
+                    for peer in reply.peers {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                    for found in reply
+                        .nodes
+                        .into_iter()
+                        .filter(|found| found.id != self.id())
+                    {
+                        candidates
+                            .entry(distance(&found.id, &info_hash))
+                            .or_insert(found);
+                    }
+                    if let Some(token) = reply.token {
+                        responders.insert(key, (node, token));
+                    }
+                }
+                Err(_) => {
+                    candidates.remove(&key);
+                    self.inner.table.lock().unwrap().remove(&node.id);
+                }
+            }
+        }
+        (peers, responders.into_values().collect())
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Inner {
+    async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_PACKET];
+        loop {
+            let Ok((len, from)) = self.socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            let Ok(message) = bencode::decode(&buf[..len]) else {
+                continue;
+            };
+            let Some(transaction) = message.get("t").and_then(Value::as_bytes) else {
+                continue;
+            };
+            match message.get("y").and_then(Value::as_bytes) {
+                Some(b"q") => {
+                    let reply = self.answer(from, &message);
+                    let reply = dict([
+                        ("t", Value::Bytes(transaction.to_vec())),
+                        ("y", Value::Bytes(reply.0.as_bytes().to_vec())),
+                        (reply.0, reply.1),
+                    ]);
+                    if let Ok(reply) = bencode::encode(&reply) {
+                        let _ = self.socket.send_to(&reply, from).await;
+                    }
+                }
+                Some(b"r") | Some(b"e") => {
+                    let waiting = self.pending.lock().unwrap().remove(transaction);
+                    if let Some(waiting) = waiting {
+                        let _ = waiting.send(response(&message));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Returns the reply kind ("r" or "e") and its body.
+    fn answer(&self, from: SocketAddr, message: &Value) -> (&'static str, Value) {
+        let query = message
+            .get("q")
+            .and_then(Value::as_bytes)
+            .unwrap_or_default();
+        let Some(args) = message.get("a") else {
+            return error(203, "missing arguments");
+        };
+        let Some(sender) = args.get("id").and_then(node_id) else {
+            return error(203, "missing node id");
+        };
+        if from.is_ipv4() {
+            self.table.lock().unwrap().insert(Node {
+                id: sender,
+                address: from,
+            });
+        }
+        let own = ("id", Value::Bytes(self.id.to_vec()));
+        match query {
+            b"ping" => ("r", dict([own])),
+            b"find_node" => {
+                let Some(target) = args.get("target").and_then(node_id) else {
+                    return error(203, "missing target");
+                };
+                ("r", dict([own, ("nodes", self.closest_nodes(&target))]))
+            }
+            b"get_peers" => {
+                let Some(info_hash) = args.get("info_hash").and_then(node_id) else {
+                    return error(203, "missing info_hash");
+                };
+                let token = ("token", Value::Bytes(self.token(from.ip())));
+                let announced = self.announced.lock().unwrap().get(&info_hash).cloned();
+                let found = match announced {
+                    Some(peers) => {
+                        let peers = peers.iter().map(|peer| Value::Bytes(compact(peer)));
+                        ("values", Value::List(peers.collect()))
+                    }
+                    None => ("nodes", self.closest_nodes(&info_hash)),
+                };
+                ("r", dict([own, token, found]))
+            }
+            b"announce_peer" => {
+                let info_hash = args.get("info_hash").and_then(node_id);
+                let token = args.get("token").and_then(Value::as_bytes);
+                let (Some(info_hash), Some(token)) = (info_hash, token) else {
+                    return error(203, "missing info_hash or token");
+                };
+                if token != self.token(from.ip()) {
+                    return error(203, "bad token");
+                }
+                let implied = args.get("implied_port").and_then(Value::as_int) == Some(1);
+                let port = match (implied, args.get("port").and_then(Value::as_int)) {
+                    (true, _) => from.port(),
+                    (false, Some(port)) => match u16::try_from(port) {
+                        Ok(port) => port,
+                        Err(_) => return error(203, "bad port"),
+                    },
+                    (false, None) => return error(203, "missing port"),
+                };
+                let peer = SocketAddr::new(from.ip(), port);
+                let mut announced = self.announced.lock().unwrap();
+                let peers = announced.entry(info_hash).or_default();
+                if !peers.contains(&peer) {
+                    peers.push(peer);
+                }
+                ("r", dict([own]))
+            }
+            _ => error(204, "method unknown"),
+        }
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> Value {
+        let nodes = self.table.lock().unwrap().closest(target, K);
+        Value::Bytes(nodes.iter().flat_map(compact_node).collect())
+    }
+
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        Sha1::digest([self.secret.as_slice(), &ip].concat())[..8].to_vec()
+    }
+
+    async fn query(
+        &self,
+        address: SocketAddr,
+        method: &str,
+        args: impl IntoIterator<Item = (&'static str, Value)>,
+    ) -> Result<Value> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let mut args: BTreeMap<Vec<u8>, Value> = args
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect();
+        args.insert(b"id".to_vec(), Value::Bytes(self.id.to_vec()));
+        let message = bencode::encode(&dict([
+            ("t", Value::Bytes(transaction.clone())),
+            ("y", Value::Bytes(b"q".to_vec())),
+            ("q", Value::Bytes(method.as_bytes().to_vec())),
+            ("a", Value::Dict(args)),
+        ]))?;
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), sender);
+        let reply = async {
+            self.socket.send_to(&message, address).await?;
+            receiver
+                .await
+                .map_err(|_| Error::Protocol("DHT node stopped".to_string()))?
+        };
+        let reply = time::timeout(QUERY_TIMEOUT, reply).await;
+        self.pending.lock().unwrap().remove(&transaction);
+        let reply = reply??;
+
+        let id = reply
+            .get("id")
+            .and_then(node_id)
+            .ok_or_else(|| Error::Protocol("DHT reply without a node id".to_string()))?;
+        if address.is_ipv4() {
+            self.table.lock().unwrap().insert(Node { id, address });
+        }
+        Ok(reply)
+    }
+
+    async fn ping(&self, address: SocketAddr) -> Result<NodeId> {
+        let reply = self.query(address, "ping", []).await?;
+        Ok(reply.get("id").and_then(node_id).unwrap_or_default())
+    }
+
+    async fn find_node(&self, address: SocketAddr, target: NodeId) -> Result<Vec<Node>> {
+        let reply = self
+            .query(
+                address,
+                "find_node",
+                [("target", Value::Bytes(target.to_vec()))],
+            )
+            .await?;
+        let nodes = nodes(&reply);
+        let mut table = self.table.lock().unwrap();
+        for node in &nodes {
+            table.insert(*node);
+        }
+        Ok(nodes)
+    }
+
+    async fn get_peers(&self, address: SocketAddr, info_hash: [u8; 20]) -> Result<GetPeers> {
+        let reply = self
+            .query(
+                address,
+                "get_peers",
+                [("info_hash", Value::Bytes(info_hash.to_vec()))],
+            )
+            .await?;
+        let peers = reply
+            .get("values")
+            .and_then(Value::as_list)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|value| parse_peer(value.as_bytes()?))
+            .collect();
+        Ok(GetPeers {
+            token: reply
+                .get("token")
+                .and_then(Value::as_bytes)
+                .map(<[u8]>::to_vec),
+            peers,
+            nodes: nodes(&reply),
+        })
+    }
+
+    async fn announce_peer(
+        &self,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+    ) -> Result<()> {
+        self.query(
+            address,
+            "announce_peer",
+            [
+                ("info_hash", Value::Bytes(info_hash.to_vec())),
+                ("port", Value::Int(port.into())),
+                ("token", Value::Bytes(token)),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+// The body of a reply, or the error a node answered with.
+fn response(message: &Value) -> Result<Value> {
+    if let Some(reply) = message.get("r") {
+        return Ok(reply.clone());
+    }
+    let error = message
+        .get("e")
+        .and_then(Value::as_list)
+        .unwrap_or_default();
+    let code = error.first().and_then(Value::as_int).unwrap_or_default();
+    let reason = error
+        .get(1)
+        .and_then(Value::as_str_lossy)
+        .unwrap_or_default();
+    Err(Error::Protocol(format!("DHT error {}: {}", code, reason)))
+}
+
+fn error(code: i64, message: &str) -> (&'static str, Value) {
+    let body = vec![Value::Int(code), Value::Bytes(message.as_bytes().to_vec())];
+    ("e", Value::List(body))
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn node_id(value: &Value) -> Option<NodeId> {
+    value.as_bytes()?.try_into().ok()
+}
+
+// Compact node info: a 20-byte id followed by a compact IPv4 peer.
+fn nodes(reply: &Value) -> Vec<Node> {
+    let compact = reply
+        .get("nodes")
+        .and_then(Value::as_bytes)
+        .unwrap_or_default();
+    compact
+        .chunks_exact(26)
+        .filter_map(|chunk| {
+            Some(Node {
+                id: chunk[..20].try_into().ok()?,
+                address: parse_peer(&chunk[20..])?,
+            })
+        })
+        .collect()
+}
+
+// Only IPv4 nodes make it into the routing table.
+fn compact_node(node: &Node) -> Vec<u8> {
+    [node.id.as_slice(), &compact(&node.address)].concat()
+}
+
+fn parse_peer(compact: &[u8]) -> Option<SocketAddr> {
+    let [a, b, c, d, high, low] = compact.try_into().ok()?;
+    let ip = Ipv4Addr::new(a, b, c, d);
+    Some(SocketAddr::new(ip.into(), u16::from_be_bytes([high, low])))
+}
+
+fn compact(peer: &SocketAddr) -> Vec<u8> {
+    let ip = match peer.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    [ip, peer.port().to_be_bytes().to_vec()].concat()
+}
+
+// Searches the DHT for peers of `info_hash` from a fresh node. Finds nothing
+// while the DHT is disabled.
+pub async fn find_peers(info_hash: [u8; 20]) -> Result<Vec<SocketAddr>> {
+    let bootstrap = match enabled() {
+        true => BOOTSTRAP.lock().unwrap().clone().unwrap_or_default(),
+        false => return Ok(Vec::new()),
+    };
+    let dht = Dht::bind((Ipv4Addr::UNSPECIFIED, 0).into()).await?;
+    time::timeout(SEARCH_TIMEOUT, async {
+        dht.bootstrap(&bootstrap).await?;
+        Ok(dht.find_peers(info_hash).await)
+    })
+    .await?
+}
+
+// Runs a tracker announce and a DHT search side by side. Peers from the DHT
+// are added to the tracker's, and stand in for them if the trackers failed.
+pub(crate) async fn alongside(
+    info_hash: [u8; 20],
+    trackers: impl Future<Output = Result<Announce>>,
+) -> Result<Announce> {
+    if !enabled() {
+        return trackers.await;
+    }
+    let (trackers, dht) = tokio::join!(trackers, find_peers(info_hash));
+    let dht = dht.unwrap_or_else(|e| {
+        debug!("DHT: {}", e);
+        Vec::new()
+    });
+    let found = Announce {
+        peers: dht,
+        ..Default::default()
+    };
+    match trackers {
+        Ok(mut announce) => {
+            announce.merge(found);
+            Ok(announce)
+        }
+        Err(_) if !found.peers.is_empty() => Ok(found),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,1431 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    future::{self, Future},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::{JoinHandle, JoinSet},
+    time::{self, Instant},
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use crate::{
+    bencode,
+    choker::{self, Choker},
+    connections::Connections,
+    error::{Error, Result},
+    extension::{PexMessage, UT_PEX},
+    listener::{self, Listener},
+    peer::{Peer, Transport},
+    ratelimit::RateLimits,
+    resume::ResumeFile,
+    selection::{self, Throughput},
+    session::Shared,
+    storage::{PieceStore, PieceWriter},
+    torrent::Info,
+    tracker::Announce,
+    webseed::WebSeed,
+};
+
+const EVENT_CAPACITY: usize = 1024;
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_READAHEAD: u64 = 8 * 1024 * 1024;
+// Pieces outside the readahead window fetched at the same time, so the rest
+// of a streamed download keeps moving.
+const BACKGROUND_PIECES: usize = 4;
+// Pieces a peer may send that fail verification before it is banned from
+// the download.
+const MAX_HASH_FAILURES: u32 = 3;
+pub const DEFAULT_SEQUENTIAL_WINDOW: usize = 4;
+
+// How long an unchoked peer may go without delivering a block before it is
+// considered to be snubbing us.
+static SNUB_TIMEOUT: Mutex<Duration> = Mutex::new(Duration::from_secs(60));
+
+pub fn set_snub_timeout(timeout: Duration) {
+    *SNUB_TIMEOUT.lock().unwrap() = timeout;
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    PeerSnubbed(SocketAddr),
+    Announced { peers: usize },
+    TrackerError(String),
+    PieceVerified { index: usize, peer: SocketAddr },
+    // The piece `peer` sent did not match its hash and will be requested
+    // again. Web seeds show up as the unspecified address.
+    PieceFailed { index: usize, peer: SocketAddr },
+    // `peer` sent too many pieces that failed verification and is cut off.
+    PeerBanned(SocketAddr),
+    RateSample { bytes_per_sec: u64 },
+    Completed,
+    Error(String),
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Progress {
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub bytes_downloaded: u64, // this session, unlike `bytes_done`
+    pub bytes_uploaded: u64,
+    pub pieces_done: usize,
+    pub total_pieces: usize,
+    pub download_rate: u64, // bytes per second
+    pub upload_rate: u64,   // bytes per second
+    pub peers: usize,
+    #[serde(default)]
+    pub pieces_failed: u64,
+    #[serde(default)]
+    pub tracker_errors: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct DownloadState {
+    bytes_done: AtomicU64,
+    total_bytes: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    // Finished pieces, which peers may download from us.
+    uploads: PieceStore,
+    pieces_done: AtomicUsize,
+    total_pieces: AtomicUsize,
+    download_rate: AtomicU64,
+    upload_rate: AtomicU64,
+    peers: Mutex<HashSet<SocketAddr>>,
+    tracker: Mutex<Option<String>>,
+    pieces_failed: AtomicU64,
+    tracker_errors: AtomicU64,
+    connections: Connections,
+    // Pieces each peer sent that failed verification, the peers past the
+    // limit, and who sent a bad copy of each piece.
+    hash_failures: Mutex<HashMap<SocketAddr, u32>>,
+    banned: Mutex<HashSet<SocketAddr>>,
+    bad_copies: Mutex<HashMap<usize, HashSet<SocketAddr>>>,
+}
+
+impl DownloadState {
+    fn snapshot(&self) -> Progress {
+        Progress {
+            bytes_done: self.bytes_done.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            bytes_downloaded: self.bytes_downloaded.load(Ordering::Relaxed),
+            bytes_uploaded: self.uploads.uploaded(),
+            pieces_done: self.pieces_done.load(Ordering::Relaxed),
+            total_pieces: self.total_pieces.load(Ordering::Relaxed),
+            download_rate: self.download_rate.load(Ordering::Relaxed),
+            upload_rate: self.upload_rate.load(Ordering::Relaxed),
+            peers: self.peers.lock().unwrap().len(),
+            pieces_failed: self.pieces_failed.load(Ordering::Relaxed),
+            tracker_errors: self.tracker_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record(&self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::PeerConnected(peer) => {
+                self.peers.lock().unwrap().insert(*peer);
+            }
+            DownloadEvent::PeerDisconnected(peer) => {
+                self.peers.lock().unwrap().remove(peer);
+                self.connections.release(peer);
+            }
+            DownloadEvent::Announced { peers } => {
+                *self.tracker.lock().unwrap() = Some(format!("ok, {} peers", peers));
+            }
+            DownloadEvent::TrackerError(e) => {
+                *self.tracker.lock().unwrap() = Some(e.clone());
+                self.tracker_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            DownloadEvent::PieceFailed { .. } => {
+                self.pieces_failed.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Where a streaming consumer is reading, and how far past it to prioritize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Readahead {
+    pub position: u64, // byte offset
+    pub window: u64,   // bytes
+}
+
+impl Default for Readahead {
+    fn default() -> Self {
+        Self {
+            position: 0,
+            window: DEFAULT_READAHEAD,
+        }
+    }
+}
+
+impl Readahead {
+    fn pieces(&self, info: &Info) -> Range<usize> {
+        let piece_length = info.piece_length;
+        let num_pieces = info.piece_count();
+        let start = ((self.position / piece_length) as usize).min(num_pieces);
+        let end = (self.position.saturating_add(self.window)).div_ceil(piece_length) as usize;
+        start..end.clamp(start + 1, num_pieces.max(start + 1))
+    }
+}
+
+// Decides which missing pieces are requested next. Every time a piece
+// completes, the scheduler asks again with what is still pending and what is
+// already being fetched.
+pub trait PieceOrder: Send + Sync {
+    fn next(&self, pending: &BTreeSet<usize>, in_flight: &HashSet<usize>) -> Vec<usize>;
+}
+
+// Requests every piece up front, leaving peers to deliver in any order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllAtOnce;
+
+impl PieceOrder for AllAtOnce {
+    fn next(&self, pending: &BTreeSet<usize>, _in_flight: &HashSet<usize>) -> Vec<usize> {
+        pending.iter().copied().collect()
+    }
+}
+
+// Requests pieces in index order, at most `window` at a time, so the start of
+// a file is usable while the rest downloads.
+#[derive(Debug, Clone, Copy)]
+pub struct Sequential {
+    pub window: usize,
+}
+
+impl Default for Sequential {
+    fn default() -> Self {
+        Self {
+            window: DEFAULT_SEQUENTIAL_WINDOW,
+        }
+    }
+}
+
+impl PieceOrder for Sequential {
+    fn next(&self, pending: &BTreeSet<usize>, in_flight: &HashSet<usize>) -> Vec<usize> {
+        let free = self.window.max(1).saturating_sub(in_flight.len());
+        pending.iter().take(free).copied().collect()
+    }
+}
+
+type Discovered = mpsc::UnboundedReceiver<(Peer, Vec<usize>)>;
+type Exchanged = mpsc::UnboundedSender<(SocketAddr, Transport)>;
+
+// What an announce can return: peers, possibly with more from the tracker.
+pub(crate) trait PeerList {
+    fn peer_count(&self) -> usize;
+}
+
+impl<T> PeerList for Vec<T> {
+    fn peer_count(&self) -> usize {
+        self.len()
+    }
+}
+
+impl PeerList for Announce {
+    fn peer_count(&self) -> usize {
+        self.peers.len()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct DownloadContext {
+    events: broadcast::Sender<DownloadEvent>,
+    state: Arc<DownloadState>,
+    paused: watch::Receiver<bool>,
+    cancel: CancellationToken,
+    readahead: Option<watch::Receiver<Readahead>>,
+    // Used when there is no readahead window to follow.
+    order: Arc<dyn PieceOrder>,
+    peers: mpsc::UnboundedSender<(Peer, Vec<usize>)>,
+    // Taken by whichever piece loop runs the download.
+    discovered: Arc<Mutex<Option<Discovered>>>,
+    // Set while something keeps looking for peers, so pieces nobody has yet
+    // can wait for one instead of failing the download.
+    finding_peers: Arc<AtomicBool>,
+    // Announced to trackers, which track us by it across announces.
+    peer_id: String,
+    // Where addresses peers tell us about go, once someone wants them.
+    pex: Arc<Mutex<Option<Exchanged>>>,
+    web_seeds: Arc<Mutex<Vec<Arc<WebSeed>>>>,
+    // The only pieces to fetch, if not all of them.
+    selection: Arc<Mutex<Option<BTreeSet<usize>>>>,
+    // The bencoded info dictionary, served to peers once we have it.
+    metadata: Arc<Mutex<Option<Bytes>>>,
+    limits: RateLimits,
+    // The session's listener, and its depth of block requests per peer.
+    listener: Option<Arc<Listener>>,
+    queue_depth: Option<usize>,
+}
+
+impl DownloadContext {
+    pub(crate) fn emit(&self, event: DownloadEvent) {
+        self.state.record(&event);
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub(crate) fn connections(&self) -> &Connections {
+        &self.state.connections
+    }
+
+    // Counts a piece from `peer` that failed verification, banning the peer
+    // once it has sent too many.
+    pub(crate) fn piece_failed(&self, index: usize, peer: &Peer) {
+        let address = peer.address;
+        self.emit(DownloadEvent::PieceFailed {
+            index,
+            peer: address,
+        });
+        let state = &self.state;
+        state
+            .bad_copies
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_default()
+            .insert(address);
+        let failures = {
+            let mut hash_failures = state.hash_failures.lock().unwrap();
+            let failures = hash_failures.entry(address).or_default();
+            *failures += 1;
+            *failures
+        };
+        if failures == MAX_HASH_FAILURES {
+            warn!(peer = %address, "banning peer after {} bad pieces", failures);
+            state.banned.lock().unwrap().insert(address);
+            peer.close();
+            self.emit(DownloadEvent::PeerBanned(address));
+            self.emit(DownloadEvent::PeerDisconnected(address));
+        }
+    }
+
+    pub(crate) fn is_banned(&self, address: &SocketAddr) -> bool {
+        self.state.banned.lock().unwrap().contains(address)
+    }
+
+    // Whether `peer` already sent a copy of `piece` that failed verification.
+    fn sent_bad_copy(&self, piece: usize, peer: &SocketAddr) -> bool {
+        self.state
+            .bad_copies
+            .lock()
+            .unwrap()
+            .get(&piece)
+            .is_some_and(|peers| peers.contains(peer))
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.state.snapshot()
+    }
+
+    pub(crate) fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    // The peer ID as sent in handshakes.
+    pub(crate) fn local_id(&self) -> [u8; 20] {
+        self.peer_id.as_bytes().try_into().unwrap()
+    }
+
+    pub(crate) fn listener(&self) -> Option<&Arc<Listener>> {
+        self.listener.as_ref()
+    }
+
+    // The port to announce: the session's listener, or else the process's.
+    pub(crate) fn listen_port(&self) -> Option<u16> {
+        match &self.listener {
+            Some(listener) => Some(listener.port()),
+            None => listener::port(),
+        }
+    }
+
+    // Peers readied from now on are asked for the peers they know (BEP 11),
+    // which arrive on the returned channel.
+    pub(crate) fn exchange_peers(&self) -> mpsc::UnboundedReceiver<(SocketAddr, Transport)> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        *self.pex.lock().unwrap() = Some(sender);
+        receiver
+    }
+
+    // Hands a readied peer to the piece loop.
+    pub(crate) fn peer_joined(&self, joined: (Peer, Vec<usize>)) {
+        // Nobody is listening once the piece loop has finished.
+        let _ = self.peers.send(joined);
+    }
+
+    // Web seeds serve every piece, alongside whichever peers have it.
+    pub(crate) fn add_web_seeds(&self, seeds: impl IntoIterator<Item = WebSeed>) {
+        let mut web_seeds = self.web_seeds.lock().unwrap();
+        web_seeds.extend(seeds.into_iter().map(Arc::new));
+    }
+
+    // Peers readied from now on serve `info` over ut_metadata.
+    pub(crate) fn set_metadata(&self, info: &Info) -> Result<()> {
+        let metadata = Bytes::from(serde_bencode::to_bytes(info)?);
+        *self.metadata.lock().unwrap() = Some(metadata);
+        Ok(())
+    }
+
+    pub(crate) fn metadata(&self) -> Option<Bytes> {
+        self.metadata.lock().unwrap().clone()
+    }
+
+    // Restricts the download to `pieces`; the rest are left alone.
+    pub(crate) fn select_pieces(&self, pieces: impl IntoIterator<Item = usize>) {
+        *self.selection.lock().unwrap() = Some(pieces.into_iter().collect());
+    }
+
+    pub(crate) fn set_finding_peers(&self, finding: bool) {
+        self.finding_peers.store(finding, Ordering::Relaxed);
+    }
+
+    pub(crate) async fn until_cancelled<T>(
+        &self,
+        future: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        self.cancel
+            .run_until_cancelled(future)
+            .await
+            .unwrap_or(Err(Error::Cancelled))
+    }
+
+    // Runs a tracker announce, recording how it went.
+    pub(crate) async fn announce<T: PeerList>(
+        &self,
+        announce: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let result = self.until_cancelled(announce).await;
+        match &result {
+            Ok(peers) => self.emit(DownloadEvent::Announced {
+                peers: peers.peer_count(),
+            }),
+            Err(Error::Cancelled) => {}
+            Err(e) => self.emit(DownloadEvent::TrackerError(e.to_string())),
+        }
+        result
+    }
+
+    // For content that was already on disk before the download started.
+    pub(crate) fn mark_complete(&self, info: &Info) {
+        let (bytes, pieces) = (info.file_len(), info.piece_count());
+        self.state.total_bytes.store(bytes, Ordering::Relaxed);
+        self.state.bytes_done.store(bytes, Ordering::Relaxed);
+        self.state.total_pieces.store(pieces, Ordering::Relaxed);
+        self.state.pieces_done.store(pieces, Ordering::Relaxed);
+    }
+
+    // Resolves once the download is paused, so in-flight work can stop
+    // making requests and wait in `wait_if_paused` instead.
+    async fn paused(&self) {
+        let mut paused = self.paused.clone();
+        if paused.wait_for(|paused| *paused).await.is_err() {
+            // Nobody is left to pause us.
+            std::future::pending::<()>().await;
+        }
+    }
+
+    async fn wait_if_paused(&mut self) -> Result<()> {
+        let mut paused = self.paused.clone();
+        self.until_cancelled(async move {
+            let _ = paused.wait_for(|paused| !paused).await;
+            Ok(())
+        })
+        .await
+    }
+}
+
+pub struct DownloadHandle<T = Vec<u8>> {
+    // The first subscriber gets every event since the download started,
+    // later ones only see events emitted after they subscribed.
+    events: Mutex<Option<broadcast::Receiver<DownloadEvent>>>,
+    tail: broadcast::Receiver<DownloadEvent>,
+    state: Arc<DownloadState>,
+    paused: watch::Sender<bool>,
+    cancel: CancellationToken,
+    // Set once the task has wound down, trackers told and state saved.
+    finished: watch::Receiver<bool>,
+    limits: RateLimits,
+    task: JoinHandle<Result<T>>,
+}
+
+impl<T: Send + 'static> DownloadHandle<T> {
+    pub(crate) fn spawn<F, Fut>(download: F) -> Self
+    where
+        F: FnOnce(DownloadContext) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        Self::spawn_with(None, Arc::new(AllAtOnce), None, download)
+    }
+
+    pub(crate) fn spawn_ordered<F, Fut>(order: Arc<dyn PieceOrder>, download: F) -> Self
+    where
+        F: FnOnce(DownloadContext) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        Self::spawn_with(None, order, None, download)
+    }
+
+    // Like `spawn_ordered`, as one of a session's downloads when given.
+    pub(crate) fn spawn_in<F, Fut>(
+        session: Option<Shared>,
+        order: Arc<dyn PieceOrder>,
+        download: F,
+    ) -> Self
+    where
+        F: FnOnce(DownloadContext) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        Self::spawn_with(None, order, session, download)
+    }
+
+    // With a readahead receiver, pieces are fetched around its window first;
+    // otherwise `order` decides. A session's downloads announce with its
+    // peer ID and wait for one of its slots before starting.
+    fn spawn_with<F, Fut>(
+        readahead: Option<watch::Receiver<Readahead>>,
+        order: Arc<dyn PieceOrder>,
+        session: Option<Shared>,
+        download: F,
+    ) -> Self
+    where
+        F: FnOnce(DownloadContext) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let (sender, receiver) = broadcast::channel(EVENT_CAPACITY);
+        let tail = receiver.resubscribe();
+        let mut state = DownloadState::default();
+        let (paused, paused_receiver) = watch::channel(false);
+        let cancel = CancellationToken::new();
+        let (peers, discovered) = mpsc::unbounded_channel();
+        let (peer_id, slots, limits, listener, queue_depth) = match session {
+            Some(session) => {
+                if let Some(max_peers) = session.max_peers {
+                    state.connections = Connections::new(max_peers);
+                }
+                (
+                    session.peer_id,
+                    session.slots,
+                    RateLimits::in_session(session.totals),
+                    session.listener,
+                    session.queue_depth,
+                )
+            }
+            None => (Peer::gen_peer_id(), None, RateLimits::default(), None, None),
+        };
+        let state = Arc::new(state);
+        let ctx = DownloadContext {
+            events: sender,
+            state: state.clone(),
+            paused: paused_receiver,
+            cancel: cancel.clone(),
+            readahead,
+            order,
+            peers,
+            discovered: Arc::new(Mutex::new(Some(discovered))),
+            finding_peers: Arc::new(AtomicBool::new(false)),
+            peer_id,
+            pex: Arc::new(Mutex::new(None)),
+            web_seeds: Arc::new(Mutex::new(Vec::new())),
+            selection: Arc::new(Mutex::new(None)),
+            metadata: Arc::new(Mutex::new(None)),
+            limits: limits.clone(),
+            listener,
+            queue_depth,
+        };
+        let (finished_sender, finished) = watch::channel(false);
+        let future = download(ctx.clone());
+        let task = tokio::spawn(async move {
+            let result = async {
+                let _slot = match slots {
+                    Some(slots) => Some(
+                        ctx.until_cancelled(async { Ok(slots.acquire_owned().await) })
+                            .await?
+                            .map_err(|_| Error::Cancelled)?,
+                    ),
+                    None => None,
+                };
+                future.await
+            }
+            .await;
+            match &result {
+                Ok(_) => ctx.emit(DownloadEvent::Completed),
+                Err(e) => ctx.emit(DownloadEvent::Error(e.to_string())),
+            }
+            finished_sender.send_replace(true);
+            result
+        });
+
+        Self {
+            events: Mutex::new(Some(receiver)),
+            tail,
+            state,
+            paused,
+            cancel,
+            finished,
+            limits,
+            task,
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.state.snapshot()
+    }
+
+    // The outcome of the last tracker announce, if there has been one.
+    pub fn tracker_status(&self) -> Option<String> {
+        self.state.tracker.lock().unwrap().clone()
+    }
+
+    // A read-only view that can outlive borrows of the handle, e.g. while
+    // something else is waiting on `join`.
+    pub fn monitor(&self) -> DownloadMonitor {
+        DownloadMonitor {
+            state: self.state.clone(),
+            paused: self.paused.subscribe(),
+            finished: self.finished.clone(),
+        }
+    }
+
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    // Pauses and resumes the download like `pause` and `resume`, without
+    // holding on to the handle.
+    pub(crate) fn pause_switch(&self) -> watch::Sender<bool> {
+        self.paused.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    // Caps this download at `bytes_per_sec`, within the global limit.
+    // `None` leaves only the global limit.
+    pub fn set_download_limit(&self, bytes_per_sec: Option<u64>) {
+        self.limits.download.set_rate(bytes_per_sec);
+    }
+
+    pub fn set_upload_limit(&self, bytes_per_sec: Option<u64>) {
+        self.limits.upload.set_rate(bytes_per_sec);
+    }
+
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn events(&self) -> impl Stream<Item = DownloadEvent> {
+        let receiver = self
+            .events
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| self.tail.resubscribe());
+        BroadcastStream::new(receiver).filter_map(|event| event.ok())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    pub async fn join(self) -> Result<T> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_cancelled() => Err(Error::Cancelled),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DownloadMonitor {
+    state: Arc<DownloadState>,
+    paused: watch::Receiver<bool>,
+    finished: watch::Receiver<bool>,
+}
+
+impl DownloadMonitor {
+    pub fn progress(&self) -> Progress {
+        self.state.snapshot()
+    }
+
+    pub fn tracker_status(&self) -> Option<String> {
+        self.state.tracker.lock().unwrap().clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self.state.peers.lock().unwrap().iter().copied().collect();
+        peers.sort();
+        peers
+    }
+
+    // Waits for the download to end, including telling its trackers and
+    // saving its state after a cancel.
+    pub async fn finished(&self) {
+        // An error means the task is gone without saying so, e.g. aborted.
+        let _ = self.finished.clone().wait_for(|finished| *finished).await;
+    }
+}
+
+pub struct PieceStream {
+    pieces: mpsc::UnboundedReceiver<(usize, Bytes)>,
+    download: Option<DownloadHandle<()>>,
+    readahead: watch::Sender<Readahead>,
+}
+
+impl PieceStream {
+    pub(crate) fn spawn<F, Fut>(download: F) -> Self
+    where
+        F: FnOnce(DownloadContext, mpsc::UnboundedSender<(usize, Bytes)>) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (sender, pieces) = mpsc::unbounded_channel();
+        let (readahead, receiver) = watch::channel(Readahead::default());
+        let download =
+            DownloadHandle::spawn_with(Some(receiver), Arc::new(AllAtOnce), None, |ctx| {
+                download(ctx, sender)
+            });
+        Self {
+            pieces,
+            download: Some(download),
+            readahead,
+        }
+    }
+
+    pub fn handle(&self) -> Option<&DownloadHandle<()>> {
+        self.download.as_ref()
+    }
+
+    pub fn readahead(&self) -> Readahead {
+        *self.readahead.borrow()
+    }
+
+    // Moves playback to a byte offset. Pieces from there on are fetched
+    // first and the stream continues from the piece containing it; pieces
+    // skipped over are still downloaded and delivered after the last one.
+    pub fn seek(&self, position: u64) {
+        self.readahead
+            .send_modify(|readahead| readahead.position = position);
+    }
+
+    // How many bytes past the playback position to prioritize.
+    pub fn set_readahead(&self, window: u64) {
+        self.readahead
+            .send_modify(|readahead| readahead.window = window);
+    }
+}
+
+impl Stream for PieceStream {
+    type Item = Result<(usize, Bytes)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(piece) = ready!(self.pieces.poll_recv(cx)) {
+            return Poll::Ready(Some(Ok(piece)));
+        }
+        // All pieces are drained, surface the download error if there was one.
+        let Some(download) = self.download.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let result = ready!(Pin::new(&mut download.task).poll(cx));
+        self.download = None;
+        Poll::Ready(match result {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(e) if e.is_cancelled() => Some(Err(Error::Cancelled)),
+            Err(e) => Some(Err(e.into())),
+        })
+    }
+}
+
+impl Drop for PieceStream {
+    fn drop(&mut self) {
+        if let Some(download) = &self.download {
+            download.cancel();
+        }
+    }
+}
+
+// Records which pieces a freshly connected peer has and readies it for
+// requests.
+pub(crate) async fn add_peer(
+    peer: Peer,
+    peer_piece_map: &mut HashMap<usize, Vec<Peer>>,
+    ctx: &DownloadContext,
+) -> Result<()> {
+    let (peer, pieces) = ready_peer(peer, ctx).await?;
+    for piece in pieces {
+        peer_piece_map.entry(piece).or_default().push(peer.clone());
+    }
+    Ok(())
+}
+
+// Like `add_peer`, for a peer found while pieces are already being fetched.
+pub(crate) async fn join_peer(peer: Peer, ctx: &DownloadContext) -> Result<()> {
+    let joined = ready_peer(peer, ctx).await?;
+    ctx.peer_joined(joined);
+    Ok(())
+}
+
+pub(crate) async fn ready_peer(peer: Peer, ctx: &DownloadContext) -> Result<(Peer, Vec<usize>)> {
+    let mut peer = peer
+        .with_cancellation(ctx.cancellation_token())
+        .with_rate_limits(ctx.limits.clone())
+        .with_uploads(ctx.state.uploads.clone())
+        .with_have_sender(ctx.peers.clone());
+    if let Some(depth) = ctx.queue_depth {
+        peer = peer.with_queue_depth(depth);
+    }
+    if let Some(metadata) = ctx.metadata() {
+        peer = peer.with_metadata(metadata);
+    }
+    let pieces = peer.get_pieces().await?;
+    let pex = ctx.pex.lock().unwrap().clone();
+    if let Some(pex) = pex.filter(|_| peer.supports_extension) {
+        peer.register_extension(UT_PEX, move |payload| {
+            if let Ok(message) = bencode::from_bytes::<PexMessage>(payload) {
+                for added in message.added_transports() {
+                    let _ = pex.send(added);
+                }
+            }
+        });
+        // Peer exchange is a bonus; a peer that won't do the extension
+        // handshake can still serve pieces.
+        match time::timeout(EXTENSION_HANDSHAKE_TIMEOUT, peer.extension_handshake()).await {
+            Ok(Err(Error::Cancelled)) => return Err(Error::Cancelled),
+            Ok(Err(e)) => debug!(peer = %peer.address, "no extension handshake: {}", e),
+            Err(_) => debug!(peer = %peer.address, "no extension handshake"),
+            Ok(Ok(())) => {}
+        }
+    }
+    peer.prepare_download().await?;
+    ctx.emit(DownloadEvent::PeerConnected(peer.address));
+    Ok((peer, pieces))
+}
+
+pub(crate) async fn download_pieces(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    known: BTreeMap<usize, Vec<u8>>,
+    ctx: &DownloadContext,
+) -> Result<Vec<u8>> {
+    let mut file_bytes = vec![0u8; info.file_len() as usize];
+    fetch_pieces(info, peer_piece_map, known, ctx, |piece, data| {
+        let start = info.piece_offset(piece) as usize;
+        let end = start + data.len();
+        file_bytes[start..end].copy_from_slice(&data);
+    })
+    .await?;
+    Ok(file_bytes)
+}
+
+// Like `download_pieces`, writing each piece into `path` once verified
+// rather than holding the whole file in memory. Intact pieces already at
+// `path`, e.g. from an interrupted download, are kept and not fetched again.
+pub(crate) async fn download_pieces_to(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    known: BTreeMap<usize, Vec<u8>>,
+    ctx: &DownloadContext,
+    path: PathBuf,
+) -> Result<()> {
+    let writer = PieceWriter::resume(path, info, ctx.state.uploads.clone()).await?;
+    write_pieces(info, peer_piece_map, known, ctx, writer).await
+}
+
+// Like `download_pieces_to`, keeping a fast-resume file next to `path` up to
+// date every `RESUME_INTERVAL` and once the download stops, however it
+// stops. The pieces a matching resume file lists are trusted without a hash
+// check.
+pub(crate) async fn download_pieces_with_resume(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    ctx: &DownloadContext,
+    path: PathBuf,
+) -> Result<()> {
+    let resume_path = ResumeFile::path_for(&path);
+    let store = ctx.state.uploads.clone();
+    let (mut resume, writer) = match ResumeFile::read(&resume_path) {
+        Ok(resume) if resume.matches(info) => {
+            let writer = PieceWriter::resume_verified(path, info, store, &resume.have()).await?;
+            (resume, writer)
+        }
+        _ => (
+            ResumeFile::new(info)?,
+            PieceWriter::resume(path, info, store).await?,
+        ),
+    };
+    // Totals from earlier sessions.
+    let (downloaded, uploaded) = (resume.downloaded, resume.uploaded);
+    let save = |resume: &mut ResumeFile| {
+        let state = &ctx.state;
+        resume.set_have(state.uploads.written_pieces(), info.piece_count());
+        resume.downloaded = downloaded + state.bytes_downloaded.load(Ordering::Relaxed);
+        resume.uploaded = uploaded + state.uploads.uploaded();
+        if let Some(tracker) = state.tracker.lock().unwrap().clone() {
+            resume.tracker = Some(tracker);
+        }
+        resume.write(&resume_path)
+    };
+
+    let mut events = ctx.events.subscribe();
+    let mut ticker = time::interval(RESUME_INTERVAL);
+    let download = write_pieces(info, peer_piece_map, BTreeMap::new(), ctx, writer);
+    tokio::pin!(download);
+    let result = loop {
+        tokio::select! {
+            result = &mut download => break result,
+            _ = ticker.tick() => {
+                if let Err(e) = save(&mut resume) {
+                    warn!("saving {}: {}", resume_path.display(), e);
+                }
+            }
+            event = events.recv() => {
+                if let Ok(DownloadEvent::PieceVerified { peer, .. }) = event {
+                    resume.add_piece_from(peer);
+                }
+            }
+        }
+    };
+    // Pieces verified just before the download finished.
+    while let Ok(event) = events.try_recv() {
+        if let DownloadEvent::PieceVerified { peer, .. } = event {
+            resume.add_piece_from(peer);
+        }
+    }
+    let saved = save(&mut resume);
+    result.and(saved)
+}
+
+async fn write_pieces(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    known: BTreeMap<usize, Vec<u8>>,
+    ctx: &DownloadContext,
+    mut writer: PieceWriter,
+) -> Result<()> {
+    let (sender, mut pieces) = mpsc::unbounded_channel::<(usize, Bytes)>();
+    // Pieces verified before the download stopped, cancelled or not, are
+    // still written out so they are not fetched again.
+    let fetching = async {
+        let fetched = fetch_pieces(info, peer_piece_map, known, ctx, move |piece, data| {
+            let _ = sender.send((piece, data));
+        });
+        Ok(fetched.await)
+    };
+    let writing = async move {
+        while let Some((piece, data)) = pieces.recv().await {
+            writer.write_piece(piece, &data).await?;
+        }
+        writer.finish().await
+    };
+    let (fetched, ()) = tokio::try_join!(fetching, writing)?;
+    fetched
+}
+
+pub(crate) async fn stream_pieces(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    ctx: &DownloadContext,
+    sender: mpsc::UnboundedSender<(usize, Bytes)>,
+) -> Result<()> {
+    let num_pieces = info.piece_count();
+    let mut position = ctx.readahead.as_ref().map(|readahead| *readahead.borrow());
+    let mut next_piece = position.map_or(0, |position| position.pieces(info).start);
+    let mut delivered = vec![false; num_pieces];
+    let mut pending = BTreeMap::new();
+    fetch_pieces(info, peer_piece_map, BTreeMap::new(), ctx, |piece, data| {
+        pending.insert(piece, data);
+        let current = ctx.readahead.as_ref().map(|readahead| *readahead.borrow());
+        if current.map(|current| current.position) != position.map(|old| old.position) {
+            next_piece = current.map_or(0, |current| current.pieces(info).start);
+        }
+        position = current;
+        // Deliver in order from the playback position, then wrap around to
+        // whatever was skipped.
+        loop {
+            if next_piece >= num_pieces {
+                match delivered.iter().position(|done| !done) {
+                    Some(first) => next_piece = first,
+                    None => break,
+                }
+            }
+            if delivered[next_piece] {
+                next_piece += 1;
+                continue;
+            }
+            let Some(data) = pending.remove(&next_piece) else {
+                break;
+            };
+            let _ = sender.send((next_piece, data));
+            delivered[next_piece] = true;
+            next_piece += 1;
+        }
+    })
+    .await
+}
+
+// Where a piece is fetched from.
+#[derive(Clone)]
+enum Source {
+    Peer(Box<Peer>),
+    WebSeed(Arc<WebSeed>),
+}
+
+impl Source {
+    fn throughput(&self) -> &Arc<Throughput> {
+        match self {
+            Source::Peer(peer) => peer.throughput(),
+            Source::WebSeed(seed) => seed.throughput(),
+        }
+    }
+}
+
+// Web seeds have no peer address; pieces they serve are credited to the
+// unspecified one.
+const WEB_SEED_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+static UNADDRESSED_PEERS: AtomicU32 = AtomicU32::new(0);
+
+// A key for a peer reached other than by IP address, such as an I2P
+// destination or a WebRTC channel. Keys are numbered across the process in
+// 0.0.0.0/8, which no IP peer has, so no two peers share one whatever
+// transport they came over, and none is taken for a web seed.
+pub(crate) fn unaddressed_peer() -> SocketAddr {
+    let number = UNADDRESSED_PEERS.fetch_add(1, Ordering::Relaxed) % 0x00ff_ffff + 1;
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(number)), 0)
+}
+
+// Resolves once `peer` has gone a full snub timeout without delivering a
+// block, counting from `started` if it delivered nothing since then.
+async fn snubbed(peer: &Peer, started: Instant) {
+    loop {
+        let since = peer.last_block().map_or(started, |last| last.max(started));
+        let deadline = since + *SNUB_TIMEOUT.lock().unwrap();
+        if Instant::now() >= deadline {
+            return;
+        }
+        time::sleep_until(deadline).await;
+    }
+}
+
+// Every peer still connected, once each.
+fn connected(peer_piece_map: &Mutex<HashMap<usize, Vec<Peer>>>) -> Vec<Peer> {
+    let mut seen = HashSet::new();
+    peer_piece_map
+        .lock()
+        .unwrap()
+        .values()
+        .flatten()
+        .filter(|peer| !peer.is_closed() && seen.insert(peer.address))
+        .cloned()
+        .collect()
+}
+
+// Pieces in `known` were verified elsewhere and are handed to `on_piece`
+// without touching the network. Pieces the store already holds, e.g. found
+// intact on disk, count as done and are not handed over again.
+pub(crate) async fn fetch_pieces(
+    info: &Info,
+    peer_piece_map: HashMap<usize, Vec<Peer>>,
+    mut known: BTreeMap<usize, Vec<u8>>,
+    ctx: &DownloadContext,
+    mut on_piece: impl FnMut(usize, Bytes),
+) -> Result<()> {
+    // BEP 52 hash requests aren't supported, so v2 pieces can only be
+    // checked with the piece layers from the metainfo.
+    if !info.has_piece_layers() {
+        return Err(Error::Metadata(
+            "v2 piece layers are needed to check pieces".to_string(),
+        ));
+    }
+    let num_pieces = info.piece_count();
+    // Shared with the tasks that check the pieces they fetch.
+    let checked = Arc::new(info.clone());
+    let web_seeds = ctx.web_seeds.lock().unwrap().clone();
+    let state = &ctx.state;
+    let selection = ctx.selection.lock().unwrap().clone();
+    let selected = |piece: &usize| {
+        selection
+            .as_ref()
+            .is_none_or(|pieces| pieces.contains(piece))
+    };
+    let stored: HashSet<usize> = state.uploads.pieces().into_iter().collect();
+    known.retain(|piece, _| !stored.contains(piece));
+    let missing: Vec<usize> = (0..num_pieces)
+        .filter(|piece| selected(piece) && !known.contains_key(piece) && !stored.contains(piece))
+        .collect();
+    if peer_piece_map.is_empty() && web_seeds.is_empty() && !missing.is_empty() {
+        return Err(Error::NoPeers);
+    }
+
+    // Progress only counts the selected pieces.
+    let (total_bytes, total_pieces) = match &selection {
+        Some(pieces) => (
+            pieces
+                .iter()
+                .map(|&piece| info.piece_len(piece) as u64)
+                .sum(),
+            pieces.len(),
+        ),
+        None => (info.file_len(), num_pieces),
+    };
+    state.total_bytes.store(total_bytes, Ordering::Relaxed);
+    state.total_pieces.store(total_pieces, Ordering::Relaxed);
+    for &piece in stored.iter().filter(|piece| selected(piece)) {
+        state
+            .bytes_done
+            .fetch_add(info.piece_len(piece) as u64, Ordering::Relaxed);
+        state.pieces_done.fetch_add(1, Ordering::Relaxed);
+    }
+    for (piece, data) in known {
+        let data = Bytes::from(data);
+        state
+            .bytes_done
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        state.pieces_done.fetch_add(1, Ordering::Relaxed);
+        state.uploads.insert(piece, data.clone());
+        on_piece(piece, data);
+    }
+    let mut join_set = JoinSet::new();
+    // Peers found after the download started, e.g. by re-announcing.
+    let mut discovered = ctx.discovered.lock().unwrap().take();
+    let peer_piece_map = Mutex::new(peer_piece_map);
+    // Pieces waiting for a peer that has them.
+    let parked = Mutex::new(BTreeSet::new());
+
+    // Snubbed peers, choked ones that can't request the piece yet, and ones
+    // that already sent a bad copy of it only get work when nobody else has
+    // it, and peers that could not be reconnected or were banned get none.
+    // Web seeds that keep failing are dropped the same way. Among the rest,
+    // the fastest and least busy is asked.
+    let choose_source = |piece: usize| {
+        let peer_piece_map = peer_piece_map.lock().unwrap();
+        let peers: Vec<_> = peer_piece_map
+            .get(&piece)
+            .into_iter()
+            .flatten()
+            .filter(|peer| !peer.is_closed() && !ctx.is_banned(&peer.address))
+            .collect();
+        let responsive: Vec<_> = peers
+            .iter()
+            .filter(|peer| {
+                !peer.is_snubbed()
+                    && peer.can_request(piece)
+                    && !ctx.sent_bad_copy(piece, &peer.address)
+            })
+            .map(|peer| Source::Peer(Box::new((*peer).clone())))
+            .chain(
+                web_seeds
+                    .iter()
+                    .filter(|seed| !seed.is_retired())
+                    .map(|seed| Source::WebSeed(seed.clone())),
+            )
+            .collect();
+        let candidates = match responsive.is_empty() {
+            true => peers
+                .into_iter()
+                .map(|peer| Source::Peer(Box::new(peer.clone())))
+                .collect(),
+            false => responsive,
+        };
+        let ratings: Vec<_> = candidates
+            .iter()
+            .map(|source| source.throughput().rating())
+            .collect();
+        selection::choose(&ratings)
+            .map(|chosen| candidates[chosen].clone())
+            .ok_or(Error::NoPeers)
+    };
+
+    let spawn = |join_set: &mut JoinSet<_>, piece: usize| -> Result<()> {
+        let source = match choose_source(piece) {
+            Err(Error::NoPeers) if ctx.finding_peers.load(Ordering::Relaxed) => {
+                parked.lock().unwrap().insert(piece);
+                return Ok(());
+            }
+            source => source?,
+        };
+        let checked = checked.clone();
+        let piece_len = info.piece_len(piece);
+        let mut ctx = ctx.clone();
+        let mut peer = match source {
+            Source::Peer(peer) => *peer,
+            Source::WebSeed(seed) => {
+                let requests = seed.requests(info, piece);
+                let span = info_span!("piece", index = piece, web_seed = %seed.url());
+                let assigned = seed.throughput().assign();
+                let fetch = async move {
+                    let _assigned = assigned;
+                    if ctx.wait_if_paused().await.is_err() {
+                        return (piece, WEB_SEED_ADDRESS, Bytes::new());
+                    }
+                    let fetched = match requests {
+                        Ok(requests) => tokio::select! {
+                            fetched = seed.fetch(requests) => fetched,
+                            () = ctx.paused() => return (piece, WEB_SEED_ADDRESS, Bytes::new()),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    let data = match fetched {
+                        Ok(data) if checked.verify_piece(piece, &data) => Bytes::from(data),
+                        Ok(_) => {
+                            warn!("failed verification, will retry");
+                            ctx.emit(DownloadEvent::PieceFailed {
+                                index: piece,
+                                peer: WEB_SEED_ADDRESS,
+                            });
+                            Bytes::new()
+                        }
+                        Err(e) => {
+                            warn!("{}, will retry", e);
+                            Bytes::new()
+                        }
+                    };
+                    seed.record(!data.is_empty());
+                    if !data.is_empty() {
+                        seed.throughput().record_received(data.len() as u64);
+                        seed.throughput().record_piece();
+                    }
+                    (piece, WEB_SEED_ADDRESS, data)
+                };
+                join_set.spawn(fetch.instrument(span));
+                return Ok(());
+            }
+        };
+
+        let span = info_span!("piece", index = piece, peer = %peer.address);
+        let assigned = peer.throughput().assign();
+        let load = async move {
+            let _assigned = assigned;
+            if ctx.wait_if_paused().await.is_err() {
+                return (piece, peer.address, Bytes::new());
+            }
+            let generation = peer.generation();
+            let watched = peer.clone();
+            let loaded = tokio::select! {
+                loaded = peer.load_piece(piece as u32, piece_len) => loaded,
+                _ = snubbed(&watched, Instant::now()) => {
+                    // Dropping the load abandons its outstanding requests;
+                    // the piece is requested again from someone else.
+                    info!("peer stopped sending blocks, reassigning");
+                    peer.set_snubbed(true);
+                    peer.throughput().record_timeout();
+                    ctx.emit(DownloadEvent::PeerSnubbed(peer.address));
+                    return (piece, peer.address, Bytes::new());
+                }
+                // Likewise when pausing: the piece waits to be requested
+                // again once the download resumes.
+                () = ctx.paused() => return (piece, peer.address, Bytes::new()),
+            };
+            match loaded {
+                Ok(data) => {
+                    if !checked.verify_piece(piece, &data) {
+                        warn!("failed verification, will retry");
+                        ctx.piece_failed(piece, &peer);
+                        (piece, peer.address, Bytes::new())
+                    } else {
+                        peer.throughput().record_piece();
+                        (piece, peer.address, data)
+                    }
+                }
+                Err(e) => {
+                    warn!("{}, will retry", e);
+                    if matches!(e, Error::Timeout) {
+                        peer.throughput().record_timeout();
+                    }
+                    if matches!(e, Error::Io(_)) {
+                        match peer.reconnect(generation).await {
+                            Ok(true) => ctx.emit(DownloadEvent::PeerConnected(peer.address)),
+                            Ok(false) | Err(Error::Cancelled) => {}
+                            Err(e) => {
+                                warn!("giving up on peer: {}", e);
+                                ctx.emit(DownloadEvent::PeerDisconnected(peer.address));
+                            }
+                        }
+                    }
+                    (piece, peer.address, Bytes::new())
+                }
+            }
+        };
+        join_set.spawn(load.instrument(span));
+        Ok(())
+    };
+
+    // Without a readahead window the download's piece order picks what to
+    // request. Otherwise the window goes first and a few pieces beyond it
+    // keep the rest of the download going.
+    let mut readahead = ctx.readahead.clone();
+    let mut pending: BTreeSet<usize> = missing.into_iter().collect();
+    let mut in_flight = HashSet::new();
+    let mut schedule = |join_set: &mut JoinSet<_>,
+                        in_flight: &mut HashSet<usize>,
+                        window: Option<Readahead>|
+     -> Result<()> {
+        let next: Vec<usize> = match window {
+            None => ctx.order.next(&pending, in_flight),
+            Some(window) => {
+                let window = window.pieces(info);
+                let background = in_flight
+                    .iter()
+                    .filter(|piece| !window.contains(piece))
+                    .count();
+                let after = pending
+                    .range(window.end..)
+                    .chain(pending.range(..window.start));
+                pending
+                    .range(window.clone())
+                    .chain(after.take(BACKGROUND_PIECES.saturating_sub(background)))
+                    .copied()
+                    .collect()
+            }
+        };
+        for piece in next {
+            pending.remove(&piece);
+            in_flight.insert(piece);
+            spawn(join_set, piece)?;
+        }
+        Ok(())
+    };
+    let window = |readahead: &Option<watch::Receiver<Readahead>>| {
+        readahead.as_ref().map(|readahead| *readahead.borrow())
+    };
+    schedule(&mut join_set, &mut in_flight, window(&readahead))?;
+
+    let mut rate_sample = time::interval(RATE_SAMPLE_INTERVAL);
+    let mut sampled_bytes = 0u64;
+    let mut sampled_uploads = state.uploads.uploaded();
+    let mut choker = Choker::default();
+    let mut choke_round = time::interval(choker::ROUND_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ctx.cancel.cancelled() => {
+                join_set.shutdown().await;
+                return Err(Error::Cancelled);
+            }
+            join_result = join_set.join_next(),
+                if !join_set.is_empty() || parked.lock().unwrap().is_empty() => {
+                let Some(join_result) = join_result else {
+                    break;
+                };
+                let (piece, peer, data) = join_result?;
+                if data.is_empty() {
+                    // Letting go of a banned peer closes its connection once
+                    // any loads still using it are done.
+                    if ctx.is_banned(&peer) {
+                        for peers in peer_piece_map.lock().unwrap().values_mut() {
+                            peers.retain(|known| known.address != peer);
+                        }
+                    }
+                    spawn(&mut join_set, piece)?;
+                } else {
+                    sampled_bytes += data.len() as u64;
+                    state.bytes_downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    state.bytes_done.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    state.pieces_done.fetch_add(1, Ordering::Relaxed);
+                    state.uploads.insert(piece, data.clone());
+                    on_piece(piece, data);
+                    ctx.emit(DownloadEvent::PieceVerified { index: piece, peer });
+                    for mut peer in connected(&peer_piece_map) {
+                        tokio::spawn(async move { peer.have(piece as u32).await });
+                    }
+                    in_flight.remove(&piece);
+                    schedule(&mut join_set, &mut in_flight, window(&readahead))?;
+                }
+            }
+            Some((peer, pieces)) = async {
+                match discovered.as_mut() {
+                    Some(discovered) => discovered.recv().await,
+                    None => future::pending().await,
+                }
+            } => {
+                // New peers, and pieces known peers have completed since.
+                if ctx.is_banned(&peer.address) {
+                    continue;
+                }
+                {
+                    let mut peer_piece_map = peer_piece_map.lock().unwrap();
+                    for &piece in &pieces {
+                        let peers = peer_piece_map.entry(piece).or_default();
+                        if !peers.iter().any(|known| known.address == peer.address) {
+                            peers.push(peer.clone());
+                        }
+                    }
+                }
+                let unparked: Vec<usize> = {
+                    let mut parked = parked.lock().unwrap();
+                    pieces.iter().filter(|piece| parked.remove(piece)).copied().collect()
+                };
+                for piece in unparked {
+                    spawn(&mut join_set, piece)?;
+                }
+            }
+            // A new playback position or window takes effect right away.
+            changed = async {
+                match readahead.as_mut() {
+                    Some(readahead) => readahead.changed().await,
+                    None => future::pending().await,
+                }
+            } => {
+                if changed.is_err() {
+                    // The stream is gone; nothing will move the window again.
+                    readahead = None;
+                }
+                schedule(&mut join_set, &mut in_flight, window(&readahead))?;
+            }
+            _ = rate_sample.tick() => {
+                let bytes_per_sec = sampled_bytes / RATE_SAMPLE_INTERVAL.as_secs();
+                state.download_rate.store(bytes_per_sec, Ordering::Relaxed);
+                ctx.emit(DownloadEvent::RateSample { bytes_per_sec });
+                sampled_bytes = 0;
+                let uploaded = state.uploads.uploaded();
+                let upload_rate = (uploaded - sampled_uploads) / RATE_SAMPLE_INTERVAL.as_secs();
+                state.upload_rate.store(upload_rate, Ordering::Relaxed);
+                sampled_uploads = uploaded;
+                for peer in connected(&peer_piece_map) {
+                    peer.throughput().sample();
+                }
+                for seed in &web_seeds {
+                    seed.throughput().sample();
+                }
+            }
+            _ = choke_round.tick() => {
+                choker.rechoke(&connected(&peer_piece_map), false);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("tracker error: {0}")]
+    Tracker(String),
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("metadata error: {0}")]
+    Metadata(String),
+    #[error("storage error: {0}")]
+    Storage(#[source] io::Error),
+    #[error("invalid magnet link: {0}")]
+    Magnet(String),
+    #[error("feed error: {0}")]
+    Feed(String),
+    #[error("config error: {0}")]
+    Config(String),
+    #[error("peer rejected a request for piece {0}")]
+    Rejected(u32),
+    #[error("could not find peer")]
+    NoPeers,
+    #[error("operation timed out")]
+    Timeout,
+    #[error("download cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Bencode(#[from] serde_bencode::Error),
+    #[error(transparent)]
+    Bincode(#[from] bincode::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "http")]
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[cfg(feature = "webrtc")]
+    #[error(transparent)]
+    WebRtc(#[from] webrtc::Error),
+    #[cfg(feature = "geoip")]
+    #[error(transparent)]
+    GeoIp(#[from] maxminddb::MaxMindDBError),
+    #[error(transparent)]
+    UrlEncode(#[from] serde_urlencoded::ser::Error),
+    #[error(transparent)]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Hex(#[from] hex::FromHexError),
+    #[error(transparent)]
+    Utf8(#[from] std::string::FromUtf8Error),
+    #[error("task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        Self::Timeout
+    }
+}
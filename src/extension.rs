@@ -1,42 +1,54 @@
 #![allow(dead_code)]
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::mem;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::peer::Transport;
+
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_PEX: &str = "ut_pex";
+// Set in a peer's `added.f` flags when it accepts uTP connections.
+pub const PEX_UTP: u8 = 0x04;
+// BEP 9: metadata is exchanged in pieces of this size, the last one shorter.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
 
 #[derive(Serialize, Deserialize)]
 pub struct ExtensionHeader {
-    pub m: ExtensionMetadata,
+    pub m: BTreeMap<String, u8>,
     p: Option<u16>, // port
-    metadata_size: u32,
+    metadata_size: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ExtensionMetadata {
-    pub ut_metadata: u8,
-    ut_pex: Option<u8>,
+impl Default for ExtensionHeader {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ExtensionHeader {
     pub fn new() -> Self {
-        let metadata = ExtensionMetadata {
-            ut_metadata: 1,
-            ut_pex: Some(2),
-        };
-        let port = Some(6881);
-        let size = (mem::size_of_val(&metadata) + mem::size_of_val(&port)) as u32;
+        ExtensionRegistry::default().header()
+    }
 
-        Self {
-            m: metadata,
-            p: port,
-            metadata_size: size,
-        }
+    // The size of the info dictionary, from peers that have it.
+    pub fn metadata_size(&self) -> Option<u32> {
+        self.metadata_size
+    }
+
+    pub fn with_metadata_size(mut self, metadata_size: Option<u32>) -> Self {
+        self.metadata_size = metadata_size;
+        self
     }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ExtensionMessage {
     pub msg_type: ExtensionMessageType,
-    pub piece: u8,
+    pub piece: u32,
     pub total_size: Option<u32>,
 }
 
@@ -47,3 +59,148 @@ pub enum ExtensionMessageType {
     Data,
     Reject,
 }
+
+// BEP 11: peers a connected peer has connected to or dropped since its last
+// message, as compact IPv4 and IPv6 addresses with a flags byte for each
+// added peer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default, with = "serde_bytes")]
+    pub added: Vec<u8>,
+    #[serde(
+        rename = "added.f",
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub added_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub added6: Vec<u8>,
+    #[serde(
+        rename = "added6.f",
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub added6_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub dropped6: Vec<u8>,
+}
+
+impl PexMessage {
+    pub fn new(added: &[SocketAddr]) -> Self {
+        let added: Vec<_> = added.iter().map(|&peer| (peer, Transport::Tcp)).collect();
+        Self::with_transports(&added)
+    }
+
+    pub fn with_transports(added: &[(SocketAddr, Transport)]) -> Self {
+        let mut message = Self::default();
+        for (peer, transport) in added {
+            let (list, flags, ip) = match peer.ip() {
+                IpAddr::V4(ip) => (
+                    &mut message.added,
+                    &mut message.added_flags,
+                    ip.octets().to_vec(),
+                ),
+                IpAddr::V6(ip) => (
+                    &mut message.added6,
+                    &mut message.added6_flags,
+                    ip.octets().to_vec(),
+                ),
+            };
+            list.extend(ip);
+            list.extend(peer.port().to_be_bytes());
+            flags.push(match transport {
+                Transport::Tcp => 0,
+                Transport::Utp => PEX_UTP,
+            });
+        }
+        message
+    }
+
+    pub fn added(&self) -> Vec<SocketAddr> {
+        let v4 = self.added.chunks_exact(6).map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([chunk[4], chunk[5]]))
+        });
+        let v6 = self.added6.chunks_exact(18).map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+            let ip = Ipv6Addr::from(ip);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([chunk[16], chunk[17]]))
+        });
+        v4.chain(v6).collect()
+    }
+
+    // Added peers with the transport they prefer to be dialed over.
+    pub fn added_transports(&self) -> Vec<(SocketAddr, Transport)> {
+        let v4 = self.added.len() / 6;
+        self.added()
+            .into_iter()
+            .enumerate()
+            .map(|(i, peer)| {
+                let flags = match i.checked_sub(v4) {
+                    None => self.added_flags.get(i),
+                    Some(i) => self.added6_flags.get(i),
+                };
+                let transport = match flags.is_some_and(|flags| flags & PEX_UTP != 0) {
+                    true => Transport::Utp,
+                    false => Transport::Tcp,
+                };
+                (peer, transport)
+            })
+            .collect()
+    }
+}
+
+pub type ExtensionHandler = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+// Extensions we advertise in our handshake, keyed by the message id peers
+// must use when sending them to us.
+#[derive(Clone)]
+pub struct ExtensionRegistry {
+    ids: BTreeMap<String, u8>,
+    handlers: HashMap<u8, ExtensionHandler>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        let ids = BTreeMap::from([(UT_METADATA.to_string(), 1), (UT_PEX.to_string(), 2)]);
+        Self {
+            ids,
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl ExtensionRegistry {
+    pub fn register(&mut self, name: &str, handler: ExtensionHandler) -> u8 {
+        let id = match self.ids.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = self.ids.values().max().map_or(1, |id| id + 1);
+                self.ids.insert(name.to_string(), id);
+                id
+            }
+        };
+        self.handlers.insert(id, handler);
+        id
+    }
+
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn handler(&self, id: u8) -> Option<&ExtensionHandler> {
+        self.handlers.get(&id)
+    }
+
+    pub fn header(&self) -> ExtensionHeader {
+        ExtensionHeader {
+            m: self.ids.clone(),
+            p: Some(6881),
+            metadata_size: None, // only sent once we have the metadata
+        }
+    }
+}
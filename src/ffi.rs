@@ -0,0 +1,228 @@
+// C bindings for embedding the engine. The matching header lives in
+// include/bittorrent.h and is generated with `cbindgen`.
+use std::{
+    collections::HashMap,
+    ffi::{c_char, CStr},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+use tokio::runtime::Runtime;
+use url::Url;
+
+use crate::{
+    download::{DownloadHandle, Progress},
+    error::{Error, Result},
+    magnet::Magnet,
+};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BtDownloadState {
+    Running = 0,
+    Paused = 1,
+    Completed = 2,
+    Failed = 3,
+    Cancelled = 4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BtProgress {
+    pub state: BtDownloadState,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+    pub pieces_done: u64,
+    pub total_pieces: u64,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+enum Download {
+    Running(DownloadHandle<()>),
+    Finished(BtDownloadState, Progress),
+}
+
+pub struct BtSession {
+    runtime: Runtime,
+    downloads: Mutex<HashMap<u64, Download>>,
+    next_id: AtomicU64,
+}
+
+impl BtSession {
+    fn new() -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(Error::Io)?;
+        Ok(Self {
+            runtime,
+            downloads: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    fn add_magnet(&self, magnet_uri: &str, output_path: PathBuf) -> Result<u64> {
+        let magnet = Magnet::new(Url::parse(magnet_uri)?)?;
+        let _guard = self.runtime.enter();
+        let handle = magnet.download_to(output_path);
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.downloads
+            .lock()
+            .unwrap()
+            .insert(id, Download::Running(handle));
+        Ok(id)
+    }
+
+    fn progress(&self, id: u64) -> Option<BtProgress> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let finished =
+            matches!(downloads.get(&id)?, Download::Running(handle) if handle.is_finished());
+        if finished {
+            if let Some(Download::Running(handle)) = downloads.remove(&id) {
+                let progress = handle.progress();
+                let state = match self.runtime.block_on(handle.join()) {
+                    Ok(()) => BtDownloadState::Completed,
+                    Err(Error::Cancelled) => BtDownloadState::Cancelled,
+                    Err(_) => BtDownloadState::Failed,
+                };
+                downloads.insert(id, Download::Finished(state, progress));
+            }
+        }
+
+        let (state, progress) = match downloads.get(&id)? {
+            Download::Running(handle) if handle.is_paused() => {
+                (BtDownloadState::Paused, handle.progress())
+            }
+            Download::Running(handle) => (BtDownloadState::Running, handle.progress()),
+            Download::Finished(state, progress) => (*state, *progress),
+        };
+        Some(BtProgress {
+            state,
+            bytes_done: progress.bytes_done,
+            total_bytes: progress.total_bytes,
+            pieces_done: progress.pieces_done as u64,
+            total_pieces: progress.total_pieces as u64,
+            download_rate: progress.download_rate,
+            upload_rate: progress.upload_rate,
+        })
+    }
+
+    fn with_handle(&self, id: u64, f: impl FnOnce(&DownloadHandle<()>)) -> bool {
+        match self.downloads.lock().unwrap().get(&id) {
+            Some(Download::Running(handle)) => {
+                f(handle);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Creates a session with its own runtime. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn bt_session_new() -> *mut BtSession {
+    match BtSession::new() {
+        Ok(session) => Box::into_raw(Box::new(session)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `session` must come from `bt_session_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_free(session: *mut BtSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+/// Starts downloading a magnet link into `output_path`. Returns the download
+/// id, or -1 if the arguments are invalid.
+///
+/// # Safety
+///
+/// `session` must be a live session and both strings valid NUL-terminated
+/// UTF-8.
+#[no_mangle]
+pub unsafe extern "C" fn bt_session_add_magnet(
+    session: *const BtSession,
+    magnet_uri: *const c_char,
+    output_path: *const c_char,
+) -> i64 {
+    let (Some(session), Some(magnet_uri), Some(output_path)) =
+        (session.as_ref(), str_arg(magnet_uri), str_arg(output_path))
+    else {
+        return -1;
+    };
+    match session.add_magnet(magnet_uri, PathBuf::from(output_path)) {
+        Ok(id) => id as i64,
+        Err(_) => -1,
+    }
+}
+
+/// Fills `out` with the current progress. Returns 0 on success, -1 for an
+/// unknown id.
+///
+/// # Safety
+///
+/// `session` must be a live session and `out` a valid, writable pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_progress(
+    session: *const BtSession,
+    id: u64,
+    out: *mut BtProgress,
+) -> i32 {
+    let (Some(session), Some(out)) = (session.as_ref(), out.as_mut()) else {
+        return -1;
+    };
+    match session.progress(id) {
+        Some(progress) => {
+            *out = progress;
+            0
+        }
+        None => -1,
+    }
+}
+
+/// # Safety
+///
+/// `session` must be a live session.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_cancel(session: *const BtSession, id: u64) -> i32 {
+    control(session, id, DownloadHandle::cancel)
+}
+
+/// # Safety
+///
+/// `session` must be a live session.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_pause(session: *const BtSession, id: u64) -> i32 {
+    control(session, id, DownloadHandle::pause)
+}
+
+/// # Safety
+///
+/// `session` must be a live session.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_resume(session: *const BtSession, id: u64) -> i32 {
+    control(session, id, DownloadHandle::resume)
+}
+
+unsafe fn control(session: *const BtSession, id: u64, f: fn(&DownloadHandle<()>)) -> i32 {
+    match session.as_ref() {
+        Some(session) if session.with_handle(id, f) => 0,
+        _ => -1,
+    }
+}
+
+unsafe fn str_arg<'a>(ptr: *const c_char) -> Option<&'a str> {
+    if ptr.is_null() {
+        return None;
+    }
+    CStr::from_ptr(ptr).to_str().ok()
+}
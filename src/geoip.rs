@@ -0,0 +1,24 @@
+// Country lookups for peer addresses from a MaxMind GeoLite2/GeoIP2 database.
+use maxminddb::{geoip2, Reader};
+use std::{net::IpAddr, path::Path};
+
+use crate::error::Result;
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            reader: Reader::open_readfile(path)?,
+        })
+    }
+
+    // ISO 3166-1 alpha-2 code, or None for private and unknown addresses.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let country = record.country.or(record.registered_country)?;
+        country.iso_code.map(str::to_string)
+    }
+}
@@ -0,0 +1,321 @@
+// Downloading within the I2P network through a router's SAM v3 bridge.
+// One transient STREAM session is created per download; every tracker
+// request and peer connection is a SAM stream on it, so nothing touches the
+// clearnet. I2P trackers hand out destinations rather than IP addresses,
+// either as `<base64 destination>.i2p` strings or as compact 32-byte hashes
+// that name `<base32>.b32.i2p` addresses.
+use data_encoding::BASE32_NOPAD;
+use rand::distributions::{Alphanumeric, DistString};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+    time,
+};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::{
+    bencode::{self, Value},
+    download::{add_peer, download_pieces_to, unaddressed_peer, DownloadContext, DownloadHandle},
+    error::{Error, Result},
+    peer::Peer,
+    torrent::Torrent,
+    tracker::TrackerRequest,
+};
+
+pub const DEFAULT_SAM: &str = "127.0.0.1:7656";
+const SAM_VERSION: &str = "3.1";
+// A base64 destination is at least 516 characters; anything shorter ending
+// in `.i2p` is a hostname or b32 address the router has to look up.
+const MIN_DESTINATION_LEN: usize = 516;
+// Tunnels take a while to build, so a peer gets longer to answer than over
+// the clearnet.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+// Destinations dialed at the same time.
+const DIALS: usize = 8;
+
+pub struct SamSession {
+    sam: SocketAddr,
+    id: String,
+    // SAM tears the session down when this connection closes.
+    _control: TcpStream,
+    destination: String,
+}
+
+impl SamSession {
+    pub async fn create(sam: SocketAddr) -> Result<Self> {
+        let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 12);
+        let mut control = hello(sam).await?;
+        command(
+            &mut control,
+            &format!(
+                "SESSION CREATE STYLE=STREAM ID={} DESTINATION=TRANSIENT",
+                id
+            ),
+        )
+        .await?;
+        let mut session = Self {
+            sam,
+            id,
+            _control: control,
+            destination: String::new(),
+        };
+        session.destination = session.lookup("ME").await?;
+        Ok(session)
+    }
+
+    // Our own public destination, announced to trackers.
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+
+    pub async fn lookup(&self, name: &str) -> Result<String> {
+        let mut stream = hello(self.sam).await?;
+        let reply = command(&mut stream, &format!("NAMING LOOKUP NAME={}", name)).await?;
+        reply_value(&reply, "VALUE")
+            .map(str::to_string)
+            .ok_or_else(|| sam_error(&reply))
+    }
+
+    // Opens a stream to a destination, hostname or b32 address.
+    pub async fn connect(&self, peer: &str) -> Result<TcpStream> {
+        let destination = match peer.strip_suffix(".i2p") {
+            Some(destination) if destination.len() >= MIN_DESTINATION_LEN => {
+                destination.to_string()
+            }
+            Some(_) => self.lookup(peer).await?,
+            None => peer.to_string(),
+        };
+        let mut stream = hello(self.sam).await?;
+        command(
+            &mut stream,
+            &format!(
+                "STREAM CONNECT ID={} DESTINATION={} SILENT=false",
+                self.id, destination
+            ),
+        )
+        .await?;
+        Ok(stream)
+    }
+
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<Vec<String>> {
+        let url = Url::parse(tracker_url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| Error::Tracker("missing tracker host".to_string()))?;
+        let info_hash: String = url::form_urlencoded::byte_serialize(info_hash).collect();
+        let query = format!(
+            "{}info_hash={}&{}&ip={}.i2p",
+            url.query()
+                .map(|query| format!("{}&", query))
+                .unwrap_or_default(),
+            info_hash,
+            serde_urlencoded::to_string(request)?,
+            self.destination
+        );
+
+        let mut stream = self.connect(host).await?;
+        let request = format!(
+            "GET {}?{} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            url.path(),
+            query,
+            host
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| Error::Tracker("malformed HTTP response".to_string()))?;
+        let status = String::from_utf8_lossy(&response[..split]);
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(Error::Tracker(format!(
+                "tracker replied {}",
+                status.lines().next().unwrap_or_default()
+            )));
+        }
+        peers(&bencode::decode(&response[split + 4..])?)
+    }
+}
+
+async fn hello(sam: SocketAddr) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(sam).await?;
+    command(
+        &mut stream,
+        &format!("HELLO VERSION MIN={} MAX={}", SAM_VERSION, SAM_VERSION),
+    )
+    .await?;
+    Ok(stream)
+}
+
+// Sends one command line and returns the reply, failing unless RESULT=OK.
+async fn command(stream: &mut TcpStream, line: &str) -> Result<String> {
+    stream.write_all(format!("{}\n", line).as_bytes()).await?;
+    // Read byte by byte: after STREAM CONNECT the same socket carries the
+    // peer's data, which must not end up in a read-ahead buffer.
+    let mut reply = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte => reply.push(byte),
+        }
+    }
+    let reply = String::from_utf8_lossy(&reply).into_owned();
+    match reply_value(&reply, "RESULT") {
+        Some("OK") => Ok(reply),
+        _ => Err(sam_error(&reply)),
+    }
+}
+
+fn reply_value<'a>(reply: &'a str, key: &str) -> Option<&'a str> {
+    reply
+        .split_whitespace()
+        .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn sam_error(reply: &str) -> Error {
+    Error::Protocol(format!("SAM: {}", reply.trim()))
+}
+
+fn peers(response: &Value) -> Result<Vec<String>> {
+    if let Some(reason) = response.get("failure reason") {
+        return Err(Error::Tracker(
+            reason.as_str_lossy().unwrap_or_default().into_owned(),
+        ));
+    }
+    match response.get("peers") {
+        Some(Value::Bytes(hashes)) if hashes.len().is_multiple_of(32) => Ok(hashes
+            .chunks_exact(32)
+            .map(|hash| format!("{}.b32.i2p", BASE32_NOPAD.encode(hash).to_lowercase()))
+            .collect()),
+        Some(Value::List(peers)) => Ok(peers
+            .iter()
+            .filter_map(|peer| peer.get("ip")?.as_str_lossy())
+            .map(|ip| ip.into_owned())
+            .collect()),
+        _ => Err(Error::Tracker("malformed I2P peer list".to_string())),
+    }
+}
+
+// Downloads `torrent` into `path` using only I2P trackers and peers.
+pub fn download_to(torrent: &Torrent, sam: SocketAddr, path: PathBuf) -> DownloadHandle<()> {
+    let torrent = torrent.clone();
+    DownloadHandle::spawn(|ctx| async move {
+        let session = Arc::new(ctx.until_cancelled(SamSession::create(sam)).await?);
+        let peer_piece_map = connect_peers(&session, &torrent, &ctx).await?;
+        download_pieces_to(&torrent.info, peer_piece_map, BTreeMap::new(), &ctx, path).await
+    })
+}
+
+// Announces to the first tracker in each tier that answers, like a clearnet
+// announce, and returns every destination they gave.
+async fn announce_each_tier(
+    session: &SamSession,
+    tiers: &[Vec<String>],
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Vec<String>> {
+    let mut destinations = BTreeSet::new();
+    let mut answered = false;
+    let mut last_error = None;
+    for tier in tiers {
+        for tracker_url in tier {
+            match session.announce(tracker_url, info_hash, request).await {
+                Ok(found) => {
+                    answered = true;
+                    destinations.extend(found);
+                    break;
+                }
+                Err(e) => {
+                    warn!(tracker = %tracker_url, "{}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    match (answered, last_error) {
+        (false, Some(e)) => Err(e),
+        (false, None) => Err(Error::Tracker("no trackers to announce to".to_string())),
+        (true, _) => Ok(destinations.into_iter().collect()),
+    }
+}
+
+async fn connect_peers(
+    session: &Arc<SamSession>,
+    torrent: &Torrent,
+    ctx: &DownloadContext,
+) -> Result<HashMap<usize, Vec<Peer>>> {
+    let info_hash = torrent.info_hash()?;
+    let piece_count = torrent.info.piece_count();
+    let request = TrackerRequest::builder().left(torrent.len()).build();
+    let destinations = ctx
+        .announce(announce_each_tier(
+            session,
+            &torrent.tiers(),
+            &info_hash,
+            &request,
+        ))
+        .await?;
+    let mut destinations = destinations.into_iter();
+    let mut dialing = JoinSet::new();
+    let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
+
+    loop {
+        while dialing.len() < DIALS {
+            let Some(destination) = destinations.next() else {
+                break;
+            };
+            let session = session.clone();
+            let local_id = ctx.local_id();
+            dialing.spawn(async move {
+                let connect = async {
+                    let stream = session.connect(&destination).await?;
+                    // I2P peers have no IP address; they are keyed by one
+                    // no other peer has.
+                    let address = unaddressed_peer();
+                    let peer =
+                        Peer::connect_stream_as(stream, address, info_hash, local_id).await?;
+                    Ok(peer.with_piece_count(piece_count))
+                };
+                let connected: Result<Peer> = time::timeout(CONNECT_TIMEOUT, connect)
+                    .await
+                    .unwrap_or(Err(Error::Timeout));
+                (destination, connected)
+            });
+        }
+        let Some(dialed) = ctx
+            .until_cancelled(async { Ok(dialing.join_next().await) })
+            .await?
+        else {
+            break;
+        };
+        let (destination, connected) = dialed?;
+        let added = match connected {
+            Ok(peer) => add_peer(peer, &mut peer_piece_map, ctx).await,
+            Err(e) => Err(e),
+        };
+        // A destination that fails any step is dropped; the others carry on.
+        match added {
+            Ok(()) => {}
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => debug!(%destination, "{}", e),
+        }
+    }
+
+    Ok(peer_piece_map)
+}
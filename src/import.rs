@@ -0,0 +1,101 @@
+// Migration of torrents and their progress from other clients into the
+// session store.
+use std::{fs, path::Path};
+use tracing::warn;
+
+use crate::{
+    bencode::{self, Value},
+    error::{Error, Result},
+    store::{SessionStore, StoredTorrent},
+    torrent::Torrent,
+};
+
+// Imports every `<hash>.torrent` + `<hash>.fastresume` pair in qBittorrent's
+// BT_backup directory. Torrents without metadata (magnets that never
+// resolved) are skipped.
+pub fn import_qbittorrent(
+    backup_dir: &Path,
+    store: &mut SessionStore,
+) -> Result<Vec<StoredTorrent>> {
+    let mut imported = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(backup_dir)
+        .map_err(Error::Storage)?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "fastresume"))
+        .collect();
+    entries.sort();
+
+    for resume_path in entries {
+        let torrent_path = resume_path.with_extension("torrent");
+        if !torrent_path.exists() {
+            warn!("skipping {}: no metadata", resume_path.display());
+            continue;
+        }
+        match import_pair(&torrent_path, &resume_path, store) {
+            Ok(stored) => imported.push(stored),
+            Err(e) => warn!("skipping {}: {}", resume_path.display(), e),
+        }
+    }
+    store.save()?;
+    Ok(imported)
+}
+
+fn import_pair(
+    torrent_path: &Path,
+    resume_path: &Path,
+    store: &mut SessionStore,
+) -> Result<StoredTorrent> {
+    let resume = bencode::decode(&fs::read(resume_path).map_err(Error::Storage)?)?;
+    let metainfo = bencode::decode(&fs::read(torrent_path).map_err(Error::Storage)?)?;
+    let metainfo = with_announce(metainfo, &resume)?;
+
+    let save_path = ["qBt-savePath", "save_path"]
+        .iter()
+        .find_map(|key| resume.get(key)?.as_str_lossy())
+        .filter(|path| !path.is_empty())
+        .ok_or_else(|| Error::Metadata("fastresume has no save path".to_string()))?;
+    let torrent = Torrent::from_bytes(&metainfo)?;
+    let piece_count = torrent.info.piece_count();
+    // libtorrent stores one byte per piece, with bit 0 set once verified.
+    let have: Vec<usize> = resume
+        .get("pieces")
+        .and_then(Value::as_bytes)
+        .unwrap_or_default()
+        .iter()
+        .enumerate()
+        .filter(|(_, state)| *state & 1 == 1)
+        .map(|(piece, _)| piece)
+        .collect();
+    let paused = resume.get("paused").and_then(Value::as_int) == Some(1);
+
+    let stored = store.add(&metainfo, save_path.into_owned().into())?;
+    stored.set_have(have, piece_count);
+    stored.paused = paused;
+    Ok(stored.clone())
+}
+
+// qBittorrent keeps trackers in the fastresume file, and newer versions
+// write metadata-only .torrent files without an announce URL.
+fn with_announce(metainfo: Value, resume: &Value) -> Result<Vec<u8>> {
+    let Value::Dict(mut metainfo) = metainfo else {
+        return Err(Error::Metadata("metainfo is not a dictionary".to_string()));
+    };
+    if !metainfo.contains_key(b"announce".as_slice()) {
+        let tracker = resume
+            .get("trackers")
+            .and_then(Value::as_list)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_list)
+            .flatten()
+            .find_map(Value::as_bytes)
+            .ok_or_else(|| Error::Metadata("no tracker to announce to".to_string()))?;
+        metainfo.insert(b"announce".to_vec(), Value::Bytes(tracker.to_vec()));
+    }
+    if !metainfo.contains_key(b"info".as_slice()) {
+        return Err(Error::Metadata(
+            "metainfo has no info dictionary".to_string(),
+        ));
+    }
+    bencode::encode(&Value::Dict(metainfo))
+}
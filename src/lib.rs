@@ -1,6 +1,56 @@
+#[cfg(unix)]
+pub mod api;
+pub mod aria2;
+pub mod bencode;
+pub mod blocking;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod choker;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod codec;
+pub mod config;
+pub mod connections;
+#[cfg(unix)]
+pub mod control;
+pub mod create;
 pub mod decode;
+pub mod dedupe;
+pub mod dht;
+pub mod download;
+pub mod error;
+pub mod extension;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod i2p;
+pub mod import;
+pub mod listener;
 pub mod magnet;
+#[cfg(unix)]
+pub mod metrics;
+pub mod nat;
 pub mod peer;
+pub mod ratelimit;
+pub mod record;
+pub mod resume;
+#[cfg(feature = "rss")]
+pub mod rss;
+pub mod selection;
+pub mod session;
+pub mod source;
+pub mod storage;
+pub mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tor;
 pub mod torrent;
 pub mod tracker;
-pub mod extension;
+pub mod utp;
+pub mod v2;
+pub mod webseed;
+#[cfg(feature = "webrtc")]
+pub mod webtorrent;
+
+pub use error::{Error, Result};
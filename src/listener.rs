@@ -0,0 +1,204 @@
+// Accepting connections from peers. A listener serves every download
+// registered with it: an incoming handshake is checked against their info
+// hashes, and the peer joins the download it asked for exactly like one we
+// dialed. A session may run its own listener; other downloads share the
+// process's.
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{net::TcpListener, task::JoinHandle, time};
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::{
+    download::{join_peer, DownloadContext},
+    error::Result,
+    peer::Peer,
+};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Registered {
+    ctx: DownloadContext,
+    piece_count: usize,
+}
+
+// The downloads a listener hands peers to, by info hash.
+type Downloads = Mutex<BTreeMap<[u8; 20], Registered>>;
+
+pub(crate) struct Listener {
+    address: SocketAddr,
+    // What the router forwards to `address`, when it is mapped.
+    external_port: Mutex<Option<u16>>,
+    downloads: Arc<Downloads>,
+    task: JoinHandle<()>,
+}
+
+static LISTENER: Mutex<Option<Arc<Listener>>> = Mutex::new(None);
+// Kept apart from the process's listener so downloads registered before it
+// starts, or while it is replaced, are still found.
+static DOWNLOADS: LazyLock<Arc<Downloads>> = LazyLock::new(Arc::default);
+
+impl Listener {
+    // Binds `address` and accepts peers for downloads registered with the
+    // listener until it is stopped or dropped.
+    pub(crate) async fn bind(address: SocketAddr) -> Result<Arc<Self>> {
+        Self::serve(address, Arc::default()).await
+    }
+
+    async fn serve(address: SocketAddr, downloads: Arc<Downloads>) -> Result<Arc<Self>> {
+        let listener = TcpListener::bind(address).await?;
+        let address = listener.local_addr()?;
+        let task = tokio::spawn(accept(listener, downloads.clone()));
+        Ok(Arc::new(Self {
+            address,
+            external_port: Mutex::new(None),
+            downloads,
+            task,
+        }))
+    }
+
+    pub(crate) fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    // The port to announce.
+    pub(crate) fn port(&self) -> u16 {
+        self.external_port
+            .lock()
+            .unwrap()
+            .unwrap_or(self.address.port())
+    }
+
+    pub(crate) fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// Binds `address` and accepts peers for every download outside a session
+// with its own listener, replacing any listener already running. Returns
+// the bound address.
+pub async fn listen(address: SocketAddr) -> Result<SocketAddr> {
+    let listener = Listener::serve(address, DOWNLOADS.clone()).await?;
+    let address = listener.address();
+    if let Some(old) = LISTENER.lock().unwrap().replace(listener) {
+        old.stop();
+    }
+    Ok(address)
+}
+
+pub fn stop() {
+    if let Some(old) = LISTENER.lock().unwrap().take() {
+        old.stop();
+    }
+}
+
+// The port to announce, if the process is listening.
+pub fn port() -> Option<u16> {
+    LISTENER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|listener| listener.port())
+}
+
+pub fn set_external_port(port: Option<u16>) {
+    if let Some(listener) = LISTENER.lock().unwrap().as_ref() {
+        *listener.external_port.lock().unwrap() = port;
+    }
+}
+
+// Incoming peers for `info_hash` join `ctx`'s download until the returned
+// guard is dropped. They arrive through the download's session listener if
+// it has one, and the process's otherwise.
+pub(crate) fn register(
+    info_hash: [u8; 20],
+    ctx: &DownloadContext,
+    piece_count: usize,
+) -> Registration {
+    let downloads = match ctx.listener() {
+        Some(listener) => listener.downloads.clone(),
+        None => DOWNLOADS.clone(),
+    };
+    let registered = Registered {
+        ctx: ctx.clone(),
+        piece_count,
+    };
+    downloads.lock().unwrap().insert(info_hash, registered);
+    Registration {
+        downloads,
+        info_hash,
+    }
+}
+
+pub(crate) struct Registration {
+    downloads: Arc<Downloads>,
+    info_hash: [u8; 20],
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.downloads.lock().unwrap().remove(&self.info_hash);
+    }
+}
+
+async fn accept(listener: TcpListener, downloads: Arc<Downloads>) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("accepting peers: {}", e);
+                continue;
+            }
+        };
+        let span = info_span!("peer", %address);
+        let downloads = downloads.clone();
+        let serve =
+            async move {
+                // Each download answers with its own peer ID.
+                let expected: Vec<_> = downloads
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(info_hash, registered)| (*info_hash, registered.ctx.local_id()))
+                    .collect();
+                let peer =
+                    match time::timeout(HANDSHAKE_TIMEOUT, Peer::from_incoming(stream, &expected))
+                        .await
+                    {
+                        Ok(Ok(peer)) => peer,
+                        Ok(Err(e)) => return debug!("{}", e),
+                        Err(_) => return debug!("no handshake"),
+                    };
+                // The download may have finished during the handshake.
+                let Some((ctx, piece_count)) = downloads
+                    .lock()
+                    .unwrap()
+                    .get(&peer.info_hash)
+                    .map(|registered| (registered.ctx.clone(), registered.piece_count))
+                else {
+                    return;
+                };
+                let address = peer.address;
+                if ctx.is_banned(&address) {
+                    return debug!("turned away, banned");
+                }
+                if !ctx.connections().try_reserve(address) {
+                    return debug!("turned away, at the peer limit");
+                }
+                if let Err(e) = join_peer(peer.with_piece_count(piece_count), &ctx).await {
+                    ctx.connections().release(&address);
+                    debug!("{}", e);
+                }
+            };
+        tokio::spawn(serve.instrument(span));
+    }
+}
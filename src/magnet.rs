@@ -1,205 +1,448 @@
-use anyhow::Context;
-use rand::seq::SliceRandom;
-use sha1::{Digest, Sha1};
-use std::{collections::HashMap, net::SocketAddr};
+use bytes::Bytes;
+use data_encoding::BASE32_NOPAD;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
 use tokio::task::JoinSet;
+use tracing::debug;
 use url::{form_urlencoded, Url};
 
 use crate::{
-    peer::Peer,
+    dht,
+    download::{
+        download_pieces, download_pieces_to, AllAtOnce, DownloadContext, DownloadEvent,
+        DownloadHandle,
+    },
+    error::{Error, Result},
+    peer::{Peer, Transport},
+    session::Shared,
+    tor,
     torrent::Info,
-    tracker::{TrackerRequest, TrackerResponse},
+    tracker::{self, TrackerRequest},
+    webseed::WebSeed,
 };
 
-const MAGNET_XT_PREFIX: &'static str = "urn:btih:";
+const MAGNET_XT_PREFIX: &str = "urn:btih:";
+// BEP 52: a multihash, where 0x12 0x20 marks a 32-byte SHA-256.
+const MAGNET_XT_V2_PREFIX: &str = "urn:btmh:1220";
+// Length of a base32 info hash; hex ones are 40 characters.
+const BASE32_INFO_HASH_LEN: usize = 32;
+// Peers `handshake` dials at the same time.
+const HANDSHAKE_DIALS: usize = 16;
 
+#[derive(Clone)]
 pub struct Magnet {
     pub info_hash: [u8; 20], // raw bytes
+    pub info_hash_v2: Option<[u8; 32]>,
     pub file_name: Option<String>,
-    pub tracker_url: Option<Url>,
+    pub trackers: Vec<Url>,
+    // `x.pe` hints: `host:port` of peers to try before any others.
+    pub peers: Vec<String>,
+    // `ws` web seeds (BEP 19), used once a peer has sent the metadata.
+    pub web_seeds: Vec<String>,
+    // `so` (BEP 53): indices of the only files to download, if not all.
+    pub select_only: Option<Vec<usize>>,
+}
+
+impl From<[u8; 20]> for Magnet {
+    fn from(info_hash: [u8; 20]) -> Self {
+        Self {
+            info_hash,
+            info_hash_v2: None,
+            file_name: None,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            web_seeds: Vec::new(),
+            select_only: None,
+        }
+    }
 }
 
 impl Magnet {
-    pub fn new(url: Url) -> anyhow::Result<Self> {
+    pub fn new(url: Url) -> Result<Self> {
         if url.scheme() != "magnet" {
-            return Err(anyhow::anyhow!("invalid magnet link"));
+            return Err(Error::Magnet(format!(
+                "unexpected scheme: {}",
+                url.scheme()
+            )));
         }
 
         let query_pairs = url.query_pairs().collect::<HashMap<_, _>>();
-        let xt = query_pairs.get("xt").ok_or(anyhow::anyhow!("missing xt"))?;
-        if !xt.starts_with(MAGNET_XT_PREFIX) {
-            return Err(anyhow::anyhow!("invalid xt"));
+        // Hybrid torrents may have both a v1 and a v2 `xt`.
+        let xts: Vec<_> = url.query_pairs().filter(|(key, _)| key == "xt").collect();
+        if xts.is_empty() {
+            return Err(Error::Magnet("missing xt".to_string()));
         }
-
-        let info_hash = hex::decode(&xt[MAGNET_XT_PREFIX.len()..])?
-            .try_into()
-            .map_err(|_| anyhow::anyhow!("info hash must be 20 bytes"))?;
+        let (mut info_hash, mut info_hash_v2) = (None, None);
+        for (_, xt) in &xts {
+            if let Some(encoded) = xt.strip_prefix(MAGNET_XT_PREFIX) {
+                info_hash = Some(decode_info_hash(encoded)?);
+            } else if let Some(encoded) = xt.strip_prefix(MAGNET_XT_V2_PREFIX) {
+                let hash: [u8; 32] = hex::decode(encoded)?
+                    .try_into()
+                    .map_err(|_| Error::Magnet("v2 info hash must be 32 bytes".to_string()))?;
+                info_hash_v2 = Some(hash);
+            }
+        }
+        // v2-only swarms go by the v2 info hash truncated to 20 bytes.
+        let info_hash = match (info_hash, info_hash_v2) {
+            (Some(info_hash), _) => info_hash,
+            (None, Some(info_hash_v2)) => info_hash_v2[..20].try_into().unwrap(),
+            (None, None) => return Err(Error::Magnet("invalid xt".to_string())),
+        };
         let file_name = query_pairs.get("dn").map(|s| s.to_string());
-        let tracker_url = query_pairs.get("tr").map(|s| Url::parse(s)).transpose()?;
+        let trackers = url
+            .query_pairs()
+            .filter(|(key, _)| key == "tr")
+            .map(|(_, tr)| Url::parse(&tr))
+            .collect::<std::result::Result<_, _>>()?;
+        let peers = url
+            .query_pairs()
+            .filter(|(key, _)| key == "x.pe")
+            .map(|(_, peer)| match peer.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(peer.to_string())
+                }
+                _ => Err(Error::Magnet(format!("invalid x.pe: {}", peer))),
+            })
+            .collect::<Result<_>>()?;
+        let web_seeds = url
+            .query_pairs()
+            .filter(|(key, _)| key == "ws")
+            .map(|(_, ws)| Ok(Url::parse(&ws)?.to_string()))
+            .collect::<Result<_>>()?;
+        let select_only = query_pairs
+            .get("so")
+            .map(|so| parse_select_only(so))
+            .transpose()?;
 
         let magnet = Self {
             info_hash,
+            info_hash_v2,
             file_name,
-            tracker_url,
+            trackers,
+            peers,
+            web_seeds,
+            select_only,
         };
         Ok(magnet)
     }
 
-    pub async fn get_peer_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
-        let request = TrackerRequest::new(1);
-        let params = serde_urlencoded::to_string(&request)?;
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash).collect();
-        let url = format!(
-            "{}?{}&info_hash={}",
-            self.tracker_url.as_ref().unwrap(),
-            params,
-            info_hash_str,
+    // The magnet link naming this, which `new` parses back.
+    pub fn to_url(&self) -> Url {
+        let encode =
+            |value: &str| form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        let mut params: Vec<String> = Vec::new();
+        // A v2-only link has no v1 info hash to give.
+        if self
+            .info_hash_v2
+            .is_none_or(|info_hash_v2| info_hash_v2[..20] != self.info_hash)
+        {
+            params.push(format!(
+                "xt={}{}",
+                MAGNET_XT_PREFIX,
+                hex::encode(self.info_hash)
+            ));
+        }
+        if let Some(info_hash_v2) = self.info_hash_v2 {
+            let xt = format!("{}{}", MAGNET_XT_V2_PREFIX, hex::encode(info_hash_v2));
+            params.push(format!("xt={}", xt));
+        }
+        if let Some(name) = &self.file_name {
+            params.push(format!("dn={}", encode(name)));
+        }
+        params.extend(
+            self.trackers
+                .iter()
+                .map(|tracker| format!("tr={}", encode(tracker.as_str()))),
+        );
+        params.extend(
+            self.web_seeds
+                .iter()
+                .map(|seed| format!("ws={}", encode(seed))),
         );
+        params.extend(
+            self.peers
+                .iter()
+                .map(|peer| format!("x.pe={}", encode(peer))),
+        );
+        if let Some(files) = &self.select_only {
+            let files: Vec<String> = files.iter().map(usize::to_string).collect();
+            params.push(format!("so={}", files.join(",")));
+        }
+        Url::parse(&format!("magnet:?{}", params.join("&"))).expect("magnet links always parse")
+    }
 
-        let response = reqwest::get(url).await?;
-        let tracker_response =
-            serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
-        let peer_addrs = tracker_response.peers();
-        println!("Found peers: {:?}", peer_addrs);
-        Ok(peer_addrs)
+    // The link's `x.pe` peers come first. Every tracker in the link is
+    // asked, each as a tier of its own, and their peers are merged. Without
+    // any trackers the DHT is the only other way to find peers.
+    pub async fn get_peer_addrs(&self) -> Result<Vec<SocketAddr>> {
+        if self.trackers.is_empty() && self.peers.is_empty() && !dht::enabled() {
+            return Err(Error::Magnet(
+                "no tracker, x.pe peer or DHT to find peers with".to_string(),
+            ));
+        }
+        let mut peers = self.hinted_peers().await;
+        let trackers = async {
+            if self.trackers.is_empty() {
+                return Err(Error::Magnet("missing tracker url".to_string()));
+            }
+            let request = TrackerRequest::builder().left(1).build();
+            tracker::announce_tiers(&self.tiers(), &self.info_hash, &request).await
+        };
+        let found = match dht::alongside(self.info_hash, trackers).await {
+            Ok(announce) => announce.peers,
+            // The hinted peers may be all there is.
+            Err(_) if !peers.is_empty() => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        for peer in found {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        Ok(peers)
     }
 
-    pub async fn handshake(&self) -> anyhow::Result<Peer> {
-        let peer_addrs = self.get_peer_addrs().await?;
-        for peer_address in peer_addrs {
-            match Peer::new(peer_address, self.info_hash).await {
-                Ok(mut peer) => {
-                    if peer.supports_extension {
-                        peer.get_pieces().await?;
-                        peer.extension_handshake().await?;
-                    }
-                    return Ok(peer);
-                }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+    // Hostnames are resolved, except through Tor where that would leak;
+    // hints that don't resolve are skipped.
+    async fn hinted_peers(&self) -> Vec<SocketAddr> {
+        let mut peers = Vec::new();
+        for hint in &self.peers {
+            if let Ok(peer) = hint.parse() {
+                peers.push(peer);
+                continue;
+            }
+            if tor::enabled() {
+                debug!(%hint, "not resolved outside Tor");
+                continue;
+            }
+            match tokio::net::lookup_host(hint.as_str()).await {
+                Ok(mut addrs) => peers.extend(addrs.next()),
+                Err(e) => debug!(%hint, "{}", e),
+            }
+        }
+        peers
+    }
+
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.trackers
+            .iter()
+            .map(|tracker| vec![tracker.to_string()])
+            .collect()
+    }
+
+    // Dials peers a few at a time and keeps whichever completes the
+    // handshakes first.
+    pub async fn handshake(&self) -> Result<Peer> {
+        let mut peer_addrs = self.get_peer_addrs().await?.into_iter();
+        let mut dialing = JoinSet::new();
+        loop {
+            while dialing.len() < HANDSHAKE_DIALS {
+                let Some(peer_address) = peer_addrs.next() else {
+                    break;
+                };
+                let info_hash = self.info_hash;
+                dialing.spawn(async move {
+                    let handshake = async {
+                        let mut peer = Peer::new(peer_address, info_hash).await?;
+                        if peer.supports_extension {
+                            peer.get_pieces().await?;
+                            peer.extension_handshake().await?;
+                        }
+                        Ok::<_, Error>(peer)
+                    };
+                    (peer_address, handshake.await)
+                });
+            }
+            let Some(dialed) = dialing.join_next().await else {
+                return Err(Error::NoPeers);
+            };
+            match dialed? {
+                (_, Ok(peer)) => return Ok(peer),
+                (peer_address, Err(e)) => debug!(peer = %peer_address, "{}", e),
             }
         }
-        Err(anyhow::anyhow!("Could not find peer"))
     }
 
-    pub async fn download_piece(&self, piece: usize) -> anyhow::Result<Vec<u8>> {
+    // Asks each peer in turn for the info dictionary, until one sends
+    // metadata matching the info hash.
+    pub async fn fetch_metadata(&self) -> Result<Info> {
+        for peer_address in self.get_peer_addrs().await? {
+            let fetched = async {
+                let mut peer = Peer::new(peer_address, self.info_hash).await?;
+                peer.get_pieces().await?;
+                peer.extension_handshake().await?;
+                peer.extension_metadata().await
+            };
+            match fetched.await {
+                Ok(metadata) => return Ok(metadata),
+                Err(e) => debug!(peer = %peer_address, "{}", e),
+            }
+        }
+        Err(Error::NoPeers)
+    }
+
+    pub async fn download_piece(&self, piece: usize) -> Result<Bytes> {
         let peer_addrs = self.get_peer_addrs().await?;
         // Establish TCP connection with a peer and perform base handshake
         for peer_address in peer_addrs {
             match Peer::new(peer_address, self.info_hash).await {
                 Ok(mut peer) => {
                     let pieces = peer.get_pieces().await?;
-                    if pieces.contains(&piece) && peer.supports_extension {
+                    if (pieces.contains(&piece) || peer.has_all()) && peer.supports_extension {
                         peer.extension_handshake().await?;
-                        let metadata = peer.extension_metadata().await?;
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            metadata.piece_length,                               // piece_len
-                            metadata.file_len() - piece * metadata.piece_length, // last piece
-                        );
+                        let metadata = match peer.extension_metadata().await {
+                            Ok(metadata) => metadata,
+                            Err(e) => {
+                                debug!(peer = %peer_address, "{}", e);
+                                continue;
+                            }
+                        };
+                        let piece_len = metadata.piece_len(piece);
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+                Err(e) => debug!(peer = %peer_address, "{}", e),
             }
         }
-        Err(anyhow::anyhow!("Could not find peer"))
+        Err(Error::NoPeers)
     }
 
-    pub async fn download(&self) -> anyhow::Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
+    pub fn download(&self) -> DownloadHandle {
+        let magnet = self.clone();
+        DownloadHandle::spawn(|ctx| async move {
+            let (metadata, peer_piece_map) = magnet.connect_peers(&ctx).await?;
+            download_pieces(&metadata, peer_piece_map, BTreeMap::new(), &ctx).await
+        })
+    }
+
+    pub fn download_to(&self, path: PathBuf) -> DownloadHandle<()> {
+        self.spawn_download_to(None, path)
+    }
+
+    // Like `download_to`, as one of `session`'s downloads when given.
+    pub(crate) fn spawn_download_to(
+        &self,
+        session: Option<Shared>,
+        path: PathBuf,
+    ) -> DownloadHandle<()> {
+        let magnet = self.clone();
+        DownloadHandle::spawn_in(session, Arc::new(AllAtOnce), |ctx| async move {
+            let (metadata, peer_piece_map) = magnet.connect_peers(&ctx).await?;
+            download_pieces_to(&metadata, peer_piece_map, BTreeMap::new(), &ctx, path).await
+        })
+    }
+
+    async fn connect_peers(
+        &self,
+        ctx: &DownloadContext,
+    ) -> Result<(Info, HashMap<usize, Vec<Peer>>)> {
+        let peer_addrs = ctx.announce(self.get_peer_addrs()).await?;
         let mut metadata: Option<Info> = None;
         let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
-        let mut join_set = JoinSet::new();
 
         for peer_address in peer_addrs {
-            match Peer::new(peer_address, self.info_hash).await {
-                Ok(mut peer) => {
+            match ctx
+                .until_cancelled(Peer::dial_as(
+                    peer_address,
+                    self.info_hash,
+                    Transport::Tcp,
+                    ctx.local_id(),
+                ))
+                .await
+            {
+                Ok(peer) => {
+                    let mut peer = peer.with_cancellation(ctx.cancellation_token());
+                    if let Some(metadata) = ctx.metadata() {
+                        peer = peer.with_metadata(metadata);
+                    }
                     if peer.supports_extension {
                         let pieces = peer.get_pieces().await?;
                         peer.extension_handshake().await?;
                         if metadata.is_none() {
-                            metadata = Some(peer.extension_metadata().await?);
+                            // A peer sending metadata that doesn't match the
+                            // info hash is dropped; the next one may do better.
+                            match peer.extension_metadata().await {
+                                Ok(info) => {
+                                    ctx.set_metadata(&info)?;
+                                    metadata = Some(info);
+                                }
+                                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                                Err(e) => {
+                                    debug!(peer = %peer_address, "{}", e);
+                                    continue;
+                                }
+                            }
                         }
+                        // A Have All arrived before we knew how many pieces
+                        // there are.
+                        let pieces = match (peer.has_all(), &metadata) {
+                            (true, Some(metadata)) => (0..metadata.piece_count()).collect(),
+                            _ => pieces,
+                        };
                         for piece in pieces {
-                            peer_piece_map
-                                .entry(piece)
-                                .or_insert_with(Vec::new)
-                                .push(peer.clone());
+                            peer_piece_map.entry(piece).or_default().push(peer.clone());
                         }
                         peer.prepare_download().await?;
+                        ctx.emit(DownloadEvent::PeerConnected(peer_address));
                     }
                 }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(e) => debug!(peer = %peer_address, "{}", e),
             }
         }
 
-        if peer_piece_map.is_empty() || metadata.is_none() {
-            return Err(anyhow::anyhow!("Could not connect to any peers"));
+        let metadata = metadata.ok_or(Error::NoPeers)?;
+        ctx.add_web_seeds(self.web_seeds.iter().map(|url| WebSeed::new(url)));
+        if let Some(files) = &self.select_only {
+            ctx.select_pieces(selected_pieces(&metadata, files)?);
         }
+        Ok((metadata, peer_piece_map))
+    }
+}
 
-        let metadata = metadata.unwrap();
-        let piece_hashes = metadata.pieces();
-        let num_pieces = piece_hashes.len();
-        let piece_len = metadata.piece_length;
-        let file_len = metadata.file_len();
-
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
-        };
-
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
-            let piece_hashes = piece_hashes.clone();
-            let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
-
-            join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
-                    Ok(data) => {
-                        println!(
-                            "Downloaded piece {}/{} from peer {}",
-                            piece_number, num_pieces, peer.address
-                        );
-                        if piece_hashes[piece] != *Sha1::digest(&data) {
-                            eprintln!(
-                                "Piece {}/{} failed verification. Will retry...",
-                                piece_number, num_pieces
-                            );
-                            (piece, vec![])
-                        } else {
-                            (piece, data)
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!(
-                            "Error loading piece {}/{}: {}. Will retry...",
-                            piece_number, num_pieces, e
-                        );
-                        (piece, vec![])
-                    }
-                }
-            });
-        };
-
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
+// `so` lists file indices and inclusive ranges of them, e.g. `0,2,4-6`.
+fn parse_select_only(so: &str) -> Result<Vec<usize>> {
+    let invalid = || Error::Magnet(format!("invalid so: {}", so));
+    let mut files = BTreeSet::new();
+    for part in so.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let first: usize = first.parse().map_err(|_| invalid())?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
         }
+        files.extend(first..=last);
+    }
+    Ok(files.into_iter().collect())
+}
 
-        let mut file_bytes = vec![0u8; file_len as usize];
-        while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
-            } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
-            }
-        }
-        Ok(file_bytes)
+// Every piece holding bytes of one of `files`.
+fn selected_pieces(info: &Info, files: &[usize]) -> Result<BTreeSet<usize>> {
+    let mut pieces = BTreeSet::new();
+    for &file in files {
+        let range = info
+            .file_pieces(file)
+            .ok_or_else(|| Error::Magnet(format!("so: no file {}", file)))?;
+        pieces.extend(range);
+    }
+    Ok(pieces)
+}
+
+// A `urn:btih:` info hash, in hex or base32.
+fn decode_info_hash(encoded: &str) -> Result<[u8; 20]> {
+    match encoded.len() {
+        BASE32_INFO_HASH_LEN => BASE32_NOPAD
+            .decode(encoded.to_ascii_uppercase().as_bytes())
+            .map_err(|e| Error::Magnet(format!("invalid base32 info hash: {}", e)))?,
+        _ => hex::decode(encoded)?,
     }
+    .try_into()
+    .map_err(|_| Error::Magnet("info hash must be 20 bytes".to_string()))
 }
@@ -0,0 +1,106 @@
+// Download statistics in the Prometheus text exposition format, one series
+// per torrent labelled with its info hash and name.
+use std::fmt::Write;
+
+use crate::control::TorrentStatus;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+struct Metric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&TorrentStatus) -> f64,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "bittorrent_downloaded_bytes_total",
+        kind: "counter",
+        help: "Bytes downloaded from peers and web seeds since the download started.",
+        value: |torrent| torrent.progress.bytes_downloaded as f64,
+    },
+    Metric {
+        name: "bittorrent_uploaded_bytes_total",
+        kind: "counter",
+        help: "Bytes uploaded to peers.",
+        value: |torrent| torrent.progress.bytes_uploaded as f64,
+    },
+    Metric {
+        name: "bittorrent_piece_failures_total",
+        kind: "counter",
+        help: "Pieces that failed hash verification.",
+        value: |torrent| torrent.progress.pieces_failed as f64,
+    },
+    Metric {
+        name: "bittorrent_tracker_errors_total",
+        kind: "counter",
+        help: "Tracker announces that failed.",
+        value: |torrent| torrent.progress.tracker_errors as f64,
+    },
+    Metric {
+        name: "bittorrent_peers",
+        kind: "gauge",
+        help: "Connected peers.",
+        value: |torrent| torrent.progress.peers as f64,
+    },
+    Metric {
+        name: "bittorrent_download_rate_bytes",
+        kind: "gauge",
+        help: "Download rate in bytes per second.",
+        value: |torrent| torrent.progress.download_rate as f64,
+    },
+    Metric {
+        name: "bittorrent_upload_rate_bytes",
+        kind: "gauge",
+        help: "Upload rate in bytes per second.",
+        value: |torrent| torrent.progress.upload_rate as f64,
+    },
+    Metric {
+        name: "bittorrent_pieces_done",
+        kind: "gauge",
+        help: "Pieces downloaded and verified.",
+        value: |torrent| torrent.progress.pieces_done as f64,
+    },
+    Metric {
+        name: "bittorrent_progress_ratio",
+        kind: "gauge",
+        help: "Fraction of the torrent's bytes on disk, from 0 to 1.",
+        value: |torrent| match torrent.progress.total_bytes {
+            0 => 0.0,
+            total => torrent.progress.bytes_done as f64 / total as f64,
+        },
+    },
+];
+
+pub fn render(torrents: &[TorrentStatus]) -> String {
+    let mut text = String::new();
+    let _ = writeln!(
+        text,
+        "# HELP bittorrent_torrents Torrents being downloaded or seeded."
+    );
+    let _ = writeln!(text, "# TYPE bittorrent_torrents gauge");
+    let _ = writeln!(text, "bittorrent_torrents {}", torrents.len());
+    for metric in METRICS {
+        let _ = writeln!(text, "# HELP {} {}", metric.name, metric.help);
+        let _ = writeln!(text, "# TYPE {} {}", metric.name, metric.kind);
+        for torrent in torrents {
+            let _ = writeln!(
+                text,
+                "{}{{info_hash=\"{}\",name=\"{}\"}} {}",
+                metric.name,
+                torrent.id,
+                escape(&torrent.name),
+                (metric.value)(torrent)
+            );
+        }
+    }
+    text
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
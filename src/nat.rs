@@ -0,0 +1,269 @@
+// Opening the listen port on the local router so peers outside the NAT can
+// connect to us. NAT-PMP is tried first, since it is a single UDP exchange
+// with the default gateway; otherwise a UPnP Internet Gateway Device is
+// found over SSDP and asked through its SOAP control URL. Mappings are
+// leases, which `keep_mapped` renews for as long as it runs.
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle, time};
+use tracing::{info, warn};
+
+use crate::{
+    error::{Error, Result},
+    listener, tor,
+};
+
+const NATPMP_PORT: u16 = 5351;
+const NATPMP_ATTEMPTS: u32 = 3;
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+// How long we ask for; gateways may grant less.
+const LEASE: Duration = Duration::from_secs(3600);
+const RETRY_DELAY: Duration = Duration::from_secs(300);
+const MIN_RENEWAL: Duration = Duration::from_secs(30);
+const IGD_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub method: Method,
+    pub external_port: u16,
+    pub lifetime: Duration,
+}
+
+// Maps TCP `port` on the router to the same port on this host.
+pub async fn map_port(port: u16) -> Result<PortMapping> {
+    if tor::enabled() {
+        return Err(Error::Protocol(
+            "port mapping is disabled with Tor".to_string(),
+        ));
+    }
+    let natpmp = match default_gateway() {
+        Some(gateway) => natpmp(SocketAddr::from((gateway, NATPMP_PORT)), port).await,
+        None => Err(Error::Protocol("no default gateway".to_string())),
+    };
+    match natpmp {
+        Ok(mapping) => Ok(mapping),
+        Err(natpmp) => upnp(port)
+            .await
+            .map_err(|upnp| Error::Protocol(format!("NAT-PMP: {}; UPnP: {}", natpmp, upnp))),
+    }
+}
+
+// Maps `port` and keeps renewing the lease, announcing the external port
+// the router gave us.
+pub fn keep_mapped(port: u16) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let renew = match map_port(port).await {
+                Ok(mapping) => {
+                    info!(
+                        "mapped port {} to external port {} with {:?}",
+                        port, mapping.external_port, mapping.method
+                    );
+                    listener::set_external_port(Some(mapping.external_port));
+                    (mapping.lifetime / 2).max(MIN_RENEWAL)
+                }
+                Err(e) => {
+                    warn!("could not map port {}: {}", port, e);
+                    RETRY_DELAY
+                }
+            };
+            time::sleep(renew).await;
+        }
+    })
+}
+
+// RFC 6886: one request, retried with a doubling timeout.
+pub async fn natpmp(gateway: SocketAddr, port: u16) -> Result<PortMapping> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut request = vec![0, 2, 0, 0]; // version 0, map TCP
+    request.extend(port.to_be_bytes());
+    request.extend(port.to_be_bytes());
+    request.extend((LEASE.as_secs() as u32).to_be_bytes());
+
+    let mut reply = [0u8; 16];
+    let mut wait = Duration::from_millis(250);
+    let mut received = None;
+    for _ in 0..NATPMP_ATTEMPTS {
+        socket.send(&request).await?;
+        if let Ok(n) = time::timeout(wait, socket.recv(&mut reply)).await {
+            received = Some(n?);
+            break;
+        }
+        wait *= 2;
+    }
+    let Some(n) = received else {
+        return Err(Error::Timeout);
+    };
+    if n < reply.len() || reply[0] != 0 || reply[1] != 128 + 2 {
+        return Err(Error::Protocol("malformed NAT-PMP reply".to_string()));
+    }
+    let field = |i: usize| u16::from_be_bytes([reply[i], reply[i + 1]]);
+    match field(2) {
+        0 => Ok(PortMapping {
+            method: Method::NatPmp,
+            external_port: field(10),
+            lifetime: Duration::from_secs(
+                u32::from_be_bytes(reply[12..16].try_into().unwrap()) as u64
+            ),
+        }),
+        code => Err(Error::Protocol(format!(
+            "gateway refused the mapping (result code {})",
+            code
+        ))),
+    }
+}
+
+pub async fn upnp(port: u16) -> Result<PortMapping> {
+    let location = discover().await?;
+    upnp_at(&location, port).await
+}
+
+// Asks on the SSDP multicast group for an Internet Gateway Device and
+// returns the URL of its device description.
+async fn discover() -> Result<String> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let search = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_ADDRESS
+    );
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS).await?;
+    let mut buf = [0u8; 2048];
+    time::timeout(REQUEST_TIMEOUT, async {
+        loop {
+            let (n, _) = socket.recv_from(&mut buf).await?;
+            let reply = String::from_utf8_lossy(&buf[..n]);
+            let location = reply.lines().find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("location")
+                    .then(|| value.trim().to_string())
+            });
+            if let Some(location) = location {
+                return Ok(location);
+            }
+        }
+    })
+    .await?
+}
+
+// Maps `port` through the gateway described at `location`.
+#[cfg(feature = "http")]
+pub async fn upnp_at(location: &str, port: u16) -> Result<PortMapping> {
+    let url = url::Url::parse(location)?;
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()?;
+    let description = client
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let (service, control) = control_url(&description)
+        .ok_or_else(|| Error::Protocol("gateway has no WAN connection service".to_string()))?;
+    let control = url.join(&control)?;
+    let gateway = control
+        .socket_addrs(|| None)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::Protocol(format!("cannot resolve {}", control)))?;
+
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+         <u:AddPortMapping xmlns:u=\"{service}\">\
+         <NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{port}</NewExternalPort>\
+         <NewProtocol>TCP</NewProtocol>\
+         <NewInternalPort>{port}</NewInternalPort>\
+         <NewInternalClient>{client}</NewInternalClient>\
+         <NewEnabled>1</NewEnabled>\
+         <NewPortMappingDescription>bittorrent-rust</NewPortMappingDescription>\
+         <NewLeaseDuration>{lease}</NewLeaseDuration>\
+         </u:AddPortMapping></s:Body></s:Envelope>",
+        service = service,
+        port = port,
+        client = local_ip(gateway).await?,
+        lease = LEASE.as_secs(),
+    );
+    client
+        .post(control)
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#AddPortMapping\"", service))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(PortMapping {
+        method: Method::Upnp,
+        external_port: port,
+        lifetime: LEASE,
+    })
+}
+
+#[cfg(not(feature = "http"))]
+pub async fn upnp_at(_location: &str, _port: u16) -> Result<PortMapping> {
+    Err(Error::Protocol("UPnP needs the http feature".to_string()))
+}
+
+// The service type and control URL of the first WAN connection service in
+// a device description.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+fn control_url(description: &str) -> Option<(String, String)> {
+    IGD_SERVICES.iter().find_map(|service| {
+        let start = description.find(&format!("<serviceType>{}</serviceType>", service))?;
+        let rest = &description[start..];
+        let rest = &rest[..rest.find("</service>").unwrap_or(rest.len())];
+        let url = rest
+            .split("<controlURL>")
+            .nth(1)?
+            .split("</controlURL>")
+            .next()?;
+        Some((service.to_string(), url.trim().to_string()))
+    })
+}
+
+// The address this host uses to reach `gateway`, which is what the router
+// must forward to.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+async fn local_ip(gateway: SocketAddr) -> Result<std::net::IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+// The IPv4 default route's gateway, where NAT-PMP is answered.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        // The address bytes, printed as a host-order integer.
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
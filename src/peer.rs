@@ -1,22 +1,106 @@
-use anyhow::Context;
 use bitvec::prelude::*;
+use bytes::{Bytes, BytesMut};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{mem, net::SocketAddr, sync::Arc};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
-    sync::Mutex,
-    task::JoinSet,
+    sync::{mpsc, oneshot, Mutex, OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
 };
+use tokio_stream::StreamExt;
+use tokio_util::{codec::FramedRead, sync::CancellationToken};
+use tracing::{debug, trace};
 
+use crate::bencode;
+use crate::codec::{Frame, Message, MessageId, PeerCodec};
+use crate::dht;
+use crate::error::{Error, Result};
 use crate::extension::*;
+use crate::ratelimit::RateLimits;
+use crate::record::{self, Direction};
+use crate::selection::Throughput;
+use crate::storage::PieceStore;
+use crate::tor;
 use crate::torrent::Info;
+use crate::utp;
 
 const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB
 const EXTENSION_SUPPORT_FLAG: u64 = 1 << 20;
+const FAST_SUPPORT_FLAG: u64 = 1 << 2;
+const DHT_SUPPORT_FLAG: u64 = 1;
+const HANDSHAKE_LEN: usize = 68;
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024; // the most we serve in one block
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024; // the most we fetch
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+pub const DEFAULT_QUEUE_DEPTH: usize = 8;
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Messages the reader queues before it waits for someone to take them.
+const INBOX_CAPACITY: usize = 64;
+
+// How many block requests may be outstanding on one connection. Applies to
+// peers connected afterwards.
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_QUEUE_DEPTH);
+
+pub fn set_queue_depth(depth: usize) {
+    QUEUE_DEPTH.store(depth.max(1), Ordering::Relaxed);
+}
+
+// Starts the peer IDs generated afterwards. Only its first 20 ASCII
+// characters are used.
+static PEER_ID_PREFIX: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+
+pub fn set_peer_id_prefix(prefix: &str) {
+    *PEER_ID_PREFIX.lock().unwrap() = prefix.chars().filter(char::is_ascii).take(20).collect();
+}
+
+// How long a connection may go without us sending anything before we send
+// a keep-alive, so the peer doesn't take it for dead.
+static KEEP_ALIVE_INTERVAL: std::sync::Mutex<Duration> =
+    std::sync::Mutex::new(Duration::from_secs(120));
+
+pub fn set_keep_alive_interval(interval: Duration) {
+    *KEEP_ALIVE_INTERVAL.lock().unwrap() = interval;
+}
 
-#[derive(Serialize, Deserialize)]
+// How long dialing a peer may take, handshake included, before we give up
+// on it.
+static CONNECT_TIMEOUT: std::sync::Mutex<Duration> = std::sync::Mutex::new(DEFAULT_CONNECT_TIMEOUT);
+
+pub fn set_connect_timeout(timeout: Duration) {
+    *CONNECT_TIMEOUT.lock().unwrap() = timeout;
+}
+
+type Reader = FramedRead<ReadHalf<Box<dyn PeerStream>>, PeerCodec>;
+type Writer = WriteHalf<Box<dyn PeerStream>>;
+type Inbox = mpsc::Receiver<Result<Message>>;
+type BlockSender = mpsc::UnboundedSender<Result<(u32, Bytes)>>;
+type HaveSender = mpsc::UnboundedSender<(Peer, Vec<usize>)>;
+
+enum Outgoing {
+    // With where to report whether it was written.
+    Frame(Frame, Option<oneshot::Sender<io::Result<()>>>),
+    // Moves a connection's write half between writer tasks on redial.
+    Attach(Writer),
+    Detach(oneshot::Sender<Writer>),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Handshake {
     pub length: u8,
     pub protocol: [u8; 19],
@@ -26,10 +110,12 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn new(info_hash: [u8; 20]) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         let mut reserved = 0;
-        reserved |= EXTENSION_SUPPORT_FLAG;
-        let peer_id: [u8; 20] = Peer::gen_peer_id().as_bytes().try_into().unwrap();
+        reserved |= EXTENSION_SUPPORT_FLAG | FAST_SUPPORT_FLAG;
+        if dht::enabled() {
+            reserved |= DHT_SUPPORT_FLAG;
+        }
         Self {
             length: 19,
             protocol: *b"BitTorrent protocol",
@@ -39,214 +125,1339 @@ impl Handshake {
         }
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != HANDSHAKE_LEN {
+            return Err(Error::Protocol(format!(
+                "handshake is {} bytes, expected {}",
+                bytes.len(),
+                HANDSHAKE_LEN
+            )));
+        }
+        let handshake: Handshake = bincode::deserialize(bytes)?;
+        if handshake.length != 19 || &handshake.protocol != b"BitTorrent protocol" {
+            return Err(Error::Protocol("unexpected handshake protocol".to_string()));
+        }
+        Ok(handshake)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
     pub fn supports_extension(&self) -> bool {
         self.reserved[5] & 0x10 != 0
     }
+
+    // BEP 6.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    // BEP 5.
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+}
+
+pub trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PeerStream for T {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Utp,
 }
 
 #[derive(Clone)]
 pub struct Peer {
     pub address: SocketAddr,
     pub id: [u8; 20],
-    pub stream: Arc<Mutex<TcpStream>>,
+    pub info_hash: [u8; 20],
+    // The connection is served by a reader task, which hands blocks
+    // straight to whoever requested them and queues every other message in
+    // the inbox, and a writer task that sends what is queued in the outbox.
+    inbox: Arc<Mutex<Inbox>>,
+    outbox: mpsc::UnboundedSender<Outgoing>,
     pub supports_extension: bool,
+    pub supports_fast: bool,
+    pub supports_dht: bool,
     pub metadata_extension_id: Option<u8>,
+    // The size of the info dictionary, if the peer said in its extension
+    // handshake.
+    metadata_size: Option<usize>,
+    // Our info dictionary, served to peers that ask for it.
+    metadata: Option<Bytes>,
+    // Needed to make sense of a Have All; see `has_all` when unknown.
+    piece_count: Option<usize>,
+    has_all: bool,
+    extensions: ExtensionRegistry,
+    remote_extensions: BTreeMap<String, u8>,
+    cancel: CancellationToken,
+    activity: Arc<Activity>,
+    // Only peers we dialed can be dialed again, the same way.
+    dialed: Option<Transport>,
+    // Where blocks the peer requests from us are read from.
+    uploads: Option<PieceStore>,
+    // One permit per request we may have outstanding.
+    request_slots: Arc<Semaphore>,
+    // The peer ID we introduced ourselves with, used again on reconnect.
+    local_id: [u8; 20],
+    // Where pieces the peer finishes during the session are announced.
+    haves: Option<HaveSender>,
+}
+
+// Shared by every clone of a peer, so all tasks using the connection see
+// when it last delivered and whether it has been replaced or given up on.
+#[derive(Default)]
+struct Activity {
+    last_block: std::sync::Mutex<Option<Instant>>,
+    snubbed: AtomicBool,
+    generation: AtomicU64,
+    reconnecting: Mutex<()>,
+    closed: AtomicBool,
+    unchoked: AtomicBool,
+    // Pieces we may request while choked, and pieces the peer suggested.
+    allowed_fast: std::sync::Mutex<HashSet<usize>>,
+    suggested: std::sync::Mutex<Vec<usize>>,
+    // The other direction: whether we let the peer request from us, whether
+    // it wants to, and how much it got.
+    unchoking: AtomicBool,
+    interested: AtomicBool,
+    uploaded: AtomicU64,
+    // What it sent us, for choosing whom to request pieces from.
+    throughput: Arc<Throughput>,
+    last_sent: std::sync::Mutex<Option<Instant>>,
+    // Who is waiting for each requested block, by (index, begin).
+    requests: std::sync::Mutex<HashMap<(u32, u32), BlockSender>>,
+    // The download's limits; the global ones apply regardless.
+    limits: std::sync::Mutex<RateLimits>,
+}
+
+impl Activity {
+    // Hands a block, or the rejection of a request, to the task waiting for
+    // it. Returns false for anything else, and for blocks nobody waits for.
+    fn route_block(&self, msg: &Message, supports_fast: bool) -> Result<bool> {
+        match msg.id {
+            MessageId::Piece => {}
+            MessageId::RejectRequest if supports_fast => {}
+            _ => return Ok(false),
+        }
+        if msg.payload.len() < 8 {
+            return Err(Error::Protocol(format!("malformed {:?} message", msg.id)));
+        }
+        let field = |i: usize| u32::from_be_bytes(msg.payload[i..i + 4].try_into().unwrap());
+        let (index, begin) = (field(0), field(4));
+        let Some(waiting) = self.requests.lock().unwrap().remove(&(index, begin)) else {
+            return Ok(false);
+        };
+        let block = match msg.id {
+            MessageId::Piece => {
+                *self.last_block.lock().unwrap() = Some(Instant::now());
+                self.throughput
+                    .record_received(msg.payload.len() as u64 - 8);
+                self.snubbed.store(false, Ordering::Relaxed);
+                Ok((begin, msg.payload.slice(8..)))
+            }
+            _ => Err(Error::Rejected(index)),
+        };
+        let _ = waiting.send(block);
+        Ok(true)
+    }
 }
 
 impl Peer {
-    pub async fn new(address: SocketAddr, info_hash: [u8; 20]) -> anyhow::Result<Self> {
-        let mut handshake = Handshake::new(info_hash);
-        let mut handshake_bytes = bincode::serialize(&handshake)?;
+    pub async fn new(address: SocketAddr, info_hash: [u8; 20]) -> Result<Self> {
+        Self::dial(address, info_hash, Transport::Tcp).await
+    }
 
-        let mut peer_stream = TcpStream::connect(address)
-            .await
-            .context("failed to connect to peer")?;
-        peer_stream
-            .write_all(&handshake_bytes)
-            .await
-            .context("failed to send handshake")?;
-        peer_stream
-            .read_exact(&mut handshake_bytes)
-            .await
-            .context("failed to receive handshake")?;
+    pub async fn dial(
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        transport: Transport,
+    ) -> Result<Self> {
+        Self::dial_as(address, info_hash, transport, new_local_id()).await
+    }
+
+    // Like `dial`, introducing ourselves as `peer_id`.
+    pub async fn dial_as(
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        transport: Transport,
+        peer_id: [u8; 20],
+    ) -> Result<Self> {
+        let timeout = *CONNECT_TIMEOUT.lock().unwrap();
+        let connected = async {
+            let stream = connect(address, transport).await?;
+            Self::connect_stream_as(stream, address, info_hash, peer_id).await
+        };
+        let mut peer = time::timeout(timeout, connected).await??;
+        peer.dialed = Some(transport);
+        Ok(peer)
+    }
+
+    // Tries `preferred` first, falling back to TCP.
+    pub async fn dial_preferring(
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        preferred: Transport,
+        peer_id: [u8; 20],
+    ) -> Result<Self> {
+        match Self::dial_as(address, info_hash, preferred, peer_id).await {
+            Err(e) if preferred == Transport::Utp => {
+                debug!(peer = %address, "uTP failed, trying TCP: {}", e);
+                Self::dial_as(address, info_hash, Transport::Tcp, peer_id).await
+            }
+            peer => peer,
+        }
+    }
+
+    pub async fn connect_stream(
+        stream: impl PeerStream + 'static,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+    ) -> Result<Self> {
+        Self::connect_stream_as(stream, address, info_hash, new_local_id()).await
+    }
+
+    // Like `connect_stream`, introducing ourselves as `peer_id`.
+    pub async fn connect_stream_as(
+        mut stream: impl PeerStream + 'static,
+        address: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+    ) -> Result<Self> {
+        let handshake = handshake(&mut stream, address, info_hash, peer_id).await?;
+        Ok(Self::from_handshake(
+            address,
+            stream,
+            info_hash,
+            &handshake,
+            peer_id,
+            Arc::default(),
+        ))
+    }
+
+    // Takes a peer for any of the `expected` info hashes, answering with
+    // the peer ID paired with it.
+    pub async fn from_incoming(
+        stream: TcpStream,
+        expected: &[([u8; 20], [u8; 20])],
+    ) -> Result<Self> {
+        let address = stream.peer_addr()?;
+        Self::accept_as(stream, address, expected).await
+    }
+
+    pub async fn accept_stream(
+        stream: impl PeerStream + 'static,
+        address: SocketAddr,
+        expected_info_hashes: &[[u8; 20]],
+    ) -> Result<Self> {
+        let local_id = new_local_id();
+        let expected: Vec<_> = expected_info_hashes
+            .iter()
+            .map(|&info_hash| (info_hash, local_id))
+            .collect();
+        Self::accept_as(stream, address, &expected).await
+    }
+
+    async fn accept_as(
+        mut stream: impl PeerStream + 'static,
+        address: SocketAddr,
+        expected: &[([u8; 20], [u8; 20])],
+    ) -> Result<Self> {
+        let mut handshake_bytes = [0u8; HANDSHAKE_LEN];
+        stream.read_exact(&mut handshake_bytes).await?;
+        record::log(address, Direction::Received, &handshake_bytes);
+        let handshake = Handshake::from_bytes(&handshake_bytes)?;
+        let Some(&(_, local_id)) = expected
+            .iter()
+            .find(|(info_hash, _)| *info_hash == handshake.info_hash)
+        else {
+            return Err(Error::Protocol(format!(
+                "unknown info hash {}",
+                hex::encode(handshake.info_hash)
+            )));
+        };
 
-        handshake = bincode::deserialize(&handshake_bytes)?;
-        let peer = Peer {
+        let reply = Handshake::new(handshake.info_hash, local_id).to_bytes()?;
+        stream.write_all(&reply).await?;
+        record::log(address, Direction::Sent, &reply);
+        Ok(Self::from_handshake(
+            address,
+            stream,
+            handshake.info_hash,
+            &handshake,
+            local_id,
+            Arc::default(),
+        ))
+    }
+
+    fn from_handshake(
+        address: SocketAddr,
+        stream: impl PeerStream + 'static,
+        info_hash: [u8; 20],
+        handshake: &Handshake,
+        local_id: [u8; 20],
+        activity: Arc<Activity>,
+    ) -> Self {
+        let stream: Box<dyn PeerStream> = Box::new(stream);
+        let (reader, writer) = tokio::io::split(stream);
+        let (queued, inbox) = mpsc::channel(INBOX_CAPACITY);
+        let (outbox, outgoing) = mpsc::unbounded_channel();
+        tokio::spawn(read_frames(
+            address,
+            FramedRead::new(reader, PeerCodec),
+            handshake.supports_fast(),
+            activity.clone(),
+            queued,
+        ));
+        tokio::spawn(write_frames(address, writer, activity.clone(), outgoing));
+        Peer {
             address,
             id: handshake.peer_id,
-            stream: Arc::new(Mutex::new(peer_stream)),
+            info_hash,
+            inbox: Arc::new(Mutex::new(inbox)),
+            outbox,
             supports_extension: handshake.supports_extension(),
+            supports_fast: handshake.supports_fast(),
+            supports_dht: handshake.supports_dht(),
             metadata_extension_id: None,
+            metadata_size: None,
+            metadata: None,
+            piece_count: None,
+            has_all: false,
+            extensions: ExtensionRegistry::default(),
+            remote_extensions: BTreeMap::new(),
+            cancel: CancellationToken::new(),
+            activity,
+            dialed: None,
+            uploads: None,
+            request_slots: Arc::new(Semaphore::new(QUEUE_DEPTH.load(Ordering::Relaxed))),
+            local_id,
+            haves: None,
+        }
+    }
+
+    // Keeps up to `depth` block requests outstanding in place of the global
+    // queue depth. Only for a peer nothing has been requested from yet.
+    pub(crate) fn with_queue_depth(mut self, depth: usize) -> Self {
+        self.request_slots = Arc::new(Semaphore::new(depth.max(1)));
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // How many pieces the torrent has, so `get_pieces` can expand a Have All.
+    pub fn with_piece_count(mut self, piece_count: usize) -> Self {
+        self.piece_count = Some(piece_count);
+        self
+    }
+
+    // Whether the peer announced it has every piece (BEP 6), which
+    // `get_pieces` can only list once it knows the piece count.
+    pub fn has_all(&self) -> bool {
+        self.has_all
+    }
+
+    pub fn is_unchoked(&self) -> bool {
+        self.activity.unchoked.load(Ordering::Relaxed)
+    }
+
+    // Whether piece `index` can be requested right now: always once
+    // unchoked, and only allowed fast pieces before that.
+    pub fn can_request(&self, index: usize) -> bool {
+        self.is_unchoked() || self.activity.allowed_fast.lock().unwrap().contains(&index)
+    }
+
+    // Sends the peer, with the piece, whenever it announces a piece it has
+    // completed since its bitfield.
+    pub(crate) fn with_have_sender(mut self, haves: HaveSender) -> Self {
+        self.haves = Some(haves);
+        self
+    }
+
+    // Serves `metadata`, the bencoded info dictionary, to the peer over
+    // ut_metadata. Applies to extension handshakes from then on.
+    pub fn with_metadata(mut self, metadata: Bytes) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    // Caps blocks to and from the peer at `limits`, as well as the global
+    // limits. Applies to every clone of the peer.
+    pub fn with_rate_limits(self, limits: RateLimits) -> Self {
+        *self.activity.limits.lock().unwrap() = limits;
+        self
+    }
+
+    // Answers the peer's requests from `store`, while we unchoke it.
+    pub fn with_uploads(mut self, store: PieceStore) -> Self {
+        self.uploads = Some(store);
+        self
+    }
+
+    // Whether the peer may not request from us. Every peer starts choked.
+    pub fn is_choking(&self) -> bool {
+        !self.activity.unchoking.load(Ordering::Relaxed)
+    }
+
+    // Whether the peer wants pieces from us.
+    pub fn is_interested(&self) -> bool {
+        self.activity.interested.load(Ordering::Relaxed)
+    }
+
+    // Bytes uploaded to the peer over every connection to it.
+    pub fn uploaded(&self) -> u64 {
+        self.activity.uploaded.load(Ordering::Relaxed)
+    }
+
+    // Block bytes the peer sent us, likewise.
+    pub fn downloaded(&self) -> u64 {
+        self.activity.throughput.received()
+    }
+
+    pub(crate) fn throughput(&self) -> &Arc<Throughput> {
+        &self.activity.throughput
+    }
+
+    pub async fn choke(&mut self) -> Result<()> {
+        if self.activity.unchoking.swap(false, Ordering::Relaxed) {
+            self.send(Message::new(MessageId::Choke, vec![])).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn unchoke(&mut self) -> Result<()> {
+        if !self.activity.unchoking.swap(true, Ordering::Relaxed) {
+            self.send(Message::new(MessageId::Unchoke, vec![])).await?;
+        }
+        Ok(())
+    }
+
+    pub fn suggested(&self) -> Vec<usize> {
+        self.activity.suggested.lock().unwrap().clone()
+    }
+
+    // When the peer last delivered a block, if it ever has.
+    pub fn last_block(&self) -> Option<Instant> {
+        *self.activity.last_block.lock().unwrap()
+    }
+
+    // A snubbed peer stopped sending blocks while unchoked. It is cleared as
+    // soon as the peer delivers again.
+    pub fn is_snubbed(&self) -> bool {
+        self.activity.snubbed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_snubbed(&self, snubbed: bool) {
+        self.activity.snubbed.store(snubbed, Ordering::Relaxed);
+    }
+
+    // Set once reconnecting has been given up on.
+    pub fn is_closed(&self) -> bool {
+        self.activity.closed.load(Ordering::Relaxed)
+    }
+
+    // Stops the peer being given work, as if reconnecting had failed.
+    pub(crate) fn close(&self) {
+        self.activity.closed.store(true, Ordering::Relaxed);
+    }
+
+    // Bumped every time the connection is replaced.
+    pub(crate) fn generation(&self) -> u64 {
+        self.activity.generation.load(Ordering::Relaxed)
+    }
+
+    // Replaces a dropped connection, retrying with capped exponential
+    // backoff. Every clone that saw `generation` fail shares one attempt:
+    // returns true only for the caller that actually redialed, and an error
+    // only for the caller that gave up.
+    pub(crate) async fn reconnect(&mut self, generation: u64) -> Result<bool> {
+        let activity = self.activity.clone();
+        let _reconnecting = activity.reconnecting.lock().await;
+        if self.generation() != generation || self.is_closed() {
+            return Ok(false);
+        }
+        let Some(transport) = self.dialed else {
+            activity.closed.store(true, Ordering::Relaxed);
+            return Err(Error::Protocol(format!(
+                "{} cannot be redialed",
+                self.address
+            )));
         };
-        Ok(peer)
+
+        let cancel = self.cancel.clone();
+        let mut delay = Duration::ZERO;
+        let mut last_error = None;
+        for _ in 0..RECONNECT_ATTEMPTS {
+            if cancel
+                .run_until_cancelled(time::sleep(delay))
+                .await
+                .is_none()
+            {
+                return Err(Error::Cancelled);
+            }
+            match cancel.run_until_cancelled(self.redial(transport)).await {
+                Some(Ok(())) => return Ok(true),
+                Some(Err(e)) => last_error = Some(e),
+                None => return Err(Error::Cancelled),
+            }
+            delay = (delay * 2).clamp(RECONNECT_DELAY, MAX_RECONNECT_DELAY);
+        }
+        activity.closed.store(true, Ordering::Relaxed);
+        Err(last_error.unwrap_or(Error::NoPeers))
     }
 
-    pub async fn extension_handshake(&mut self) -> anyhow::Result<()> {
-        let ext_header = ExtensionHeader::new();
+    // Readies the new connection on its own before swapping it in, so tasks
+    // still waiting on the old one can't read its bitfield or unchoke.
+    async fn redial(&mut self, transport: Transport) -> Result<()> {
+        let mut stream = connect(self.address, transport).await?;
+        let handshake = handshake(&mut stream, self.address, self.info_hash, self.local_id).await?;
+        if handshake.peer_id != self.id {
+            return Err(Error::Protocol(format!(
+                "{} came back with a different peer id",
+                self.address
+            )));
+        }
+        // Choking starts over with the new connection.
+        let activity = &self.activity;
+        activity.unchoked.store(false, Ordering::Relaxed);
+        activity.allowed_fast.lock().unwrap().clear();
+        activity.unchoking.store(false, Ordering::Relaxed);
+        activity.interested.store(false, Ordering::Relaxed);
+        let mut fresh = Self::from_handshake(
+            self.address,
+            stream,
+            self.info_hash,
+            &handshake,
+            self.local_id,
+            activity.clone(),
+        );
+        fresh.piece_count = self.piece_count;
+        fresh.get_pieces().await?;
+        fresh.prepare_download().await?;
+
+        let (detached, writer) = oneshot::channel();
+        let _ = fresh.outbox.send(Outgoing::Detach(detached));
+        let writer = writer.await.map_err(|_| disconnected())?;
+        let Ok(inbox) = Arc::try_unwrap(fresh.inbox) else {
+            unreachable!("the fresh connection is never shared");
+        };
+        let mut current = self.inbox.lock().await;
+        *current = inbox.into_inner();
+        let _ = self.outbox.send(Outgoing::Attach(writer));
+        self.activity.generation.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn register_extension(
+        &mut self,
+        name: &str,
+        handler: impl Fn(&[u8]) + Send + Sync + 'static,
+    ) -> u8 {
+        self.extensions.register(name, Arc::new(handler))
+    }
+
+    pub fn remote_extension_id(&self, name: &str) -> Option<u8> {
+        self.remote_extensions.get(name).copied()
+    }
+
+    pub async fn send_extended(&mut self, name: &str, payload: Vec<u8>) -> Result<()> {
+        let id = self
+            .remote_extension_id(name)
+            .ok_or_else(|| Error::Protocol(format!("peer does not support {}", name)))?;
+        let payload = [vec![id], payload].concat();
+        self.send(Message::new(MessageId::Extension, payload)).await
+    }
+
+    pub async fn extension_handshake(&mut self) -> Result<()> {
+        self.send_extension_header().await?;
+        let reply = self.recv().await?;
+        if reply.id != MessageId::Extension || reply.payload.is_empty() {
+            return Err(Error::Protocol("expected extension handshake".to_string()));
+        }
+        self.note_extension_header(&reply.payload[1..])
+    }
+
+    async fn send_extension_header(&mut self) -> Result<()> {
+        let metadata_size = self.metadata.as_ref().map(|metadata| metadata.len() as u32);
+        let ext_header = self.extensions.header().with_metadata_size(metadata_size);
         let mut payload = serde_bencode::to_bytes(&ext_header)?;
         payload.insert(0, 0);
+        self.send(Message::new(MessageId::Extension, payload)).await
+    }
 
-        let handshake = Message::new(MessageId::EXTENSION, payload);
-        self.send(handshake).await?;
-        let reply = self.recv().await?;
-        let ext_header = serde_bencode::from_bytes::<ExtensionHeader>(&reply.payload[1..])?;
-        self.metadata_extension_id = Some(ext_header.m.ut_metadata);
+    fn note_extension_header(&mut self, payload: &[u8]) -> Result<()> {
+        let ext_header = bencode::from_bytes::<ExtensionHeader>(payload)?;
+        self.metadata_size = ext_header.metadata_size().map(|size| size as usize);
+        // An id of 0 means the peer disabled that extension.
+        self.remote_extensions = ext_header
+            .m
+            .into_iter()
+            .filter(|(_, id)| *id != 0)
+            .collect();
+        self.metadata_extension_id = self.remote_extension_id(UT_METADATA);
         Ok(())
     }
 
-    pub async fn extension_metadata(&mut self) -> anyhow::Result<Info> {
+    // BEP 9: the info dictionary arrives in `METADATA_PIECE_SIZE` pieces,
+    // requested one after another until there are as many bytes as the peer
+    // said. The whole must hash to the info hash before it is parsed.
+    pub async fn extension_metadata(&mut self) -> Result<Info> {
+        let extension_msg_id = self
+            .metadata_extension_id
+            .ok_or_else(|| Error::Metadata("peer did not advertise ut_metadata".to_string()))?;
+        let mut metadata = Vec::new();
+        let mut total_size = self.metadata_size;
+        let mut piece = 0;
+        while total_size.is_none_or(|total_size| metadata.len() < total_size) {
+            let (size, payload) = self.metadata_piece(extension_msg_id, piece).await?;
+            let total_size = *total_size.get_or_insert(size);
+            if size != total_size || total_size > MAX_METADATA_SIZE {
+                return Err(Error::Metadata("metadata size out of bounds".to_string()));
+            }
+            // The data follows the message's dictionary.
+            let piece_len = METADATA_PIECE_SIZE.min(total_size - metadata.len());
+            if piece_len > payload.len() {
+                return Err(Error::Metadata(format!(
+                    "metadata piece {} is short",
+                    piece
+                )));
+            }
+            metadata.extend_from_slice(&payload[payload.len() - piece_len..]);
+            piece += 1;
+        }
+
+        // v2-only swarms go by the truncated SHA-256 instead.
+        let sha1: [u8; 20] = Sha1::digest(&metadata).into();
+        let sha256 = Sha256::digest(&metadata);
+        if sha1 != self.info_hash && sha256[..20] != self.info_hash {
+            return Err(Error::Metadata(
+                "metadata does not match the info hash".to_string(),
+            ));
+        }
+        let torrent_info = bencode::from_bytes::<Info>(&metadata)?;
+        torrent_info.validate()?;
+        Ok(torrent_info)
+    }
+
+    // Requests one metadata piece, returning the total size the peer gives
+    // and the reply's payload.
+    async fn metadata_piece(&mut self, extension_msg_id: u8, piece: u32) -> Result<(usize, Bytes)> {
         let ext_msg = ExtensionMessage {
             msg_type: ExtensionMessageType::Request,
-            piece: 0,
+            piece,
             total_size: None,
         };
         let mut payload = serde_bencode::to_bytes(&ext_msg)?;
-        let extension_msg_id = self
-            .metadata_extension_id
-            .expect("metadata extension id should be set during handshake");
         payload.insert(0, extension_msg_id);
+        self.send(Message::new(MessageId::Extension, payload))
+            .await?;
 
-        let msg = Message::new(MessageId::EXTENSION, payload);
-        self.send(msg).await?;
         let reply = self.recv().await?;
-        let ext_msg = serde_bencode::from_bytes::<ExtensionMessage>(&reply.payload[1..])?;
-        let metadata_piece_len = ext_msg.total_size.unwrap();
-        let metadata = &reply.payload[reply.payload.len() - metadata_piece_len as usize..];
-        let torrent_info = serde_bencode::from_bytes::<Info>(metadata)?;
-        Ok(torrent_info)
+        if reply.id != MessageId::Extension || reply.payload.is_empty() {
+            return Err(Error::Metadata("expected metadata reply".to_string()));
+        }
+        let ext_msg = bencode::from_bytes::<ExtensionMessage>(&reply.payload[1..])?;
+        match ext_msg.msg_type {
+            ExtensionMessageType::Data if ext_msg.piece == piece => {}
+            ExtensionMessageType::Data => {
+                return Err(Error::Metadata(format!(
+                    "expected metadata piece {}, got {}",
+                    piece, ext_msg.piece
+                )))
+            }
+            _ => {
+                return Err(Error::Metadata(format!(
+                    "peer rejected metadata piece {}",
+                    piece
+                )))
+            }
+        }
+        let total_size = ext_msg
+            .total_size
+            .ok_or_else(|| Error::Metadata("missing total_size".to_string()))?;
+        Ok((total_size as usize, reply.payload))
+    }
+
+    async fn recv(&mut self) -> Result<Message> {
+        self.recv_since(None).await
+    }
+
+    // With a generation, fails instead of reading from a connection that
+    // replaced the one a request went out on.
+    // Choking, Allowed Fast and Suggest Piece can arrive at any time and
+    // never answer a request, so they are recorded here rather than returned.
+    async fn recv_since(&mut self, generation: Option<u64>) -> Result<Message> {
+        loop {
+            let msg = self.recv_message(generation).await?;
+            if !self.absorb(&msg).await? {
+                return Ok(msg);
+            }
+        }
     }
 
-    async fn recv(&mut self) -> anyhow::Result<Message> {
-        let mut stream = self.stream.lock().await;
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf).await?;
-        let length = u32::from_be_bytes(buf);
+    // Handles messages that can arrive whatever we are waiting for: blocks
+    // for pending requests, advisories, extensions and uploads. Returns false
+    // for anything else.
+    async fn absorb(&mut self, msg: &Message) -> Result<bool> {
+        let advisory = matches!(
+            msg.id,
+            MessageId::Choke
+                | MessageId::Unchoke
+                | MessageId::Have
+                | MessageId::AllowedFast
+                | MessageId::SuggestPiece
+        );
+        if self.dispatch_extension(msg)
+            || self.answer_metadata(msg).await?
+            || (advisory && self.note_state(msg)?)
+            || self.route_block(msg)?
+            || self.note_port(msg)?
+        {
+            return Ok(true);
+        }
+        Ok(self.uploads.is_some() && self.note_upload(msg).await?)
+    }
 
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
-        let id: MessageId = unsafe { mem::transmute(buf[0]) };
+    // Handles what the peer sends as a downloader from us. Returns false for
+    // anything else.
+    async fn note_upload(&mut self, msg: &Message) -> Result<bool> {
+        let interested = &self.activity.interested;
+        match msg.id {
+            MessageId::Interested => interested.store(true, Ordering::Relaxed),
+            MessageId::NotInterested => interested.store(false, Ordering::Relaxed),
+            MessageId::Request => self.answer_request(&msg.payload).await?,
+            // Requests are answered as they arrive, so there is never
+            // anything left to cancel.
+            MessageId::Cancel => {}
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
 
-        let mut buf = vec![0u8; length as usize - mem::size_of::<MessageId>()];
-        stream.read_exact(&mut buf).await?;
-        Ok(Message {
-            length,
-            id,
-            payload: buf,
-        })
+    // Sends the requested block if the peer is unchoked and we have it.
+    // Otherwise the request is rejected under the fast extension and
+    // dropped without it.
+    async fn answer_request(&mut self, payload: &[u8]) -> Result<()> {
+        if payload.len() != 12 {
+            return Err(Error::Protocol("malformed request message".to_string()));
+        }
+        let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        let (index, begin, length) = (field(0), field(4), field(8));
+        let block = match (&self.uploads, self.is_choking()) {
+            (Some(store), false) if length <= MAX_REQUEST_LENGTH => {
+                store.read_block(index as usize, begin, length).await.ok()
+            }
+            _ => None,
+        };
+        match block {
+            Some(block) => {
+                let limits = self.activity.limits.lock().unwrap().clone();
+                limits.send(block.len() as u64).await;
+                let piece = [&payload[..8], &block].concat();
+                self.send(Message::new(MessageId::Piece, piece)).await?;
+                self.activity
+                    .uploaded
+                    .fetch_add(block.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            None if self.supports_fast => {
+                let reject = Message::new(MessageId::RejectRequest, payload.to_vec());
+                self.send(reject).await
+            }
+            None => Ok(()),
+        }
     }
 
-    async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(&msg.as_bytes()).await?;
-        Ok(())
+    // Uploads to the peer until it disconnects: tells it which pieces we
+    // have, unchokes it whenever it is interested and answers its requests.
+    pub async fn seed(&mut self) -> Result<()> {
+        let (Some(store), Some(piece_count)) = (&self.uploads, self.piece_count) else {
+            return Err(Error::Protocol(
+                "seeding needs uploads and a piece count".to_string(),
+            ));
+        };
+        let pieces = store.pieces();
+        let announce = match (pieces.len(), self.supports_fast) {
+            (0, true) => Message::new(MessageId::HaveNone, vec![]),
+            (n, true) if n == piece_count => Message::new(MessageId::HaveAll, vec![]),
+            _ => {
+                let mut bitfield = bitvec![u8, Msb0; 0; piece_count];
+                for piece in pieces.into_iter().filter(|&piece| piece < piece_count) {
+                    bitfield.set(piece, true);
+                }
+                Message::new(MessageId::Bitfield, bitfield.into_vec())
+            }
+        };
+        self.send(announce).await?;
+        loop {
+            let msg = match self.recv_message(None).await {
+                Ok(msg) => msg,
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            if self.is_extension_handshake(&msg) {
+                // Peers that only download from us start the handshake.
+                self.note_extension_header(&msg.payload[1..])?;
+                self.send_extension_header().await?;
+            } else if !self.dispatch_extension(&msg) && !self.answer_metadata(&msg).await? {
+                self.note_upload(&msg).await?;
+            }
+            if self.is_interested() && self.is_choking() {
+                self.unchoke().await?;
+            }
+        }
+    }
+
+    fn is_extension_handshake(&self, msg: &Message) -> bool {
+        msg.id == MessageId::Extension && msg.payload.first() == Some(&0)
+    }
+
+    // Answers a ut_metadata request with the piece asked for, or a reject
+    // without the metadata. Returns false for anything else, including the
+    // peer's replies to our own requests.
+    async fn answer_metadata(&mut self, msg: &Message) -> Result<bool> {
+        if msg.id != MessageId::Extension
+            || msg.payload.first().copied() != self.extensions.id(UT_METADATA)
+        {
+            return Ok(false);
+        }
+        let Ok(request) = bencode::from_bytes::<ExtensionMessage>(&msg.payload[1..]) else {
+            return Ok(false);
+        };
+        if !matches!(request.msg_type, ExtensionMessageType::Request) {
+            return Ok(false);
+        }
+        let Some(extension_msg_id) = self.metadata_extension_id else {
+            return Ok(true);
+        };
+        let start = request.piece as usize * METADATA_PIECE_SIZE;
+        let data = self
+            .metadata
+            .as_ref()
+            .filter(|metadata| start < metadata.len())
+            .map(|metadata| {
+                let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+                (metadata.len(), metadata.slice(start..end))
+            });
+        let reply = match &data {
+            Some((total_size, _)) => ExtensionMessage {
+                msg_type: ExtensionMessageType::Data,
+                piece: request.piece,
+                total_size: Some(*total_size as u32),
+            },
+            None => ExtensionMessage {
+                msg_type: ExtensionMessageType::Reject,
+                piece: request.piece,
+                total_size: None,
+            },
+        };
+        let mut payload = serde_bencode::to_bytes(&reply)?;
+        payload.insert(0, extension_msg_id);
+        if let Some((_, data)) = data {
+            payload.extend_from_slice(&data);
+        }
+        self.send(Message::new(MessageId::Extension, payload))
+            .await?;
+        Ok(true)
+    }
+
+    // Hands an extension message to its registered handler, if any.
+    fn dispatch_extension(&self, msg: &Message) -> bool {
+        if msg.id != MessageId::Extension {
+            return false;
+        }
+        match msg
+            .payload
+            .first()
+            .and_then(|id| self.extensions.handler(*id))
+        {
+            Some(handler) => {
+                handler(&msg.payload[1..]);
+                true
+            }
+            None => false,
+        }
     }
 
-    pub async fn get_pieces(&mut self) -> anyhow::Result<Vec<usize>> {
+    async fn recv_message(&mut self, generation: Option<u64>) -> Result<Message> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::delay().await;
+        let mut inbox = self.inbox.lock().await;
+        if generation.is_some_and(|generation| generation != self.generation()) {
+            return Err(connection_replaced());
+        }
+        inbox.recv().await.unwrap_or_else(|| Err(end_of_stream()))
+    }
+
+    async fn send(&mut self, msg: Message) -> Result<()> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::disconnect()?;
+        let (sent, written) = oneshot::channel();
+        self.outbox
+            .send(Outgoing::Frame(Frame::Message(msg), Some(sent)))
+            .map_err(|_| disconnected())?;
+        Ok(written.await.map_err(|_| disconnected())??)
+    }
+
+    // Pushes every remaining inbound message through the receive path, e.g.
+    // over a `ReplayStream`. Returns how many messages were consumed.
+    pub async fn replay(&mut self) -> Result<usize> {
+        let mut count = 0;
+        loop {
+            match self.recv_message(None).await {
+                Ok(msg) => {
+                    self.absorb(&msg).await?;
+                    count += 1;
+                }
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(count),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub async fn get_pieces(&mut self) -> Result<Vec<usize>> {
         let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::BITFIELD);
-        let bitfield = BitVec::<u8, Msb0>::from_vec(msg.payload);
-        let pieces = bitfield.iter_ones().collect();
-        Ok(pieces)
+        match msg.id {
+            MessageId::Bitfield => {
+                let bitfield = BitVec::<u8, Msb0>::from_slice(&msg.payload);
+                Ok(bitfield.iter_ones().collect())
+            }
+            MessageId::HaveAll if self.supports_fast => {
+                self.has_all = true;
+                Ok((0..self.piece_count.unwrap_or(0)).collect())
+            }
+            MessageId::HaveNone if self.supports_fast => Ok(Vec::new()),
+            id => Err(Error::Protocol(format!("expected bitfield, got {:?}", id))),
+        }
     }
 
-    pub async fn prepare_download(&mut self) -> anyhow::Result<()> {
-        let interested = Message::new(MessageId::INTERESTED, vec![]);
+    // Declares interest and waits to be unchoked, or with the fast
+    // extension, for the first piece we may request while still choked.
+    pub async fn prepare_download(&mut self) -> Result<()> {
+        let interested = Message::new(MessageId::Interested, vec![]);
         self.send(interested).await?;
-        let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::UNCHOKE);
+        let ready = |peer: &Self| {
+            peer.is_unchoked() || !peer.activity.allowed_fast.lock().unwrap().is_empty()
+        };
+        while !ready(self) {
+            let msg = self.recv_message(None).await?;
+            if self.dispatch_extension(&msg) {
+                continue;
+            }
+            if !self.note_state(&msg)? {
+                return Err(Error::Protocol(format!(
+                    "expected unchoke, got {:?}",
+                    msg.id
+                )));
+            }
+        }
         Ok(())
     }
 
-    pub async fn load_piece(&mut self, index: u32, piece_len: u32) -> anyhow::Result<Vec<u8>> {
-        let mut piece = vec![0u8; piece_len as usize];
-        let mut join_set = JoinSet::new();
-
-        let spawn = |join_set: &mut JoinSet<_>, mut peer: Peer, offset: u32| {
-            let length = BLOCK_SIZE.min(piece_len - offset);
-            join_set.spawn(async move {
-                match peer.load_block(index, offset, length).await {
-                    Ok(msg) => (offset, msg.payload[8..].to_vec()),
-                    Err(err) => {
-                        eprintln!("Error loading block: {}. Will retry...", err);
-                        (offset, vec![])
-                    }
+    // Records messages that change what we may request. Returns false for
+    // anything else.
+    fn note_state(&self, msg: &Message) -> Result<bool> {
+        let index = || -> Result<usize> {
+            let index: [u8; 4] = msg
+                .payload
+                .as_ref()
+                .try_into()
+                .map_err(|_| Error::Protocol(format!("malformed {:?} message", msg.id)))?;
+            Ok(u32::from_be_bytes(index) as usize)
+        };
+        match msg.id {
+            MessageId::Choke => self.activity.unchoked.store(false, Ordering::Relaxed),
+            MessageId::Unchoke => self.activity.unchoked.store(true, Ordering::Relaxed),
+            MessageId::Have => {
+                let index = index()?;
+                if self.piece_count.is_some_and(|count| index >= count) {
+                    return Err(Error::Protocol(format!("have for unknown piece {}", index)));
                 }
-            });
+                if let Some(haves) = &self.haves {
+                    let _ = haves.send((self.clone(), vec![index]));
+                }
+            }
+            MessageId::AllowedFast if self.supports_fast => {
+                let index = index()?;
+                self.activity.allowed_fast.lock().unwrap().insert(index);
+            }
+            MessageId::SuggestPiece if self.supports_fast => {
+                let index = index()?;
+                self.activity.suggested.lock().unwrap().push(index);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // Keeps up to the queue depth of block requests outstanding on the
+    // connection, shared with other pieces loading from the same peer.
+    // The reader task hands us our blocks by (index, begin).
+    pub async fn load_piece(&mut self, index: u32, piece_len: u32) -> Result<Bytes> {
+        let mut piece = BytesMut::zeroed(piece_len as usize);
+        let mut unrequested: VecDeque<u32> = (0..piece_len).step_by(BLOCK_SIZE as usize).collect();
+        let generation = self.generation();
+        let mut outstanding = Outstanding {
+            peer: self.clone(),
+            index,
+            piece_len,
+            generation,
+            requests: HashMap::new(),
         };
+        let (sender, mut blocks) = mpsc::unbounded_channel();
+        let length = |begin: u32| BLOCK_SIZE.min(piece_len - begin);
+        let inbox = self.inbox.clone();
+        let cancel = self.cancel.clone();
+        // Without the fast extension a choke silently drops our outstanding
+        // requests. They are parked, along with the blocks not requested
+        // yet, and made again once we are unchoked. Fast peers reject them
+        // instead, and the piece goes to someone else.
+        let mut resend_when_unchoked = false;
 
-        for offset in (0..piece_len).step_by(BLOCK_SIZE as usize) {
-            spawn(&mut join_set, self.clone(), offset);
+        while !unrequested.is_empty() || !outstanding.requests.is_empty() {
+            let choked = !self.can_request(index as usize);
+            if choked && !self.supports_fast && !outstanding.requests.is_empty() {
+                resend_when_unchoked = true;
+            }
+            if resend_when_unchoked && !choked {
+                resend_when_unchoked = false;
+                let begins: Vec<u32> = outstanding.requests.keys().copied().collect();
+                for begin in begins {
+                    self.request(index, begin, length(begin)).await?;
+                }
+            }
+            let may_request = !unrequested.is_empty() && (!choked || self.supports_fast);
+            let block = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return Err(Error::Cancelled),
+                Some(block) = blocks.recv() => block,
+                permit = self.request_slots.clone().acquire_owned(), if may_request => {
+                    let begin = unrequested.pop_front().unwrap();
+                    let permit = permit.expect("request slots are never closed");
+                    outstanding.requests.insert(begin, permit);
+                    self.activity
+                        .requests
+                        .lock()
+                        .unwrap()
+                        .insert((index, begin), sender.clone());
+                    self.request(index, begin, length(begin)).await?;
+                    continue;
+                }
+                received = async {
+                    let mut inbox = inbox.lock().await;
+                    // Whoever held the inbox may have got us unchoked, or
+                    // freed a slot, in which case nothing more may come
+                    // until we request.
+                    if choked == self.can_request(index as usize)
+                        || (may_request && self.request_slots.available_permits() > 0)
+                    {
+                        return None;
+                    }
+                    if generation != self.generation() {
+                        return Some(Err(connection_replaced()));
+                    }
+                    Some(inbox.recv().await.unwrap_or_else(|| Err(end_of_stream())))
+                } => {
+                    let Some(msg) = received else {
+                        continue;
+                    };
+                    let msg = msg?;
+                    if !self.absorb(&msg).await? {
+                        return Err(Error::Protocol(format!(
+                            "expected piece, got {:?}",
+                            msg.id
+                        )));
+                    }
+                    continue;
+                }
+            };
+            // Only blocks we requested are routed to us.
+            let (begin, data) = block?;
+            outstanding.requests.remove(&begin);
+            if data.len() != length(begin) as usize {
+                return Err(Error::Protocol("malformed piece message".to_string()));
+            }
+            let start = begin as usize;
+            piece[start..start + data.len()].copy_from_slice(&data);
         }
 
-        while let Some(join_result) = join_set.join_next().await {
-            let (offset, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                spawn(&mut join_set, self.clone(), offset);
-            } else {
-                let start = offset as usize;
-                let end = start + data.len();
-                piece[start..end].copy_from_slice(&data);
+        // Our blocks skip the inbox, so whatever the peer sent in between,
+        // like a Have, is handled now rather than whenever the next read is.
+        if let Ok(mut inbox) = inbox.try_lock() {
+            while let Ok(msg) = inbox.try_recv() {
+                let msg = msg?;
+                if !self.absorb(&msg).await? {
+                    return Err(Error::Protocol(format!("expected piece, got {:?}", msg.id)));
+                }
             }
         }
+        Ok(piece.freeze())
+    }
 
-        Ok(piece)
+    async fn request(&mut self, index: u32, begin: u32, length: u32) -> Result<()> {
+        let request = Message::block(MessageId::Request, index, begin, length);
+        self.send(request).await
     }
 
-    async fn load_block(&mut self, index: u32, begin: u32, length: u32) -> anyhow::Result<Message> {
-        let payload = vec![
-            index.to_be_bytes(),
-            begin.to_be_bytes(),
-            length.to_be_bytes(),
-        ]
-        .concat();
-        let request = Message::new(MessageId::REQUEST, payload);
-        self.send(request).await?;
-        let msg = self.recv().await?;
-        anyhow::ensure!(msg.id == MessageId::PIECE);
-        Ok(msg)
+    // Tells the peer we completed a piece.
+    pub async fn have(&mut self, index: u32) -> Result<()> {
+        let have = Message::new(MessageId::Have, index.to_be_bytes().to_vec());
+        self.send(have).await
+    }
+
+    pub async fn not_interested(&mut self) -> Result<()> {
+        self.send(Message::new(MessageId::NotInterested, vec![]))
+            .await
+    }
+
+    // BEP 5: the peer runs a DHT node on this port, a good place to start
+    // searches from.
+    fn note_port(&self, msg: &Message) -> Result<bool> {
+        if msg.id != MessageId::Port {
+            return Ok(false);
+        }
+        let port: [u8; 2] = msg
+            .payload
+            .as_ref()
+            .try_into()
+            .map_err(|_| Error::Protocol("malformed Port message".to_string()))?;
+        match u16::from_be_bytes(port) {
+            0 => {}
+            port => dht::add_node(SocketAddr::new(self.address.ip(), port)),
+        }
+        Ok(true)
+    }
+
+    // Blocks reach the inbox when nobody waited for them as they arrived.
+    // Someone may be by now; otherwise they are dropped.
+    fn route_block(&self, msg: &Message) -> Result<bool> {
+        let block = msg.id == MessageId::Piece
+            || (msg.id == MessageId::RejectRequest && self.supports_fast);
+        if block {
+            self.activity.route_block(msg, self.supports_fast)?;
+        }
+        Ok(block)
+    }
+
+    // The peer ID we introduced ourselves with.
+    pub fn local_id(&self) -> [u8; 20] {
+        self.local_id
     }
 
     pub fn gen_peer_id() -> String {
         let peer_id_len = 20;
-        (0..peer_id_len)
-            .map(|_| rand::thread_rng().gen_range(0..10).to_string())
-            .collect()
+        let mut peer_id = PEER_ID_PREFIX.lock().unwrap().clone();
+        while peer_id.len() < peer_id_len {
+            peer_id.push_str(&rand::thread_rng().gen_range(0..10).to_string());
+        }
+        peer_id
+    }
+}
+
+// A piece load's requests in flight, each holding one of the peer's request
+// slots. Whatever is still out when the load ends early is cancelled with
+// the peer, so it stops sending blocks nobody will read.
+struct Outstanding {
+    peer: Peer,
+    index: u32,
+    piece_len: u32,
+    generation: u64,
+    requests: HashMap<u32, OwnedSemaphorePermit>,
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        if self.requests.is_empty() {
+            return;
+        }
+        let index = self.index;
+        let cancels: Vec<Message> = {
+            let mut waiting = self.peer.activity.requests.lock().unwrap();
+            self.requests
+                .drain()
+                .map(|(begin, _)| {
+                    waiting.remove(&(index, begin));
+                    let length = BLOCK_SIZE.min(self.piece_len - begin);
+                    Message::block(MessageId::Cancel, index, begin, length)
+                })
+                .collect()
+        };
+        // Requests on a connection since replaced are gone already.
+        if self.peer.is_closed() || self.peer.generation() != self.generation {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut peer = self.peer.clone();
+        runtime.spawn(async move {
+            for cancel in cancels {
+                if peer.send(cancel).await.is_err() {
+                    return;
+                }
+            }
+        });
     }
 }
 
-#[derive(Debug)]
-struct Message {
-    length: u32,
-    id: MessageId,
-    payload: Vec<u8>,
+fn connection_replaced() -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "connection was replaced",
+    ))
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-#[repr(u8)]
-enum MessageId {
-    BITFIELD = 5,
-    INTERESTED = 2,
-    UNCHOKE = 1,
-    REQUEST = 6,
-    PIECE = 7,
-    EXTENSION = 20,
+fn end_of_stream() -> Error {
+    Error::Io(io::ErrorKind::UnexpectedEof.into())
 }
 
-impl Message {
-    fn new(id: MessageId, payload: Vec<u8>) -> Self {
-        let length = (mem::size_of::<MessageId>() + payload.len()) as u32;
-        Self {
-            length,
-            id,
-            payload,
+fn disconnected() -> Error {
+    Error::Io(io::ErrorKind::NotConnected.into())
+}
+
+// Reads the connection until it fails or every clone of the peer is gone.
+// Blocks go straight to the task that requested them; everything else is
+// queued in order, followed by the error the connection failed with.
+async fn read_frames(
+    address: SocketAddr,
+    mut stream: Reader,
+    supports_fast: bool,
+    activity: Arc<Activity>,
+    inbox: mpsc::Sender<Result<Message>>,
+) {
+    loop {
+        let frame = tokio::select! {
+            frame = stream.next() => frame,
+            _ = inbox.closed() => return,
+        };
+        let frame = match frame {
+            Some(Ok(frame)) => frame,
+            Some(Err(e)) => {
+                let _ = inbox.send(Err(e)).await;
+                return;
+            }
+            None => return,
+        };
+        if record::is_recording() {
+            record::log(address, Direction::Received, &frame.to_bytes());
+        }
+        // Skip keep-alives, and ignore message types we don't know as BEP 3 asks.
+        #[cfg_attr(not(feature = "chaos"), allow(unused_mut))]
+        let Frame::Message(mut msg) = frame
+        else {
+            continue;
+        };
+        if let MessageId::Unknown(id) = msg.id {
+            trace!(peer = %address, "skipping unknown message {}", id);
+            continue;
+        }
+        #[cfg(feature = "chaos")]
+        if msg.id == MessageId::Piece && msg.payload.len() > 8 {
+            let mut payload = BytesMut::from(&msg.payload[..]);
+            crate::chaos::corrupt_block(&mut payload[8..]);
+            msg.payload = payload.freeze();
+        }
+        // Holding off reading throttles the sender too.
+        if msg.id == MessageId::Piece {
+            let limits = activity.limits.lock().unwrap().clone();
+            limits
+                .receive(msg.payload.len().saturating_sub(8) as u64)
+                .await;
+        }
+        let queued = match activity.route_block(&msg, supports_fast) {
+            Ok(true) => continue,
+            Ok(false) => Ok(msg),
+            Err(e) => Err(e),
+        };
+        let failed = queued.is_err();
+        if inbox.send(queued).await.is_err() || failed {
+            return;
         }
     }
+}
 
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(self.length.to_be_bytes());
-        bytes.push(self.id as u8);
-        bytes.extend(self.payload.as_slice());
-        bytes
+// Sends what is queued in the outbox until every clone of the peer is gone,
+// and a keep-alive whenever we have been quiet for the keep-alive interval.
+// Once a write fails, everything fails until a new connection is attached.
+async fn write_frames(
+    address: SocketAddr,
+    writer: Writer,
+    activity: Arc<Activity>,
+    mut outbox: mpsc::UnboundedReceiver<Outgoing>,
+) {
+    let mut writer = Some(writer);
+    let started = Instant::now();
+    loop {
+        let interval = *KEEP_ALIVE_INTERVAL.lock().unwrap();
+        let quiet_until = activity.last_sent.lock().unwrap().unwrap_or(started) + interval;
+        let outgoing = match writer {
+            Some(_) => time::timeout_at(quiet_until, outbox.recv())
+                .await
+                .unwrap_or(Some(Outgoing::Frame(Frame::KeepAlive, None))),
+            None => outbox.recv().await,
+        };
+        let (frame, sent) = match outgoing {
+            Some(Outgoing::Frame(frame, sent)) => (frame, sent),
+            Some(Outgoing::Attach(attached)) => {
+                writer = Some(attached);
+                continue;
+            }
+            Some(Outgoing::Detach(detached)) => {
+                if let Some(writer) = writer {
+                    let _ = detached.send(writer);
+                }
+                return;
+            }
+            None => return,
+        };
+        let bytes = frame.to_bytes();
+        let written = match writer.as_mut() {
+            Some(stream) => stream.write_all(&bytes).await,
+            None => Err(io::ErrorKind::NotConnected.into()),
+        };
+        match written {
+            Ok(()) => {
+                record::log(address, Direction::Sent, &bytes);
+                *activity.last_sent.lock().unwrap() = Some(Instant::now());
+            }
+            Err(_) => writer = None,
+        }
+        if let Some(sent) = sent {
+            let _ = sent.send(written);
+        }
     }
 }
+
+async fn connect(address: SocketAddr, transport: Transport) -> Result<Box<dyn PeerStream>> {
+    Ok(match transport {
+        Transport::Tcp => Box::new(tor::connect(address).await?),
+        Transport::Utp => Box::new(utp::connect(address).await?),
+    })
+}
+
+// A peer ID for connections made outside any download.
+fn new_local_id() -> [u8; 20] {
+    Peer::gen_peer_id().as_bytes().try_into().unwrap()
+}
+
+async fn handshake(
+    stream: &mut (impl PeerStream + 'static),
+    address: SocketAddr,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+) -> Result<Handshake> {
+    let mut handshake_bytes = Handshake::new(info_hash, peer_id).to_bytes()?;
+
+    stream.write_all(&handshake_bytes).await?;
+    record::log(address, Direction::Sent, &handshake_bytes);
+    stream.read_exact(&mut handshake_bytes).await?;
+    record::log(address, Direction::Received, &handshake_bytes);
+
+    Handshake::from_bytes(&handshake_bytes)
+}
@@ -0,0 +1,149 @@
+// Token buckets capping how fast blocks move, for the whole process or a
+// session, and per download. A bucket holds up to a second's worth of
+// bytes; taking more than it holds leaves it in debt, which the next taker
+// waits out.
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::time;
+
+static DOWNLOAD: RateLimiter = RateLimiter::unlimited();
+static UPLOAD: RateLimiter = RateLimiter::unlimited();
+
+// Caps every download together, in bytes per second. `None` lifts the cap.
+pub fn set_download_limit(bytes_per_sec: Option<u64>) {
+    DOWNLOAD.set_rate(bytes_per_sec);
+}
+
+pub fn set_upload_limit(bytes_per_sec: Option<u64>) {
+    UPLOAD.set_rate(bytes_per_sec);
+}
+
+struct Bucket {
+    rate: u64, // bytes per second
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    // Takes `bytes`, returning how long to wait before using them.
+    fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled = now;
+        self.tokens -= bytes as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+pub struct RateLimiter {
+    // None while unlimited.
+    bucket: Mutex<Option<Bucket>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+impl RateLimiter {
+    pub const fn unlimited() -> Self {
+        Self {
+            bucket: Mutex::new(None),
+        }
+    }
+
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let limiter = Self::unlimited();
+        limiter.set_rate(Some(bytes_per_sec));
+        limiter
+    }
+
+    // A rate of zero is taken as one byte per second rather than a stall.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        *bucket = match (bytes_per_sec, bucket.take()) {
+            (None, _) => None,
+            (Some(rate), Some(old)) if old.rate == rate.max(1) => Some(old),
+            (Some(rate), _) => Some(Bucket::new(rate.max(1))),
+        };
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|bucket| bucket.rate)
+    }
+
+    // Waits until `bytes` may go through.
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = match self.bucket.lock().unwrap().as_mut() {
+            Some(bucket) => bucket.take(bytes),
+            None => return,
+        };
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+// A session's caps over all its downloads, which take the place of the
+// global ones.
+#[derive(Default)]
+pub(crate) struct Totals {
+    pub(crate) download: RateLimiter,
+    pub(crate) upload: RateLimiter,
+}
+
+// A download's own limits, on top of the global or session ones. Clones
+// share them.
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    pub download: Arc<RateLimiter>,
+    pub upload: Arc<RateLimiter>,
+    totals: Option<Arc<Totals>>,
+}
+
+impl RateLimits {
+    // Limits for a download in a session capped by `totals`.
+    pub(crate) fn in_session(totals: Arc<Totals>) -> Self {
+        Self {
+            totals: Some(totals),
+            ..Default::default()
+        }
+    }
+
+    // Waits until `bytes` more may be received.
+    pub(crate) async fn receive(&self, bytes: u64) {
+        match &self.totals {
+            Some(totals) => totals.download.acquire(bytes).await,
+            None => DOWNLOAD.acquire(bytes).await,
+        }
+        self.download.acquire(bytes).await;
+    }
+
+    // Waits until `bytes` more may be sent.
+    pub(crate) async fn send(&self, bytes: u64) {
+        match &self.totals {
+            Some(totals) => totals.upload.acquire(bytes).await,
+            None => UPLOAD.acquire(bytes).await,
+        }
+        self.upload.acquire(bytes).await;
+    }
+}
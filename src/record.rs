@@ -0,0 +1,179 @@
+// Recording of peer wire traffic for offline debugging. While a recording is
+// active every frame sent to or received from a peer is appended to the log
+// as a bincode record; `ReplayStream` turns a peer's received frames back
+// into a stream that `Peer` can be driven over.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    pub micros: u64,
+    pub peer: SocketAddr,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    pub fn describe(&self) -> String {
+        match self.frame.as_slice() {
+            [19, rest @ ..] if rest.starts_with(b"BitTorrent protocol") => "handshake".to_string(),
+            [0, 0, 0, 0] => "keep-alive".to_string(),
+            [_, _, _, _, id, ..] => format!("message {}", id),
+            _ => "truncated frame".to_string(),
+        }
+    }
+
+    pub fn info_hash(&self) -> Option<[u8; 20]> {
+        match self.describe().as_str() {
+            "handshake" => self.frame.get(28..48)?.try_into().ok(),
+            _ => None,
+        }
+    }
+}
+
+struct Recorder {
+    start: Instant,
+    log: BufWriter<File>,
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+pub fn start(path: &Path) -> Result<()> {
+    let log = BufWriter::new(File::create(path).map_err(Error::Storage)?);
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        start: Instant::now(),
+        log,
+    });
+    Ok(())
+}
+
+pub fn stop() -> Result<()> {
+    if let Some(mut recorder) = RECORDER.lock().unwrap().take() {
+        recorder.log.flush().map_err(Error::Storage)?;
+    }
+    Ok(())
+}
+
+// Whether frames are being recorded, so callers can skip assembling them.
+pub(crate) fn is_recording() -> bool {
+    RECORDER.lock().unwrap().is_some()
+}
+
+pub(crate) fn log(peer: SocketAddr, direction: Direction, frame: &[u8]) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    let record = Record {
+        micros: recorder.start.elapsed().as_micros() as u64,
+        peer,
+        direction,
+        frame: frame.to_vec(),
+    };
+    if let Err(e) = bincode::serialize_into(&mut recorder.log, &record) {
+        warn!("failed to record frame: {}", e);
+    }
+}
+
+pub fn read_log(path: &Path) -> Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path).map_err(Error::Storage)?);
+    let mut records = Vec::new();
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io) if io.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(records)
+                }
+                _ => return Err(e.into()),
+            },
+        }
+    }
+}
+
+pub fn peers(records: &[Record]) -> Vec<SocketAddr> {
+    let peers: BTreeSet<_> = records.iter().map(|record| record.peer).collect();
+    peers.into_iter().collect()
+}
+
+// Yields the frames a peer sent us, in order, and discards whatever we write.
+pub struct ReplayStream {
+    inbound: Vec<u8>,
+    position: usize,
+}
+
+impl ReplayStream {
+    pub fn new(records: &[Record], peer: SocketAddr) -> Self {
+        let inbound = records
+            .iter()
+            .filter(|record| record.peer == peer && record.direction == Direction::Received)
+            .flat_map(|record| record.frame.iter().copied())
+            .collect();
+        Self {
+            inbound,
+            position: 0,
+        }
+    }
+}
+
+// Raw inbound bytes, e.g. a captured or generated byte stream.
+impl From<Vec<u8>> for ReplayStream {
+    fn from(inbound: Vec<u8>) -> Self {
+        Self {
+            inbound,
+            position: 0,
+        }
+    }
+}
+
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let remaining = &self.inbound[self.position..];
+        let n = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..n]);
+        self.position += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,95 @@
+// Fast-resume files, kept next to a download as `<output>.resume` so that a
+// restarted download trusts the pieces it had already written instead of
+// hash-checking everything again. The pieces are only trusted while every
+// file of the download is still there at its full length.
+use bitvec::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    torrent::Info,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeFile {
+    pub info_hash: String, // hex
+    have: String,          // hex bitfield of pieces written to disk
+    pub downloaded: u64,   // bytes, over every session
+    pub uploaded: u64,     // bytes, over every session
+    #[serde(default)]
+    pub peers: Vec<PeerStats>,
+    // The outcome of the last tracker announce.
+    #[serde(default)]
+    pub tracker: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub address: SocketAddr,
+    pub pieces: usize, // verified pieces it sent us
+}
+
+impl ResumeFile {
+    pub fn new(info: &Info) -> Result<Self> {
+        Ok(Self {
+            info_hash: hex::encode(info.info_hash()?),
+            have: String::new(),
+            downloaded: 0,
+            uploaded: 0,
+            peers: Vec::new(),
+            tracker: None,
+        })
+    }
+
+    pub fn path_for(download: &Path) -> PathBuf {
+        let mut path = download.as_os_str().to_owned();
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    pub fn matches(&self, info: &Info) -> bool {
+        info.info_hash()
+            .is_ok_and(|info_hash| hex::encode(info_hash) == self.info_hash)
+            && self.have().iter().all(|&piece| piece < info.piece_count())
+    }
+
+    pub fn have(&self) -> Vec<usize> {
+        let bytes = hex::decode(&self.have).unwrap_or_default();
+        BitVec::<u8, Msb0>::from_vec(bytes).iter_ones().collect()
+    }
+
+    pub fn set_have(&mut self, pieces: impl IntoIterator<Item = usize>, piece_count: usize) {
+        let mut bitfield = bitvec![u8, Msb0; 0; piece_count];
+        for piece in pieces.into_iter().filter(|&piece| piece < piece_count) {
+            bitfield.set(piece, true);
+        }
+        self.have = hex::encode(bitfield.into_vec());
+    }
+
+    pub fn add_piece_from(&mut self, address: SocketAddr) {
+        match self.peers.iter_mut().find(|peer| peer.address == address) {
+            Some(peer) => peer.pieces += 1,
+            None => self.peers.push(PeerStats { address, pieces: 1 }),
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = fs::read(path).map_err(Error::Storage)?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| Error::Metadata(format!("corrupt resume file: {}", e)))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        // Write then rename, so a crash keeps the old file.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let bytes = serde_json::to_vec(self).map_err(|e| Error::Metadata(e.to_string()))?;
+        fs::write(&tmp, bytes).map_err(Error::Storage)?;
+        fs::rename(tmp, path).map_err(Error::Storage)
+    }
+}
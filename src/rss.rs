@@ -0,0 +1,137 @@
+// Polls RSS/Atom feeds and picks out items whose titles match a feed's
+// filters, for automatic downloads.
+use regex::Regex;
+use roxmltree::{Document, Node};
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+use tracing::warn;
+use url::Url;
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeedItem {
+    pub title: String,
+    pub link: String, // magnet link or .torrent URL
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedConfig {
+    pub url: Url,
+    pub filters: Vec<Regex>,
+    pub download_dir: PathBuf,
+}
+
+impl FeedConfig {
+    // A feed without filters accepts every item.
+    pub fn matches(&self, title: &str) -> bool {
+        self.filters.is_empty() || self.filters.iter().any(|filter| filter.is_match(title))
+    }
+}
+
+pub struct FeedWatcher {
+    feeds: Vec<FeedConfig>,
+    interval: Duration,
+    seen: HashSet<String>,
+}
+
+impl FeedWatcher {
+    pub fn new(feeds: Vec<FeedConfig>, interval: Duration) -> Self {
+        Self {
+            feeds,
+            interval,
+            seen: HashSet::new(),
+        }
+    }
+
+    // Fetches every feed once, returning matching items not returned before
+    // along with the directory they should be downloaded to.
+    pub async fn poll(&mut self) -> Vec<(FeedItem, PathBuf)> {
+        let mut matches = Vec::new();
+        for feed in &self.feeds {
+            let items = match fetch(&feed.url).await {
+                Ok(items) => items,
+                Err(e) => {
+                    warn!("failed to poll {}: {}", feed.url, e);
+                    continue;
+                }
+            };
+            for item in items {
+                if feed.matches(&item.title) && self.seen.insert(item.link.clone()) {
+                    matches.push((item, feed.download_dir.clone()));
+                }
+            }
+        }
+        matches
+    }
+
+    pub async fn run(mut self, mut on_match: impl FnMut(FeedItem, PathBuf)) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            for (item, download_dir) in self.poll().await {
+                on_match(item, download_dir);
+            }
+        }
+    }
+}
+
+async fn fetch(url: &Url) -> Result<Vec<FeedItem>> {
+    let response = crate::tor::http_client(url.host_str().unwrap_or_default())?
+        .get(url.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    parse_feed(&response.text().await?)
+}
+
+pub fn parse_feed(xml: &str) -> Result<Vec<FeedItem>> {
+    let document = Document::parse(xml).map_err(|e| Error::Feed(e.to_string()))?;
+    let root = document.root_element();
+    let items = match root.tag_name().name() {
+        "rss" | "RDF" => root
+            .descendants()
+            .filter(|node| node.has_tag_name("item"))
+            .filter_map(rss_item)
+            .collect(),
+        "feed" => root
+            .children()
+            .filter(|node| node.has_tag_name("entry"))
+            .filter_map(atom_entry)
+            .collect(),
+        other => return Err(Error::Feed(format!("unsupported feed format: {}", other))),
+    };
+    Ok(items)
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    let child = node.children().find(|child| child.has_tag_name(name))?;
+    Some(child.text()?.trim().to_string())
+}
+
+// Prefers the torrent enclosure over the item link, which often points at
+// a web page.
+fn rss_item(item: Node) -> Option<FeedItem> {
+    let title = child_text(item, "title")?;
+    let enclosure = item
+        .children()
+        .find(|child| child.has_tag_name("enclosure"))
+        .and_then(|enclosure| enclosure.attribute("url"))
+        .map(str::to_string);
+    let link = enclosure.or_else(|| child_text(item, "link"))?;
+    Some(FeedItem { title, link })
+}
+
+fn atom_entry(entry: Node) -> Option<FeedItem> {
+    let title = child_text(entry, "title")?;
+    let links: Vec<_> = entry
+        .children()
+        .filter(|child| child.has_tag_name("link"))
+        .collect();
+    let link = links
+        .iter()
+        .find(|link| link.attribute("rel") == Some("enclosure"))
+        .or_else(|| links.first())?
+        .attribute("href")?
+        .to_string();
+    Some(FeedItem { title, link })
+}
@@ -0,0 +1,127 @@
+// Deciding where each piece is requested from. A source is rated by how
+// fast it has been delivering lately, shared among the pieces it is already
+// loading, and halved for every recent time it stopped delivering. Sources
+// not measured yet are rated like the best one, so they get tried.
+use rand::seq::SliceRandom;
+use std::{
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+// Halvings past this make no difference.
+const MAX_TIMEOUTS: u32 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rating {
+    // Recent bytes per second; `None` until it has delivered something.
+    pub rate: Option<u64>,
+    // Pieces being loaded from it right now.
+    pub assigned: usize,
+    // Times it stopped delivering, less the pieces it delivered since.
+    pub timeouts: u32,
+}
+
+// Returns which of `ratings` to request the next piece from, picking at
+// random between equally good ones.
+pub fn choose(ratings: &[Rating]) -> Option<usize> {
+    let best_rate = ratings
+        .iter()
+        .filter_map(|rating| rating.rate)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let scores: Vec<f64> = ratings
+        .iter()
+        .map(|rating| {
+            let rate = rating.rate.unwrap_or(best_rate) as f64;
+            let demotion = f64::from(1u32 << rating.timeouts.min(MAX_TIMEOUTS));
+            rate / (rating.assigned + 1) as f64 / demotion
+        })
+        .collect();
+    let best = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let tied: Vec<usize> = (0..scores.len()).filter(|&i| scores[i] == best).collect();
+    tied.choose(&mut rand::thread_rng()).copied()
+}
+
+// What a peer or web seed has delivered, shared by all its clones.
+#[derive(Default)]
+pub(crate) struct Throughput {
+    received: AtomicU64,
+    // Smoothed bytes per second; 0 until first measured.
+    rate: AtomicU64,
+    assigned: AtomicUsize,
+    timeouts: AtomicU32,
+    // When `rate` was last sampled, and `received` at the time.
+    sampled: Mutex<Option<(Instant, u64)>>,
+}
+
+impl Throughput {
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // Counts a piece against the source until the returned guard drops.
+    pub(crate) fn assign(self: &Arc<Self>) -> Assignment {
+        self.assigned.fetch_add(1, Ordering::Relaxed);
+        Assignment(self.clone())
+    }
+
+    pub(crate) fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_piece(&self) {
+        let _ = self
+            .timeouts
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |timeouts| {
+                timeouts.checked_sub(1)
+            });
+    }
+
+    // Folds what arrived since the last sample into the rate. A source with
+    // nothing to do keeps the rate it had.
+    pub(crate) fn sample(&self) {
+        let now = Instant::now();
+        let received = self.received();
+        let mut sampled = self.sampled.lock().unwrap();
+        let Some((then, before)) = sampled.replace((now, received)) else {
+            return;
+        };
+        let elapsed = now.duration_since(then).as_secs_f64();
+        if self.assigned.load(Ordering::Relaxed) == 0 || received == 0 || elapsed <= 0.0 {
+            return;
+        }
+        let current = ((received - before) as f64 / elapsed) as u64;
+        let rate = match self.rate.load(Ordering::Relaxed) {
+            0 => current,
+            old => (old + current) / 2,
+        };
+        self.rate.store(rate.max(1), Ordering::Relaxed);
+    }
+
+    pub(crate) fn rating(&self) -> Rating {
+        Rating {
+            rate: match self.rate.load(Ordering::Relaxed) {
+                0 => None,
+                rate => Some(rate),
+            },
+            assigned: self.assigned.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) struct Assignment(Arc<Throughput>);
+
+impl Drop for Assignment {
+    fn drop(&mut self) {
+        self.0.assigned.fetch_sub(1, Ordering::Relaxed);
+    }
+}
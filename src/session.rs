@@ -0,0 +1,344 @@
+// Many downloads run from one process. A session's downloads announce and
+// handshake with the same peer ID, take turns when only so many may run at
+// once, and share the session's listener, rate limits and per-download
+// connection limit. Sessions in one process don't affect each other; a
+// session without its own listener takes peers from the process's.
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    download::{AllAtOnce, DownloadHandle, DownloadMonitor},
+    error::{Error, Result},
+    listener::Listener,
+    magnet::Magnet,
+    peer::Peer,
+    ratelimit::Totals,
+    source::Source,
+    torrent::Torrent,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct SessionConfig {
+    // Accept connections from peers here.
+    pub listen: Option<SocketAddr>,
+    // How many downloads may run at once. The rest wait, in the order they
+    // were added, for one to finish.
+    pub max_active: Option<usize>,
+    // Where downloads added without a path go, named after their torrent.
+    pub download_dir: Option<PathBuf>,
+    // Connections per download, in place of the global limit.
+    pub max_peers: Option<usize>,
+    // Bytes per second over all the session's downloads, in place of the
+    // global limits.
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
+    // Block requests outstanding per peer, in place of the global depth.
+    pub queue_depth: Option<usize>,
+}
+
+// What a session's downloads have in common.
+#[derive(Clone)]
+pub(crate) struct Shared {
+    pub(crate) peer_id: String,
+    pub(crate) slots: Option<Arc<Semaphore>>,
+    pub(crate) listener: Option<Arc<Listener>>,
+    pub(crate) max_peers: Option<usize>,
+    pub(crate) totals: Arc<Totals>,
+    pub(crate) queue_depth: Option<usize>,
+}
+
+// A download as the session sees it.
+#[derive(Clone)]
+pub struct SessionTorrent {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub monitor: DownloadMonitor,
+}
+
+// Totals over a session's downloads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub torrents: usize,
+    pub paused: usize,
+    pub download_rate: u64, // bytes per second
+    pub upload_rate: u64,   // bytes per second
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub peers: usize,
+}
+
+struct Entry {
+    torrent: SessionTorrent,
+    paused: watch::Sender<bool>,
+    cancel: CancellationToken,
+}
+
+#[derive(Clone)]
+pub struct Session {
+    shared: Shared,
+    download_dir: Option<PathBuf>,
+    torrents: Arc<Mutex<BTreeMap<[u8; 20], Entry>>>,
+}
+
+impl Session {
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let listener = match config.listen {
+            Some(address) => Some(Listener::bind(address).await?),
+            None => None,
+        };
+        let totals = Arc::new(Totals::default());
+        totals.download.set_rate(config.download_rate_limit);
+        totals.upload.set_rate(config.upload_rate_limit);
+        let shared = Shared {
+            peer_id: Peer::gen_peer_id(),
+            slots: config
+                .max_active
+                .map(|max_active| Arc::new(Semaphore::new(max_active.max(1)))),
+            listener,
+            max_peers: config.max_peers,
+            totals,
+            queue_depth: config.queue_depth,
+        };
+        Ok(Self {
+            shared,
+            download_dir: config.download_dir,
+            torrents: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.shared.peer_id
+    }
+
+    // Where the session accepts peers, if it has its own listener.
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.shared
+            .listener
+            .as_ref()
+            .map(|listener| listener.address())
+    }
+
+    // Caps the session's downloads together, in bytes per second. `None`
+    // lifts the cap.
+    pub fn set_download_limit(&self, bytes_per_sec: Option<u64>) {
+        self.shared.totals.download.set_rate(bytes_per_sec);
+    }
+
+    pub fn set_upload_limit(&self, bytes_per_sec: Option<u64>) {
+        self.shared.totals.upload.set_rate(bytes_per_sec);
+    }
+
+    pub fn add_torrent(&self, torrent: &Torrent, path: PathBuf) -> Result<DownloadHandle<()>> {
+        self.add(torrent.info_hash()?, torrent.info.name(), |shared| {
+            torrent.spawn_download_to(Some(shared), Arc::new(AllAtOnce), path)
+        })
+    }
+
+    pub fn add_magnet(&self, magnet: &Magnet, path: PathBuf) -> Result<DownloadHandle<()>> {
+        self.add(magnet.info_hash, &magnet_name(magnet), |shared| {
+            magnet.spawn_download_to(Some(shared), path)
+        })
+    }
+
+    // Adds whatever `source` names, returning its info hash with the handle.
+    // Without a path the download goes to the download directory. Magnet
+    // links are added without waiting for their metadata.
+    pub async fn add_source(
+        &self,
+        source: &Source,
+        path: Option<PathBuf>,
+    ) -> Result<([u8; 20], DownloadHandle<()>)> {
+        if let Some(path) = path {
+            return self.add_source_with(source, |_| Ok(path)).await;
+        }
+        let dir = self
+            .download_dir
+            .as_ref()
+            .ok_or_else(|| Error::Config("no download directory configured".to_string()))?;
+        self.add_source_in(source, dir).await
+    }
+
+    // Like `add_source` without a path, saving to `dir` in place of the
+    // download directory.
+    pub async fn add_source_in(
+        &self,
+        source: &Source,
+        dir: &Path,
+    ) -> Result<([u8; 20], DownloadHandle<()>)> {
+        self.add_source_with(source, |name| path_in(dir, name))
+            .await
+    }
+
+    async fn add_source_with(
+        &self,
+        source: &Source,
+        path_for: impl FnOnce(&str) -> Result<PathBuf>,
+    ) -> Result<([u8; 20], DownloadHandle<()>)> {
+        let magnet = match source {
+            Source::MagnetUri(url) => Magnet::new(url.clone())?,
+            Source::InfoHash(info_hash) => Magnet::from(*info_hash),
+            Source::TorrentFile(_) | Source::HttpUrl(_) => {
+                let torrent = source.resolve().await?;
+                let path = path_for(torrent.info.name())?;
+                let handle = self.add_torrent(&torrent, path)?;
+                return Ok((torrent.info_hash()?, handle));
+            }
+        };
+        let path = path_for(&magnet_name(&magnet))?;
+        Ok((magnet.info_hash, self.add_magnet(&magnet, path)?))
+    }
+
+    // A torrent is only ever downloaded once per session; remove it to add
+    // it again.
+    fn add(
+        &self,
+        info_hash: [u8; 20],
+        name: &str,
+        spawn: impl FnOnce(Shared) -> DownloadHandle<()>,
+    ) -> Result<DownloadHandle<()>> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::Protocol(format!(
+                "{} is already in the session",
+                hex::encode(info_hash)
+            )));
+        }
+        let handle = spawn(self.shared.clone());
+        let entry = Entry {
+            torrent: SessionTorrent {
+                info_hash,
+                name: name.to_string(),
+                monitor: handle.monitor(),
+            },
+            paused: handle.pause_switch(),
+            cancel: handle.cancellation_token(),
+        };
+        torrents.insert(info_hash, entry);
+        Ok(handle)
+    }
+
+    pub fn torrents(&self) -> Vec<SessionTorrent> {
+        let torrents = self.torrents.lock().unwrap();
+        torrents
+            .values()
+            .map(|entry| entry.torrent.clone())
+            .collect()
+    }
+
+    // The info hash of the one torrent whose hex info hash starts with `id`.
+    pub fn find(&self, id: &str) -> Result<[u8; 20]> {
+        let id = id.to_ascii_lowercase();
+        let torrents = self.torrents.lock().unwrap();
+        let mut matches = torrents
+            .keys()
+            .filter(|info_hash| hex::encode(info_hash).starts_with(&id));
+        match (matches.next(), matches.next()) {
+            (Some(info_hash), None) => Ok(*info_hash),
+            (None, _) => Err(Error::Protocol(format!("no torrent matches {}", id))),
+            (Some(_), Some(_)) => Err(Error::Protocol(format!(
+                "{} matches more than one torrent",
+                id
+            ))),
+        }
+    }
+
+    // Returns whether the torrent was in the session.
+    pub fn pause(&self, info_hash: &[u8; 20]) -> bool {
+        self.set_paused(info_hash, true)
+    }
+
+    pub fn resume(&self, info_hash: &[u8; 20]) -> bool {
+        self.set_paused(info_hash, false)
+    }
+
+    fn set_paused(&self, info_hash: &[u8; 20], paused: bool) -> bool {
+        match self.torrents.lock().unwrap().get(info_hash) {
+            Some(entry) => {
+                entry.paused.send_replace(paused);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        let torrents = self.torrents.lock().unwrap();
+        let mut stats = SessionStats {
+            torrents: torrents.len(),
+            ..Default::default()
+        };
+        for entry in torrents.values() {
+            let monitor = &entry.torrent.monitor;
+            let progress = monitor.progress();
+            stats.paused += usize::from(monitor.is_paused());
+            stats.download_rate += progress.download_rate;
+            stats.upload_rate += progress.upload_rate;
+            stats.bytes_downloaded += progress.bytes_downloaded;
+            stats.bytes_uploaded += progress.bytes_uploaded;
+            stats.peers += progress.peers;
+        }
+        stats
+    }
+
+    // Cancels the torrent's download if it is still running. Returns whether
+    // the torrent was in the session.
+    pub fn remove(&self, info_hash: &[u8; 20]) -> bool {
+        match self.torrents.lock().unwrap().remove(info_hash) {
+            Some(entry) => {
+                entry.cancel.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Like `shutdown`, then waits for the downloads to wind down: resume
+    // state saved and trackers told they stopped.
+    pub async fn close(&self) {
+        let monitors: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.torrent.monitor.clone())
+            .collect();
+        self.shutdown();
+        for monitor in monitors {
+            monitor.finished().await;
+        }
+    }
+
+    // Cancels every download and stops the session's listener, if it has
+    // one. Other sessions, and the process's listener, carry on.
+    pub fn shutdown(&self) {
+        let torrents = std::mem::take(&mut *self.torrents.lock().unwrap());
+        for entry in torrents.into_values() {
+            entry.cancel.cancel();
+        }
+        if let Some(listener) = &self.shared.listener {
+            listener.stop();
+        }
+    }
+}
+
+// The name comes from the torrent; never let it leave the directory.
+fn path_in(dir: &Path, name: &str) -> Result<PathBuf> {
+    let name = Path::new(name)
+        .file_name()
+        .ok_or_else(|| Error::Metadata(format!("invalid torrent name {:?}", name)))?;
+    Ok(dir.join(name))
+}
+
+fn magnet_name(magnet: &Magnet) -> String {
+    match &magnet.file_name {
+        Some(name) => name.clone(),
+        None => hex::encode(magnet.info_hash),
+    }
+}
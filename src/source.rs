@@ -0,0 +1,80 @@
+use std::{fmt, path::PathBuf, str::FromStr};
+use url::Url;
+
+use crate::{
+    error::{Error, Result},
+    magnet::Magnet,
+    torrent::Torrent,
+};
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    TorrentFile(PathBuf),
+    MagnetUri(Url),
+    InfoHash([u8; 20]),
+    HttpUrl(Url),
+}
+
+impl Source {
+    pub async fn resolve(&self) -> Result<Torrent> {
+        match self {
+            Source::TorrentFile(path) => Torrent::new(path.clone()),
+            Source::HttpUrl(url) => Self::fetch(url).await,
+            Source::MagnetUri(url) => Self::resolve_magnet(Magnet::new(url.clone())?).await,
+            Source::InfoHash(info_hash) => Self::resolve_magnet(Magnet::from(*info_hash)).await,
+        }
+    }
+
+    #[cfg(feature = "http")]
+    async fn fetch(url: &Url) -> Result<Torrent> {
+        let response = crate::tor::http_client(url.host_str().unwrap_or_default())?
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        Torrent::from_bytes(&response.bytes().await?)
+    }
+
+    #[cfg(not(feature = "http"))]
+    async fn fetch(_url: &Url) -> Result<Torrent> {
+        Err(Error::Metadata(
+            "fetching torrents over HTTP requires the `http` feature".to_string(),
+        ))
+    }
+
+    async fn resolve_magnet(magnet: Magnet) -> Result<Torrent> {
+        let metadata = magnet.fetch_metadata().await?;
+        Torrent::from_magnet_and_metadata(magnet, metadata)
+    }
+}
+
+impl FromStr for Source {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("magnet:") {
+            return Ok(Source::MagnetUri(Url::parse(s)?));
+        }
+        if s.starts_with("http://") || s.starts_with("https://") {
+            return Ok(Source::HttpUrl(Url::parse(s)?));
+        }
+        let path = PathBuf::from(s);
+        if !path.exists() && s.len() == 40 {
+            if let Ok(info_hash) = hex::decode(s) {
+                let info_hash = info_hash.try_into().expect("40 hex chars are 20 bytes");
+                return Ok(Source::InfoHash(info_hash));
+            }
+        }
+        Ok(Source::TorrentFile(path))
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::TorrentFile(path) => write!(f, "{}", path.display()),
+            Source::MagnetUri(url) | Source::HttpUrl(url) => write!(f, "{}", url),
+            Source::InfoHash(info_hash) => write!(f, "{}", hex::encode(info_hash)),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+// Writing pieces out as they complete, and reading blocks back to upload
+// them, either from pieces completed this session or from a downloaded
+// file. A piece on disk is re-hashed the first time it is read in a session
+// so that local disk corruption is refused rather than passed on to the
+// swarm; the result is cached, so later blocks of the same piece are read
+// directly. A single-file torrent is stored at the output path itself, a
+// multi-file one as a directory of its files there; pieces are mapped onto
+// files by their offset in the torrent, and may span several.
+use bytes::Bytes;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{
+    error::{Error, Result},
+    torrent::Info,
+};
+
+// Verified pieces kept in memory while a download runs, so peers can be
+// served before anything is written out. Pieces a `PieceWriter` has written
+// are dropped from memory and read back from the file. Clones share the
+// pieces.
+#[derive(Clone, Default)]
+pub struct PieceStore {
+    pieces: Arc<Mutex<HashMap<usize, Bytes>>>,
+    written: Arc<Mutex<Option<Written>>>,
+    uploaded: Arc<AtomicU64>,
+}
+
+struct Written {
+    layout: Layout,
+    info: Info,
+    pieces: HashSet<usize>,
+}
+
+impl PieceStore {
+    pub fn insert(&self, index: usize, piece: Bytes) {
+        self.pieces.lock().unwrap().insert(index, piece);
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces.lock().unwrap().contains_key(&index)
+            || self
+                .written
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|written| written.pieces.contains(&index))
+    }
+
+    // Pieces a `PieceWriter` has written out, which survive a restart.
+    pub fn written_pieces(&self) -> Vec<usize> {
+        let mut pieces: Vec<_> = match self.written.lock().unwrap().as_ref() {
+            Some(written) => written.pieces.iter().copied().collect(),
+            None => Vec::new(),
+        };
+        pieces.sort_unstable();
+        pieces
+    }
+
+    pub fn pieces(&self) -> Vec<usize> {
+        let mut pieces: Vec<_> = self.pieces.lock().unwrap().keys().copied().collect();
+        if let Some(written) = self.written.lock().unwrap().as_ref() {
+            pieces.extend(&written.pieces);
+        }
+        pieces.sort_unstable();
+        pieces
+    }
+
+    // Piece `index` is now on disk, so its copy in memory can go.
+    fn written(&self, index: usize, layout: &Layout, info: &Info) {
+        let mut written = self.written.lock().unwrap();
+        let written = written.get_or_insert_with(|| Written {
+            layout: layout.clone(),
+            info: info.clone(),
+            pieces: HashSet::new(),
+        });
+        written.pieces.insert(index);
+        self.pieces.lock().unwrap().remove(&index);
+    }
+
+    // Bytes read out of the store for peers so far.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub async fn read_block(&self, index: usize, begin: u32, length: u32) -> Result<Bytes> {
+        let piece = self.pieces.lock().unwrap().get(&index).cloned();
+        let block = match piece {
+            Some(piece) => {
+                let (start, end) = (begin as usize, begin as usize + length as usize);
+                if end > piece.len() {
+                    return Err(out_of_range(index, begin, length));
+                }
+                piece.slice(start..end)
+            }
+            None => self.read_written(index, begin, length).await?,
+        };
+        self.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+        Ok(block)
+    }
+
+    async fn read_written(&self, index: usize, begin: u32, length: u32) -> Result<Bytes> {
+        let (layout, offset) = match self.written.lock().unwrap().as_ref() {
+            Some(written)
+                if written.pieces.contains(&index)
+                    && begin as u64 + (length as u64) <= written.info.piece_len(index) as u64 =>
+            {
+                (
+                    written.layout.clone(),
+                    written.info.piece_offset(index) + begin as u64,
+                )
+            }
+            Some(written) if written.pieces.contains(&index) => {
+                return Err(out_of_range(index, begin, length))
+            }
+            _ => return Err(Error::Protocol(format!("piece {} is not complete", index))),
+        };
+        // The block is in range, so failing to read it is the disk's fault.
+        let block = layout.read(offset, length).await?;
+        Ok(Bytes::from(block))
+    }
+}
+
+// Where each file of a torrent is stored, in the order its bytes appear.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    files: Vec<(PathBuf, u64)>,
+}
+
+impl Layout {
+    // Refuses file paths that would leave `path`.
+    pub fn new(info: &Info, path: &Path) -> Result<Self> {
+        let single_file = info.is_single_file();
+        let files = info
+            .files()
+            .into_iter()
+            .map(|(components, length)| {
+                let unsafe_component = |component: &String| {
+                    matches!(component.as_str(), "" | "." | "..")
+                        || component.contains(['/', '\\', '\0'])
+                };
+                if (!single_file && components.is_empty())
+                    || components.iter().any(unsafe_component)
+                {
+                    return Err(Error::Metadata(format!(
+                        "unsafe file path {:?}",
+                        components
+                    )));
+                }
+                // Joining no components at all would add a trailing separator.
+                let file = if components.is_empty() {
+                    path.to_path_buf()
+                } else {
+                    path.join(components.iter().collect::<PathBuf>())
+                };
+                Ok((file, length))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { files })
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(|(path, _)| path.as_path())
+    }
+
+    // Whether every file is there at its full length.
+    pub async fn is_allocated(&self) -> bool {
+        for (path, length) in &self.files {
+            match fs::metadata(path).await {
+                Ok(metadata) if metadata.is_file() && metadata.len() == *length => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // Creates every file at its full length, and the directories they are
+    // in.
+    pub async fn create(&self) -> Result<()> {
+        self.allocate(true).await
+    }
+
+    // Like `create`, keeping whatever files already hold.
+    pub async fn open(&self) -> Result<()> {
+        self.allocate(false).await
+    }
+
+    async fn allocate(&self, truncate: bool) -> Result<()> {
+        for (path, length) in &self.files {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await.map_err(Error::Storage)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(truncate)
+                .write(true)
+                .open(path)
+                .await
+                .map_err(Error::Storage)?;
+            file.set_len(*length).await.map_err(Error::Storage)?;
+        }
+        Ok(())
+    }
+
+    // The parts of the torrent's bytes `offset..offset + length` in each
+    // file: the file, where they start in it and where in the range.
+    fn spans(&self, offset: u64, length: u64) -> Vec<(&Path, u64, std::ops::Range<usize>)> {
+        let mut spans = Vec::new();
+        let mut file_start = 0;
+        for (path, file_length) in &self.files {
+            let file_end = file_start + file_length;
+            let (start, end) = (offset.max(file_start), (offset + length).min(file_end));
+            if start < end {
+                let range = (start - offset) as usize..(end - offset) as usize;
+                spans.push((path.as_path(), start - file_start, range));
+            }
+            file_start = file_end;
+        }
+        spans
+    }
+
+    pub async fn read(&self, offset: u64, length: u32) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; length as usize];
+        let spans = self.spans(offset, length as u64);
+        if spans.last().map_or(0, |(_, _, range)| range.end) != data.len() {
+            return Err(Error::Storage(io::ErrorKind::UnexpectedEof.into()));
+        }
+        for (path, position, range) in spans {
+            let mut file = File::open(path).await.map_err(Error::Storage)?;
+            file.seek(SeekFrom::Start(position))
+                .await
+                .map_err(Error::Storage)?;
+            file.read_exact(&mut data[range])
+                .await
+                .map_err(Error::Storage)?;
+        }
+        Ok(data)
+    }
+
+    pub async fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        for (path, position, range) in self.spans(offset, data.len() as u64) {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(path)
+                .await
+                .map_err(Error::Storage)?;
+            file.seek(SeekFrom::Start(position))
+                .await
+                .map_err(Error::Storage)?;
+            file.write_all(&data[range]).await.map_err(Error::Storage)?;
+            file.flush().await.map_err(Error::Storage)?;
+        }
+        Ok(())
+    }
+}
+
+// Writes verified pieces at their place in the output files as they
+// complete, so a download only holds the pieces still in flight.
+pub struct PieceWriter {
+    layout: Layout,
+    info: Info,
+    store: PieceStore,
+}
+
+impl PieceWriter {
+    // Creates the torrent's files under `path` at their full length.
+    // Written pieces are handed over to `store`, which reads them back from
+    // there.
+    pub async fn create(path: PathBuf, info: &Info, store: PieceStore) -> Result<Self> {
+        let layout = Layout::new(info, &path)?;
+        layout.create().await?;
+        Ok(Self {
+            layout,
+            info: info.clone(),
+            store,
+        })
+    }
+
+    // Like `create`, keeping files already under `path` and hash-checking
+    // every piece in them. Intact pieces are handed over to `store` as
+    // written, so only the missing or corrupt ones are downloaded again.
+    pub async fn resume(path: PathBuf, info: &Info, store: PieceStore) -> Result<Self> {
+        let layout = Layout::new(info, &path)?;
+        let existed = layout.files().any(Path::exists);
+        layout.open().await?;
+        if existed {
+            let intact = verify_pieces(info, &path).await?;
+            for index in (0..intact.len()).filter(|&index| intact[index]) {
+                store.written(index, &layout, info);
+            }
+        }
+        Ok(Self {
+            layout,
+            info: info.clone(),
+            store,
+        })
+    }
+
+    // Like `resume`, trusting that the `verified` pieces are intact instead
+    // of hash-checking the files, as long as none has changed length since.
+    pub async fn resume_verified(
+        path: PathBuf,
+        info: &Info,
+        store: PieceStore,
+        verified: &[usize],
+    ) -> Result<Self> {
+        let layout = Layout::new(info, &path)?;
+        if !layout.is_allocated().await {
+            return Self::resume(path, info, store).await;
+        }
+        for &index in verified {
+            store.written(index, &layout, info);
+        }
+        Ok(Self {
+            layout,
+            info: info.clone(),
+            store,
+        })
+    }
+
+    pub async fn write_piece(&mut self, index: usize, piece: &[u8]) -> Result<()> {
+        self.layout
+            .write(self.info.piece_offset(index), piece)
+            .await?;
+        self.store.written(index, &self.layout, &self.info);
+        Ok(())
+    }
+
+    // Makes sure everything written is on disk.
+    pub async fn finish(self) -> Result<()> {
+        for path in self.layout.files() {
+            let file = File::open(path).await.map_err(Error::Storage)?;
+            file.sync_all().await.map_err(Error::Storage)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct PieceReader {
+    info: Info,
+    path: PathBuf,
+    verify: bool,
+    verified: Mutex<HashMap<usize, bool>>,
+}
+
+impl PieceReader {
+    pub fn new(info: Info, path: PathBuf) -> Self {
+        Self {
+            info,
+            path,
+            verify: true,
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub async fn read_block(&self, index: usize, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let piece_len = self.info.piece_len(index);
+        if index >= self.info.piece_count() || begin as u64 + length as u64 > piece_len as u64 {
+            return Err(Error::Protocol(format!(
+                "block {}+{} of piece {} is out of range",
+                begin, length, index
+            )));
+        }
+        let offset = self.info.piece_offset(index);
+        let verified = self.verified.lock().unwrap().get(&index).copied();
+        match (self.verify, verified) {
+            (false, _) | (true, Some(true)) => self.read(offset + begin as u64, length).await,
+            (true, Some(false)) => Err(corrupt(index)),
+            (true, None) => {
+                let piece = self.read(offset, piece_len).await?;
+                let intact = self.info.verify_piece(index, &piece);
+                self.verified.lock().unwrap().insert(index, intact);
+                if !intact {
+                    return Err(corrupt(index));
+                }
+                let begin = begin as usize;
+                Ok(piece[begin..begin + length as usize].to_vec())
+            }
+        }
+    }
+
+    async fn read(&self, offset: u64, length: u32) -> Result<Vec<u8>> {
+        Layout::new(&self.info, &self.path)?
+            .read(offset, length)
+            .await
+    }
+}
+
+// Hash-checks the torrent's files under `path`, telling for each piece
+// whether it is intact. Pieces in missing or short files are not.
+pub async fn verify_pieces(info: &Info, path: &Path) -> Result<Vec<bool>> {
+    let layout = Layout::new(info, path)?;
+    let mut intact = Vec::new();
+    for index in 0..info.piece_count() {
+        let piece = layout
+            .read(info.piece_offset(index), info.piece_len(index))
+            .await;
+        intact.push(piece.is_ok_and(|piece| info.verify_piece(index, &piece)));
+    }
+    Ok(intact)
+}
+
+fn out_of_range(index: usize, begin: u32, length: u32) -> Error {
+    Error::Protocol(format!(
+        "block {}+{} of piece {} is out of range",
+        begin, length, index
+    ))
+}
+
+fn corrupt(index: usize) -> Error {
+    Error::Storage(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("piece {} on disk no longer matches its hash", index),
+    ))
+}
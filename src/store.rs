@@ -0,0 +1,130 @@
+// Persistent record of the torrents a session manages. Metainfo files are
+// kept under `<dir>/torrents` and everything else in `<dir>/session.json`.
+use bitvec::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Error, Result},
+    torrent::Torrent,
+};
+
+const SESSION_FILE: &str = "session.json";
+const TORRENTS_DIR: &str = "torrents";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredTorrent {
+    pub info_hash: String, // hex
+    pub name: String,
+    pub save_path: PathBuf,
+    have: String, // hex bitfield of verified pieces
+    pub paused: bool,
+}
+
+impl StoredTorrent {
+    pub fn have(&self) -> Vec<usize> {
+        let bytes = hex::decode(&self.have).unwrap_or_default();
+        BitVec::<u8, Msb0>::from_vec(bytes).iter_ones().collect()
+    }
+
+    pub fn set_have(&mut self, pieces: impl IntoIterator<Item = usize>, piece_count: usize) {
+        let mut bitfield = bitvec![u8, Msb0; 0; piece_count];
+        for piece in pieces.into_iter().filter(|&piece| piece < piece_count) {
+            bitfield.set(piece, true);
+        }
+        self.have = hex::encode(bitfield.into_vec());
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SessionFile {
+    torrents: Vec<StoredTorrent>,
+}
+
+pub struct SessionStore {
+    dir: PathBuf,
+    session: SessionFile,
+}
+
+impl SessionStore {
+    pub fn default_dir() -> PathBuf {
+        dirs::data_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("bittorrent-starter-rust")
+    }
+
+    pub fn open(dir: &Path) -> Result<Self> {
+        let session = match fs::read(dir.join(SESSION_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| Error::Metadata(format!("corrupt session file: {}", e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SessionFile::default(),
+            Err(e) => return Err(Error::Storage(e)),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            session,
+        })
+    }
+
+    pub fn torrents(&self) -> &[StoredTorrent] {
+        &self.session.torrents
+    }
+
+    pub fn get(&self, info_hash: &str) -> Option<&StoredTorrent> {
+        self.session
+            .torrents
+            .iter()
+            .find(|t| t.info_hash == info_hash)
+    }
+
+    pub fn get_mut(&mut self, info_hash: &str) -> Option<&mut StoredTorrent> {
+        self.session
+            .torrents
+            .iter_mut()
+            .find(|t| t.info_hash == info_hash)
+    }
+
+    // Adds (or replaces) a torrent from its metainfo bytes.
+    pub fn add(&mut self, metainfo: &[u8], save_path: PathBuf) -> Result<&mut StoredTorrent> {
+        let torrent = Torrent::from_bytes(metainfo)?;
+        let info_hash = hex::encode(torrent.info_hash()?);
+        let torrents_dir = self.dir.join(TORRENTS_DIR);
+        fs::create_dir_all(&torrents_dir).map_err(Error::Storage)?;
+        fs::write(
+            torrents_dir.join(format!("{}.torrent", info_hash)),
+            metainfo,
+        )
+        .map_err(Error::Storage)?;
+
+        self.session.torrents.retain(|t| t.info_hash != info_hash);
+        self.session.torrents.push(StoredTorrent {
+            info_hash,
+            name: torrent.info.name().to_string(),
+            save_path,
+            have: String::new(),
+            paused: false,
+        });
+        Ok(self.session.torrents.last_mut().unwrap())
+    }
+
+    pub fn torrent(&self, stored: &StoredTorrent) -> Result<Torrent> {
+        let path = self
+            .dir
+            .join(TORRENTS_DIR)
+            .join(format!("{}.torrent", stored.info_hash));
+        Torrent::new(path)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(&self.dir).map_err(Error::Storage)?;
+        let json =
+            serde_json::to_vec_pretty(&self.session).map_err(|e| Error::Metadata(e.to_string()))?;
+        // Write then rename so a crash never leaves a truncated session file.
+        let tmp = self.dir.join(format!("{}.tmp", SESSION_FILE));
+        fs::write(&tmp, json).map_err(Error::Storage)?;
+        fs::rename(tmp, self.dir.join(SESSION_FILE)).map_err(Error::Storage)
+    }
+}
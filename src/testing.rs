@@ -0,0 +1,1004 @@
+// In-process doubles for a tracker and a seeding peer, so the protocol code
+// can be exercised without touching the network.
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::{
+    io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+use tracing::warn;
+
+use crate::{
+    bencode::{self, Value},
+    error::{Error, Result},
+    extension::{
+        ExtensionHeader, ExtensionMessage, ExtensionMessageType, PexMessage, METADATA_PIECE_SIZE,
+        UT_METADATA, UT_PEX,
+    },
+    peer::{Handshake, PeerStream, Transport},
+    storage::PieceReader,
+    torrent::{Info, Torrent},
+    utp::UtpSocket,
+};
+
+const MOCK_METADATA_ID: u8 = 3;
+const MAX_REQUEST_LENGTH: u32 = 16 * 1024;
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+pub struct MockTracker {
+    address: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+    swarm: Arc<Mutex<Swarm>>,
+    task: JoinHandle<()>,
+}
+
+// What the tracker currently answers announces with.
+struct Swarm {
+    peers: Vec<SocketAddr>,
+    interval: i64,
+    failure: Option<String>,
+}
+
+impl MockTracker {
+    pub async fn start(peers: Vec<SocketAddr>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let swarm = Arc::new(Mutex::new(Swarm {
+            peers,
+            interval: 1800,
+            failure: None,
+        }));
+
+        let (recorded, current) = (requests.clone(), swarm.clone());
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                let current = current.clone();
+                tokio::spawn(async move {
+                    let Some((path, query)) = read_request(&mut stream).await else {
+                        return;
+                    };
+                    let body = {
+                        let swarm = current.lock().unwrap();
+                        match path.ends_with("/scrape") {
+                            true => Self::scrape_response(&query, swarm.peers.len()),
+                            false => {
+                                recorded.lock().unwrap().push(query);
+                                swarm.announce_response()
+                            }
+                        }
+                    };
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    let _ = stream.write_all(&[head.as_bytes(), &body].concat()).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            address,
+            requests,
+            swarm,
+            task,
+        })
+    }
+
+    // Changes the peers handed out from the next announce on.
+    pub fn set_peers(&self, peers: Vec<SocketAddr>) {
+        self.swarm.lock().unwrap().peers = peers;
+    }
+
+    // Makes announces fail with `reason` until cleared.
+    pub fn set_failure(&self, reason: Option<&str>) {
+        self.swarm.lock().unwrap().failure = reason.map(str::to_string);
+    }
+
+    // Changes how often clients are told to re-announce.
+    pub fn set_interval(&self, seconds: u32) {
+        self.swarm.lock().unwrap().interval = seconds.into();
+    }
+
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.address)
+    }
+
+    // Raw query strings of every announce received so far.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Every tracked peer counts as a seeder of every torrent scraped.
+    fn scrape_response(query: &str, seeders: usize) -> Vec<u8> {
+        let files = query
+            .split('&')
+            .filter_map(|pair| pair.strip_prefix("info_hash="))
+            .map(|info_hash| {
+                let stats = Value::Dict(BTreeMap::from([
+                    (b"complete".to_vec(), Value::Int(seeders as i64)),
+                    (b"downloaded".to_vec(), Value::Int(0)),
+                    (b"incomplete".to_vec(), Value::Int(0)),
+                ]));
+                (percent_decode(info_hash), stats)
+            })
+            .collect();
+        let response = Value::Dict(BTreeMap::from([(b"files".to_vec(), Value::Dict(files))]));
+        bencode::encode(&response).unwrap_or_default()
+    }
+
+    fn response(peers: &[SocketAddr], interval: i64) -> Vec<u8> {
+        let (mut compact, mut compact6) = (Vec::new(), Vec::new());
+        for peer in peers {
+            let (list, ip) = match peer.ip() {
+                IpAddr::V4(ip) => (&mut compact, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (&mut compact6, ip.octets().to_vec()),
+            };
+            list.extend(ip);
+            list.extend(peer.port().to_be_bytes());
+        }
+        let mut response = BTreeMap::from([
+            (b"interval".to_vec(), Value::Int(interval)),
+            (b"peers".to_vec(), Value::Bytes(compact)),
+        ]);
+        if !compact6.is_empty() {
+            response.insert(b"peers6".to_vec(), Value::Bytes(compact6));
+        }
+        bencode::encode(&Value::Dict(response)).unwrap_or_default()
+    }
+}
+
+impl Swarm {
+    fn announce_response(&self) -> Vec<u8> {
+        match &self.failure {
+            Some(reason) => bencode::encode(&Value::Dict(BTreeMap::from([(
+                b"failure reason".to_vec(),
+                Value::Bytes(reason.as_bytes().to_vec()),
+            )])))
+            .unwrap_or_default(),
+            None => MockTracker::response(&self.peers, self.interval),
+        }
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn percent_decode(encoded: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail.get(..2).and_then(|hex| hex::decode(hex).ok())) {
+            (b'%', Some(decoded)) => {
+                bytes.extend(decoded);
+                rest = &tail[2..];
+            }
+            (b'+', _) => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    bytes
+}
+
+// Returns the path and query of an HTTP request.
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String)> {
+    let head = read_head(stream).await?;
+    let target = head.lines().next()?.split_whitespace().nth(1)?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    Some((path.to_string(), query.to_string()))
+}
+
+async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        request.extend(&buf[..n]);
+    }
+    Some(String::from_utf8_lossy(&request).into_owned())
+}
+
+// An HTTP server hosting one file, either at `/<name>` answering range
+// requests the way a web seed (BEP 19) would, or piece by piece at `/seed`
+// like an HTTP seed (BEP 17).
+pub struct MockWebSeed {
+    url: String,
+    requests: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+enum SeedRoute {
+    File(String),
+    Pieces(u64), // piece length
+}
+
+impl MockWebSeed {
+    pub async fn start(name: &str, data: Vec<u8>) -> Result<Self> {
+        Self::serve(SeedRoute::File(format!("/{}", name)), data).await
+    }
+
+    pub async fn http_seed(piece_length: u64, data: Vec<u8>) -> Result<Self> {
+        Self::serve(SeedRoute::Pieces(piece_length), data).await
+    }
+
+    async fn serve(route: SeedRoute, data: Vec<u8>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let url = match route {
+            SeedRoute::File(_) => format!("http://{}/", address),
+            SeedRoute::Pieces(_) => format!("http://{}/seed", address),
+        };
+        let requests = Arc::new(AtomicUsize::new(0));
+        let data = Arc::new(data);
+
+        let counter = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                let (route, data) = (route.clone(), data.clone());
+                tokio::spawn(async move {
+                    let Some(head) = read_head(&mut stream).await else {
+                        return;
+                    };
+                    let target = head
+                        .lines()
+                        .next()
+                        .and_then(|line| line.split_whitespace().nth(1))
+                        .unwrap_or_default();
+                    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                    let headers = head.to_ascii_lowercase();
+                    let range = headers
+                        .lines()
+                        .find_map(|line| line.strip_prefix("range: bytes="))
+                        .and_then(|range| range.split_once('-'))
+                        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+                    let piece = query
+                        .split('&')
+                        .find_map(|pair| pair.strip_prefix("piece="))
+                        .and_then(|piece| piece.parse::<usize>().ok());
+                    let (status, body) = match (route, range, piece) {
+                        (SeedRoute::File(file), _, _) if file != path => ("404 Not Found", &[][..]),
+                        (SeedRoute::File(_), Some((start, end)), _)
+                            if start <= end && end < data.len() =>
+                        {
+                            ("206 Partial Content", &data[start..=end])
+                        }
+                        (SeedRoute::File(_), Some(_), _) => ("416 Range Not Satisfiable", &[][..]),
+                        (SeedRoute::File(_), None, _) => ("200 OK", &data[..]),
+                        (SeedRoute::Pieces(length), _, Some(piece)) if path == "/seed" => {
+                            let start = (piece * length as usize).min(data.len());
+                            let end = (start + length as usize).min(data.len());
+                            ("200 OK", &data[start..end])
+                        }
+                        (SeedRoute::Pieces(_), _, _) => ("400 Bad Request", &[][..]),
+                    };
+                    let head = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    );
+                    let _ = stream.write_all(&[head.as_bytes(), body].concat()).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            url,
+            requests,
+            task,
+        })
+    }
+
+    // The URL to list in a torrent's `url-list` or `httpseeds`.
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    // How many requests have been made so far.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockWebSeed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+// A home router answering NAT-PMP over UDP and UPnP IGD over HTTP. NAT-PMP
+// maps every port to one 10000 higher; UPnP keeps the port. Each mapping is
+// recorded as (internal, external).
+pub struct MockGateway {
+    natpmp: SocketAddr,
+    http: SocketAddr,
+    mappings: Arc<Mutex<Vec<(u16, u16)>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+const MOCK_IGD_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+impl MockGateway {
+    pub async fn start() -> Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        let natpmp = socket.local_addr()?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let http = listener.local_addr()?;
+        let mappings = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = mappings.clone();
+        let udp = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                if n != 12 || buf[..2] != [0, 2] {
+                    continue;
+                }
+                let internal = u16::from_be_bytes([buf[4], buf[5]]);
+                let external = internal.wrapping_add(10000);
+                recorded.lock().unwrap().push((internal, external));
+                let mut reply = vec![0, 128 + 2, 0, 0, 0, 0, 0, 1];
+                reply.extend(internal.to_be_bytes());
+                reply.extend(external.to_be_bytes());
+                reply.extend(buf[8..12].iter());
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+
+        let recorded = mappings.clone();
+        let tcp = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let Some(request) = read_http_request(&mut stream).await else {
+                        return;
+                    };
+                    let body = match request.lines().next().unwrap_or_default() {
+                        line if line.starts_with("GET /rootDesc.xml") => format!(
+                            "<root><device><serviceList><service>\
+                             <serviceType>{}</serviceType>\
+                             <controlURL>/ctl/IPConn</controlURL>\
+                             </service></serviceList></device></root>",
+                            MOCK_IGD_SERVICE
+                        ),
+                        line if line.starts_with("POST /ctl/IPConn")
+                            && request.contains("#AddPortMapping") =>
+                        {
+                            let port = request
+                                .split("<NewExternalPort>")
+                                .nth(1)
+                                .and_then(|rest| rest.split('<').next())
+                                .and_then(|port| port.parse().ok());
+                            if let Some(port) = port {
+                                recorded.lock().unwrap().push((port, port));
+                            }
+                            "<s:Envelope><s:Body><u:AddPortMappingResponse/></s:Body></s:Envelope>"
+                                .to_string()
+                        }
+                        _ => String::new(),
+                    };
+                    let status = match body.is_empty() {
+                        true => "404 Not Found",
+                        false => "200 OK",
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            natpmp,
+            http,
+            mappings,
+            tasks: vec![udp, tcp],
+        })
+    }
+
+    pub fn natpmp_address(&self) -> SocketAddr {
+        self.natpmp
+    }
+
+    // Where an SSDP reply from this gateway would point.
+    pub fn description_url(&self) -> String {
+        format!("http://{}/rootDesc.xml", self.http)
+    }
+
+    pub fn mappings(&self) -> Vec<(u16, u16)> {
+        self.mappings.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockGateway {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// Reads a whole HTTP request, body included.
+async fn read_http_request(stream: &mut TcpStream) -> Option<String> {
+    let mut request = read_head(stream).await?.into_bytes();
+    let body_start = request.windows(4).position(|w| w == b"\r\n\r\n")? + 4;
+    let length: usize = String::from_utf8_lossy(&request[..body_start])
+        .to_ascii_lowercase()
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|length| length.trim().parse().ok())
+        .unwrap_or(0);
+    let mut buf = [0u8; 1024];
+    while request.len() < body_start + length {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        request.extend(&buf[..n]);
+    }
+    Some(String::from_utf8_lossy(&request).into_owned())
+}
+
+// `len` bytes of test content. The pattern doesn't line up with pieces or
+// blocks, so data put in the wrong place fails verification.
+pub fn sample_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// A seeder that answers handshakes, interest, block requests and ut_metadata
+// requests. Builder methods script deviations from a well-behaved peer.
+#[derive(Clone)]
+pub struct MockPeer {
+    info: Info,
+    data: Arc<Vec<u8>>,
+    storage: Option<Arc<PieceReader>>,
+    peer_id: [u8; 20],
+    pieces: Option<HashSet<usize>>,
+    corrupt: HashSet<usize>,
+    metadata: bool,
+    // Sent over ut_metadata in place of `info`.
+    bogus_metadata: Option<Info>,
+    responsive: bool,
+    drop_after: Option<usize>,
+    exchanged: Vec<(SocketAddr, Transport)>,
+    have_all: bool,
+    // Pieces served while choked, when set; nothing else is unchoked.
+    allowed_fast: Option<HashSet<usize>>,
+    // Left out of the bitfield and announced with Have after the first block.
+    later: Vec<usize>,
+    connections: Arc<AtomicUsize>,
+    strict: bool,
+    failures: Arc<Mutex<Vec<String>>>,
+    // The peer IDs clients introduced themselves with, in order.
+    client_ids: Arc<Mutex<Vec<[u8; 20]>>>,
+}
+
+impl MockPeer {
+    pub fn new(info: Info, data: Vec<u8>) -> Self {
+        Self {
+            info,
+            data: Arc::new(data),
+            storage: None,
+            peer_id: *b"-MK0001-mockpeer0000",
+            pieces: None,
+            corrupt: HashSet::new(),
+            metadata: true,
+            bogus_metadata: None,
+            responsive: true,
+            drop_after: None,
+            exchanged: Vec::new(),
+            have_all: false,
+            allowed_fast: None,
+            later: Vec::new(),
+            connections: Arc::new(AtomicUsize::new(0)),
+            strict: false,
+            failures: Arc::new(Mutex::new(Vec::new())),
+            client_ids: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn seeding(name: &str, piece_length: u64, data: Vec<u8>) -> Self {
+        Self::new(Info::single_file(name, piece_length, &data), data)
+    }
+
+    // Serves blocks from a file on disk instead of memory.
+    pub fn from_storage(storage: PieceReader) -> Self {
+        let mut peer = Self::new(storage.info().clone(), Vec::new());
+        peer.storage = Some(Arc::new(storage));
+        peer
+    }
+
+    pub fn with_pieces(mut self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        self.pieces = Some(pieces.into_iter().collect());
+        self
+    }
+
+    pub fn with_corrupt_piece(mut self, piece: usize) -> Self {
+        self.corrupt.insert(piece);
+        self
+    }
+
+    pub fn without_metadata(mut self) -> Self {
+        self.metadata = false;
+        self
+    }
+
+    // Sends `info` when asked for the metadata, as if it were the
+    // torrent's.
+    pub fn with_bogus_metadata(mut self, info: Info) -> Self {
+        self.bogus_metadata = Some(info);
+        self
+    }
+
+    // Unchokes but never answers block requests.
+    pub fn unresponsive(mut self) -> Self {
+        self.responsive = false;
+        self
+    }
+
+    // Hangs up on its first connection after serving `blocks` blocks.
+    pub fn dropping_after(mut self, blocks: usize) -> Self {
+        self.drop_after = Some(blocks);
+        self
+    }
+
+    // Tells clients that support peer exchange about `peers` right after the
+    // extension handshake.
+    pub fn exchanging(self, peers: Vec<SocketAddr>) -> Self {
+        let peers = peers.into_iter().map(|peer| (peer, Transport::Tcp));
+        self.exchanging_over(peers.collect())
+    }
+
+    // Like `exchanging`, flagging how each peer prefers to be dialed.
+    pub fn exchanging_over(mut self, peers: Vec<(SocketAddr, Transport)>) -> Self {
+        self.exchanged = peers;
+        self
+    }
+
+    // Announces a complete copy with Have All (BEP 6) to clients that
+    // support the fast extension.
+    pub fn with_have_all(mut self) -> Self {
+        self.have_all = true;
+        self
+    }
+
+    // Never unchokes, but lets fast extension clients request `pieces`
+    // anyway and rejects requests for the rest.
+    pub fn choking_except(mut self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        self.allowed_fast = Some(pieces.into_iter().collect());
+        self
+    }
+
+    // Pretends to finish `pieces` during the session: they are missing from
+    // the bitfield and announced once the first block has been served.
+    pub fn completing_later(mut self, pieces: impl IntoIterator<Item = usize>) -> Self {
+        self.later = pieces.into_iter().collect();
+        self
+    }
+
+    // How many connections this peer (and its clones) has accepted.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    // Rejects any deviation from the wire protocol: bad framing, out-of-order
+    // bitfields, requests while choked or outside the piece, unknown messages.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    // Errors that ended sessions served through `connect` or `listen`.
+    pub fn failures(&self) -> Vec<String> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    pub fn client_ids(&self) -> Vec<[u8; 20]> {
+        self.client_ids.lock().unwrap().clone()
+    }
+
+    pub fn info(&self) -> &Info {
+        &self.info
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info.info_hash().expect("mock info always encodes")
+    }
+
+    pub fn torrent(&self, announce: &str) -> Torrent {
+        Torrent {
+            announce: announce.to_string(),
+            announce_list: Vec::new(),
+            url_list: Vec::new(),
+            http_seeds: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            info: self.info.clone(),
+            piece_layers: BTreeMap::new(),
+        }
+    }
+
+    // Returns the client end of an in-memory connection served by this peer.
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = duplex(64 * 1024);
+        let peer = self.clone();
+        tokio::spawn(peer.serve_logged(server));
+        client
+    }
+
+    pub async fn listen(&self) -> Result<SocketAddr> {
+        self.listen_on(Ipv4Addr::LOCALHOST.into()).await
+    }
+
+    pub async fn listen_on(&self, ip: IpAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind((ip, 0)).await?;
+        let address = listener.local_addr()?;
+        let peer = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(peer.clone().serve_logged(stream));
+            }
+        });
+        Ok(address)
+    }
+
+    // Like `listen`, over uTP.
+    pub async fn listen_utp(&self) -> Result<SocketAddr> {
+        let socket = UtpSocket::bind((Ipv4Addr::LOCALHOST, 0).into()).await?;
+        let address = socket.local_addr()?;
+        let peer = self.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = socket.accept().await {
+                tokio::spawn(peer.clone().serve_logged(stream));
+            }
+        });
+        Ok(address)
+    }
+
+    async fn serve_logged(self, stream: impl PeerStream) {
+        let failures = self.failures.clone();
+        if let Err(e) = self.serve(stream).await {
+            failures.lock().unwrap().push(e.to_string());
+        }
+    }
+
+    pub async fn serve(self, mut stream: impl PeerStream) -> Result<()> {
+        let mut handshake_bytes = [0u8; 68];
+        stream.read_exact(&mut handshake_bytes).await?;
+        let handshake = Handshake::from_bytes(&handshake_bytes)?;
+        if handshake.info_hash != self.info_hash() {
+            return Err(Error::Protocol("unexpected info hash".to_string()));
+        }
+        let reply = Handshake::new(handshake.info_hash, self.peer_id);
+        stream.write_all(&reply.to_bytes()?).await?;
+        self.session(stream, handshake).await
+    }
+
+    // Connects to a client listening at `address` and serves it the same
+    // way, until it hangs up.
+    pub async fn dial(self, address: SocketAddr) -> Result<()> {
+        let mut stream = TcpStream::connect(address).await?;
+        let handshake = Handshake::new(self.info_hash(), self.peer_id);
+        stream.write_all(&handshake.to_bytes()?).await?;
+        let mut handshake_bytes = [0u8; 68];
+        stream.read_exact(&mut handshake_bytes).await?;
+        let handshake = Handshake::from_bytes(&handshake_bytes)?;
+        if handshake.info_hash != self.info_hash() {
+            return Err(Error::Protocol("unexpected info hash".to_string()));
+        }
+        self.session(stream, handshake).await
+    }
+
+    async fn session(self, mut stream: impl PeerStream, handshake: Handshake) -> Result<()> {
+        self.client_ids.lock().unwrap().push(handshake.peer_id);
+        let fast = handshake.supports_fast();
+        match self.have_all && fast && self.pieces.is_none() {
+            true => write_message(&mut stream, 14, &[]).await?,
+            false => write_message(&mut stream, 5, &self.bitfield()).await?,
+        }
+        for &piece in self.allowed_fast.iter().flatten().filter(|_| fast) {
+            write_message(&mut stream, 17, &(piece as u32).to_be_bytes()).await?;
+        }
+
+        let first_connection = self.connections.fetch_add(1, Ordering::Relaxed) == 0;
+        let drop_after = self.drop_after.filter(|_| first_connection);
+        let mut client_metadata_id = None;
+        let mut session = Session::default();
+        while let Some((id, payload)) = read_message(&mut stream).await? {
+            if self.strict {
+                self.check(&mut session, id, &payload)?;
+            }
+            match id {
+                2 if self.allowed_fast.is_some() => {}
+                2 => {
+                    session.unchoked = true;
+                    write_message(&mut stream, 1, &[]).await?
+                }
+                6 if !self.responsive => {}
+                // Choked requests are rejected under the fast extension and
+                // dropped without it.
+                6 if !session.unchoked && !self.allows_fast(&payload) && fast => {
+                    write_message(&mut stream, 16, &payload).await?
+                }
+                6 if !session.unchoked && !self.allows_fast(&payload) => {}
+                6 => {
+                    if let Some(block) = self.block(&payload).await {
+                        write_message(&mut stream, 7, &block).await?;
+                        session.blocks += 1;
+                        if session.blocks == 1 {
+                            for &piece in &self.later {
+                                write_message(&mut stream, 4, &(piece as u32).to_be_bytes())
+                                    .await?;
+                            }
+                        }
+                    }
+                    if drop_after.is_some_and(|blocks| session.blocks >= blocks) {
+                        return Ok(());
+                    }
+                }
+                20 if payload.first() == Some(&0) => {
+                    let header = bencode::from_bytes::<ExtensionHeader>(&payload[1..])?;
+                    client_metadata_id = header.m.get(UT_METADATA).copied();
+                    let reply = self.extension_header()?;
+                    write_message(&mut stream, 20, &[&[0], reply.as_slice()].concat()).await?;
+                    let client_pex_id = header.m.get(UT_PEX).copied();
+                    if let Some(client_id) = client_pex_id.filter(|_| !self.exchanged.is_empty()) {
+                        let pex =
+                            serde_bencode::to_bytes(&PexMessage::with_transports(&self.exchanged))?;
+                        write_message(&mut stream, 20, &[&[client_id], pex.as_slice()].concat())
+                            .await?;
+                    }
+                }
+                20 if payload.first() == Some(&MOCK_METADATA_ID) && self.metadata => {
+                    let Some(client_id) = client_metadata_id else {
+                        continue;
+                    };
+                    let request = bencode::from_bytes::<ExtensionMessage>(&payload[1..])?;
+                    let reply = self.metadata_piece(request.piece)?;
+                    write_message(&mut stream, 20, &[&[client_id], reply.as_slice()].concat())
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, session: &mut Session, id: u8, payload: &[u8]) -> Result<()> {
+        session.messages += 1;
+        let expect_len = |len: usize| {
+            ensure(payload.len() == len, || {
+                format!(
+                    "message {} has a {}-byte payload, expected {}",
+                    id,
+                    payload.len(),
+                    len
+                )
+            })
+        };
+        match id {
+            0 | 1 => expect_len(0),
+            2 => {
+                session.interested = true;
+                expect_len(0)
+            }
+            3 => {
+                session.interested = false;
+                expect_len(0)
+            }
+            4 => {
+                expect_len(4)?;
+                let index = u32::from_be_bytes(payload.try_into().unwrap()) as usize;
+                ensure(index < self.info.piece_count(), || {
+                    format!("have for unknown piece {}", index)
+                })
+            }
+            5 => {
+                ensure(session.messages == 1, || {
+                    "bitfield sent after the first message".to_string()
+                })?;
+                let piece_count = self.info.piece_count();
+                expect_len(piece_count.div_ceil(8))?;
+                let spare_bits = (0..payload.len() * 8 - piece_count)
+                    .any(|bit| payload[payload.len() - 1] & (1 << bit) != 0);
+                ensure(!spare_bits, || "bitfield sets spare bits".to_string())
+            }
+            6 | 8 => {
+                expect_len(12)?;
+                let allowed = session.unchoked || self.allows_fast(payload);
+                ensure(id != 6 || (session.interested && allowed), || {
+                    "request while choked or not interested".to_string()
+                })?;
+                let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+                let (index, begin, length) = (field(0) as usize, field(4), field(8));
+                ensure(index < self.info.piece_count(), || {
+                    format!("request for unknown piece {}", index)
+                })?;
+                ensure(length > 0 && length <= MAX_REQUEST_LENGTH, || {
+                    format!("request length {}", length)
+                })?;
+                ensure(
+                    begin as u64 + length as u64 <= self.info.piece_len(index) as u64,
+                    || "request extends past the end of the piece".to_string(),
+                )
+            }
+            9 => expect_len(2),
+            20 if !payload.is_empty() => Ok(()),
+            u8::MAX => Ok(()), // keep-alive
+            _ => Err(violation(format!("unexpected message {}", id))),
+        }
+    }
+
+    fn allows_fast(&self, request: &[u8]) -> bool {
+        let Some(index) = request.get(..4) else {
+            return false;
+        };
+        let index = u32::from_be_bytes(index.try_into().unwrap()) as usize;
+        self.allowed_fast
+            .as_ref()
+            .is_some_and(|allowed| allowed.contains(&index))
+    }
+
+    fn has_piece(&self, piece: usize) -> bool {
+        self.pieces.as_ref().is_none_or(|p| p.contains(&piece))
+    }
+
+    fn bitfield(&self) -> Vec<u8> {
+        let piece_count = self.info.piece_count();
+        let mut bitfield = vec![0u8; piece_count.div_ceil(8)];
+        let announced = |piece: usize| self.has_piece(piece) && !self.later.contains(&piece);
+        for piece in (0..piece_count).filter(|&piece| announced(piece)) {
+            bitfield[piece / 8] |= 0x80 >> (piece % 8);
+        }
+        bitfield
+    }
+
+    async fn block(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let field = |i: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(request.get(i..i + 4)?.try_into().ok()?))
+        };
+        let (index, begin, length) = (field(0)?, field(4)?, field(8)?);
+        if !self.has_piece(index as usize) {
+            return None;
+        }
+        let mut block = match &self.storage {
+            Some(storage) => match storage.read_block(index as usize, begin, length).await {
+                Ok(block) => block,
+                Err(e) => {
+                    warn!("not serving piece {}: {}", index, e);
+                    return None;
+                }
+            },
+            None => {
+                let start = self.info.piece_offset(index as usize) as usize + begin as usize;
+                self.data.get(start..start + length as usize)?.to_vec()
+            }
+        };
+        if self.corrupt.contains(&(index as usize)) {
+            block.iter_mut().for_each(|b| *b = !*b);
+        }
+        Some([&request[..8], block.as_slice()].concat())
+    }
+
+    fn extension_header(&self) -> Result<Vec<u8>> {
+        let mut m = BTreeMap::new();
+        if self.metadata {
+            m.insert(
+                UT_METADATA.as_bytes().to_vec(),
+                Value::Int(MOCK_METADATA_ID as i64),
+            );
+        }
+        let metadata_size = self.raw_metadata()?.len() as i64;
+        bencode::encode(&Value::Dict(BTreeMap::from([
+            (b"m".to_vec(), Value::Dict(m)),
+            (b"metadata_size".to_vec(), Value::Int(metadata_size)),
+        ])))
+    }
+
+    fn raw_metadata(&self) -> Result<Vec<u8>> {
+        let info = self.bogus_metadata.as_ref().unwrap_or(&self.info);
+        Ok(serde_bencode::to_bytes(info)?)
+    }
+
+    fn metadata_piece(&self, piece: u32) -> Result<Vec<u8>> {
+        let metadata = self.raw_metadata()?;
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let Some(data) = metadata.get(start..).filter(|data| !data.is_empty()) else {
+            let message = ExtensionMessage {
+                msg_type: ExtensionMessageType::Reject,
+                piece,
+                total_size: None,
+            };
+            return Ok(serde_bencode::to_bytes(&message)?);
+        };
+        let message = ExtensionMessage {
+            msg_type: ExtensionMessageType::Data,
+            piece,
+            total_size: Some(metadata.len() as u32),
+        };
+        let data = &data[..data.len().min(METADATA_PIECE_SIZE)];
+        Ok([serde_bencode::to_bytes(&message)?.as_slice(), data].concat())
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    messages: usize,
+    blocks: usize,
+    interested: bool,
+    unchoked: bool,
+}
+
+fn violation(reason: String) -> Error {
+    Error::Protocol(format!("conformance violation: {}", reason))
+}
+
+fn ensure(condition: bool, reason: impl FnOnce() -> String) -> Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(violation(reason()))
+    }
+}
+
+async fn read_message(stream: &mut impl PeerStream) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(violation(format!("{}-byte message", length)));
+    }
+    if length == 0 {
+        return Ok(Some((u8::MAX, vec![]))); // keep-alive
+    }
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await?;
+    let payload = message.split_off(1);
+    Ok(Some((message[0], payload)))
+}
+
+async fn write_message(stream: &mut impl PeerStream, id: u8, payload: &[u8]) -> Result<()> {
+    let length = (payload.len() + 1) as u32;
+    stream
+        .write_all(&[&length.to_be_bytes()[..], &[id], payload].concat())
+        .await?;
+    Ok(())
+}
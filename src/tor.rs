@@ -0,0 +1,84 @@
+// Routing through a Tor SOCKS5 proxy. While enabled, peer connections and
+// HTTP requests (trackers, .torrent and feed downloads) go through the proxy
+// and hostnames are resolved by Tor, so `.onion` trackers work and nothing
+// leaks through local DNS. Every peer and host gets its own SOCKS
+// credentials, which Tor's IsolateSOCKSAuth (on by default) turns into
+// separate circuits. Anything that can't be proxied, like UDP trackers or
+// DHT and local peer discovery, must check `enabled()` and stay off.
+use rand::distributions::{Alphanumeric, DistString};
+use std::{net::SocketAddr, sync::Mutex};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+use crate::error::{Error, Result};
+
+pub const DEFAULT_PROXY: &str = "127.0.0.1:9050";
+
+struct Tor {
+    proxy: SocketAddr,
+    // Mixed into the credentials so isolation keys from earlier runs are
+    // never reused.
+    nonce: String,
+}
+
+static TOR: Mutex<Option<Tor>> = Mutex::new(None);
+
+pub fn enable(proxy: SocketAddr) {
+    let nonce = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    *TOR.lock().unwrap() = Some(Tor { proxy, nonce });
+}
+
+pub fn disable() {
+    *TOR.lock().unwrap() = None;
+}
+
+pub fn enabled() -> bool {
+    TOR.lock().unwrap().is_some()
+}
+
+fn credentials(isolation: &str) -> Option<(SocketAddr, String, String)> {
+    let tor = TOR.lock().unwrap();
+    let tor = tor.as_ref()?;
+    Some((tor.proxy, isolation.to_string(), tor.nonce.clone()))
+}
+
+// Connects directly, or through Tor on a circuit of its own when enabled.
+pub(crate) async fn connect(address: SocketAddr) -> Result<TcpStream> {
+    match credentials(&address.to_string()) {
+        Some((proxy, user, password)) => socks_connect(proxy, address, &user, &password).await,
+        None => Ok(TcpStream::connect(address).await?),
+    }
+}
+
+async fn socks_connect(
+    proxy: SocketAddr,
+    target: SocketAddr,
+    user: &str,
+    password: &str,
+) -> Result<TcpStream> {
+    let stream = Socks5Stream::connect_with_password(proxy, target, user, password)
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(format!("tor: {}", e))))?;
+    Ok(stream.into_inner())
+}
+
+// Rejects transports Tor can't carry instead of silently going around it.
+pub(crate) fn ensure_tcp(what: &str) -> Result<()> {
+    match enabled() {
+        true => Err(Error::Protocol(format!("{} cannot be used over Tor", what))),
+        false => Ok(()),
+    }
+}
+
+// An HTTP client for requests to `host`, proxied through Tor when enabled.
+#[cfg(feature = "http")]
+pub(crate) fn http_client(host: &str) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some((proxy, user, password)) = credentials(host) {
+        // socks5h: let Tor resolve the hostname, which `.onion` requires.
+        let proxy =
+            reqwest::Proxy::all(format!("socks5h://{}", proxy))?.basic_auth(&user, &password);
+        builder = builder.proxy(proxy);
+    }
+    Ok(builder.build()?)
+}
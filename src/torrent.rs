@@ -1,231 +1,1053 @@
-use anyhow::Context;
-use rand::seq::SliceRandom;
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    ops::Range,
     path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
-use tokio::{net::UdpSocket, task::JoinSet};
-use url::form_urlencoded;
+use tokio::{sync::mpsc, task::JoinSet, time};
+use tracing::{debug, warn};
+use url::Url;
 
 use crate::{
+    bencode, dht,
+    download::{
+        download_pieces, download_pieces_to, download_pieces_with_resume, ready_peer,
+        stream_pieces, AllAtOnce, DownloadContext, DownloadHandle, PieceOrder, PieceStream,
+    },
+    error::{Error, Result},
+    listener::{self, Registration},
     magnet::Magnet,
-    peer::Peer,
-    tracker::{TrackerRequest, TrackerResponse},
+    peer::{Peer, Transport},
+    session::Shared,
+    tracker::{self, Announce, TrackerEvent, TrackerRequest},
+    v2::{self, FileTree},
+    webseed::WebSeed,
 };
 
+// Where more peers come from while a download runs.
+struct PeerSources {
+    announce: Announce,
+    // Addresses from peer exchange, with how each prefers to be dialed.
+    exchanged: mpsc::UnboundedReceiver<(SocketAddr, Transport)>,
+    // Addresses waiting for a connection slot.
+    backlog: VecDeque<(SocketAddr, Transport)>,
+    // Dials still going when the download started.
+    connecting: JoinSet<Result<(Peer, Vec<usize>)>>,
+    // Peers connecting to us join until this is dropped.
+    listening: Registration,
+}
+
+// How long a finished or stopped download waits to tell its trackers.
+const FINAL_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+// How many peers a download waits for before it starts on pieces.
+const READY_PEERS: usize = 4;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Torrent {
+    #[serde(default)]
     pub announce: String,
+    // BEP 12 tiers, tried in order. When present, `announce` is ignored.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub announce_list: Vec<Vec<String>>,
+    // BEP 19 web seeds: HTTP servers hosting the content itself.
+    #[serde(
+        rename = "url-list",
+        default,
+        deserialize_with = "one_or_many",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub url_list: Vec<String>,
+    // BEP 17 HTTP seeds, which serve pieces by number.
+    #[serde(rename = "httpseeds", default, skip_serializing_if = "Vec::is_empty")]
+    pub http_seeds: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    // Seconds since the Unix epoch.
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    // The character set of the strings in the metainfo, if not UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
     pub info: Info,
+    // BEP 52: each file's piece layer, keyed by its `pieces root`, for files
+    // longer than a piece.
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub piece_layers: BTreeMap<ByteBuf, ByteBuf>,
+}
+
+// `url-list` may be a single URL rather than a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(url) => vec![url],
+        OneOrMany::Many(urls) => urls,
+    })
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Info {
     #[serde(rename = "piece length")]
-    pub piece_length: u32,
-    #[serde(with = "serde_bytes")]
+    pub piece_length: u64,
+    // Empty, along with `additional`, in v2-only torrents.
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
     name: String,
     #[serde(flatten)]
-    additional: Additional,
+    additional: Option<Additional>,
+    // BEP 52: 2 in v2 and hybrid torrents, which describe their files in
+    // `file tree`.
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    meta_version: Option<u8>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    file_tree: Option<FileTree>,
+    // BEP 38: info hashes of torrents sharing files with this one, and
+    // collection names grouping related torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub similar: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collections: Option<Vec<String>>,
+    // BEP 27: 1 if the torrent is private.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private: Option<u8>,
+    // The metainfo's `piece layers`, which live outside the info dict but
+    // are needed wherever v2 pieces are checked.
+    #[serde(skip)]
+    piece_layers: BTreeMap<ByteBuf, ByteBuf>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 enum Additional {
-    SingleFile { length: u32 },
+    SingleFile { length: u64 },
     MultiFile { files: Vec<File> },
 }
 
 impl Info {
-    pub fn pieces(&self) -> Vec<Vec<u8>> {
-        self.pieces.chunks(20).map(|c| c.to_vec()).collect()
+    pub fn single_file(name: &str, piece_length: u64, data: &[u8]) -> Self {
+        let pieces = data
+            .chunks(piece_length as usize)
+            .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+            .collect();
+        Self {
+            piece_length,
+            pieces,
+            name: name.to_string(),
+            additional: Some(Additional::SingleFile {
+                length: data.len() as u64,
+            }),
+            meta_version: None,
+            file_tree: None,
+            similar: None,
+            collections: None,
+            private: None,
+            piece_layers: BTreeMap::new(),
+        }
+    }
+
+    // `files` as returned by `files`: a single file with no path makes a
+    // single-file torrent.
+    pub fn new(
+        name: &str,
+        piece_length: u64,
+        pieces: Vec<[u8; 20]>,
+        files: Vec<(Vec<String>, u64)>,
+    ) -> Self {
+        let additional = match files.as_slice() {
+            [(path, length)] if path.is_empty() => Additional::SingleFile { length: *length },
+            _ => Additional::MultiFile {
+                files: files
+                    .into_iter()
+                    .map(|(path, length)| File { length, path })
+                    .collect(),
+            },
+        };
+        Self {
+            piece_length,
+            pieces: pieces.concat(),
+            name: name.to_string(),
+            additional: Some(additional),
+            meta_version: None,
+            file_tree: None,
+            similar: None,
+            collections: None,
+            private: None,
+            piece_layers: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_private(mut self, private: bool) -> Self {
+        self.private = private.then_some(1);
+        self
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    // `files` are each a `/`-separated path below `name` and the file's
+    // contents.
+    pub fn multi_file(name: &str, piece_length: u64, files: &[(&str, &[u8])]) -> Self {
+        let data: Vec<u8> = files.iter().flat_map(|(_, data)| *data).copied().collect();
+        Self {
+            additional: Some(Additional::MultiFile {
+                files: files
+                    .iter()
+                    .map(|(path, data)| File {
+                        length: data.len() as u64,
+                        path: path.split('/').map(str::to_string).collect(),
+                    })
+                    .collect(),
+            }),
+            ..Self::single_file(name, piece_length, &data)
+        }
+    }
+
+    // What trackers and peers know the torrent by: the SHA-1 of the info
+    // dict, or for v2-only torrents the truncated v2 hash (BEP 52).
+    pub fn info_hash(&self) -> Result<[u8; 20]> {
+        if self.is_v2_only() {
+            return Ok(self.info_hash_v2()?[..20].try_into().unwrap());
+        }
+        Ok(Sha1::digest(serde_bencode::to_bytes(self)?).into())
+    }
+
+    // BEP 52: v2 swarms identify the torrent by this instead, truncated to
+    // 20 bytes where the protocol has no room for more.
+    pub fn info_hash_v2(&self) -> Result<[u8; 32]> {
+        Ok(Sha256::digest(serde_bencode::to_bytes(self)?).into())
+    }
+
+    // Whether this is the info dictionary `info_hash` names, by its v1 hash
+    // or the truncated v2 one v2-only swarms go by.
+    pub fn matches_info_hash(&self, info_hash: &[u8; 20]) -> Result<bool> {
+        Ok(self.info_hash()? == *info_hash || self.info_hash_v2()?[..20] == info_hash[..])
     }
 
-    pub fn file_len(&self) -> u32 {
+    // Both v2 and hybrid torrents.
+    pub fn is_v2(&self) -> bool {
+        self.file_tree.is_some()
+    }
+
+    // With no v1 piece hashes; pieces are checked against the piece layers
+    // and never span files.
+    pub fn is_v2_only(&self) -> bool {
+        self.is_v2() && self.additional.is_none()
+    }
+
+    // The files of the v2 file tree, empty for v1 torrents.
+    pub fn v2_files(&self) -> Vec<v2::TreeFile> {
+        self.file_tree.as_ref().map_or(Vec::new(), FileTree::files)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn pieces(&self) -> Vec<[u8; 20]> {
+        self.piece_hashes().collect()
+    }
+
+    pub fn similar(&self) -> impl Iterator<Item = [u8; 20]> + '_ {
+        self.similar
+            .iter()
+            .flatten()
+            .filter_map(|hash| hash.as_slice().try_into().ok())
+    }
+
+    pub fn piece_infos(&self) -> impl Iterator<Item = (usize, [u8; 20], u32)> + '_ {
+        self.piece_hashes()
+            .enumerate()
+            .map(|(index, hash)| (index, hash, self.piece_len(index)))
+    }
+
+    pub fn piece_count(&self) -> usize {
+        match self.is_v2_only() {
+            true => self
+                .v2_files()
+                .iter()
+                .map(|file| file.length.div_ceil(self.piece_length) as usize)
+                .sum(),
+            false => self.pieces.len() / 20,
+        }
+    }
+
+    // Where the `index`th piece starts in the torrent's data, all files
+    // end to end.
+    pub fn piece_offset(&self, index: usize) -> u64 {
+        match self.v2_piece(index) {
+            Some(piece) => piece.file_start + piece.index as u64 * self.piece_length,
+            None => index as u64 * self.piece_length,
+        }
+    }
+
+    pub fn piece_len(&self, index: usize) -> u32 {
+        let (start, end) = match self.v2_piece(index) {
+            Some(piece) => (piece.index as u64 * self.piece_length, piece.file.length),
+            None => (index as u64 * self.piece_length, self.file_len()),
+        };
+        end.saturating_sub(start).min(self.piece_length) as u32
+    }
+
+    // Whether `data` is the `index`th piece, by its SHA-1 hash or, in v2-only
+    // torrents, against its file's merkle tree.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        if !self.is_v2_only() {
+            let hash = self.pieces.get(index * 20..index * 20 + 20);
+            return hash == Some(&Sha1::digest(data)[..]);
+        }
+        self.v2_piece(index).is_some_and(|piece| {
+            self.verify_v2_piece(piece.file_index, piece.index, data)
+                .unwrap_or(false)
+        })
+    }
+
+    // v2-only torrents number their pieces file by file, each file starting
+    // on a new piece. Empty files have none.
+    fn v2_piece(&self, index: usize) -> Option<V2Piece> {
+        if !self.is_v2_only() {
+            return None;
+        }
+        let (mut first, mut file_start) = (0, 0);
+        for (file_index, file) in self.v2_files().into_iter().enumerate() {
+            let count = file.length.div_ceil(self.piece_length) as usize;
+            if index < first + count {
+                return Some(V2Piece {
+                    file_index,
+                    file,
+                    file_start,
+                    index: index - first,
+                });
+            }
+            first += count;
+            file_start += file.length;
+        }
+        None
+    }
+
+    // Whether every v2 piece can be checked, which takes the piece layers
+    // of all files longer than a piece.
+    pub fn has_piece_layers(&self) -> bool {
+        self.v2_files().iter().all(|file| {
+            file.length <= self.piece_length
+                || file
+                    .pieces_root
+                    .is_some_and(|root| self.piece_layer(&root).is_some())
+        })
+    }
+
+    fn piece_layer(&self, root: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
+        let layer = self.piece_layers.get(serde_bytes::Bytes::new(root))?;
+        let hashes = layer.chunks_exact(32);
+        hashes.remainder().is_empty().then(|| {
+            hashes
+                .map(|hash| hash.try_into().expect("chunks are 32 bytes"))
+                .collect()
+        })
+    }
+
+    // BEP 52: checks `data` as the `piece`th piece of the `file`th file in
+    // the file tree.
+    pub fn verify_v2_piece(&self, file: usize, piece: usize, data: &[u8]) -> Result<bool> {
+        let files = self.v2_files();
+        let file = files
+            .get(file)
+            .ok_or_else(|| Error::Metadata(format!("no file {} in the file tree", file)))?;
+        let piece_length = self.piece_length;
+        let Some(root) = file.pieces_root else {
+            return Ok(data.is_empty());
+        };
+        if file.length <= piece_length {
+            return Ok(piece == 0 && v2::pieces_root(data, piece_length) == root);
+        }
+        let layer = self.piece_layer(&root).ok_or_else(|| {
+            Error::Metadata(format!("no piece layer for {}", file.path.join("/")))
+        })?;
+        Ok(layer.get(piece) == Some(&v2::piece_hash(data, piece_length)))
+    }
+
+    // Rejects metainfo whose sizes don't add up, so later indexing is safe.
+    pub fn validate(&self) -> Result<()> {
+        if self.piece_length == 0 {
+            return Err(Error::Metadata("piece length is zero".to_string()));
+        }
+        // Pieces are verified whole in memory and addressed with 32-bit
+        // offsets on the wire, unlike the torrent itself.
+        if self.piece_length > u32::MAX as u64 {
+            return Err(Error::Metadata("piece length is too large".to_string()));
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(Error::Metadata(
+                "piece hashes are not a multiple of 20 bytes".to_string(),
+            ));
+        }
+        if self.is_v2() {
+            self.validate_v2()?;
+        }
+        let total = match &self.additional {
+            Some(Additional::SingleFile { length }) => *length,
+            Some(Additional::MultiFile { files }) => files
+                .iter()
+                .try_fold(0u64, |total, f| total.checked_add(f.length))
+                .ok_or_else(|| Error::Metadata("torrent is too large".to_string()))?,
+            None if self.is_v2() => return Ok(()),
+            None => return Err(Error::Metadata("missing length or files".to_string())),
+        };
+        let expected_pieces = total.div_ceil(self.piece_length);
+        if expected_pieces != (self.pieces.len() / 20) as u64 {
+            return Err(Error::Metadata(format!(
+                "expected {} piece hashes, found {}",
+                expected_pieces,
+                self.pieces.len() / 20
+            )));
+        }
+        Ok(())
+    }
+
+    // v2 pieces never span files, so they are a power of two of at least a
+    // block and line up with the merkle trees.
+    fn validate_v2(&self) -> Result<()> {
+        if self.meta_version != Some(2) {
+            return Err(Error::Metadata("unsupported meta version".to_string()));
+        }
+        if self.piece_length < v2::BLOCK_SIZE as u64 || !self.piece_length.is_power_of_two() {
+            return Err(Error::Metadata(format!(
+                "invalid v2 piece length {}",
+                self.piece_length
+            )));
+        }
+        if let Some(file) = self
+            .v2_files()
+            .iter()
+            .find(|file| file.length > 0 && file.pieces_root.is_none())
+        {
+            return Err(Error::Metadata(format!(
+                "{} has no pieces root",
+                file.path.join("/")
+            )));
+        }
+        Ok(())
+    }
+
+    fn piece_hashes(&self) -> impl Iterator<Item = [u8; 20]> + '_ {
+        self.pieces.chunks_exact(20).map(|hash| {
+            let mut piece_hash = [0u8; 20];
+            piece_hash.copy_from_slice(hash);
+            piece_hash
+        })
+    }
+
+    pub fn is_single_file(&self) -> bool {
+        match &self.additional {
+            Some(additional) => matches!(additional, Additional::SingleFile { .. }),
+            None => matches!(self.files().as_slice(), [(path, _)] if path.is_empty()),
+        }
+    }
+
+    // Each file's path below the torrent's name and its length, in the order
+    // their bytes appear. A single-file torrent has one file with no path.
+    pub fn files(&self) -> Vec<(Vec<String>, u64)> {
+        match &self.additional {
+            Some(Additional::SingleFile { length }) => vec![(Vec::new(), *length)],
+            Some(Additional::MultiFile { files }) => files
+                .iter()
+                .map(|file| (file.path.clone(), file.length))
+                .collect(),
+            // A v2 file tree holding just a file named after the torrent is a
+            // single-file torrent.
+            None => match self.v2_files().as_slice() {
+                [file] if file.path == [self.name.as_str()] => vec![(Vec::new(), file.length)],
+                files => files
+                    .iter()
+                    .map(|file| (file.path.clone(), file.length))
+                    .collect(),
+            },
+        }
+    }
+
+    pub fn file_len(&self) -> u64 {
         match &self.additional {
-            Additional::SingleFile { length } => *length,
-            Additional::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+            Some(Additional::SingleFile { length }) => *length,
+            Some(Additional::MultiFile { files }) => files.iter().map(|f| f.length).sum(),
+            None => self.v2_files().iter().map(|f| f.length).sum(),
         }
     }
+
+    // The pieces holding any of the bytes of the `index`th file in `files`,
+    // or `None` if there is no such file.
+    pub fn file_pieces(&self, index: usize) -> Option<Range<usize>> {
+        let files = self.files();
+        let (_, length) = files.get(index)?;
+        if self.is_v2_only() {
+            let first = files[..index]
+                .iter()
+                .map(|(_, length)| length.div_ceil(self.piece_length) as usize)
+                .sum();
+            return Some(first..first + length.div_ceil(self.piece_length) as usize);
+        }
+        let start: u64 = files[..index].iter().map(|(_, length)| length).sum();
+        let first = (start / self.piece_length) as usize;
+        if *length == 0 {
+            return Some(first..first);
+        }
+        Some(first..(start + length).div_ceil(self.piece_length) as usize)
+    }
+}
+
+struct V2Piece {
+    file_index: usize,
+    file: v2::TreeFile,
+    // Where the file starts in the torrent's data.
+    file_start: u64,
+    // The piece's index within the file.
+    index: usize,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 struct File {
-    length: u32,
+    length: u64,
     path: Vec<String>,
 }
 
 impl Torrent {
-    pub fn new(file_name: PathBuf) -> anyhow::Result<Self> {
-        let content = std::fs::read(file_name)?;
-        Ok(serde_bencode::from_bytes::<Self>(&content)?)
+    pub fn new(file_name: PathBuf) -> Result<Self> {
+        let content = std::fs::read(file_name).map_err(Error::Storage)?;
+        Self::from_bytes(&content)
     }
 
-    pub fn from_magnet_and_metadata(magnet: Magnet, metadata: Info) -> anyhow::Result<Self> {
+    pub fn from_bytes(content: &[u8]) -> Result<Self> {
+        let mut torrent = bencode::from_bytes::<Self>(content)?;
+        torrent.info.piece_layers = torrent.piece_layers.clone();
+        torrent.info.validate()?;
+        torrent.validate_piece_layers()?;
+        Ok(torrent)
+    }
+
+    // Every v2 file longer than a piece needs a piece layer, which must hash
+    // up to the file's root.
+    fn validate_piece_layers(&self) -> Result<()> {
+        let piece_length = self.info.piece_length;
+        for file in self.info.v2_files() {
+            let Some(root) = file.pieces_root.filter(|_| file.length > piece_length) else {
+                continue;
+            };
+            let matches = self.info.piece_layer(&root).is_some_and(|layer| {
+                layer.len() as u64 == file.length.div_ceil(piece_length)
+                    && v2::layer_root(&layer, piece_length) == root
+            });
+            if !matches {
+                return Err(Error::Metadata(format!(
+                    "piece layer for {} does not match its root",
+                    file.path.join("/")
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn verify_v2_piece(&self, file: usize, piece: usize, data: &[u8]) -> Result<bool> {
+        self.info.verify_v2_piece(file, piece, data)
+    }
+
+    pub fn from_magnet_and_metadata(magnet: Magnet, metadata: Info) -> Result<Self> {
+        if !metadata.matches_info_hash(&magnet.info_hash)? {
+            return Err(Error::Metadata(
+                "metadata does not match the magnet's info hash".to_string(),
+            ));
+        }
+        // Without trackers, the torrent relies on the DHT and web seeds.
+        let announce = magnet
+            .trackers
+            .first()
+            .map(|tracker| tracker.to_string())
+            .unwrap_or_default();
+        let announce_list = match magnet.trackers.len() {
+            1 => Vec::new(),
+            _ => magnet.tiers(),
+        };
         Ok(Self {
-            announce: magnet.tracker_url.unwrap().to_string(),
+            announce,
+            announce_list,
+            url_list: magnet.web_seeds.clone(),
+            http_seeds: Vec::new(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
             info: metadata,
+            piece_layers: BTreeMap::new(),
         })
     }
 
-    pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        Ok(Sha1::digest(serde_bencode::to_bytes(&self.info)?).into())
+    // A magnet link for the torrent: its info hashes, name, trackers and
+    // web seeds. Trackers are listed once each, leaving out any that are
+    // not valid URLs.
+    pub fn magnet(&self) -> Result<Magnet> {
+        let mut magnet = Magnet::from(self.info_hash()?);
+        if self.info.is_v2() {
+            magnet.info_hash_v2 = Some(self.info.info_hash_v2()?);
+        }
+        magnet.file_name = Some(self.info.name().to_string());
+        for tracker in self.tiers().iter().flatten() {
+            if let Ok(tracker) = Url::parse(tracker) {
+                if !magnet.trackers.contains(&tracker) {
+                    magnet.trackers.push(tracker);
+                }
+            }
+        }
+        magnet.web_seeds = self.url_list.clone();
+        Ok(magnet)
+    }
+
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        match (tiers.is_empty(), self.announce.is_empty()) {
+            (false, _) => tiers,
+            (true, false) => vec![vec![self.announce.clone()]],
+            (true, true) => Vec::new(),
+        }
+    }
+
+    pub fn info_hash(&self) -> Result<[u8; 20]> {
+        self.info.info_hash()
     }
 
-    pub fn len(&self) -> u32 {
+    pub fn len(&self) -> u64 {
         self.info.file_len()
     }
 
-    pub fn pieces(&self) -> Vec<Vec<u8>> {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn pieces(&self) -> Vec<[u8; 20]> {
         self.info.pieces()
     }
 
-    pub async fn get_peer_addrs(&self) -> anyhow::Result<Vec<SocketAddr>> {
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash()?).collect();
-        let request = TrackerRequest::new(self.len());
-        let announce = &self.announce;
-        if announce.starts_with("http") {
-            let params = serde_urlencoded::to_string(&request)?;
-            let url = format!("{}?{}&info_hash={}", announce, params, info_hash_str);
-            let response = reqwest::get(url).await?;
-            let tracker_response =
-                serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
-            let peer_addrs = tracker_response.peers();
-            println!("Found peers: {:?}", peer_addrs);
-            Ok(peer_addrs)
-        } else if announce.starts_with("udp") {
-            let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-            let address = Self::parse_udp_url(announce)?;
-            sock.connect(address).await?;
-            Ok(vec![])
-        } else {
-            Err(anyhow::anyhow!("Unsupported tracker protocol"))
-        }
-    }
-
-    fn parse_udp_url(url: &str) -> anyhow::Result<String> {
-        let parts: Vec<&str> = url.split(':').collect();
-        let host = parts[1].trim_start_matches('/');
-        let port = parts[2];
-        let addr = format!("{}:{}", host, port);
-        Ok(addr)
-    }
-
-    pub async fn download_piece(&self, piece: usize) -> anyhow::Result<Vec<u8>> {
+    pub fn piece_infos(&self) -> impl Iterator<Item = (usize, [u8; 20], u32)> + '_ {
+        self.info.piece_infos()
+    }
+
+    pub async fn announce(&self) -> Result<Announce> {
+        let request = TrackerRequest::builder().left(self.len()).build();
+        self.announce_request(&request).await
+    }
+
+    // Asks the trackers and, when enabled, the DHT. Private torrents
+    // (BEP 27) only ever ask their trackers.
+    async fn announce_request(&self, request: &TrackerRequest) -> Result<Announce> {
+        let (info_hash, tiers) = (self.info_hash()?, self.tiers());
+        let trackers = tracker::announce_tiers(&tiers, &info_hash, request);
+        match self.info.is_private() {
+            true => trackers.await,
+            false => dht::alongside(info_hash, trackers).await,
+        }
+    }
+
+    // An announce carrying how far `ctx`'s download has got.
+    fn tracker_request(
+        &self,
+        ctx: &DownloadContext,
+        event: Option<TrackerEvent>,
+    ) -> TrackerRequest {
+        let progress = ctx.progress();
+        let mut request = TrackerRequest::builder()
+            .peer_id(ctx.peer_id().to_string())
+            .uploaded(progress.bytes_uploaded)
+            .downloaded(progress.bytes_downloaded)
+            .left(self.len().saturating_sub(progress.bytes_done));
+        if let Some(event) = event {
+            request = request.event(event);
+        }
+        if let Some(port) = ctx.listen_port() {
+            request = request.port(port);
+        }
+        request.build()
+    }
+
+    pub async fn get_peer_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self.announce().await?.peers)
+    }
+
+    // Asks each tracker in turn until one answers for this torrent.
+    pub async fn scrape(&self) -> Result<tracker::ScrapeStats> {
+        let info_hash = self.info_hash()?;
+        let mut last_error = Error::Tracker("no trackers to scrape".to_string());
+        for tracker_url in self.tiers().iter().flatten() {
+            match tracker::scrape(tracker_url, &[info_hash]).await {
+                Ok(stats) => match stats.get(&info_hash) {
+                    Some(stats) => return Ok(*stats),
+                    None => {
+                        last_error =
+                            Error::Tracker(format!("{} does not track this torrent", tracker_url))
+                    }
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    pub async fn handshake(&self, peer_address: SocketAddr) -> Result<Peer> {
+        Peer::new(peer_address, self.info_hash()?).await
+    }
+
+    pub async fn download_piece(&self, piece: usize) -> Result<Bytes> {
         let peer_addrs = self.get_peer_addrs().await?;
         let info_hash = self.info_hash()?;
         for peer_address in peer_addrs {
             match Peer::new(peer_address, info_hash).await {
-                Ok(mut peer) => {
+                Ok(peer) => {
+                    let mut peer = peer.with_piece_count(self.info.piece_count());
                     let pieces = peer.get_pieces().await?;
                     if pieces.contains(&piece) {
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            self.info.piece_length,                      // piece_len
-                            self.len() - piece * self.info.piece_length, // last piece
-                        );
+                        let piece_len = self.info.piece_len(piece);
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+                Err(e) => debug!(peer = %peer_address, "{}", e),
             }
         }
-        Err(anyhow::anyhow!("Could not find peer"))
+        Err(Error::NoPeers)
     }
 
-    pub async fn download(&self) -> anyhow::Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
-        let piece_hashes = self.pieces();
-        let num_pieces = piece_hashes.len();
-        let info_hash = self.info_hash()?;
-        let piece_len = self.info.piece_length;
-        let file_len = self.len();
+    // Keeps the whole file in memory, which suits small torrents;
+    // `download_to` writes pieces out as they complete.
+    pub fn download(&self) -> DownloadHandle {
+        self.download_ordered(AllAtOnce)
+    }
 
-        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
-        let mut join_set = JoinSet::new();
+    // Like `download`, requesting pieces in the given order, e.g.
+    // `Sequential` to play media while it downloads.
+    pub fn download_ordered(&self, order: impl PieceOrder + 'static) -> DownloadHandle {
+        let torrent = self.clone();
+        DownloadHandle::spawn_ordered(Arc::new(order), |ctx| async move {
+            let (peer_piece_map, sources) = torrent.connect_swarm(&ctx).await?;
+            let download = download_pieces(&torrent.info, peer_piece_map, BTreeMap::new(), &ctx);
+            torrent.reannouncing(&ctx, sources, download).await
+        })
+    }
 
-        for peer_address in peer_addrs {
-            match Peer::new(peer_address, info_hash).await {
-                Ok(mut peer) => {
-                    let pieces = peer.get_pieces().await?;
+    pub fn download_to(&self, path: PathBuf) -> DownloadHandle<()> {
+        self.download_ordered_to(AllAtOnce, path)
+    }
+
+    pub fn download_ordered_to(
+        &self,
+        order: impl PieceOrder + 'static,
+        path: PathBuf,
+    ) -> DownloadHandle<()> {
+        self.spawn_download_to(None, Arc::new(order), path)
+    }
+
+    // Like `download_ordered_to`, as one of `session`'s downloads when given.
+    pub(crate) fn spawn_download_to(
+        &self,
+        session: Option<Shared>,
+        order: Arc<dyn PieceOrder>,
+        path: PathBuf,
+    ) -> DownloadHandle<()> {
+        let torrent = self.clone();
+        DownloadHandle::spawn_in(session, order, |ctx| async move {
+            let (peer_piece_map, sources) = torrent.connect_swarm(&ctx).await?;
+            let download =
+                download_pieces_to(&torrent.info, peer_piece_map, BTreeMap::new(), &ctx, path);
+            torrent.reannouncing(&ctx, sources, download).await
+        })
+    }
+
+    // Like `download_to`, keeping a fast-resume file next to `path` so a
+    // restarted download picks up where it stopped without hash-checking
+    // what it already has.
+    pub fn download_with_resume(&self, path: PathBuf) -> DownloadHandle<()> {
+        let torrent = self.clone();
+        DownloadHandle::spawn(|ctx| async move {
+            let (peer_piece_map, sources) = torrent.connect_swarm(&ctx).await?;
+            let download = download_pieces_with_resume(&torrent.info, peer_piece_map, &ctx, path);
+            torrent.reannouncing(&ctx, sources, download).await
+        })
+    }
+
+    pub fn piece_stream(&self) -> PieceStream {
+        let torrent = self.clone();
+        PieceStream::spawn(|ctx, sender| async move {
+            let (peer_piece_map, sources) = torrent.connect_swarm(&ctx).await?;
+            let stream = stream_pieces(&torrent.info, peer_piece_map, &ctx, sender);
+            torrent.reannouncing(&ctx, sources, stream).await
+        })
+    }
+
+    fn web_seeds(&self, info_hash: [u8; 20]) -> Vec<WebSeed> {
+        let web_seeds = self.url_list.iter().map(|url| WebSeed::new(url));
+        let http_seeds = self
+            .http_seeds
+            .iter()
+            .map(|url| WebSeed::http_seed(url, info_hash));
+        web_seeds.chain(http_seeds).collect()
+    }
+
+    pub(crate) async fn connect_peers(
+        &self,
+        ctx: &DownloadContext,
+    ) -> Result<HashMap<usize, Vec<Peer>>> {
+        let (mut peer_piece_map, mut sources) = self.connect_swarm(ctx).await?;
+        // Nothing joins later, so every dial is waited out.
+        while let Some(connected) = sources.connecting.join_next().await {
+            match connected? {
+                Ok((peer, pieces)) => {
                     for piece in pieces {
-                        peer_piece_map
-                            .entry(piece)
-                            .or_insert_with(Vec::new)
-                            .push(peer.clone());
+                        peer_piece_map.entry(piece).or_default().push(peer.clone());
                     }
-                    peer.prepare_download().await?;
                 }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(_) => {}
             }
         }
+        Ok(peer_piece_map)
+    }
 
-        if peer_piece_map.is_empty() {
-            return Err(anyhow::anyhow!("Could not connect to any peers"));
+    // Like `connect_peers`, also returning the tracker's answer so the
+    // download can re-announce on its schedule.
+    async fn connect_swarm(
+        &self,
+        ctx: &DownloadContext,
+    ) -> Result<(HashMap<usize, Vec<Peer>>, PeerSources)> {
+        // Nothing is ever exchanged for a private torrent.
+        let exchanged = match self.info.is_private() {
+            true => mpsc::unbounded_channel().1,
+            false => ctx.exchange_peers(),
+        };
+        let info_hash = self.info_hash()?;
+        ctx.set_metadata(&self.info)?;
+        let listening = listener::register(info_hash, ctx, self.info.piece_count());
+        let web_seeds = self.web_seeds(info_hash);
+        let seeded = !web_seeds.is_empty();
+        ctx.add_web_seeds(web_seeds);
+        let request = self.tracker_request(ctx, Some(TrackerEvent::Started));
+        // With web seeds to fall back on, a download can start without any
+        // tracker answering.
+        let announce = match ctx.announce(self.announce_request(&request)).await {
+            Err(e) if seeded && !matches!(e, Error::Cancelled) => {
+                warn!("announce failed, downloading from web seeds: {}", e);
+                Announce::default()
+            }
+            announce => announce?,
+        };
+        // As many peers as there are slots are dialed at once. The rest wait
+        // for one to free up, here when a dial fails and later when a peer
+        // is given up on. Pieces are fetched once a few peers are ready;
+        // dials still going by then join the download when they finish.
+        let piece_count = self.info.piece_count();
+        let mut backlog: VecDeque<_> = announce
+            .peers
+            .iter()
+            .map(|&peer_address| (peer_address, Transport::Tcp))
+            .collect();
+        let mut connecting = JoinSet::new();
+        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
+        let mut ready = 0;
+        while ready < READY_PEERS {
+            while let Some(&(peer_address, transport)) = backlog.front() {
+                if !ctx.connections().try_reserve(peer_address) {
+                    break;
+                }
+                backlog.pop_front();
+                connecting.spawn(connect(
+                    ctx.clone(),
+                    peer_address,
+                    transport,
+                    info_hash,
+                    piece_count,
+                ));
+            }
+            let Some(connected) = connecting.join_next().await else {
+                break;
+            };
+            match connected? {
+                Ok((peer, pieces)) => {
+                    ready += 1;
+                    for piece in pieces {
+                        peer_piece_map.entry(piece).or_default().push(peer.clone());
+                    }
+                }
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
+                Err(_) => {}
+            }
         }
 
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
+        Ok((
+            peer_piece_map,
+            PeerSources {
+                announce,
+                exchanged,
+                backlog,
+                connecting,
+                listening,
+            },
+        ))
+    }
+
+    // Runs `download` while looking for more peers, so peers that join the
+    // swarm later still get to contribute. Trackers are told
+    // when it ends either way.
+    async fn reannouncing<T>(
+        &self,
+        ctx: &DownloadContext,
+        sources: PeerSources,
+        download: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        ctx.set_finding_peers(true);
+        tokio::pin!(download);
+        let result = tokio::select! {
+            result = &mut download => result,
+            () = self.find_more_peers(ctx, sources) => download.await,
+        };
+        let event = match result {
+            Ok(_) => TrackerEvent::Completed,
+            Err(_) => TrackerEvent::Stopped,
         };
+        // Not cancellable: a stopped download still owes the tracker this.
+        let request = self.tracker_request(ctx, Some(event));
+        let trackers =
+            async { tracker::announce_tiers(&self.tiers(), &self.info_hash()?, &request).await };
+        let _ = time::timeout(FINAL_ANNOUNCE_TIMEOUT, trackers).await;
+        result
+    }
 
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
-            let piece_hashes = piece_hashes.clone();
-            let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
-
-            join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
-                    Ok(data) => {
-                        println!(
-                            "Downloaded piece {}/{} from peer {}",
-                            piece_number, num_pieces, peer.address
-                        );
-                        if piece_hashes[piece] != *Sha1::digest(&data) {
-                            eprintln!(
-                                "Piece {}/{} failed verification. Will retry...",
-                                piece_number, num_pieces
-                            );
-                            (piece, vec![])
-                        } else {
-                            (piece, data)
-                        }
+    // Re-announces on the tracker's schedule and dials peers that other
+    // peers tell us about. Only returns once the download is cancelled.
+    async fn find_more_peers(&self, ctx: &DownloadContext, sources: PeerSources) {
+        let Ok(info_hash) = self.info_hash() else {
+            return;
+        };
+        let PeerSources {
+            mut announce,
+            mut exchanged,
+            mut backlog,
+            mut connecting,
+            listening: _listening,
+        } = sources;
+        let mut known: HashSet<SocketAddr> = announce.peers.iter().copied().collect();
+        let mut next_announce = time::Instant::now() + announce.next_announce();
+        let cancel = ctx.cancellation_token();
+        let piece_count = self.info.piece_count();
+        loop {
+            let next = backlog.front().copied();
+            let found = tokio::select! {
+                _ = cancel.cancelled() => return,
+                // Whoever is next in the backlog gets the first slot to free
+                // up, when a peer is given up on.
+                reserved = async {
+                    match next {
+                        Some((peer_address, _)) => ctx.connections().reserve(peer_address).await,
+                        None => std::future::pending().await,
                     }
-                    Err(e) => {
-                        eprintln!(
-                            "Error loading piece {}/{}: {}. Will retry...",
-                            piece_number, num_pieces, e
-                        );
-                        (piece, vec![])
+                } => {
+                    let (peer_address, transport) = backlog.pop_front().unwrap();
+                    if reserved {
+                        connecting.spawn(connect(
+                            ctx.clone(),
+                            peer_address,
+                            transport,
+                            info_hash,
+                            piece_count,
+                        ));
                     }
+                    continue;
                 }
-            });
-        };
-
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
-        }
-
-        let mut file_bytes = vec![0u8; file_len as usize];
-        while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
-            } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
-            }
+                Some(connected) = connecting.join_next() => {
+                    match connected {
+                        Ok(Ok(joined)) => ctx.peer_joined(joined),
+                        Ok(Err(Error::Cancelled)) => return,
+                        _ => {}
+                    }
+                    continue;
+                }
+                _ = time::sleep_until(next_announce) => {
+                    // A failed announce is retried on the old schedule.
+                    let request = self.tracker_request(ctx, None);
+                    match ctx.announce(self.announce_request(&request)).await {
+                        Ok(reply) => announce = reply,
+                        Err(Error::Cancelled) => return,
+                        Err(e) => warn!("re-announce failed: {}", e),
+                    }
+                    next_announce = time::Instant::now() + announce.next_announce();
+                    announce.peers.iter().map(|&peer| (peer, Transport::Tcp)).collect()
+                }
+                Some(exchanged) = exchanged.recv() => vec![exchanged],
+            };
+            backlog.extend(found.into_iter().filter(|(peer, _)| known.insert(*peer)));
         }
+    }
+}
 
-        Ok(file_bytes)
+// Dials and readies a peer holding a connection slot, giving the slot back
+// if it fails.
+async fn connect(
+    ctx: DownloadContext,
+    address: SocketAddr,
+    transport: Transport,
+    info_hash: [u8; 20],
+    piece_count: usize,
+) -> Result<(Peer, Vec<usize>)> {
+    let connected = async {
+        let peer = ctx
+            .until_cancelled(Peer::dial_preferring(
+                address,
+                info_hash,
+                transport,
+                ctx.local_id(),
+            ))
+            .await?;
+        ready_peer(peer.with_piece_count(piece_count), &ctx).await
+    }
+    .await;
+    if let Err(e) = &connected {
+        ctx.connections().release(&address);
+        debug!(peer = %address, "{}", e);
     }
+    connected
 }
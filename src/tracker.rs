@@ -1,48 +1,547 @@
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket};
+use std::{collections::HashMap, time::Duration};
+#[cfg(feature = "http")]
+use tracing::debug;
+use tracing::warn;
+use url::Url;
 
-use crate::peer::Peer;
+#[cfg(feature = "http")]
+use crate::tor;
+use crate::{
+    error::{Error, Result},
+    peer::Peer,
+};
 
-#[derive(Debug, Serialize)]
+// Used when a tracker doesn't say how often to announce.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// Unreachable trackers are retried this many times, waiting twice as long
+// before each retry.
+const ANNOUNCE_RETRIES: u32 = 3;
+const ANNOUNCE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+// A tracker's answer to an announce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Announce {
+    pub peers: Vec<SocketAddr>,
+    pub interval: Option<Duration>,
+    pub min_interval: Option<Duration>,
+    pub warning: Option<String>,
+}
+
+impl Announce {
+    // When to announce again: the tracker's interval, but never sooner than
+    // its minimum.
+    pub fn next_announce(&self) -> Duration {
+        self.interval
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(self.min_interval.unwrap_or_default())
+    }
+
+    // Adds peers not seen yet and keeps the more frequent schedule.
+    pub(crate) fn merge(&mut self, other: Announce) {
+        for peer in other.peers {
+            if !self.peers.contains(&peer) {
+                self.peers.push(peer);
+            }
+        }
+        let earliest = |a: Option<Duration>, b: Option<Duration>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.warning = self.warning.take().or(other.warning);
+        self.interval = earliest(self.interval, other.interval);
+        self.min_interval = earliest(self.min_interval, other.min_interval);
+    }
+}
+
+pub async fn announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Vec<SocketAddr>> {
+    let tiers = [vec![tracker_url.to_string()]];
+    Ok(announce_tiers(&tiers, info_hash, request).await?.peers)
+}
+
+pub async fn announce_response(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Announce> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::tracker_failure()?;
+    let url = Url::parse(tracker_url)?;
+    match url.scheme() {
+        "http" | "https" => http_announce(&url, info_hash, request).await,
+        "udp" => Err(Error::Tracker("UDP trackers are not supported".into())),
+        scheme => Err(Error::Tracker(format!(
+            "unsupported tracker protocol: {}",
+            scheme
+        ))),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub leechers: u64,
+    pub completed: u64,
+}
+
+// By convention a tracker supports scrape if the last path segment of its
+// announce URL starts with "announce"; that part becomes "scrape".
+pub fn scrape_url(tracker_url: &str) -> Result<Url> {
+    let mut url = Url::parse(tracker_url)?;
+    let path = url.path().to_string();
+    let (dir, last) = path.rsplit_once('/').unwrap_or(("", &path));
+    let Some(suffix) = last.strip_prefix("announce") else {
+        return Err(Error::Tracker(format!(
+            "{} does not support scrape",
+            tracker_url
+        )));
+    };
+    url.set_path(&format!("{}/scrape{}", dir, suffix));
+    Ok(url)
+}
+
+pub async fn scrape(
+    tracker_url: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    #[cfg(feature = "chaos")]
+    crate::chaos::tracker_failure()?;
+    let url = scrape_url(tracker_url)?;
+    match url.scheme() {
+        "http" | "https" => http_scrape(url, info_hashes).await,
+        scheme => Err(Error::Tracker(format!(
+            "scrape is not supported over {}",
+            scheme
+        ))),
+    }
+}
+
+#[cfg(feature = "http")]
+async fn http_scrape(
+    mut url: Url,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let mut query = url.query().map(str::to_string).unwrap_or_default();
+    for info_hash in info_hashes {
+        let info_hash: String = url::form_urlencoded::byte_serialize(info_hash).collect();
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str(&format!("info_hash={}", info_hash));
+    }
+    url.set_query(Some(&query));
+    let client = tor::http_client(url.host_str().unwrap_or_default())?;
+    let body = client.get(url).send().await?.bytes().await?;
+    parse_scrape(&crate::bencode::decode(&body)?)
+}
+
+#[cfg(not(feature = "http"))]
+async fn http_scrape(
+    _url: Url,
+    _info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    Err(Error::Tracker(
+        "HTTP trackers require the `http` feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "http")]
+fn parse_scrape(response: &crate::bencode::Value) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    if let Some(reason) = response.get("failure reason") {
+        return Err(Error::Tracker(
+            reason.as_str_lossy().unwrap_or_default().into_owned(),
+        ));
+    }
+    let files = response
+        .get("files")
+        .and_then(|files| files.as_dict())
+        .ok_or_else(|| Error::Tracker("scrape response has no files".to_string()))?;
+    let count = |stats: &crate::bencode::Value, key: &str| {
+        stats
+            .get(key)
+            .and_then(|count| count.as_int())
+            .unwrap_or(0)
+            .max(0) as u64
+    };
+    Ok(files
+        .iter()
+        .filter_map(|(info_hash, stats)| {
+            let info_hash = info_hash.as_slice().try_into().ok()?;
+            let stats = ScrapeStats {
+                seeders: count(stats, "complete"),
+                leechers: count(stats, "incomplete"),
+                completed: count(stats, "downloaded"),
+            };
+            Some((info_hash, stats))
+        })
+        .collect())
+}
+
+// The public addresses this host would reach the internet from, per family.
+// Connecting a UDP socket only picks a route; nothing is sent.
+pub fn local_addresses() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    let local_ip = |bind: IpAddr, remote: IpAddr| {
+        let socket = StdUdpSocket::bind((bind, 0)).ok()?;
+        socket.connect((remote, 80)).ok()?;
+        Some(socket.local_addr().ok()?.ip())
+    };
+    let ipv4 = match local_ip(
+        Ipv4Addr::UNSPECIFIED.into(),
+        Ipv4Addr::new(8, 8, 8, 8).into(),
+    ) {
+        Some(IpAddr::V4(ip)) if !(ip.is_private() || ip.is_loopback() || ip.is_link_local()) => {
+            Some(ip)
+        }
+        _ => None,
+    };
+    let google_dns = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+    let ipv6 = match local_ip(Ipv6Addr::UNSPECIFIED.into(), google_dns.into()) {
+        // Only global unicast (2000::/3) is reachable by other peers.
+        Some(IpAddr::V6(ip)) if ip.segments()[0] & 0xe000 == 0x2000 => Some(ip),
+        _ => None,
+    };
+    (ipv4, ipv6)
+}
+
+// Announces to one tracker per tier (BEP 12), moving on to the next tracker
+// in a tier when one fails, and merges the peers every tier returned. If no
+// tracker could be reached at all, everything is tried again after a backoff;
+// a tracker that answered with a failure reason is not asked again.
+pub async fn announce_tiers(
+    tiers: &[Vec<String>],
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Announce> {
+    let mut delay = ANNOUNCE_RETRY_DELAY;
+    for _ in 0..ANNOUNCE_RETRIES {
+        match announce_each_tier(tiers, info_hash, request).await {
+            Err(e) if !matches!(e, Error::Tracker(_) | Error::Url(_)) => {
+                warn!("announce failed: {}, retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    announce_each_tier(tiers, info_hash, request).await
+}
+
+async fn announce_each_tier(
+    tiers: &[Vec<String>],
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Announce> {
+    let mut merged = Announce::default();
+    let mut answered = false;
+    let mut last_error = None;
+    for tier in tiers {
+        // The first tracker in the tier to answer is the one used. One that
+        // fails, or that we can't speak to at all, passes to the next.
+        for tracker_url in tier {
+            match announce_response(tracker_url, info_hash, request).await {
+                Ok(announce) => {
+                    answered = true;
+                    merged.merge(announce);
+                    break;
+                }
+                Err(e) => {
+                    warn!(tracker = %tracker_url, "{}", e);
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+    match (answered, last_error) {
+        (false, Some(e)) => Err(e),
+        (false, None) => Err(Error::Tracker("no trackers to announce to".to_string())),
+        (true, _) => Ok(merged),
+    }
+}
+
+// Announces with our addresses in `ipv4=`/`ipv6=` (BEP 7). A dual-stack host
+// announcing to a tracker by name asks once over each family, since the
+// tracker only hands out peers it can see us on, and merges the answers.
+#[cfg(feature = "http")]
+async fn http_announce(
+    url: &Url,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<Announce> {
+    let (ipv4, ipv6) = match tor::enabled() {
+        true => (None, None),
+        false => local_addresses(),
+    };
+    let mut request = request.clone();
+    request.ipv4 = request.ipv4.or(ipv4);
+    request.ipv6 = request.ipv6.or(ipv6);
+
+    let by_name = matches!(url.host(), Some(url::Host::Domain(_)));
+    let announce = match (ipv4, ipv6) {
+        (Some(ipv4), Some(ipv6)) if by_name => {
+            let (over_v4, over_v6) = tokio::join!(
+                http_announce_from(url, info_hash, &request, Some(ipv4.into())),
+                http_announce_from(url, info_hash, &request, Some(ipv6.into())),
+            );
+            match (over_v4, over_v6) {
+                (Err(e), Err(_)) => return Err(e),
+                (Ok(mut announce), Ok(over_v6)) => {
+                    announce.merge(over_v6);
+                    announce
+                }
+                (Ok(announce), Err(_)) | (Err(_), Ok(announce)) => announce,
+            }
+        }
+        _ => http_announce_from(url, info_hash, &request, None).await?,
+    };
+    debug!("found peers: {:?}", announce.peers);
+    Ok(announce)
+}
+
+#[cfg(feature = "http")]
+async fn http_announce_from(
+    url: &Url,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+    local: Option<IpAddr>,
+) -> Result<Announce> {
+    let info_hash_str: String = url::form_urlencoded::byte_serialize(info_hash).collect();
+    let params = serde_urlencoded::to_string(request)?;
+    // Binding to a local address pins the request to that address family.
+    let client = match local {
+        Some(local) => reqwest::Client::builder().local_address(local).build()?,
+        None => tor::http_client(url.host_str().unwrap_or_default())?,
+    };
+    let url = format!("{}?{}&info_hash={}", url, params, info_hash_str);
+    let response = client.get(url).send().await?;
+    let tracker_response = crate::bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
+    if let Some(reason) = tracker_response.failure_reason {
+        return Err(Error::Tracker(reason));
+    }
+    if let Some(warning) = &tracker_response.warning_message {
+        warn!("tracker warning: {}", warning);
+    }
+    let seconds = |seconds: Option<u32>| seconds.map(|seconds| Duration::from_secs(seconds.into()));
+    Ok(Announce {
+        peers: tracker_response.peers()?,
+        interval: seconds(tracker_response.interval),
+        min_interval: seconds(tracker_response.min_interval),
+        warning: tracker_response.warning_message,
+    })
+}
+
+#[cfg(not(feature = "http"))]
+async fn http_announce(
+    _url: &Url,
+    _info_hash: &[u8; 20],
+    _request: &TrackerRequest,
+) -> Result<Announce> {
+    Err(Error::Tracker(
+        "HTTP trackers require the `http` feature".to_string(),
+    ))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
     peer_id: String,
     port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: u32,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
     compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<TrackerEvent>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    numwant: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv4: Option<Ipv4Addr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6: Option<Ipv6Addr>,
 }
 
 impl TrackerRequest {
-    pub fn new(left: u32) -> Self {
-        let peer_id = Peer::gen_peer_id();
-        Self {
-            peer_id,
+    pub fn builder() -> TrackerRequestBuilder {
+        TrackerRequestBuilder::default()
+    }
+}
+
+pub struct TrackerRequestBuilder {
+    request: TrackerRequest,
+}
+
+impl Default for TrackerRequestBuilder {
+    fn default() -> Self {
+        let request = TrackerRequest {
+            peer_id: Peer::gen_peer_id(),
             port: 6881,
             uploaded: 0,
             downloaded: 0,
-            left,
+            left: 0,
             compact: 1,
-        }
+            event: None,
+            numwant: None,
+            key: None,
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+        };
+        Self { request }
+    }
+}
+
+impl TrackerRequestBuilder {
+    pub fn peer_id(mut self, peer_id: String) -> Self {
+        self.request.peer_id = peer_id;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.request.port = port;
+        self
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.request.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.request.downloaded = downloaded;
+        self
+    }
+
+    pub fn left(mut self, left: u64) -> Self {
+        self.request.left = left;
+        self
+    }
+
+    pub fn event(mut self, event: TrackerEvent) -> Self {
+        self.request.event = Some(event);
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.request.numwant = Some(numwant);
+        self
+    }
+
+    pub fn key(mut self, key: String) -> Self {
+        self.request.key = Some(key);
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.request.ip = Some(ip);
+        self
+    }
+
+    // Announced alongside `ip`, so a dual-stack peer is reachable on both.
+    pub fn ipv4(mut self, ipv4: Ipv4Addr) -> Self {
+        self.request.ipv4 = Some(ipv4);
+        self
+    }
+
+    pub fn ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.request.ipv6 = Some(ipv6);
+        self
+    }
+
+    pub fn build(self) -> TrackerRequest {
+        self.request
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TrackerResponse {
+    // A tracker refusing the announce sends only this.
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message", default)]
+    warning_message: Option<String>,
     interval: Option<u32>,
-    #[serde(with = "serde_bytes")]
-    peers: Vec<u8>,
+    #[serde(rename = "min interval", default)]
+    min_interval: Option<u32>,
+    #[serde(default)]
+    peers: Peers,
+    #[serde(default, with = "serde_bytes")]
+    peers6: Vec<u8>,
+}
+
+// Trackers send 6-byte compact entries unless the client asked otherwise,
+// but some ignore `compact=1` and send the original list of dictionaries.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Peers {
+    Compact(ByteBuf),
+    Dictionaries(Vec<PeerEntry>),
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Self::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PeerEntry {
+    ip: String,
+    port: u16,
+    #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
 }
 
 impl TrackerResponse {
-    pub fn peers(&self) -> Vec<SocketAddr> {
-        self.peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
-                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                SocketAddr::new(ip, port)
-            })
-            .collect()
+    pub fn peers(&self) -> Result<Vec<SocketAddr>> {
+        if !self.peers6.len().is_multiple_of(18) {
+            return Err(Error::Tracker(format!(
+                "compact IPv6 peer list of {} bytes is not a multiple of 18",
+                self.peers6.len()
+            )));
+        }
+        let mut peers = match &self.peers {
+            Peers::Compact(compact) if !compact.len().is_multiple_of(6) => {
+                return Err(Error::Tracker(format!(
+                    "compact peer list of {} bytes is not a multiple of 6",
+                    compact.len()
+                )));
+            }
+            Peers::Compact(compact) => compact
+                .chunks_exact(6)
+                .map(|chunk| {
+                    let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
+                    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                    SocketAddr::new(ip, port)
+                })
+                .collect(),
+            // Entries may name hosts instead of addresses; those are skipped
+            // rather than resolved.
+            Peers::Dictionaries(entries) => entries
+                .iter()
+                .filter_map(|entry| Some(SocketAddr::new(entry.ip.parse().ok()?, entry.port)))
+                .collect::<Vec<_>>(),
+        };
+        peers.extend(self.peers6.chunks_exact(18).map(|chunk| {
+            let ip: [u8; 16] = chunk[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(IpAddr::V6(ip.into()), port)
+        }));
+        Ok(peers)
     }
 }
@@ -0,0 +1,436 @@
+// uTP (BEP 29): BitTorrent's reliable, ordered byte stream over UDP. Each
+// connection is run by a task that moves bytes between the UDP socket and
+// one end of an in-memory pipe; the other end is the stream handed out. A
+// fixed window stands in for LEDBAT's delay-based congestion control, and
+// acknowledgements are cumulative only.
+use rand::Rng;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
+    net::UdpSocket,
+    sync::{self, mpsc},
+    time::{self, Instant},
+};
+
+use crate::{
+    error::{Error, Result},
+    tor,
+};
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+// Keeps datagrams under common path MTUs.
+const MAX_PAYLOAD: usize = 1200;
+const SEND_WINDOW: usize = 64; // packets in flight
+const RECEIVE_WINDOW: u32 = 1 << 20;
+const PIPE_CAPACITY: usize = 256 * 1024;
+// How far ahead of the next expected packet we buffer.
+const MAX_REORDER: u16 = 1024;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const CONNECT_ATTEMPTS: u32 = 4;
+// A connection that hears nothing for this long is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Packet {
+    kind: u8,
+    connection_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] & 0x0f != VERSION || bytes[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        // Skip the extension chain; selective acks are not used.
+        let (mut extension, mut at) = (bytes[1], HEADER_LEN);
+        while extension != 0 {
+            let (next, len) = (*bytes.get(at)?, *bytes.get(at + 1)? as usize);
+            at += 2 + len;
+            extension = next;
+        }
+        Some(Self {
+            kind: bytes[0] >> 4,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            payload: bytes.get(at..)?.to_vec(),
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push(self.kind << 4 | VERSION);
+        bytes.push(0);
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(RECEIVE_WINDOW.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        bytes.extend(&self.payload);
+        bytes
+    }
+}
+
+fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+// Whether sequence number `a` comes after `b`, allowing for wraparound.
+fn after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+// Connections on a socket, keyed by remote address and the id their
+// packets carry.
+type Routes = Arc<Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>>;
+
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    incoming: sync::Mutex<mpsc::UnboundedReceiver<(SocketAddr, Packet)>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address).await?);
+        let routes = Routes::default();
+        let (accept, incoming) = mpsc::unbounded_channel();
+        tokio::spawn(demultiplex(socket.clone(), routes.clone(), accept));
+        Ok(Self {
+            socket,
+            routes,
+            incoming: sync::Mutex::new(incoming),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    pub async fn connect(&self, remote: SocketAddr) -> Result<DuplexStream> {
+        let (recv_id, mut inbound) = loop {
+            let id = rand::thread_rng().gen();
+            if let Some(inbound) = self.route(remote, id) {
+                break (id, inbound);
+            }
+        };
+        let mut connection = Connection {
+            socket: self.socket.clone(),
+            routes: self.routes.clone(),
+            remote,
+            recv_id,
+            send_id: recv_id.wrapping_add(1),
+            seq_nr: 1,
+            ack_nr: 0,
+            timestamp_diff: 0,
+            unacked: VecDeque::new(),
+            out_of_order: HashMap::new(),
+        };
+        // The SYN alone carries our receive id.
+        let syn = Packet {
+            connection_id: recv_id,
+            ..connection.packet(ST_SYN, Vec::new())
+        };
+        connection.seq_nr = 2;
+        let mut timeout = RETRANSMIT_TIMEOUT;
+        for _ in 0..CONNECT_ATTEMPTS {
+            let _ = self.socket.send_to(&syn.to_bytes(), remote).await;
+            match time::timeout(timeout, inbound.recv()).await {
+                Ok(Some(reply)) if reply.kind == ST_STATE => {
+                    // The acceptor's first data packet reuses this number.
+                    connection.ack_nr = reply.seq_nr.wrapping_sub(1);
+                    let (stream, pipe) = duplex(PIPE_CAPACITY);
+                    tokio::spawn(connection.run(pipe, inbound));
+                    return Ok(stream);
+                }
+                Ok(Some(reply)) if reply.kind == ST_RESET => break,
+                Ok(_) | Err(_) => timeout *= 2,
+            }
+        }
+        connection.close();
+        Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("uTP connection to {} failed", remote),
+        )))
+    }
+
+    pub async fn accept(&self) -> Result<(DuplexStream, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        loop {
+            let (remote, syn) = incoming
+                .recv()
+                .await
+                .ok_or_else(|| Error::Protocol("uTP socket closed".to_string()))?;
+            let recv_id = syn.connection_id.wrapping_add(1);
+            // A repeated SYN may have been queued before its connection was.
+            let Some(inbound) = self.route(remote, recv_id) else {
+                continue;
+            };
+            let connection = Connection {
+                socket: self.socket.clone(),
+                routes: self.routes.clone(),
+                remote,
+                recv_id,
+                send_id: syn.connection_id,
+                seq_nr: rand::thread_rng().gen(),
+                ack_nr: syn.seq_nr,
+                timestamp_diff: now_micros().wrapping_sub(syn.timestamp),
+                unacked: VecDeque::new(),
+                out_of_order: HashMap::new(),
+            };
+            connection.acknowledge().await;
+            let (stream, pipe) = duplex(PIPE_CAPACITY);
+            tokio::spawn(connection.run(pipe, inbound));
+            return Ok((stream, remote));
+        }
+    }
+
+    // Claims `id` for a connection with `remote`, unless it is taken.
+    fn route(&self, remote: SocketAddr, id: u16) -> Option<mpsc::UnboundedReceiver<Packet>> {
+        let mut routes = self.routes.lock().unwrap();
+        if routes.contains_key(&(remote, id)) {
+            return None;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        routes.insert((remote, id), sender);
+        Some(receiver)
+    }
+}
+
+// Dials `remote` from a socket of its own.
+pub async fn connect(remote: SocketAddr) -> Result<DuplexStream> {
+    tor::ensure_tcp("uTP")?;
+    let local = match remote {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    UtpSocket::bind(local).await?.connect(remote).await
+}
+
+// Hands each datagram to its connection, and SYNs for new ones to `accept`.
+// Runs until the socket is dropped and its last connection has closed.
+async fn demultiplex(
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    accept: mpsc::UnboundedSender<(SocketAddr, Packet)>,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut check = time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let Ok((len, from)) = received else {
+                    continue;
+                };
+                let Some(packet) = Packet::parse(&buf[..len]) else {
+                    continue;
+                };
+                // A SYN carries the id the connection will receive on, less one.
+                let id = match packet.kind {
+                    ST_SYN => packet.connection_id.wrapping_add(1),
+                    _ => packet.connection_id,
+                };
+                let route = routes.lock().unwrap().get(&(from, id)).cloned();
+                match route {
+                    Some(route) => {
+                        let _ = route.send(packet);
+                    }
+                    None if packet.kind == ST_SYN => {
+                        let _ = accept.send((from, packet));
+                    }
+                    None => {}
+                }
+            }
+            _ = check.tick() => {
+                if accept.is_closed() && routes.lock().unwrap().is_empty() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+struct Sent {
+    packet: Packet,
+    at: Instant,
+}
+
+struct Connection {
+    socket: Arc<UdpSocket>,
+    routes: Routes,
+    remote: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    seq_nr: u16, // next to send
+    ack_nr: u16, // last received in order
+    timestamp_diff: u32,
+    unacked: VecDeque<Sent>,
+    out_of_order: HashMap<u16, Packet>,
+}
+
+impl Connection {
+    fn packet(&self, kind: u8, payload: Vec<u8>) -> Packet {
+        Packet {
+            kind,
+            connection_id: self.send_id,
+            timestamp: now_micros(),
+            timestamp_diff: self.timestamp_diff,
+            seq_nr: self.seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        }
+    }
+
+    async fn transmit(&self, packet: &Packet) {
+        let _ = self.socket.send_to(&packet.to_bytes(), self.remote).await;
+    }
+
+    async fn acknowledge(&self) {
+        self.transmit(&self.packet(ST_STATE, Vec::new())).await;
+    }
+
+    async fn send(&mut self, kind: u8, payload: Vec<u8>) {
+        let packet = self.packet(kind, payload);
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.transmit(&packet).await;
+        self.unacked.push_back(Sent {
+            packet,
+            at: Instant::now(),
+        });
+    }
+
+    fn close(&self) {
+        self.routes
+            .lock()
+            .unwrap()
+            .remove(&(self.remote, self.recv_id));
+    }
+
+    async fn run(mut self, pipe: DuplexStream, mut inbound: mpsc::UnboundedReceiver<Packet>) {
+        let (mut reader, mut writer) = split(pipe);
+        let mut buf = vec![0u8; MAX_PAYLOAD];
+        let (mut sent_fin, mut received_fin) = (false, false);
+        let mut last_heard = Instant::now();
+        let mut tick = time::interval(RETRANSMIT_TIMEOUT / 2);
+        while !(sent_fin && received_fin && self.unacked.is_empty()) {
+            tokio::select! {
+                packet = inbound.recv() => {
+                    let Some(packet) = packet else {
+                        break;
+                    };
+                    last_heard = Instant::now();
+                    match self.receive(packet, &mut writer).await {
+                        Ok(fin) => received_fin |= fin,
+                        Err(()) => break,
+                    }
+                }
+                read = reader.read(&mut buf), if !sent_fin && self.unacked.len() < SEND_WINDOW => {
+                    match read {
+                        Ok(n) if n > 0 => self.send(ST_DATA, buf[..n].to_vec()).await,
+                        _ => {
+                            self.send(ST_FIN, Vec::new()).await;
+                            sent_fin = true;
+                        }
+                    }
+                }
+                _ = tick.tick() => {
+                    if last_heard.elapsed() > IDLE_TIMEOUT {
+                        break;
+                    }
+                    self.retransmit().await;
+                }
+            }
+        }
+        self.close();
+    }
+
+    // Handles one packet from the remote end. Returns whether it finished
+    // sending, or an error once the connection should be dropped.
+    async fn receive(
+        &mut self,
+        packet: Packet,
+        writer: &mut WriteHalf<DuplexStream>,
+    ) -> std::result::Result<bool, ()> {
+        self.timestamp_diff = now_micros().wrapping_sub(packet.timestamp);
+        match packet.kind {
+            ST_RESET => return Err(()),
+            // Our reply to their SYN was lost.
+            ST_SYN => {
+                self.acknowledge().await;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        while let Some(sent) = self.unacked.front() {
+            if after(sent.packet.seq_nr, packet.ack_nr) {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+        if packet.kind == ST_STATE {
+            return Ok(false);
+        }
+
+        let ahead = packet.seq_nr.wrapping_sub(self.ack_nr);
+        if after(packet.seq_nr, self.ack_nr) && ahead <= MAX_REORDER {
+            self.out_of_order.insert(packet.seq_nr, packet);
+        }
+        let mut fin = false;
+        while let Some(next) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = next.seq_nr;
+            if next.kind == ST_FIN {
+                let _ = writer.shutdown().await;
+                self.out_of_order.clear();
+                fin = true;
+                break;
+            }
+            // Nobody is reading any more; tell the other end to stop.
+            if writer.write_all(&next.payload).await.is_err() {
+                self.transmit(&self.packet(ST_RESET, Vec::new())).await;
+                return Err(());
+            }
+        }
+        self.acknowledge().await;
+        Ok(fin)
+    }
+
+    async fn retransmit(&mut self) {
+        let now = Instant::now();
+        let mut resent = Vec::new();
+        for sent in self.unacked.iter_mut() {
+            if now.duration_since(sent.at) >= RETRANSMIT_TIMEOUT {
+                sent.at = now;
+                sent.packet.ack_nr = self.ack_nr;
+                sent.packet.timestamp = now_micros();
+                resent.push(sent.packet.clone());
+            }
+        }
+        for packet in resent {
+            self.transmit(&packet).await;
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// BitTorrent v2 (BEP 52). Every file has its own SHA-256 merkle tree over
+// 16 KiB blocks; its root is the file's `pieces root` in the `file tree`,
+// and the metainfo's `piece layers` hold the layer of the tree with one
+// hash per piece. Missing leaves beyond the end of a file are zero hashes.
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+// A directory maps names to subtrees; a file is a dict whose only key is
+// the empty string.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FileTree(BTreeMap<String, Node>);
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Node {
+    File {
+        #[serde(rename = "")]
+        file: FileEntry,
+    },
+    Directory(FileTree),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct FileEntry {
+    length: u64,
+    // Absent for empty files.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pieces_root: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attr: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeFile {
+    pub path: Vec<String>,
+    pub length: u64,
+    pub pieces_root: Option<[u8; 32]>,
+}
+
+impl FileTree {
+    // Every file, depth first in name order, which is also the order
+    // pieces are numbered in.
+    pub fn files(&self) -> Vec<TreeFile> {
+        let mut files = Vec::new();
+        self.collect(&mut Vec::new(), &mut files);
+        files
+    }
+
+    fn collect(&self, path: &mut Vec<String>, files: &mut Vec<TreeFile>) {
+        for (name, node) in &self.0 {
+            path.push(name.clone());
+            match node {
+                Node::File { file } => files.push(TreeFile {
+                    path: path.clone(),
+                    length: file.length,
+                    pieces_root: file
+                        .pieces_root
+                        .as_ref()
+                        .and_then(|root| root.as_slice().try_into().ok()),
+                }),
+                Node::Directory(tree) => tree.collect(path, files),
+            }
+            path.pop();
+        }
+    }
+}
+
+fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+// The leaves: one hash per block, the last block as long as it is.
+pub fn block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_SIZE)
+        .map(|block| Sha256::digest(block).into())
+        .collect()
+}
+
+// The root of `layer` padded with `pad` to `width` hashes, a power of two.
+pub fn merkle_root(layer: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = layer.to_vec();
+    layer.resize(width.max(1), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+// The root of a subtree of `leaves` zero hashes, which pads the piece layer.
+pub fn pad_hash(leaves: usize) -> [u8; 32] {
+    merkle_root(&[], leaves, [0; 32])
+}
+
+fn blocks_per_piece(piece_length: u64) -> usize {
+    (piece_length as usize / BLOCK_SIZE).max(1)
+}
+
+// The hash of one piece as the piece layer lists it. The last piece of a
+// file is padded to a whole piece's worth of leaves.
+pub fn piece_hash(data: &[u8], piece_length: u64) -> [u8; 32] {
+    merkle_root(&block_hashes(data), blocks_per_piece(piece_length), [0; 32])
+}
+
+// One hash per piece of a file's `data`.
+pub fn piece_layer(data: &[u8], piece_length: u64) -> Vec<[u8; 32]> {
+    data.chunks(piece_length as usize)
+        .map(|piece| piece_hash(piece, piece_length))
+        .collect()
+}
+
+// A file's `pieces root`. Files of at most one piece have no piece layer
+// and are padded only to the next power of two.
+pub fn pieces_root(data: &[u8], piece_length: u64) -> [u8; 32] {
+    let blocks = block_hashes(data);
+    if blocks.len() <= blocks_per_piece(piece_length) {
+        return merkle_root(&blocks, blocks.len().next_power_of_two(), [0; 32]);
+    }
+    layer_root(&piece_layer(data, piece_length), piece_length)
+}
+
+// The root a file's piece layer hashes up to.
+pub fn layer_root(layer: &[[u8; 32]], piece_length: u64) -> [u8; 32] {
+    let pad = pad_hash(blocks_per_piece(piece_length));
+    merkle_root(layer, layer.len().next_power_of_two(), pad)
+}
+
+// Checks that `leaf`, the `index`th node of its layer, hashes up to `root`
+// through `proof`, its uncles from the bottom up, as hash requests
+// (BEP 52) return them.
+pub fn verify_proof(leaf: [u8; 32], mut index: usize, proof: &[[u8; 32]], root: &[u8; 32]) -> bool {
+    let mut node = leaf;
+    for uncle in proof {
+        node = match index % 2 {
+            0 => hash_pair(&node, uncle),
+            _ => hash_pair(uncle, &node),
+        };
+        index /= 2;
+    }
+    &node == root
+}
@@ -0,0 +1,193 @@
+// Web seeds (BEP 19): plain HTTP servers hosting a torrent's content, listed
+// in its `url-list`. A piece is fetched with one range request per file it
+// overlaps and verified like a piece from any peer. The older HTTP seeds
+// (BEP 17, `httpseeds`) are scripts that hand out whole pieces by number.
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::warn;
+use url::Url;
+
+use crate::{
+    error::{Error, Result},
+    selection::Throughput,
+    torrent::Info,
+};
+
+// A web seed that fails this many times in a row is given up on.
+const MAX_FAILURES: usize = 5;
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct WebSeed {
+    url: String,
+    // The info hash an HTTP seed is asked for; web seeds serve files.
+    http_seed: Option<[u8; 20]>,
+    failures: AtomicUsize,
+    throughput: Arc<Throughput>,
+}
+
+// One HTTP request making up part of a piece, for `length` bytes of the
+// response or of `range` within the file at `url`.
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub(crate) struct SeedRequest {
+    url: Url,
+    range: Option<Range<u64>>,
+    length: u64,
+}
+
+impl WebSeed {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            http_seed: None,
+            failures: AtomicUsize::new(0),
+            throughput: Arc::default(),
+        }
+    }
+
+    pub fn http_seed(url: &str, info_hash: [u8; 20]) -> Self {
+        Self {
+            http_seed: Some(info_hash),
+            ..Self::new(url)
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn is_retired(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) >= MAX_FAILURES
+    }
+
+    pub(crate) fn throughput(&self) -> &Arc<Throughput> {
+        &self.throughput
+    }
+
+    // Counts a fetch towards retiring the seed; any success starts over.
+    pub(crate) fn record(&self, fetched: bool) {
+        match fetched {
+            true => self.failures.store(0, Ordering::Relaxed),
+            false => {
+                if self.failures.fetch_add(1, Ordering::Relaxed) + 1 == MAX_FAILURES {
+                    warn!("giving up on web seed {}", self.url);
+                }
+            }
+        }
+    }
+
+    // The requests that together return piece `index`.
+    pub(crate) fn requests(&self, info: &Info, index: usize) -> Result<Vec<SeedRequest>> {
+        let start = info.piece_offset(index);
+        let end = start + info.piece_len(index) as u64;
+        if let Some(info_hash) = self.http_seed {
+            let mut url = Url::parse(&self.url)?;
+            let info_hash: String = url::form_urlencoded::byte_serialize(&info_hash).collect();
+            let query = format!("info_hash={}&piece={}", info_hash, index);
+            let query = match url.query().filter(|query| !query.is_empty()) {
+                Some(existing) => format!("{}&{}", existing, query),
+                None => query,
+            };
+            url.set_query(Some(&query));
+            return Ok(vec![SeedRequest {
+                url,
+                range: None,
+                length: end - start,
+            }]);
+        }
+        let mut requests = Vec::new();
+        let mut file_start = 0;
+        for (path, length) in info.files() {
+            let file_end = file_start + length;
+            let (from, to) = (start.max(file_start), end.min(file_end));
+            if from < to {
+                requests.push(SeedRequest {
+                    url: self.file_url(info, &path)?,
+                    range: Some(from - file_start..to - file_start),
+                    length: to - from,
+                });
+            }
+            file_start = file_end;
+        }
+        Ok(requests)
+    }
+
+    // A URL ending in `/` is a directory holding the torrent under its name;
+    // multi-file torrents are always laid out that way.
+    fn file_url(&self, info: &Info, path: &[String]) -> Result<Url> {
+        let mut url = Url::parse(&self.url)?;
+        if info.is_single_file() && !self.url.ends_with('/') {
+            return Ok(url);
+        }
+        url.path_segments_mut()
+            .map_err(|_| Error::Protocol(format!("{} cannot be a web seed", self.url)))?
+            .pop_if_empty()
+            .push(info.name())
+            .extend(path);
+        Ok(url)
+    }
+
+    pub(crate) async fn fetch(&self, requests: Vec<SeedRequest>) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for request in requests {
+            data.extend(get(request).await?);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "http")]
+async fn get(request: SeedRequest) -> Result<Vec<u8>> {
+    let SeedRequest { url, range, length } = request;
+    let client = crate::tor::http_client(url.host_str().unwrap_or_default())?;
+    let mut builder = client.get(url.clone());
+    if let Some(range) = &range {
+        builder = builder.header(
+            reqwest::header::RANGE,
+            format!("bytes={}-{}", range.start, range.end - 1),
+        );
+    }
+    let fetch = async {
+        let response = builder.send().await?;
+        // An HTTP seed too busy to serve says how many seconds to back off.
+        if response.status() == reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            let retry = response.text().await.unwrap_or_default();
+            return Err(Error::Protocol(format!(
+                "{} is busy, retry in {}s",
+                url,
+                retry.trim()
+            )));
+        }
+        let response = response.error_for_status()?;
+        let partial = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        Ok((partial, response.bytes().await?))
+    };
+    let (partial, body) = tokio::time::timeout(REQUEST_TIMEOUT, fetch)
+        .await
+        .map_err(|_| Error::Timeout)??;
+    // A web seed that ignores the range sends the whole file instead.
+    let wanted = match (partial, &range) {
+        (false, Some(range)) => range.start as usize..(range.end as usize).min(body.len()),
+        _ => 0..body.len(),
+    };
+    match body.get(wanted) {
+        Some(data) if data.len() as u64 == length => Ok(data.to_vec()),
+        _ => Err(Error::Protocol(format!(
+            "{} sent the wrong amount of data",
+            url
+        ))),
+    }
+}
+
+#[cfg(not(feature = "http"))]
+async fn get(_request: SeedRequest) -> Result<Vec<u8>> {
+    Err(Error::Protocol(
+        "web seeds require the `http` feature".to_string(),
+    ))
+}
@@ -0,0 +1,388 @@
+// WebTorrent interop: peers reached over WebRTC data channels, with SDP
+// offers and answers relayed by a WebSocket tracker. Browsers can't open
+// TCP connections, so this is the only way to trade pieces with them. Inside
+// the data channel the usual BitTorrent wire protocol is spoken.
+//
+// Tracker messages are JSON. Info hashes, peer ids and offer ids are sent as
+// "binary strings", one char per byte.
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{mpsc, oneshot},
+    task::JoinSet,
+    time::{self, Instant},
+};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::debug;
+use webrtc::{
+    api::{setting_engine::SettingEngine, APIBuilder},
+    data::data_channel::PollDataChannel,
+    data_channel::RTCDataChannel,
+    ice_transport::ice_server::RTCIceServer,
+    peer_connection::{
+        configuration::RTCConfiguration, sdp::session_description::RTCSessionDescription,
+        RTCPeerConnection,
+    },
+};
+
+use crate::{
+    download::{add_peer, download_pieces_to, unaddressed_peer, DownloadContext, DownloadHandle},
+    error::{Error, Result},
+    peer::Peer,
+    tor,
+    torrent::Torrent,
+};
+
+// Large enough for any single data channel message a peer sends.
+const READ_BUFFER: usize = 256 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct WebRtcConfig {
+    pub ice_servers: Vec<String>,
+    // Offers sent per announce, and so the most peers connected.
+    pub offers: usize,
+    // How long to wait for answers and incoming offers.
+    pub window: Duration,
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            ice_servers: vec!["stun:stun.l.google.com:19302".to_string()],
+            offers: 10,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+// A data channel as a byte stream. Keeps its peer connection alive.
+pub struct DataChannelStream {
+    channel: PollDataChannel,
+    _connection: Arc<RTCPeerConnection>,
+}
+
+impl DataChannelStream {
+    async fn open(
+        connection: Arc<RTCPeerConnection>,
+        channel: Arc<RTCDataChannel>,
+    ) -> Result<Self> {
+        let mut channel = PollDataChannel::new(channel.detach().await?);
+        channel.set_read_buf_capacity(READ_BUFFER);
+        Ok(Self {
+            channel,
+            _connection: connection,
+        })
+    }
+}
+
+impl AsyncRead for DataChannelStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DataChannelStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.channel).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.channel).poll_shutdown(cx)
+    }
+}
+
+async fn peer_connection(config: &WebRtcConfig) -> Result<Arc<RTCPeerConnection>> {
+    // ICE sends UDP straight past the proxy.
+    tor::ensure_tcp("WebRTC")?;
+    let mut settings = SettingEngine::default();
+    settings.detach_data_channels();
+    let api = APIBuilder::new().with_setting_engine(settings).build();
+    let ice_servers = match config.ice_servers.is_empty() {
+        true => vec![],
+        false => vec![RTCIceServer {
+            urls: config.ice_servers.clone(),
+            ..Default::default()
+        }],
+    };
+    let configuration = RTCConfiguration {
+        ice_servers,
+        ..Default::default()
+    };
+    Ok(Arc::new(api.new_peer_connection(configuration).await?))
+}
+
+// WebTorrent doesn't trickle ICE, so candidates have to be in the SDP.
+async fn local_sdp(
+    connection: &RTCPeerConnection,
+    description: RTCSessionDescription,
+) -> Result<String> {
+    let mut gathered = connection.gathering_complete_promise().await;
+    connection.set_local_description(description).await?;
+    let _ = gathered.recv().await;
+    let description = connection
+        .local_description()
+        .await
+        .ok_or_else(|| Error::Protocol("no local description".to_string()))?;
+    Ok(description.sdp)
+}
+
+fn on_open(channel: &Arc<RTCDataChannel>, opened: oneshot::Sender<Arc<RTCDataChannel>>) {
+    let open = channel.clone();
+    channel.on_open(Box::new(move || {
+        let _ = opened.send(open);
+        Box::pin(async {})
+    }));
+}
+
+pub struct PendingOffer {
+    pub sdp: String,
+    connection: Arc<RTCPeerConnection>,
+    opened: oneshot::Receiver<Arc<RTCDataChannel>>,
+}
+
+impl PendingOffer {
+    pub async fn new(config: &WebRtcConfig) -> Result<Self> {
+        let connection = peer_connection(config).await?;
+        let channel = connection.create_data_channel("webtorrent", None).await?;
+        let (opened, opened_receiver) = oneshot::channel();
+        on_open(&channel, opened);
+        let offer = connection.create_offer(None).await?;
+        Ok(Self {
+            sdp: local_sdp(&connection, offer).await?,
+            connection,
+            opened: opened_receiver,
+        })
+    }
+
+    pub async fn accept(self, answer_sdp: String) -> Result<DataChannelStream> {
+        let answer = RTCSessionDescription::answer(answer_sdp)?;
+        self.connection.set_remote_description(answer).await?;
+        let channel = self.opened.await.map_err(|_| closed())?;
+        DataChannelStream::open(self.connection, channel).await
+    }
+}
+
+pub struct PendingAnswer {
+    pub sdp: String,
+    connection: Arc<RTCPeerConnection>,
+    opened: oneshot::Receiver<Arc<RTCDataChannel>>,
+}
+
+impl PendingAnswer {
+    pub async fn new(config: &WebRtcConfig, offer_sdp: String) -> Result<Self> {
+        let connection = peer_connection(config).await?;
+        let (opened, opened_receiver) = oneshot::channel();
+        let opened = Arc::new(Mutex::new(Some(opened)));
+        connection.on_data_channel(Box::new(move |channel| {
+            if let Some(opened) = opened.lock().unwrap().take() {
+                on_open(&channel, opened);
+            }
+            Box::pin(async {})
+        }));
+        let offer = RTCSessionDescription::offer(offer_sdp)?;
+        connection.set_remote_description(offer).await?;
+        let answer = connection.create_answer(None).await?;
+        Ok(Self {
+            sdp: local_sdp(&connection, answer).await?,
+            connection,
+            opened: opened_receiver,
+        })
+    }
+
+    pub async fn open(self) -> Result<DataChannelStream> {
+        let channel = self.opened.await.map_err(|_| closed())?;
+        DataChannelStream::open(self.connection, channel).await
+    }
+}
+
+fn closed() -> Error {
+    Error::Protocol("peer connection closed before the data channel opened".to_string())
+}
+
+pub fn binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&byte| byte as char).collect()
+}
+
+fn tracker_error(e: impl std::fmt::Display) -> Error {
+    Error::Tracker(e.to_string())
+}
+
+// Announces to a WebSocket tracker with a batch of offers, and returns the
+// data channels opened within the window: answers to our offers as well as
+// offers relayed from other peers, which we answer.
+pub async fn announce(
+    tracker_url: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    left: u64,
+    config: &WebRtcConfig,
+) -> Result<Vec<DataChannelStream>> {
+    tor::ensure_tcp("WebRTC")?;
+    let (mut socket, _) = tokio_tungstenite::connect_async(tracker_url)
+        .await
+        .map_err(tracker_error)?;
+
+    let mut pending = HashMap::new();
+    let mut offers = Vec::new();
+    for _ in 0..config.offers {
+        let offer = PendingOffer::new(config).await?;
+        let offer_id = binary_string(&rand::random::<[u8; 20]>());
+        offers.push(json!({
+            "offer": { "type": "offer", "sdp": offer.sdp },
+            "offer_id": offer_id,
+        }));
+        pending.insert(offer_id, offer);
+    }
+    let info_hash = binary_string(info_hash);
+    let peer_id = binary_string(peer_id);
+    let announce = json!({
+        "action": "announce",
+        "info_hash": info_hash,
+        "peer_id": peer_id,
+        "numwant": config.offers,
+        "uploaded": 0,
+        "downloaded": 0,
+        "left": left,
+        "event": "started",
+        "offers": offers,
+    });
+    socket
+        .send(Message::Text(announce.to_string()))
+        .await
+        .map_err(tracker_error)?;
+
+    let deadline = Instant::now() + config.window;
+    let mut connecting = JoinSet::new();
+    let mut streams = Vec::new();
+    // Answers to relayed offers are made off the loop, so one slow ICE
+    // gathering doesn't hold up the rest, and come back here to be sent.
+    let (replies, mut answered) = mpsc::unbounded_channel::<Value>();
+    while streams.len() < config.offers {
+        tokio::select! {
+            _ = time::sleep_until(deadline) => break,
+            Some(stream) = connecting.join_next() => match stream? {
+                Ok(stream) => streams.push(stream),
+                Err(e) => debug!("WebRTC peer failed: {}", e),
+            },
+            Some(reply) = answered.recv() => {
+                socket
+                    .send(Message::Text(reply.to_string()))
+                    .await
+                    .map_err(tracker_error)?;
+            }
+            message = socket.next() => {
+                let Some(message) = message else {
+                    break;
+                };
+                let Message::Text(text) = message.map_err(tracker_error)? else {
+                    continue;
+                };
+                let message: Value = serde_json::from_str(&text)
+                    .map_err(|e| Error::Tracker(format!("bad tracker message: {}", e)))?;
+                if let Some(reason) = message["failure reason"].as_str() {
+                    return Err(Error::Tracker(reason.to_string()));
+                }
+                let offer_id = message["offer_id"].as_str().unwrap_or_default();
+                if let Some(sdp) = message["answer"]["sdp"].as_str() {
+                    if let Some(offer) = pending.remove(offer_id) {
+                        connecting.spawn(offer.accept(sdp.to_string()));
+                    }
+                } else if let Some(sdp) = message["offer"]["sdp"].as_str() {
+                    let (config, replies) = (config.clone(), replies.clone());
+                    let mut reply = json!({
+                        "action": "announce",
+                        "info_hash": info_hash,
+                        "peer_id": peer_id,
+                        "to_peer_id": message["peer_id"],
+                        "offer_id": offer_id,
+                    });
+                    let sdp = sdp.to_string();
+                    connecting.spawn(async move {
+                        let answer = PendingAnswer::new(&config, sdp).await?;
+                        reply["answer"] = json!({ "type": "answer", "sdp": answer.sdp });
+                        let _ = replies.send(reply);
+                        answer.open().await
+                    });
+                }
+            }
+        }
+    }
+    let _ = socket.close(None).await;
+    Ok(streams)
+}
+
+// Downloads `torrent` from the WebTorrent peers its WebSocket tracker knows.
+pub fn download_to(torrent: &Torrent, config: WebRtcConfig, path: PathBuf) -> DownloadHandle<()> {
+    let torrent = torrent.clone();
+    DownloadHandle::spawn(|ctx| async move {
+        tor::ensure_tcp("WebRTC")?;
+        let peer_piece_map = connect_peers(&torrent, &config, &ctx).await?;
+        download_pieces_to(&torrent.info, peer_piece_map, BTreeMap::new(), &ctx, path).await
+    })
+}
+
+async fn connect_peers(
+    torrent: &Torrent,
+    config: &WebRtcConfig,
+    ctx: &DownloadContext,
+) -> Result<HashMap<usize, Vec<Peer>>> {
+    let info_hash = torrent.info_hash()?;
+    let streams = ctx
+        .announce(announce(
+            &torrent.announce,
+            &info_hash,
+            &ctx.local_id(),
+            torrent.len(),
+            config,
+        ))
+        .await?;
+    let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
+
+    for (index, stream) in streams.into_iter().enumerate() {
+        // WebRTC peers have no address we can dial; they are keyed by one no
+        // other peer has.
+        let address = unaddressed_peer();
+        // A peer that fails any step is dropped; the others carry on.
+        let connected = async {
+            let peer = ctx
+                .until_cancelled(Peer::connect_stream_as(
+                    stream,
+                    address,
+                    info_hash,
+                    ctx.local_id(),
+                ))
+                .await?;
+            let peer = peer.with_piece_count(torrent.info.piece_count());
+            add_peer(peer, &mut peer_piece_map, ctx).await
+        };
+        match connected.await {
+            Ok(()) => {}
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => debug!("WebRTC peer {}: {}", index + 1, e),
+        }
+    }
+
+    Ok(peer_piece_map)
+}
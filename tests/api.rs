@@ -0,0 +1,113 @@
+#![cfg(all(unix, feature = "http"))]
+use bittorrent_starter_rust::{
+    api,
+    control::Registry,
+    session::{Session, SessionConfig},
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use serde_json::Value;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// The status code and JSON body, if any, of one request.
+async fn call(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+    let (status, body) = fetch(address, method, path, body).await;
+    (status, serde_json::from_str(&body).unwrap_or(Value::Null))
+}
+
+async fn fetch(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split_whitespace().nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+#[tokio::test]
+async fn manages_torrents_over_http() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(40_000);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let peer_address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![peer_address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+    let torrent_path = dir.path().join("file.torrent");
+    std::fs::write(&torrent_path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+    let session = Session::new(SessionConfig {
+        download_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(api::serve(listener, Registry::for_session(session)));
+
+    let add = serde_json::json!({ "source": torrent_path }).to_string();
+    let (status, body) = call(address, "POST", "/torrents", &add).await;
+    assert_eq!(status, 201);
+    let id = hex::encode(torrent.info_hash().unwrap());
+    assert_eq!(body["id"], id);
+
+    let saved = dir.path().join("file.bin");
+    while std::fs::read(&saved).ok().as_ref() != Some(&data) {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let (status, body) = call(address, "GET", &format!("/torrents/{}", &id[..8]), "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body["id"], id);
+    assert_eq!(body["progress"]["bytes_done"], data.len());
+    let (status, body) = call(address, "GET", &format!("/torrents/{}/peers", id), "").await;
+    assert_eq!(status, 200);
+    assert!(body.is_array());
+    let (status, metrics) = fetch(address, "GET", "/metrics", "").await;
+    assert_eq!(status, 200);
+    assert!(metrics.contains("bittorrent_torrents 1\n"));
+    assert!(metrics.contains(&format!(
+        "bittorrent_progress_ratio{{info_hash=\"{}\",name=\"file.bin\"}} 1\n",
+        id
+    )));
+    assert!(metrics.contains("# TYPE bittorrent_piece_failures_total counter"));
+
+    let pause = format!("/torrents/{}/pause", id);
+    assert_eq!(call(address, "POST", &pause, "").await.0, 204);
+    let (_, body) = call(address, "GET", "/torrents", "").await;
+    assert_eq!(body[0]["state"], "paused");
+    let (_, body) = call(address, "GET", "/stats", "").await;
+    assert_eq!(
+        (body["torrents"].as_u64(), body["paused"].as_u64()),
+        (Some(1), Some(1))
+    );
+
+    let delete = format!("/torrents/{}", id);
+    assert_eq!(call(address, "DELETE", &delete, "").await.0, 204);
+    assert_eq!(call(address, "GET", &delete, "").await.0, 404);
+    assert_eq!(call(address, "GET", "/nowhere", "").await.0, 404);
+    assert_eq!(call(address, "POST", "/torrents", "not json").await.0, 400);
+}
+
+#[tokio::test]
+async fn serves_only_metrics_when_asked() {
+    let session = Session::new(SessionConfig::default()).await.unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(api::serve_metrics(listener, Registry::for_session(session)));
+
+    let (status, metrics) = fetch(address, "GET", "/metrics", "").await;
+    assert_eq!(status, 200);
+    assert!(metrics.contains("bittorrent_torrents 0\n"));
+    assert_eq!(call(address, "GET", "/torrents", "").await.0, 404);
+}
@@ -0,0 +1,81 @@
+use bittorrent_starter_rust::{aria2::ControlFile, testing::sample_data, torrent::Info};
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+#[test]
+fn reads_control_file_written_by_aria2() {
+    let info = Info::single_file("file.bin", PIECE_LENGTH, &sample_data(100_000));
+    let info_hash = info.info_hash().unwrap();
+
+    // Seven pieces, the first and third done, and one piece in flight.
+    let mut bytes = vec![0, 1];
+    bytes.extend(1u32.to_be_bytes());
+    bytes.extend(20u32.to_be_bytes());
+    bytes.extend(info_hash);
+    bytes.extend((PIECE_LENGTH as u32).to_be_bytes());
+    bytes.extend(100_000u64.to_be_bytes());
+    bytes.extend(12_345u64.to_be_bytes());
+    bytes.extend(1u32.to_be_bytes());
+    bytes.push(0b1010_0000);
+    bytes.extend(1u32.to_be_bytes());
+    bytes.extend(1u32.to_be_bytes());
+    bytes.extend((PIECE_LENGTH as u32).to_be_bytes());
+    bytes.extend(1u32.to_be_bytes());
+    bytes.push(0b1000_0000);
+
+    let control = ControlFile::from_bytes(&bytes).unwrap();
+    assert_eq!(control.info_hash, Some(info_hash));
+    assert_eq!(control.upload_length, 12_345);
+    assert_eq!(control.have(), vec![0, 2]);
+    assert!(control.matches(&info));
+
+    // In-flight pieces are dropped when written back.
+    let written = control.to_bytes();
+    assert_eq!(
+        written,
+        [&bytes[..bytes.len() - 17], &[0, 0, 0, 0]].concat()
+    );
+    assert_eq!(ControlFile::from_bytes(&written).unwrap(), control);
+}
+
+#[test]
+fn rejects_truncated_control_file() {
+    let info = Info::single_file("file.bin", PIECE_LENGTH, &sample_data(100_000));
+    let bytes = ControlFile::new(&info).unwrap().to_bytes();
+    assert!(ControlFile::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(ControlFile::from_bytes(&[0, 2]).is_err());
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn resumes_from_control_file() {
+    use bittorrent_starter_rust::{
+        aria2,
+        testing::{MockPeer, MockTracker},
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    // The peer only has the last two pieces; the first comes from disk.
+    let mock = MockPeer::seeding("file.bin", PIECE_LENGTH, data.clone()).with_pieces([2, 3]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let path = dir.path().join("file.bin");
+    let mut partial = vec![0u8; data.len()];
+    partial[..PIECE_LENGTH as usize * 2].copy_from_slice(&data[..PIECE_LENGTH as usize * 2]);
+    std::fs::write(&path, partial).unwrap();
+    let mut control = ControlFile::new(&torrent.info).unwrap();
+    control.set_have(0, true);
+    control.set_have(1, true);
+    let control_path = ControlFile::path_for(&path);
+    control.write(&control_path).unwrap();
+
+    aria2::download_to(&torrent, path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+    assert!(!control_path.exists());
+}
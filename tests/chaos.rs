@@ -0,0 +1,77 @@
+#![cfg(feature = "chaos")]
+
+use bittorrent_starter_rust::{
+    chaos::{self, ChaosConfig},
+    peer::Peer,
+    testing::{sample_data, MockPeer},
+    Error,
+};
+use tokio::sync::Mutex;
+
+// The chaos config is process-wide, so tests in this file take turns.
+static CHAOS_LOCK: Mutex<()> = Mutex::const_new(());
+
+#[tokio::test]
+async fn tracker_failures_surface_as_tracker_errors() {
+    let _guard = CHAOS_LOCK.lock().await;
+    chaos::install(ChaosConfig {
+        tracker_failure: 1.0,
+        ..Default::default()
+    });
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(20_000));
+    let result = mock
+        .torrent("http://127.0.0.1:1/announce")
+        .get_peer_addrs()
+        .await;
+    chaos::clear();
+    assert!(matches!(result, Err(Error::Tracker(_))));
+}
+
+#[tokio::test]
+async fn corrupted_blocks_reach_the_caller() {
+    let _guard = CHAOS_LOCK.lock().await;
+    let data = sample_data(16 * 1024);
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, data.clone());
+    let address = "127.0.0.1:6881".parse().unwrap();
+    let mut peer = Peer::connect_stream(mock.connect(), address, mock.info_hash())
+        .await
+        .unwrap();
+    peer.get_pieces().await.unwrap();
+    peer.prepare_download().await.unwrap();
+
+    chaos::install(ChaosConfig {
+        corrupt_block: 1.0,
+        ..Default::default()
+    });
+    let piece = peer.load_piece(0, data.len() as u32).await;
+    chaos::clear();
+    assert_ne!(piece.unwrap(), data);
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn download_survives_flaky_peers() {
+    use bittorrent_starter_rust::testing::MockTracker;
+    use std::time::Duration;
+
+    let _guard = CHAOS_LOCK.lock().await;
+    let data = sample_data(200_000);
+    let mock = MockPeer::seeding("sample.bin", 32 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+    let peer_addrs = torrent.get_peer_addrs().await.unwrap();
+    assert_eq!(peer_addrs, vec![address]);
+
+    chaos::install(ChaosConfig {
+        seed: 7,
+        disconnect: 0.05,
+        delay: 0.2,
+        max_delay: Duration::from_millis(5),
+        corrupt_block: 0.05,
+        ..Default::default()
+    });
+    let result = torrent.download().join().await;
+    chaos::clear();
+    assert_eq!(result.unwrap(), data);
+}
@@ -0,0 +1,48 @@
+use bittorrent_starter_rust::choker::{Candidate, Choker};
+use std::net::SocketAddr;
+
+fn candidate(port: u16, transferred: u64, interested: bool) -> Candidate {
+    Candidate {
+        address: SocketAddr::from(([127, 0, 0, 1], port)),
+        transferred,
+        interested,
+    }
+}
+
+#[test]
+fn unchokes_the_fastest_interested_peers_and_one_more() {
+    let mut choker = Choker::new(2);
+    let candidates = vec![
+        candidate(1, 900, false),
+        candidate(2, 500, true),
+        candidate(3, 400, true),
+        candidate(4, 100, true),
+        candidate(5, 50, true),
+    ];
+    let unchoked = choker.select(&candidates);
+    assert_eq!(unchoked.len(), 3);
+    assert!(unchoked.contains(&candidates[1].address));
+    assert!(unchoked.contains(&candidates[2].address));
+    // Not interested, however fast.
+    assert!(!unchoked.contains(&candidates[0].address));
+}
+
+#[test]
+fn ranks_by_rate_since_the_last_round() {
+    let mut choker = Choker::new(1);
+    choker.select(&[candidate(1, 1000, true), candidate(2, 10, true)]);
+    // Peer 1 sent nothing more; peer 2 kept going.
+    let unchoked = choker.select(&[candidate(1, 1000, true), candidate(2, 500, true)]);
+    assert!(unchoked.contains(&candidate(2, 0, true).address));
+}
+
+#[test]
+fn keeps_the_optimistic_unchoke_between_rotations() {
+    let mut choker = Choker::new(0);
+    let candidates: Vec<_> = (1..=20).map(|port| candidate(port, 0, true)).collect();
+    let first = choker.select(&candidates);
+    assert_eq!(first.len(), 1);
+    for _ in 0..2 {
+        assert_eq!(choker.select(&candidates), first);
+    }
+}
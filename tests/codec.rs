@@ -0,0 +1,56 @@
+use bittorrent_starter_rust::{
+    codec::{Frame, Message, MessageId, PeerCodec, MAX_MESSAGE_LENGTH},
+    error::Error,
+};
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[test]
+fn waits_for_whole_frames() {
+    let have = Frame::Message(Message::new(MessageId::Have, vec![0, 0, 0, 7]));
+    let mut wire = BytesMut::new();
+    PeerCodec.encode(Frame::KeepAlive, &mut wire).unwrap();
+    PeerCodec.encode(have.clone(), &mut wire).unwrap();
+
+    let mut codec = PeerCodec;
+    let mut buf = BytesMut::new();
+    let mut frames = Vec::new();
+    // Fed a byte at a time, as a slow connection might deliver it.
+    for byte in wire {
+        buf.extend_from_slice(&[byte]);
+        frames.extend(codec.decode(&mut buf).unwrap());
+    }
+    assert_eq!(frames, vec![Frame::KeepAlive, have]);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn rejects_oversized_frames() {
+    let mut buf = BytesMut::from(&(MAX_MESSAGE_LENGTH + 1).to_be_bytes()[..]);
+    assert!(matches!(
+        PeerCodec.decode(&mut buf),
+        Err(Error::Protocol(_))
+    ));
+}
+
+#[test]
+fn keeps_unknown_message_ids() {
+    let mut buf = BytesMut::from(&[0, 0, 0, 3, 42, 1, 2][..]);
+    assert_eq!(
+        PeerCodec.decode(&mut buf).unwrap(),
+        Some(Frame::Message(Message::new(
+            MessageId::Unknown(42),
+            vec![1, 2]
+        )))
+    );
+}
+
+#[test]
+fn payloads_share_the_read_buffer() {
+    let mut buf = BytesMut::from(&[0, 0, 0, 5, 7, 0, 0, 0, 1][..]);
+    let start = buf.as_ptr();
+    let Some(Frame::Message(piece)) = PeerCodec.decode(&mut buf).unwrap() else {
+        panic!("expected a message");
+    };
+    assert_eq!(piece.payload.as_ptr(), start.wrapping_add(5));
+}
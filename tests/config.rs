@@ -0,0 +1,108 @@
+use bittorrent_starter_rust::{config::Config, peer::Peer, Error};
+use std::path::PathBuf;
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[test]
+fn environment_overrides_the_file_and_flags_override_both() {
+    let file: Config = r#"
+        listen_port = 6881
+        download_dir = "/srv/torrents"
+        max_peers = 50
+        peer_id_prefix = "-BR0001-"
+    "#
+    .parse()
+    .unwrap();
+    let config = file
+        .with_env_vars(vars(&[
+            ("BITTORRENT_LISTEN_PORT", "7000"),
+            ("BITTORRENT_UPLOAD_RATE_LIMIT", "65536"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap()
+        .merge(Config {
+            max_peers: Some(10),
+            ..Default::default()
+        });
+
+    assert_eq!(config.listen_port, Some(7000));
+    assert_eq!(config.download_dir, Some(PathBuf::from("/srv/torrents")));
+    assert_eq!(config.upload_rate_limit, Some(65536));
+    assert_eq!(config.download_rate_limit, None);
+    assert_eq!(config.max_peers, Some(10));
+    assert_eq!(
+        config.session_config().listen.map(|address| address.port()),
+        Some(7000)
+    );
+    assert_eq!(config.session_config().max_peers, Some(10));
+
+    config.apply();
+    let peer_id = Peer::gen_peer_id();
+    assert_eq!(peer_id.len(), 20);
+    assert!(peer_id.starts_with("-BR0001-"));
+}
+
+#[test]
+fn rejects_bad_settings() {
+    assert!(matches!(
+        "listen_prot = 6881".parse::<Config>(),
+        Err(Error::Config(_))
+    ));
+    assert!(matches!(
+        "peer_id_prefix = \"far too long to fit a peer ID\"".parse::<Config>(),
+        Err(Error::Config(_))
+    ));
+    assert!(matches!(
+        Config::default().with_env_vars(vars(&[("BITTORRENT_MAX_PEERS", "lots")])),
+        Err(Error::Config(_))
+    ));
+    assert!(matches!(
+        "[[feeds]]\nurl = \"https://example.com/rss\"\nfilters = [\"(unclosed\"]".parse::<Config>(),
+        Err(Error::Config(_))
+    ));
+}
+
+#[cfg(feature = "rss")]
+#[test]
+fn feeds_download_to_their_own_directory() {
+    let config: Config = r#"
+        download_dir = "/srv/torrents"
+        feed_interval = 600
+
+        [[feeds]]
+        url = "https://example.com/shows.rss"
+        filters = ["S01E\\d+ 1080p"]
+        download_dir = "/srv/shows"
+
+        [[feeds]]
+        url = "https://example.com/albums.atom"
+    "#
+    .parse()
+    .unwrap();
+    assert_eq!(config.feed_interval, Some(600));
+
+    let feeds = config.feed_configs().unwrap();
+    assert_eq!(feeds[0].download_dir, PathBuf::from("/srv/shows"));
+    assert!(feeds[0].matches("Show S01E02 1080p"));
+    assert!(!feeds[0].matches("Show S01E02 720p"));
+    assert_eq!(feeds[1].download_dir, PathBuf::from("/srv/torrents"));
+    assert!(feeds[1].matches("Album (FLAC)"));
+
+    let without_dir = Config {
+        download_dir: None,
+        ..config
+    };
+    assert!(matches!(without_dir.feed_configs(), Err(Error::Config(_))));
+}
+
+#[test]
+fn missing_file_is_empty() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::load(&dir.path().join("config.toml")).unwrap();
+    assert_eq!(config, Config::default());
+}
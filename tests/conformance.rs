@@ -0,0 +1,63 @@
+use bittorrent_starter_rust::{
+    peer::Peer,
+    testing::{sample_data, MockPeer},
+};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const PIECE_LENGTH: u64 = 32 * 1024;
+
+fn strict_peer() -> MockPeer {
+    let data = sample_data(100_000);
+    MockPeer::seeding("sample.bin", PIECE_LENGTH, data).strict()
+}
+
+async fn settle(mock: &MockPeer) -> Vec<String> {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    mock.failures()
+}
+
+#[tokio::test]
+async fn client_conforms_to_reference_peer() {
+    let mock = strict_peer();
+    let address = "127.0.0.1:6881".parse().unwrap();
+    let mut peer = Peer::connect_stream(mock.connect(), address, mock.info_hash())
+        .await
+        .unwrap();
+    peer.get_pieces().await.unwrap();
+    peer.extension_handshake().await.unwrap();
+    peer.extension_metadata().await.unwrap();
+    peer.prepare_download().await.unwrap();
+    for index in 0..mock.info().pieces().len() {
+        let piece_len = mock.info().piece_len(index);
+        peer.load_piece(index as u32, piece_len).await.unwrap();
+    }
+    drop(peer);
+    assert!(settle(&mock).await.is_empty());
+}
+
+#[tokio::test]
+async fn request_while_choked_is_a_violation() {
+    let mock = strict_peer();
+    let mut stream = mock.connect();
+    let mut handshake = vec![19];
+    handshake.extend(b"BitTorrent protocol");
+    handshake.extend([0; 8]);
+    handshake.extend(mock.info_hash());
+    handshake.extend(*b"-XX0000-000000000000");
+    stream.write_all(&handshake).await.unwrap();
+    stream.read_exact(&mut [0; 68]).await.unwrap();
+
+    let request = [
+        &13u32.to_be_bytes()[..],
+        &[6],
+        &[0; 8],
+        &16384u32.to_be_bytes(),
+    ]
+    .concat();
+    stream.write_all(&request).await.unwrap();
+
+    let failures = settle(&mock).await;
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].contains("request while choked"));
+}
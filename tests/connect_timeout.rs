@@ -0,0 +1,105 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    peer::{self, Handshake},
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time,
+};
+
+// Accepts connections and never says a word.
+async fn silent_peer() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut accepted = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.push(stream);
+        }
+    });
+    address
+}
+
+// Answers the handshake, then hangs up before sending a bitfield.
+async fn hangup_peer(info_hash: [u8; 20]) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut handshake = [0u8; 68];
+            stream.read_exact(&mut handshake).await.unwrap();
+            let reply = Handshake::new(info_hash, *b"-MK0001-hangup000000");
+            stream.write_all(&reply.to_bytes().unwrap()).await.unwrap();
+        }
+    });
+    address
+}
+
+// Forwards connections to `target`, each only after `delay`.
+async fn delayed(target: SocketAddr, delay: Duration) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut inbound, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                time::sleep(delay).await;
+                let mut outbound = TcpStream::connect(target).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+            });
+        }
+    });
+    address
+}
+
+#[tokio::test]
+async fn gives_up_on_a_peer_that_never_handshakes() {
+    peer::set_connect_timeout(Duration::from_secs(2));
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![silent_peer().await, seeder.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let downloaded = time::timeout(Duration::from_secs(10), torrent.download().join())
+        .await
+        .expect("the silent peer held up the download");
+    assert_eq!(downloaded.unwrap(), data);
+}
+
+#[tokio::test]
+async fn magnet_handshake_dials_peers_at_once() {
+    peer::set_connect_timeout(Duration::from_secs(2));
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, sample_data(16 * 1024 * 4));
+    let seeder_address = seeder.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![silent_peer().await, seeder_address])
+        .await
+        .unwrap();
+    let magnet = seeder.torrent(&tracker.announce_url()).magnet().unwrap();
+
+    let peer = time::timeout(Duration::from_secs(1), magnet.handshake())
+        .await
+        .expect("waited on the silent peer")
+        .unwrap();
+    assert_eq!(peer.address, seeder_address);
+}
+
+#[tokio::test]
+async fn magnet_handshake_moves_past_peers_that_hang_up() {
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, sample_data(16 * 1024 * 4));
+    // The seeder answers after the other peer has already failed.
+    let seeder_address = delayed(seeder.listen().await.unwrap(), Duration::from_millis(300)).await;
+    let tracker = MockTracker::start(vec![hangup_peer(seeder.info_hash()).await, seeder_address])
+        .await
+        .unwrap();
+    let magnet = seeder.torrent(&tracker.announce_url()).magnet().unwrap();
+
+    let peer = time::timeout(Duration::from_secs(5), magnet.handshake())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(peer.address, seeder_address);
+}
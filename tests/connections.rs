@@ -0,0 +1,44 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    connections,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+
+#[tokio::test]
+async fn stays_within_the_peer_limit() {
+    connections::set_max_peers(1);
+    let data = sample_data(16 * 1024 * 8);
+    let first = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    // Not a clone, which would share the first's connection count.
+    let second = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![
+        first.listen().await.unwrap(),
+        second.listen().await.unwrap(),
+    ])
+    .await
+    .unwrap();
+    let torrent = first.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    assert_eq!(first.connections() + second.connections(), 1);
+}
+
+#[tokio::test]
+async fn fills_a_freed_slot_from_the_backlog() {
+    connections::set_max_peers(1);
+    let data = sample_data(16 * 1024 * 8);
+    // Nothing listens here, so the first slot frees up right away.
+    let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![dead, seeder.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    assert_eq!(seeder.connections(), 1);
+}
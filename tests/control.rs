@@ -0,0 +1,153 @@
+#![cfg(unix)]
+use bittorrent_starter_rust::control::{self, ControlRequest, ControlResponse, Registry};
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn reports_download_status() {
+    use bittorrent_starter_rust::{
+        control::TorrentState,
+        testing::{sample_data, MockPeer, MockTracker},
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(40_000);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let registry = Registry::default();
+    registry.insert(torrent.info_hash().unwrap(), "file.bin", handle.monitor());
+    let socket = dir.path().join("control.sock");
+    tokio::spawn({
+        let socket = socket.clone();
+        async move { control::serve(&socket, registry).await }
+    });
+    assert_eq!(handle.join().await.unwrap(), data);
+    while !socket.exists() {
+        tokio::task::yield_now().await;
+    }
+
+    let id = hex::encode(torrent.info_hash().unwrap());
+    let request = ControlRequest::Status {
+        id: Some(id[..8].to_string()),
+    };
+    let ControlResponse::Status { torrents } = control::request(&socket, &request).await.unwrap()
+    else {
+        panic!("expected a status response");
+    };
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(torrents[0].id, id);
+    assert_eq!(torrents[0].state, TorrentState::Seeding);
+    assert_eq!(torrents[0].progress.bytes_done, data.len() as u64);
+    assert_eq!(torrents[0].tracker.as_deref(), Some("ok, 1 peers"));
+}
+
+#[tokio::test]
+async fn rejects_unknown_torrents() {
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("control.sock");
+    tokio::spawn({
+        let socket = socket.clone();
+        async move { control::serve(&socket, Registry::default()).await }
+    });
+    // Wait for the listener to come up.
+    while !socket.exists() {
+        tokio::task::yield_now().await;
+    }
+
+    let all = ControlRequest::Status { id: None };
+    match control::request(&socket, &all).await.unwrap() {
+        ControlResponse::Status { torrents } => assert!(torrents.is_empty()),
+        response => panic!("unexpected response {:?}", response),
+    }
+    let missing = ControlRequest::Status {
+        id: Some("deadbeef".to_string()),
+    };
+    assert!(matches!(
+        control::request(&socket, &missing).await.unwrap(),
+        ControlResponse::Error { .. }
+    ));
+    // Without a session there is nothing to add torrents to.
+    assert!(matches!(
+        control::request(&socket, &ControlRequest::Stats)
+            .await
+            .unwrap(),
+        ControlResponse::Error { .. }
+    ));
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn manages_a_session_over_tcp() {
+    use bittorrent_starter_rust::{
+        control::TorrentState,
+        session::{Session, SessionConfig},
+        testing::{sample_data, MockPeer, MockTracker},
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(40_000);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent_path = dir.path().join("file.torrent");
+    let torrent = mock.torrent(&tracker.announce_url());
+    std::fs::write(&torrent_path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+    let session = Session::new(SessionConfig {
+        download_dir: Some(dir.path().to_path_buf()),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let control_address = listener.local_addr().unwrap();
+    drop(listener);
+    tokio::spawn(control::serve_tcp(
+        control_address,
+        Registry::for_session(session.clone()),
+    ));
+    let ask = |request: ControlRequest| async move {
+        loop {
+            match control::request_tcp(control_address, &request).await {
+                Ok(response) => return response,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        }
+    };
+
+    let add = ControlRequest::Add {
+        source: torrent_path.display().to_string(),
+        output: None,
+    };
+    let ControlResponse::Added { id } = ask(add.clone()).await else {
+        panic!("expected the torrent to be added");
+    };
+    assert_eq!(id, hex::encode(torrent.info_hash().unwrap()));
+    assert!(matches!(ask(add).await, ControlResponse::Error { .. }));
+
+    let saved = dir.path().join("file.bin");
+    while std::fs::read(&saved).ok().as_ref() != Some(&data) {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    let pause = ControlRequest::Pause {
+        id: id[..6].to_string(),
+    };
+    assert!(matches!(ask(pause).await, ControlResponse::Done));
+    let ControlResponse::Status { torrents } = ask(ControlRequest::Status { id: None }).await
+    else {
+        panic!("expected a status response");
+    };
+    assert_eq!(torrents.len(), 1);
+    assert_eq!(torrents[0].state, TorrentState::Paused);
+    let ControlResponse::Stats { stats } = ask(ControlRequest::Stats).await else {
+        panic!("expected stats");
+    };
+    assert_eq!((stats.torrents, stats.paused), (1, 1));
+
+    let remove = ControlRequest::Remove { id };
+    assert!(matches!(ask(remove).await, ControlResponse::Done));
+    assert!(session.torrents().is_empty());
+}
@@ -0,0 +1,48 @@
+use bittorrent_starter_rust::{
+    create::{auto_piece_length, TorrentCreator},
+    storage::verify_pieces,
+    torrent::Torrent,
+};
+
+#[tokio::test]
+async fn creates_torrents_for_directories() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("album");
+    std::fs::create_dir_all(root.join("disc 1")).unwrap();
+    std::fs::write(root.join("cover.jpg"), vec![7u8; 20_000]).unwrap();
+    std::fs::write(root.join("disc 1/track.flac"), vec![9u8; 50_000]).unwrap();
+
+    let metainfo = TorrentCreator::new(root.clone(), "http://tracker.example/announce")
+        .with_piece_length(16 * 1024)
+        .with_comment("test album")
+        .with_private(true)
+        .build()
+        .unwrap();
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+    assert_eq!(torrent.announce, "http://tracker.example/announce");
+    assert_eq!(torrent.info.name(), "album");
+    assert!(torrent.info.is_private());
+    assert_eq!(
+        torrent.info.files(),
+        vec![
+            (vec!["cover.jpg".to_string()], 20_000),
+            (vec!["disc 1".to_string(), "track.flac".to_string()], 50_000),
+        ]
+    );
+    assert_eq!(torrent.pieces().len(), 5);
+    assert!(verify_pieces(&torrent.info, &root)
+        .await
+        .unwrap()
+        .into_iter()
+        .all(|intact| intact));
+}
+
+#[test]
+fn picks_power_of_two_piece_lengths() {
+    assert_eq!(auto_piece_length(1000), 16 * 1024);
+    assert_eq!(auto_piece_length(700 * 1024 * 1024), 512 * 1024);
+    assert_eq!(
+        auto_piece_length(100 * 1024 * 1024 * 1024),
+        16 * 1024 * 1024
+    );
+}
@@ -0,0 +1,32 @@
+use bittorrent_starter_rust::decode::{
+    decode_bencoded_bytes, decode_bencoded_value, encode_json_value, BytesFormat,
+};
+
+#[test]
+fn encodes_json_as_bencode() {
+    let encoded =
+        encode_json_value(r#"{"peers":[{"port":6881,"ip":"10.0.0.1"}],"interval":-5}"#).unwrap();
+    assert_eq!(
+        encoded,
+        b"d8:intervali-5e5:peersld2:ip8:10.0.0.14:porti6881eeee"
+    );
+    let decoded = decode_bencoded_value(std::str::from_utf8(&encoded).unwrap()).unwrap();
+    assert_eq!(decoded["peers"][0]["port"], 6881);
+}
+
+#[test]
+fn refuses_json_bencode_cannot_hold() {
+    for json in ["1.5", "true", "null", "[1, {\"a\": false}]", "{"] {
+        assert!(encode_json_value(json).is_err(), "{}", json);
+    }
+}
+
+#[test]
+fn renders_binary_strings() {
+    let response = b"d8:completei3e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+    let hex = decode_bencoded_bytes(response, BytesFormat::Hex).unwrap();
+    assert_eq!(hex["peers"], "0a0000011ae1");
+    assert_eq!(hex["complete"], 3);
+    let base64 = decode_bencoded_bytes(response, BytesFormat::Base64).unwrap();
+    assert_eq!(base64["peers"], "CgAAARrh");
+}
@@ -0,0 +1,117 @@
+use bittorrent_starter_rust::{
+    bencode::{self, Value},
+    dedupe::Library,
+    testing::sample_data,
+    torrent::{Info, Torrent},
+};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+#[test]
+fn parses_similar_and_collections() {
+    let data = sample_data(20_000);
+    let info = Info::single_file("sample.bin", PIECE_LENGTH, &data);
+    let mut info = match bencode::decode(&serde_bencode::to_bytes(&info).unwrap()).unwrap() {
+        Value::Dict(dict) => dict,
+        _ => unreachable!(),
+    };
+    info.insert(
+        b"similar".to_vec(),
+        Value::List(vec![Value::Bytes(vec![7; 20])]),
+    );
+    info.insert(
+        b"collections".to_vec(),
+        Value::List(vec![Value::Bytes(b"albums".to_vec())]),
+    );
+    let info_bytes = bencode::encode(&Value::Dict(info.clone())).unwrap();
+    let torrent = bencode::encode(&Value::Dict(BTreeMap::from([
+        (
+            b"announce".to_vec(),
+            Value::Bytes(b"http://tracker".to_vec()),
+        ),
+        (b"info".to_vec(), Value::Dict(info)),
+    ])))
+    .unwrap();
+
+    let torrent = Torrent::from_bytes(&torrent).unwrap();
+    assert_eq!(torrent.info.similar().collect::<Vec<_>>(), vec![[7; 20]]);
+    assert_eq!(torrent.info.collections, Some(vec!["albums".to_string()]));
+    let expected: [u8; 20] = Sha1::digest(info_bytes).into();
+    assert_eq!(torrent.info_hash().unwrap(), expected);
+}
+
+#[tokio::test]
+async fn identical_content_is_linked_without_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(50_000);
+    let original = dir.path().join("original.bin");
+    std::fs::write(&original, &data).unwrap();
+
+    let library = Library::new();
+    let info = Info::single_file("original.bin", PIECE_LENGTH, &data);
+    library.add(info, original).unwrap();
+
+    // Same content under another name, announced to an unreachable tracker.
+    let torrent = Torrent {
+        announce: "http://127.0.0.1:1/announce".to_string(),
+        announce_list: Vec::new(),
+        url_list: Vec::new(),
+        http_seeds: Vec::new(),
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info: Info::single_file("copy.bin", PIECE_LENGTH, &data),
+        piece_layers: BTreeMap::new(),
+    };
+    let copy = dir.path().join("copy.bin");
+    let handle = library.download_to(&torrent, copy.clone());
+    handle.join().await.unwrap();
+    assert_eq!(std::fs::read(&copy).unwrap(), data);
+    assert!(library.contains(&torrent.info_hash().unwrap()));
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn shared_pieces_are_copied_and_the_rest_downloaded() {
+    use bittorrent_starter_rust::testing::{MockPeer, MockTracker};
+
+    let dir = tempfile::tempdir().unwrap();
+    let first = sample_data(PIECE_LENGTH as usize * 4);
+    let first_path = dir.path().join("first.bin");
+    std::fs::write(&first_path, &first).unwrap();
+    let library = Library::new();
+    library
+        .add(
+            Info::single_file("first.bin", PIECE_LENGTH, &first),
+            first_path,
+        )
+        .unwrap();
+
+    // The second torrent starts with two of the first torrent's pieces,
+    // followed by content the first doesn't have; the only peer has just
+    // the other two.
+    let mut second = first[..PIECE_LENGTH as usize * 2].to_vec();
+    second.extend(
+        sample_data(PIECE_LENGTH as usize + 500)
+            .iter()
+            .map(|byte| !byte),
+    );
+    let mock = MockPeer::seeding("second.bin", PIECE_LENGTH, second.clone()).with_pieces([2, 3]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let reused = library.reuse_pieces(&torrent.info).await;
+    assert_eq!(reused.keys().copied().collect::<Vec<_>>(), vec![0, 1]);
+
+    let second_path = dir.path().join("second.bin");
+    library
+        .download_to(&torrent, second_path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&second_path).unwrap(), second);
+}
@@ -0,0 +1,45 @@
+use bittorrent_starter_rust::{
+    dht::{self, Dht},
+    testing::{sample_data, MockPeer},
+};
+use std::net::SocketAddr;
+
+async fn node() -> Dht {
+    Dht::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()
+}
+
+#[tokio::test]
+async fn finds_peers_announced_by_other_nodes() {
+    let router = node().await;
+    let bootstrap = vec![router.local_addr().unwrap().to_string()];
+    let info_hash = [7u8; 20];
+
+    let seeder = node().await;
+    seeder.bootstrap(&bootstrap).await.unwrap();
+    assert!(seeder.announce(info_hash, 6881).await > 0);
+
+    let leecher = node().await;
+    leecher.bootstrap(&bootstrap).await.unwrap();
+    let expected: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+    assert_eq!(leecher.find_peers(info_hash).await, vec![expected]);
+    assert!(leecher.find_peers([8u8; 20]).await.is_empty());
+}
+
+#[tokio::test]
+async fn downloads_trackerless_torrents() {
+    let data = sample_data(16 * 1024 * 3);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let torrent = mock.torrent("");
+
+    let router = node().await;
+    let bootstrap = vec![router.local_addr().unwrap().to_string()];
+    let announcer = node().await;
+    announcer.bootstrap(&bootstrap).await.unwrap();
+    announcer.announce(mock.info_hash(), address.port()).await;
+
+    dht::enable(bootstrap);
+    let downloaded = torrent.download().join().await;
+    dht::disable();
+    assert_eq!(downloaded.unwrap(), data);
+}
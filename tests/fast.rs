@@ -0,0 +1,56 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    peer::Peer,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+
+#[tokio::test]
+async fn expands_have_all_to_every_piece() {
+    let data = sample_data(16 * 1024 * 3);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data).with_have_all();
+    let address = mock.listen().await.unwrap();
+
+    let mut peer = Peer::new(address, mock.info_hash())
+        .await
+        .unwrap()
+        .with_piece_count(3);
+    assert!(peer.supports_fast);
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![0, 1, 2]);
+    assert!(peer.has_all());
+}
+
+#[tokio::test]
+async fn downloads_allowed_fast_pieces_while_choked() {
+    let data = sample_data(16 * 1024 * 3);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone())
+        .choking_except([1])
+        .strict();
+    let address = mock.listen().await.unwrap();
+
+    let mut peer = Peer::new(address, mock.info_hash()).await.unwrap();
+    peer.get_pieces().await.unwrap();
+    peer.prepare_download().await.unwrap();
+    assert!(!peer.is_unchoked());
+    assert!(peer.can_request(1));
+    assert!(!peer.can_request(0));
+    let piece = peer.load_piece(1, 16 * 1024).await.unwrap();
+    assert_eq!(piece, data[16 * 1024..2 * 16 * 1024]);
+    assert_eq!(mock.failures(), Vec::<String>::new());
+    assert!(peer.load_piece(0, 16 * 1024).await.is_err());
+}
+
+#[tokio::test]
+async fn downloads_from_choking_peers_through_allowed_fast_pieces() {
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let first = seeder.clone().choking_except([0, 1]).with_have_all();
+    let second = seeder.clone().choking_except([2, 3]);
+    let addresses = vec![
+        first.listen().await.unwrap(),
+        second.listen().await.unwrap(),
+    ];
+    let tracker = MockTracker::start(addresses).await.unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+}
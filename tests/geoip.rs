@@ -0,0 +1,121 @@
+#![cfg(feature = "geoip")]
+
+use bittorrent_starter_rust::{
+    control::Registry,
+    geoip::GeoIp,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::net::IpAddr;
+
+// The MaxMind DB data format's types used below.
+const STRING: u8 = 2;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const UINT64: u8 = 9;
+const ARRAY: u8 = 11;
+
+fn field(kind: u8, payload: &[u8]) -> Vec<u8> {
+    [&[kind << 5 | payload.len() as u8], payload].concat()
+}
+
+// Types past 7 are numbered in a second byte; only empty values are needed.
+fn empty_extended(kind: u8) -> Vec<u8> {
+    vec![0, kind - 7]
+}
+
+fn string(value: &str) -> Vec<u8> {
+    field(STRING, value.as_bytes())
+}
+
+fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = vec![MAP << 5 | entries.len() as u8];
+    for (key, value) in entries {
+        bytes.extend(string(key));
+        bytes.extend(value);
+    }
+    bytes
+}
+
+// An IPv4 country database placing just the addresses in `network`/24 in
+// `country`: a search tree of one node per prefix bit, then the record.
+fn country_database(network: [u8; 3], country: &str) -> Vec<u8> {
+    let node_count = 24u32;
+    let record = |value: u32| value.to_be_bytes()[1..].to_vec();
+    let mut bytes = Vec::new();
+    for node in 0..node_count {
+        let bit = network[node as usize / 8] >> (7 - node % 8) & 1;
+        // The last node points past the tree and the 16-byte separator,
+        // at the record; any other turn leads nowhere.
+        let next = match node + 1 {
+            next if next < node_count => next,
+            _ => node_count + 16,
+        };
+        let (left, right) = match bit {
+            0 => (next, node_count),
+            _ => (node_count, next),
+        };
+        bytes.extend(record(left));
+        bytes.extend(record(right));
+    }
+    bytes.extend([0; 16]);
+    bytes.extend(map(&[("country", map(&[("iso_code", string(country))]))]));
+    bytes.extend(b"\xab\xcd\xefMaxMind.com");
+    bytes.extend(map(&[
+        ("binary_format_major_version", field(UINT16, &[2])),
+        ("binary_format_minor_version", field(UINT16, &[])),
+        ("build_epoch", empty_extended(UINT64)),
+        ("database_type", string("GeoLite2-Country")),
+        ("description", map(&[])),
+        ("ip_version", field(UINT16, &[4])),
+        ("languages", empty_extended(ARRAY)),
+        ("node_count", field(UINT32, &[node_count as u8])),
+        ("record_size", field(UINT16, &[24])),
+    ]));
+    bytes
+}
+
+#[test]
+fn looks_up_countries() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), country_database([203, 0, 113], "NL")).unwrap();
+    let geoip = GeoIp::open(file.path()).unwrap();
+
+    let known: IpAddr = "203.0.113.7".parse().unwrap();
+    let unknown: IpAddr = "198.51.100.7".parse().unwrap();
+    assert_eq!(geoip.country(known), Some("NL".to_string()));
+    assert_eq!(geoip.country(unknown), None);
+}
+
+#[tokio::test]
+async fn reports_the_countries_of_connected_peers() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), country_database([127, 0, 0], "NL")).unwrap();
+    // The peer only has the first piece, so the download stays connected.
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, sample_data(40_000)).with_pieces([0]);
+    let tracker = MockTracker::start(vec![mock.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let registry = Registry::default().with_geoip(GeoIp::open(file.path()).unwrap());
+    registry.insert(torrent.info_hash().unwrap(), "file.bin", handle.monitor());
+    let id = hex::encode(torrent.info_hash().unwrap());
+    let peers = loop {
+        match registry.peers(&id).unwrap() {
+            peers if peers.is_empty() => tokio::task::yield_now().await,
+            peers => break peers,
+        }
+    };
+    handle.cancel();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].country.as_deref(), Some("NL"));
+}
+
+#[test]
+fn rejects_files_that_are_not_databases() {
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), b"not a maxmind database").unwrap();
+    assert!(GeoIp::open(file.path()).is_err());
+}
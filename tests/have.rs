@@ -0,0 +1,19 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::testing::{sample_data, MockPeer, MockTracker};
+use std::time::Duration;
+use tokio::time;
+
+#[tokio::test]
+async fn downloads_pieces_a_peer_completes_during_the_session() {
+    let data = sample_data(16 * 1024 * 4);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone()).completing_later([2, 3]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    // Pieces 2 and 3 wait for a peer until the Have messages arrive.
+    let downloaded = time::timeout(Duration::from_secs(10), torrent.download().join())
+        .await
+        .expect("pieces announced with Have were never fetched");
+    assert_eq!(downloaded.unwrap(), data);
+}
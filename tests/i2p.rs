@@ -0,0 +1,246 @@
+use bittorrent_starter_rust::{
+    bencode::{self, Value},
+    i2p,
+    testing::{sample_data, MockPeer},
+};
+use data_encoding::BASE32_NOPAD;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+// A SAM bridge that resolves names from `names` and connects destinations
+// to the local listeners in `destinations`.
+async fn sam_bridge(
+    names: HashMap<String, String>,
+    destinations: HashMap<String, SocketAddr>,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (names, destinations) = (Arc::new(names), Arc::new(destinations));
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (names, destinations) = (names.clone(), destinations.clone());
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap() > 0 {
+                    let words: Vec<&str> = line.split_whitespace().collect();
+                    let arg = |key: &str| {
+                        words
+                            .iter()
+                            .find_map(|word| word.strip_prefix(&format!("{}=", key)))
+                            .unwrap()
+                            .to_string()
+                    };
+                    let reply = match words[..2] {
+                        ["HELLO", _] => "HELLO REPLY RESULT=OK VERSION=3.1".to_string(),
+                        ["SESSION", "CREATE"] => {
+                            "SESSION STATUS RESULT=OK DESTINATION=private".to_string()
+                        }
+                        ["NAMING", "LOOKUP"] => match names.get(&arg("NAME")) {
+                            Some(value) => format!("NAMING REPLY RESULT=OK VALUE={}", value),
+                            None => "NAMING REPLY RESULT=KEY_NOT_FOUND".to_string(),
+                        },
+                        ["STREAM", "CONNECT"] => {
+                            let target = destinations[&arg("DESTINATION")];
+                            let mut upstream = TcpStream::connect(target).await.unwrap();
+                            stream
+                                .write_all(b"STREAM STATUS RESULT=OK\n")
+                                .await
+                                .unwrap();
+                            let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                            return;
+                        }
+                        _ => panic!("unexpected SAM command {}", line),
+                    };
+                    stream
+                        .write_all(format!("{}\n", reply).as_bytes())
+                        .await
+                        .unwrap();
+                    line.clear();
+                }
+            });
+        }
+    });
+    address
+}
+
+// An HTTP tracker handing out a single peer in compact (hash) form.
+async fn i2p_tracker(hash: [u8; 32]) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let log = requests.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            log.lock()
+                .unwrap()
+                .push(String::from_utf8(request).unwrap());
+            let body = bencode::encode(&Value::Dict(BTreeMap::from([
+                (b"interval".to_vec(), Value::Int(900)),
+                (b"peers".to_vec(), Value::Bytes(hash.to_vec())),
+            ])))
+            .unwrap();
+            stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        }
+    });
+    (address, requests)
+}
+
+// A peer that answers the handshake and then sends a frame too long to be a
+// message.
+async fn broken_peer() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        // Stays open, so only the bad frame can end the connection.
+        std::future::pending::<()>().await;
+    });
+    address
+}
+
+#[tokio::test]
+async fn downloads_from_i2p_peers() {
+    let data = sample_data(PIECE_LENGTH as usize * 3);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let seed = mock.listen().await.unwrap();
+    let hash = [9u8; 32];
+    let b32 = format!("{}.b32.i2p", BASE32_NOPAD.encode(&hash).to_lowercase());
+    let (tracker, requests) = i2p_tracker(hash).await;
+
+    let sam = sam_bridge(
+        HashMap::from([
+            ("ME".to_string(), "ourdest".to_string()),
+            ("tracker.i2p".to_string(), "trackerdest".to_string()),
+            (b32, "seeddest".to_string()),
+        ]),
+        HashMap::from([
+            ("trackerdest".to_string(), tracker),
+            ("seeddest".to_string(), seed),
+        ]),
+    )
+    .await;
+
+    let torrent = mock.torrent("http://tracker.i2p/announce");
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    i2p::download_to(&torrent, sam, path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+
+    let requests = requests.lock().unwrap();
+    assert!(requests[0].starts_with("GET /announce?info_hash="));
+    assert!(requests[0].contains("&ip=ourdest.i2p"));
+    assert!(requests[0].contains("Host: tracker.i2p"));
+}
+
+#[tokio::test]
+async fn announces_to_every_tier() {
+    let data = sample_data(PIECE_LENGTH as usize * 4);
+    let first = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let second = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let (first_hash, second_hash) = ([3u8; 32], [4u8; 32]);
+    let b32 = |hash: &[u8; 32]| format!("{}.b32.i2p", BASE32_NOPAD.encode(hash).to_lowercase());
+    let (first_tracker, _) = i2p_tracker(first_hash).await;
+    let (second_tracker, second_requests) = i2p_tracker(second_hash).await;
+
+    let sam = sam_bridge(
+        HashMap::from([
+            ("ME".to_string(), "ourdest".to_string()),
+            ("first.i2p".to_string(), "firstdest".to_string()),
+            ("second.i2p".to_string(), "seconddest".to_string()),
+            (b32(&first_hash), "firstseed".to_string()),
+            (b32(&second_hash), "secondseed".to_string()),
+        ]),
+        HashMap::from([
+            ("firstdest".to_string(), first_tracker),
+            ("seconddest".to_string(), second_tracker),
+            ("firstseed".to_string(), first.listen().await.unwrap()),
+            ("secondseed".to_string(), second.listen().await.unwrap()),
+        ]),
+    )
+    .await;
+
+    // The first tier's first tracker can't be found; the next one answers.
+    let mut torrent = first.torrent("http://gone.i2p/announce");
+    torrent.announce_list = vec![
+        vec![
+            "http://gone.i2p/announce".to_string(),
+            "http://first.i2p/announce".to_string(),
+        ],
+        vec!["http://second.i2p/announce".to_string()],
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    i2p::download_to(&torrent, sam, path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+
+    assert_eq!(second_requests.lock().unwrap().len(), 1);
+    assert_eq!(first.client_ids().len(), 1);
+    assert_eq!(second.client_ids().len(), 1);
+}
+
+#[tokio::test]
+async fn keeps_going_when_one_destination_fails() {
+    let data = sample_data(PIECE_LENGTH as usize * 3);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let (broken_hash, seed_hash) = ([5u8; 32], [6u8; 32]);
+    let b32 = |hash: &[u8; 32]| format!("{}.b32.i2p", BASE32_NOPAD.encode(hash).to_lowercase());
+    let (broken_tracker, _) = i2p_tracker(broken_hash).await;
+    let (seed_tracker, _) = i2p_tracker(seed_hash).await;
+
+    let sam = sam_bridge(
+        HashMap::from([
+            ("ME".to_string(), "ourdest".to_string()),
+            ("broken.i2p".to_string(), "brokendest".to_string()),
+            ("seed.i2p".to_string(), "seeddest".to_string()),
+            (b32(&broken_hash), "brokenpeer".to_string()),
+            (b32(&seed_hash), "seedpeer".to_string()),
+        ]),
+        HashMap::from([
+            ("brokendest".to_string(), broken_tracker),
+            ("seeddest".to_string(), seed_tracker),
+            ("brokenpeer".to_string(), broken_peer().await),
+            ("seedpeer".to_string(), mock.listen().await.unwrap()),
+        ]),
+    )
+    .await;
+
+    let mut torrent = mock.torrent("http://broken.i2p/announce");
+    torrent.announce_list = vec![
+        vec!["http://broken.i2p/announce".to_string()],
+        vec!["http://seed.i2p/announce".to_string()],
+    ];
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    i2p::download_to(&torrent, sam, path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
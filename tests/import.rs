@@ -0,0 +1,96 @@
+use bittorrent_starter_rust::{
+    bencode::{self, Value},
+    import::import_qbittorrent,
+    store::SessionStore,
+    testing::sample_data,
+    torrent::Info,
+};
+use std::{collections::BTreeMap, fs, path::Path};
+
+fn write_pair(dir: &Path, name: &str, announce: Option<&str>, resume: Value) -> String {
+    let data = sample_data(40_000);
+    let info = Info::single_file(name, 16 * 1024, &data);
+    let info_hash = hex::encode(info.info_hash().unwrap());
+    let mut metainfo = BTreeMap::from([(
+        b"info".to_vec(),
+        bencode::decode(&serde_bencode::to_bytes(&info).unwrap()).unwrap(),
+    )]);
+    if let Some(announce) = announce {
+        metainfo.insert(
+            b"announce".to_vec(),
+            Value::Bytes(announce.as_bytes().to_vec()),
+        );
+    }
+    fs::write(
+        dir.join(format!("{}.torrent", info_hash)),
+        bencode::encode(&Value::Dict(metainfo)).unwrap(),
+    )
+    .unwrap();
+    fs::write(
+        dir.join(format!("{}.fastresume", info_hash)),
+        bencode::encode(&resume).unwrap(),
+    )
+    .unwrap();
+    info_hash
+}
+
+fn resume(entries: Vec<(&str, Value)>) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+#[test]
+fn imports_qbittorrent_backup() {
+    let backup = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+
+    let seeding = write_pair(
+        backup.path(),
+        "seeding.bin",
+        Some("http://tracker.example/announce"),
+        resume(vec![
+            ("qBt-savePath", Value::Bytes(b"/data/done".to_vec())),
+            ("pieces", Value::Bytes(vec![1, 0, 1])),
+            ("paused", Value::Int(1)),
+        ]),
+    );
+    let trackerless = write_pair(
+        backup.path(),
+        "partial.bin",
+        None,
+        resume(vec![
+            ("save_path", Value::Bytes(b"/data/partial".to_vec())),
+            ("pieces", Value::Bytes(vec![0, 1, 0])),
+            (
+                "trackers",
+                Value::List(vec![Value::List(vec![Value::Bytes(
+                    b"udp://tracker.example:6969".to_vec(),
+                )])]),
+            ),
+        ]),
+    );
+    // A magnet that never got metadata has no .torrent and is skipped.
+    fs::write(backup.path().join("ffff.fastresume"), b"de").unwrap();
+
+    let mut store = SessionStore::open(state.path()).unwrap();
+    let imported = import_qbittorrent(backup.path(), &mut store).unwrap();
+    assert_eq!(imported.len(), 2);
+
+    let store = SessionStore::open(state.path()).unwrap();
+    let stored = store.get(&seeding).unwrap();
+    assert_eq!(stored.name, "seeding.bin");
+    assert_eq!(stored.save_path, Path::new("/data/done"));
+    assert_eq!(stored.have(), vec![0, 2]);
+    assert!(stored.paused);
+
+    let stored = store.get(&trackerless).unwrap();
+    assert_eq!(stored.save_path, Path::new("/data/partial"));
+    assert_eq!(stored.have(), vec![1]);
+    assert!(!stored.paused);
+    let torrent = store.torrent(stored).unwrap();
+    assert_eq!(torrent.announce, "udp://tracker.example:6969");
+}
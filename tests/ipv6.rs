@@ -0,0 +1,17 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::testing::{sample_data, MockPeer, MockTracker};
+use std::net::Ipv6Addr;
+
+#[tokio::test]
+async fn downloads_from_ipv6_peers() {
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let partial = seeder.clone().with_pieces([0, 1]);
+    let v4 = partial.listen().await.unwrap();
+    let v6 = seeder.listen_on(Ipv6Addr::LOCALHOST.into()).await.unwrap();
+    let tracker = MockTracker::start(vec![v4, v6]).await.unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.get_peer_addrs().await.unwrap(), vec![v4, v6]);
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+}
@@ -0,0 +1,89 @@
+use bittorrent_starter_rust::{
+    error::Error,
+    storage::{verify_pieces, PieceReader, PieceStore, PieceWriter},
+    testing::sample_data,
+    torrent::Info,
+};
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+#[tokio::test]
+async fn splits_pieces_across_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("album");
+    let data = sample_data(PIECE_LENGTH as usize * 2 + 50);
+    // The small middle file sits inside the first piece, which also spans
+    // the files on either side of it.
+    let (first, rest) = data.split_at(PIECE_LENGTH as usize - 100);
+    let (second, third) = rest.split_at(60);
+    let info = Info::multi_file(
+        "album",
+        PIECE_LENGTH,
+        &[
+            ("cover.jpg", first),
+            ("notes.txt", second),
+            ("disc 1/track.flac", third),
+        ],
+    );
+
+    let mut writer = PieceWriter::create(path.clone(), &info, PieceStore::default())
+        .await
+        .unwrap();
+    for (index, piece) in data.chunks(PIECE_LENGTH as usize).enumerate().rev() {
+        writer.write_piece(index, piece).await.unwrap();
+    }
+    writer.finish().await.unwrap();
+
+    assert_eq!(std::fs::read(path.join("cover.jpg")).unwrap(), first);
+    assert_eq!(std::fs::read(path.join("notes.txt")).unwrap(), second);
+    assert_eq!(
+        std::fs::read(path.join("disc 1/track.flac")).unwrap(),
+        third
+    );
+
+    let reader = PieceReader::new(info, path);
+    assert_eq!(
+        reader
+            .read_block(0, PIECE_LENGTH as u32 - 200, 200)
+            .await
+            .unwrap(),
+        &data[PIECE_LENGTH as usize - 200..PIECE_LENGTH as usize]
+    );
+}
+
+#[tokio::test]
+async fn refuses_paths_outside_the_download() {
+    let dir = tempfile::tempdir().unwrap();
+    let info = Info::multi_file("evil", PIECE_LENGTH, &[("../escape.txt", b"gotcha")]);
+    assert!(matches!(
+        PieceWriter::create(dir.path().join("evil"), &info, PieceStore::default()).await,
+        Err(Error::Metadata(_))
+    ));
+    assert!(!dir.path().join("escape.txt").exists());
+}
+
+#[tokio::test]
+async fn verifies_pieces_across_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("album");
+    let data = sample_data(PIECE_LENGTH as usize * 3);
+    let (first, rest) = data.split_at(PIECE_LENGTH as usize + 10);
+    let (second, third) = rest.split_at(PIECE_LENGTH as usize);
+    let info = Info::multi_file(
+        "album",
+        PIECE_LENGTH,
+        &[("a.bin", first), ("b.bin", second), ("c.bin", third)],
+    );
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("a.bin"), first).unwrap();
+    let mut damaged = second.to_vec();
+    damaged[0] ^= 0xff;
+    std::fs::write(path.join("b.bin"), damaged).unwrap();
+
+    // The damage is in piece 1, and c.bin, holding the end of piece 2, is
+    // missing.
+    assert_eq!(
+        verify_pieces(&info, &path).await.unwrap(),
+        vec![true, false, false]
+    );
+}
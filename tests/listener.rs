@@ -0,0 +1,39 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    listener,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::time::Duration;
+
+// The listener is process-wide, so this is the only test that starts one.
+#[tokio::test]
+async fn incoming_peers_join_their_download() {
+    let address = listener::listen("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    // The tracker only knows a peer missing half the pieces; the rest come
+    // from a peer that connects to us.
+    let partial = seeder.clone().with_pieces([0, 1]);
+    let tracker = MockTracker::start(vec![partial.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+    let stranger = MockPeer::seeding("other.bin", 16 * 1024, data[..16 * 1024].to_vec());
+    assert!(stranger.dial(address).await.is_err());
+    let download = torrent.download();
+
+    // Refused until the download has registered.
+    let dialing = tokio::spawn(async move {
+        while seeder.clone().dial(address).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    });
+    assert_eq!(download.join().await.unwrap(), data);
+    dialing.abort();
+    assert!(tracker
+        .requests()
+        .iter()
+        .any(|request| request.contains(&format!("port={}", address.port()))));
+}
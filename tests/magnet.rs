@@ -0,0 +1,250 @@
+use bittorrent_starter_rust::magnet::Magnet;
+use url::Url;
+
+const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+#[test]
+fn reads_hex_and_base32_info_hashes() {
+    let hex = Magnet::new(Url::parse(&format!("magnet:?xt=urn:btih:{}", INFO_HASH)).unwrap());
+    let base32 =
+        Magnet::new(Url::parse("magnet:?xt=urn:btih:22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7").unwrap());
+    let lowercase =
+        Magnet::new(Url::parse("magnet:?xt=urn:btih:22pzdzvsvzgfijdi2edtu4ou5ijypgt7").unwrap());
+    assert_eq!(hex::encode(hex.unwrap().info_hash), INFO_HASH);
+    assert_eq!(hex::encode(base32.unwrap().info_hash), INFO_HASH);
+    assert_eq!(hex::encode(lowercase.unwrap().info_hash), INFO_HASH);
+}
+
+#[test]
+fn reads_v2_info_hashes() {
+    let info_hash_v2 = "6a0dbc1b9c1c21f6e56ee0c1ed6f4fb2f9b3a8a0bd1b7d86eeeb3d1b3e0a6c4d";
+    let url = format!("magnet:?xt=urn:btmh:1220{}&dn=album", info_hash_v2);
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    assert_eq!(hex::encode(magnet.info_hash_v2.unwrap()), info_hash_v2);
+    // v2-only swarms go by the truncated hash.
+    assert_eq!(hex::encode(magnet.info_hash), info_hash_v2[..40]);
+
+    let hybrid = format!("{}&xt=urn:btih:{}", url, INFO_HASH);
+    let magnet = Magnet::new(Url::parse(&hybrid).unwrap()).unwrap();
+    assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+    assert!(magnet.info_hash_v2.is_some());
+}
+
+#[test]
+fn links_a_torrent_by_its_info_hash_name_and_trackers() {
+    use bittorrent_starter_rust::torrent::{Info, Torrent};
+    use std::collections::BTreeMap;
+
+    let torrent = Torrent {
+        announce: "http://tracker.example/announce".to_string(),
+        announce_list: vec![
+            vec!["http://tracker.example/announce".to_string()],
+            vec!["udp://backup.example:6969".to_string()],
+        ],
+        url_list: vec!["http://seed.example/files/".to_string()],
+        http_seeds: Vec::new(),
+        comment: None,
+        created_by: None,
+        creation_date: None,
+        encoding: None,
+        info: Info::single_file("my file.bin", 16 * 1024, &[7; 40_000]),
+        piece_layers: BTreeMap::new(),
+    };
+    let link = torrent.magnet().unwrap().to_url();
+    let info_hash = hex::encode(torrent.info_hash().unwrap());
+    assert!(link
+        .as_str()
+        .starts_with(&format!("magnet:?xt=urn:btih:{}&dn=my+file.bin", info_hash)));
+
+    let magnet = Magnet::new(link).unwrap();
+    assert_eq!(hex::encode(magnet.info_hash), info_hash);
+    assert_eq!(magnet.info_hash_v2, None);
+    assert_eq!(magnet.file_name.as_deref(), Some("my file.bin"));
+    let trackers: Vec<_> = magnet.trackers.iter().map(Url::as_str).collect();
+    assert_eq!(
+        trackers,
+        [
+            "http://tracker.example/announce",
+            "udp://backup.example:6969"
+        ]
+    );
+    assert_eq!(magnet.web_seeds, ["http://seed.example/files/"]);
+}
+
+#[test]
+fn round_trips_v2_only_links() {
+    let info_hash_v2 = "6a0dbc1b9c1c21f6e56ee0c1ed6f4fb2f9b3a8a0bd1b7d86eeeb3d1b3e0a6c4d";
+    let url = format!("magnet:?xt=urn:btmh:1220{}&dn=album", info_hash_v2);
+    let link = Magnet::new(Url::parse(&url).unwrap()).unwrap().to_url();
+    assert_eq!(link.as_str(), url);
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn asks_every_tracker_in_the_link() {
+    use bittorrent_starter_rust::testing::MockTracker;
+    use std::net::SocketAddr;
+
+    let peers: [SocketAddr; 2] = [
+        "10.0.0.1:6881".parse().unwrap(),
+        "10.0.0.2:6881".parse().unwrap(),
+    ];
+    let first = MockTracker::start(vec![peers[0]]).await.unwrap();
+    let second = MockTracker::start(vec![peers[1]]).await.unwrap();
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&tr={}&tr={}",
+        INFO_HASH,
+        first.announce_url(),
+        second.announce_url()
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    assert_eq!(magnet.trackers.len(), 2);
+
+    let mut found = magnet.get_peer_addrs().await.unwrap();
+    found.sort();
+    assert_eq!(found, peers);
+}
+
+#[tokio::test]
+async fn downloads_from_hinted_peers_without_a_tracker() {
+    use bittorrent_starter_rust::testing::{sample_data, MockPeer};
+
+    let data = sample_data(16 * 1024 * 3);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let address = seeder.listen().await.unwrap();
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&x.pe={}",
+        hex::encode(seeder.info_hash()),
+        address
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    assert_eq!(magnet.peers, vec![address.to_string()]);
+
+    assert_eq!(magnet.download().join().await.unwrap(), data);
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn downloads_from_web_seeds_in_the_link() {
+    use bittorrent_starter_rust::testing::{sample_data, MockPeer, MockWebSeed};
+
+    let data = sample_data(16 * 1024 * 3 + 100);
+    // Sends the metadata but has no pieces.
+    let peer = MockPeer::seeding("file.bin", 16 * 1024, data.clone()).with_pieces([]);
+    let address = peer.listen().await.unwrap();
+    let seed = MockWebSeed::start("file.bin", data.clone()).await.unwrap();
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&x.pe={}&ws={}",
+        hex::encode(peer.info_hash()),
+        address,
+        seed.url()
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+
+    assert_eq!(magnet.download().join().await.unwrap(), data);
+    assert!(seed.requests() >= 4);
+}
+
+#[test]
+fn reads_file_selections() {
+    let url = format!("magnet:?xt=urn:btih:{}&so=0,2,4-6", INFO_HASH);
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    assert_eq!(magnet.select_only, Some(vec![0, 2, 4, 5, 6]));
+
+    for so in ["", "1-", "3-1", "a"] {
+        let url = format!("magnet:?xt=urn:btih:{}&so={}", INFO_HASH, so);
+        assert!(Magnet::new(Url::parse(&url).unwrap()).is_err(), "{}", so);
+    }
+}
+
+#[tokio::test]
+async fn downloads_only_the_selected_files() {
+    use bittorrent_starter_rust::{testing::MockPeer, torrent::Info};
+
+    const PIECE_LENGTH: usize = 16 * 1024;
+    let first = vec![1u8; PIECE_LENGTH * 2];
+    let second = vec![2u8; PIECE_LENGTH + 100];
+    let third = vec![3u8; PIECE_LENGTH];
+    let files: [(&str, &[u8]); 3] = [("a.bin", &first), ("b.bin", &second), ("c.bin", &third)];
+    let info = Info::multi_file("album", PIECE_LENGTH as u64, &files);
+    let data = [first.clone(), second.clone(), third].concat();
+    // Only has the pieces covering b.bin, so asking for any other fails.
+    let seeder = MockPeer::new(info, data).with_pieces([2, 3]);
+    let address = seeder.listen().await.unwrap();
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&x.pe={}&so=1",
+        hex::encode(seeder.info_hash()),
+        address
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+
+    let handle = magnet.download();
+    let monitor = handle.monitor();
+    let downloaded = handle.join().await.unwrap();
+    let b = first.len()..first.len() + second.len();
+    assert_eq!(downloaded[b], second[..]);
+    assert_eq!(monitor.progress().total_pieces, 2);
+}
+
+#[tokio::test]
+async fn skips_peers_sending_the_wrong_metadata() {
+    use bittorrent_starter_rust::{
+        testing::{sample_data, MockPeer},
+        torrent::Info,
+    };
+
+    let data = sample_data(16 * 1024 * 2);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let bogus = Info::single_file("file.bin", 16 * 1024, &vec![0; data.len()]);
+    let liar = seeder.clone().with_bogus_metadata(bogus);
+    let liar_address = liar.listen().await.unwrap();
+    let seeder_address = seeder.listen().await.unwrap();
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&x.pe={}&x.pe={}",
+        hex::encode(seeder.info_hash()),
+        liar_address,
+        seeder_address
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+
+    let metadata = magnet.fetch_metadata().await.unwrap();
+    assert_eq!(metadata.info_hash().unwrap(), seeder.info_hash());
+    assert_eq!(magnet.download().join().await.unwrap(), data);
+}
+
+#[test]
+fn rejects_metadata_for_another_torrent() {
+    use bittorrent_starter_rust::torrent::{Info, Torrent};
+
+    let url = format!(
+        "magnet:?xt=urn:btih:{}&tr=http://tracker.test/announce",
+        INFO_HASH
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    let info = Info::single_file("file.bin", 16 * 1024, &[1; 100]);
+    assert!(Torrent::from_magnet_and_metadata(magnet, info).is_err());
+}
+
+#[tokio::test]
+async fn links_without_a_tracker_need_another_peer_source() {
+    use bittorrent_starter_rust::{
+        torrent::{Info, Torrent},
+        Error,
+    };
+
+    let url = format!("magnet:?xt=urn:btih:{}", INFO_HASH);
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    assert!(matches!(
+        magnet.get_peer_addrs().await,
+        Err(Error::Magnet(_))
+    ));
+
+    let info = Info::single_file("file.bin", 16 * 1024, &[1; 100]);
+    let url = format!(
+        "magnet:?xt=urn:btih:{}",
+        hex::encode(info.info_hash().unwrap())
+    );
+    let magnet = Magnet::new(Url::parse(&url).unwrap()).unwrap();
+    let torrent = Torrent::from_magnet_and_metadata(magnet, info).unwrap();
+    assert!(torrent.tiers().is_empty());
+}
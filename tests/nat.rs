@@ -0,0 +1,26 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    nat::{self, Method},
+    testing::MockGateway,
+};
+
+#[tokio::test]
+async fn maps_ports_over_natpmp() {
+    let gateway = MockGateway::start().await.unwrap();
+    let mapping = nat::natpmp(gateway.natpmp_address(), 6881).await.unwrap();
+    assert_eq!(mapping.method, Method::NatPmp);
+    assert_eq!(mapping.external_port, 16881);
+    assert!(!mapping.lifetime.is_zero());
+    assert_eq!(gateway.mappings(), vec![(6881, 16881)]);
+}
+
+#[tokio::test]
+async fn maps_ports_over_upnp() {
+    let gateway = MockGateway::start().await.unwrap();
+    let mapping = nat::upnp_at(&gateway.description_url(), 6881)
+        .await
+        .unwrap();
+    assert_eq!(mapping.method, Method::Upnp);
+    assert_eq!(mapping.external_port, 6881);
+    assert_eq!(gateway.mappings(), vec![(6881, 6881)]);
+}
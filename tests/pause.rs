@@ -0,0 +1,35 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::DownloadEvent,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn pausing_stops_requests_until_resumed() {
+    let data = sample_data(16 * 1024 * 32);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![seeder.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let mut events = handle.events();
+    while !matches!(
+        events.next().await,
+        Some(DownloadEvent::PieceVerified { .. })
+    ) {}
+    handle.pause();
+    // Pieces already verified may still be on their way in.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let paused_at = handle.progress().pieces_done;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(handle.progress().pieces_done, paused_at);
+    assert!(paused_at < 32);
+    assert!(!handle.is_finished());
+
+    handle.resume();
+    assert_eq!(handle.join().await.unwrap(), data);
+}
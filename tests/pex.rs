@@ -0,0 +1,48 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    testing::{sample_data, MockPeer, MockTracker},
+    torrent::Info,
+};
+use std::time::Duration;
+
+#[tokio::test]
+async fn downloads_from_peers_learned_through_exchange() {
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let seeder_address = seeder.listen().await.unwrap();
+    // The tracker only knows a peer missing half the file, which knows the
+    // seeder.
+    let partial = seeder
+        .clone()
+        .with_pieces([0, 1])
+        .exchanging(vec![seeder_address]);
+    let partial_address = partial.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![partial_address]).await.unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    assert_eq!(tracker.requests().len(), 2); // started and completed
+}
+
+#[tokio::test]
+async fn private_torrents_ignore_exchanged_peers() {
+    let data = sample_data(16 * 1024 * 4);
+    let info = Info::single_file("file.bin", 16 * 1024, &data).with_private(true);
+    let seeder = MockPeer::new(info.clone(), data.clone());
+    let seeder_address = seeder.listen().await.unwrap();
+    // Not a clone, so connections to each are counted apart.
+    let partial = MockPeer::new(info, data)
+        .with_pieces([0, 1])
+        .exchanging(vec![seeder_address]);
+    let partial_address = partial.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![partial_address]).await.unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    // The missing pieces only come from the seeder, which nobody may dial.
+    let download = torrent.download().join();
+    assert!(tokio::time::timeout(Duration::from_secs(3), download)
+        .await
+        .is_err());
+    assert_eq!(seeder.connections(), 0);
+    assert!(partial.connections() > 0);
+}
@@ -0,0 +1,67 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::DownloadEvent,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn reports_pieces_that_fail_verification() {
+    let data = sample_data(16 * 1024 * 16);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let corrupt = (0..16).fold(seeder.clone(), |peer, piece| peer.with_corrupt_piece(piece));
+    let seeder_address = seeder.listen().await.unwrap();
+    let corrupt_address = corrupt.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![corrupt_address, seeder_address])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let events = handle.events();
+    let monitor = handle.monitor();
+    assert_eq!(handle.join().await.unwrap(), data);
+
+    let events: Vec<_> = events.collect().await;
+    let failed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DownloadEvent::PieceFailed { peer, .. } => Some(*peer),
+            _ => None,
+        })
+        .collect();
+    assert!(!failed.is_empty());
+    assert_eq!(monitor.progress().pieces_failed, failed.len() as u64);
+    assert!(failed.iter().all(|peer| *peer == corrupt_address));
+    assert!(events.iter().all(|event| !matches!(
+        event,
+        DownloadEvent::PieceVerified { peer, .. } if *peer == corrupt_address
+    )));
+}
+
+#[tokio::test]
+async fn bans_a_peer_that_keeps_sending_bad_pieces() {
+    let data = sample_data(16 * 1024 * 32);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let corrupt = (0..32).fold(seeder.clone(), |peer, piece| peer.with_corrupt_piece(piece));
+    let seeder_address = seeder.listen().await.unwrap();
+    let corrupt_address = corrupt.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![corrupt_address, seeder_address])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let events = handle.events();
+    assert_eq!(handle.join().await.unwrap(), data);
+
+    let events: Vec<_> = events.collect().await;
+    let banned: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DownloadEvent::PeerBanned(peer) => Some(*peer),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(banned, vec![corrupt_address]);
+}
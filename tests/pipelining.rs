@@ -0,0 +1,201 @@
+use bittorrent_starter_rust::peer::{self, Handshake, Peer};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time,
+};
+
+const BLOCK: usize = 16 * 1024;
+const PIECE_LENGTH: usize = 8 * BLOCK;
+const INFO_HASH: [u8; 20] = [3; 20];
+const QUEUE_DEPTH: usize = 5;
+
+fn block(index: u32, begin: u32) -> Vec<u8> {
+    (0..BLOCK)
+        .map(|i| (index as usize * 7 + begin as usize + i) as u8)
+        .collect()
+}
+
+async fn write_message(stream: &mut DuplexStream, id: u8, payload: &[u8]) {
+    let length = (payload.len() + 1) as u32;
+    let message = [&length.to_be_bytes()[..], &[id], payload].concat();
+    stream.write_all(&message).await.unwrap();
+}
+
+async fn read_message(stream: &mut DuplexStream) -> (u8, Vec<u8>) {
+    let length = stream.read_u32().await.unwrap() as usize;
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await.unwrap();
+    let payload = message.split_off(1);
+    (message[0], payload)
+}
+
+async fn read_request(stream: &mut DuplexStream) -> (u32, u32) {
+    let (id, payload) = read_message(stream).await;
+    assert_eq!(id, 6);
+    let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+    assert_eq!(field(8), BLOCK as u32);
+    (field(0), field(4))
+}
+
+async fn send_block(stream: &mut DuplexStream, (index, begin): (u32, u32)) {
+    let payload = [
+        &index.to_be_bytes()[..],
+        &begin.to_be_bytes(),
+        &block(index, begin),
+    ]
+    .concat();
+    write_message(stream, 7, &payload).await;
+}
+
+// A plain peer that has every piece and has unchoked us, with the other end
+// of the connection left to the test.
+async fn unchoked_peer() -> (Peer, DuplexStream) {
+    peer::set_queue_depth(QUEUE_DEPTH);
+    let (ours, mut theirs) = tokio::io::duplex(1 << 20);
+    let seeder = tokio::spawn(async move {
+        let mut handshake = [0u8; 68];
+        theirs.read_exact(&mut handshake).await.unwrap();
+        let mut reply = Handshake::new(INFO_HASH, *b"-MK0001-remote000000");
+        reply.reserved = [0; 8];
+        theirs.write_all(&reply.to_bytes().unwrap()).await.unwrap();
+        write_message(&mut theirs, 5, &[0xff]).await;
+        assert_eq!(read_message(&mut theirs).await.0, 2);
+        write_message(&mut theirs, 1, &[]).await;
+        theirs
+    });
+    let mut peer = Peer::connect_stream(ours, "127.0.0.1:6881".parse().unwrap(), INFO_HASH)
+        .await
+        .unwrap();
+    assert_eq!(peer.get_pieces().await.unwrap().len(), 8);
+    peer.prepare_download().await.unwrap();
+    (peer, seeder.await.unwrap())
+}
+
+#[tokio::test]
+async fn keeps_the_queue_depth_of_requests_outstanding() {
+    let (mut peer, mut theirs) = unchoked_peer().await;
+    let load = tokio::spawn(async move { peer.load_piece(1, PIECE_LENGTH as u32).await });
+
+    let mut requests = Vec::new();
+    for _ in 0..QUEUE_DEPTH {
+        requests.push(read_request(&mut theirs).await);
+    }
+    // Nothing more until a block arrives.
+    assert!(time::timeout(Duration::from_millis(100), theirs.read_u8())
+        .await
+        .is_err());
+    // Answered out of order, blocks still land where they belong.
+    for &request in requests.iter().rev() {
+        send_block(&mut theirs, request).await;
+    }
+    for _ in QUEUE_DEPTH..PIECE_LENGTH / BLOCK {
+        let request = read_request(&mut theirs).await;
+        send_block(&mut theirs, request).await;
+    }
+
+    let piece = load.await.unwrap().unwrap();
+    let expected: Vec<u8> = (0..PIECE_LENGTH as u32)
+        .step_by(BLOCK)
+        .flat_map(|begin| block(1, begin))
+        .collect();
+    assert_eq!(piece, expected);
+}
+
+#[tokio::test]
+async fn pieces_loading_together_share_the_connection() {
+    let (peer, mut theirs) = unchoked_peer().await;
+    let loads: Vec<_> = [2, 5]
+        .into_iter()
+        .map(|index| {
+            let mut peer = peer.clone();
+            tokio::spawn(async move { peer.load_piece(index, PIECE_LENGTH as u32).await })
+        })
+        .collect();
+
+    // The two pieces share one queue; answer newest first until both are done.
+    let mut pending = Vec::new();
+    for _ in 0..2 * PIECE_LENGTH / BLOCK {
+        while pending.len() < QUEUE_DEPTH {
+            match time::timeout(Duration::from_millis(100), read_request(&mut theirs)).await {
+                Ok(request) => pending.push(request),
+                Err(_) => break,
+            }
+        }
+        assert!(pending.len() <= QUEUE_DEPTH);
+        let request = pending.pop().unwrap();
+        send_block(&mut theirs, request).await;
+    }
+
+    for (load, index) in loads.into_iter().zip([2, 5]) {
+        let piece = load.await.unwrap().unwrap();
+        assert_eq!(piece[..BLOCK], block(index, 0));
+        assert_eq!(
+            piece[PIECE_LENGTH - BLOCK..],
+            block(index, (PIECE_LENGTH - BLOCK) as u32)
+        );
+    }
+}
+
+#[tokio::test]
+async fn requests_again_after_being_choked_mid_piece() {
+    let (mut peer, mut theirs) = unchoked_peer().await;
+    let load = tokio::spawn(async move { peer.load_piece(4, PIECE_LENGTH as u32).await });
+
+    let mut outstanding = Vec::new();
+    for _ in 0..QUEUE_DEPTH {
+        outstanding.push(read_request(&mut theirs).await);
+    }
+    for _ in 0..2 {
+        let request = outstanding.remove(0);
+        send_block(&mut theirs, request).await;
+        outstanding.push(read_request(&mut theirs).await);
+    }
+    // Choking drops what was outstanding; nothing is asked while choked.
+    write_message(&mut theirs, 0, &[]).await;
+    assert!(time::timeout(Duration::from_millis(100), theirs.read_u8())
+        .await
+        .is_err());
+
+    write_message(&mut theirs, 1, &[]).await;
+    let mut reissued = Vec::new();
+    for _ in 0..QUEUE_DEPTH {
+        reissued.push(read_request(&mut theirs).await);
+    }
+    reissued.sort();
+    outstanding.sort();
+    assert_eq!(reissued, outstanding);
+    for request in reissued {
+        send_block(&mut theirs, request).await;
+    }
+    let last = read_request(&mut theirs).await;
+    send_block(&mut theirs, last).await;
+
+    let piece = load.await.unwrap().unwrap();
+    let expected: Vec<u8> = (0..PIECE_LENGTH as u32)
+        .step_by(BLOCK)
+        .flat_map(|begin| block(4, begin))
+        .collect();
+    assert_eq!(piece, expected);
+}
+
+#[tokio::test]
+async fn cancels_requests_of_an_abandoned_piece() {
+    let (mut peer, mut theirs) = unchoked_peer().await;
+    let load = tokio::spawn(async move { peer.load_piece(6, PIECE_LENGTH as u32).await });
+
+    let mut requests = Vec::new();
+    for _ in 0..QUEUE_DEPTH {
+        requests.push(read_request(&mut theirs).await);
+    }
+    load.abort();
+    let mut cancelled = Vec::new();
+    for _ in 0..QUEUE_DEPTH {
+        let (id, payload) = read_message(&mut theirs).await;
+        assert_eq!(id, 8);
+        let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap());
+        cancelled.push((field(0), field(4)));
+    }
+    cancelled.sort();
+    assert_eq!(cancelled, requests);
+}
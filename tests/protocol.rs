@@ -0,0 +1,99 @@
+use bittorrent_starter_rust::{
+    peer::Peer,
+    testing::{sample_data, MockPeer},
+    Error,
+};
+use std::net::SocketAddr;
+
+const PIECE_LENGTH: u64 = 32 * 1024;
+
+fn mock_address() -> SocketAddr {
+    "127.0.0.1:6881".parse().unwrap()
+}
+
+async fn connect(mock: &MockPeer) -> Peer {
+    Peer::connect_stream(mock.connect(), mock_address(), mock.info_hash())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn handshake_exchanges_peer_ids() {
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, sample_data(50_000));
+    let peer = connect(&mock).await;
+    assert_eq!(peer.id, mock.peer_id());
+    assert_eq!(peer.info_hash, mock.info_hash());
+    assert!(peer.supports_extension);
+}
+
+#[tokio::test]
+async fn handshake_with_wrong_info_hash_fails() {
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, sample_data(50_000));
+    let result = Peer::connect_stream(mock.connect(), mock_address(), [0u8; 20]).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn bitfield_lists_available_pieces() {
+    let data = sample_data(PIECE_LENGTH as usize * 9 + 10);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data).with_pieces([0, 3, 9]);
+    let mut peer = connect(&mock).await;
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![0, 3, 9]);
+}
+
+#[tokio::test]
+async fn downloads_piece_blocks() {
+    let data = sample_data(PIECE_LENGTH as usize * 2 + 1234);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let mut peer = connect(&mock).await;
+    peer.get_pieces().await.unwrap();
+    peer.prepare_download().await.unwrap();
+
+    let info = mock.info();
+    for index in 0..info.pieces().len() {
+        let piece = peer
+            .load_piece(index as u32, info.piece_len(index))
+            .await
+            .unwrap();
+        let start = index * PIECE_LENGTH as usize;
+        assert_eq!(piece, data[start..start + piece.len()]);
+    }
+}
+
+#[tokio::test]
+async fn fetches_metadata_over_ut_metadata() {
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, sample_data(70_000));
+    let mut peer = connect(&mock).await;
+    peer.get_pieces().await.unwrap();
+    peer.extension_handshake().await.unwrap();
+    assert!(peer.metadata_extension_id.is_some());
+
+    let info = peer.extension_metadata().await.unwrap();
+    assert_eq!(info.info_hash().unwrap(), mock.info_hash());
+    assert_eq!(info.pieces(), mock.info().pieces());
+}
+
+#[tokio::test]
+async fn fetches_metadata_spanning_several_pieces() {
+    // 1000 piece hashes make for more than 16 KiB of metadata.
+    let mock = MockPeer::seeding("sample.bin", 1024, sample_data(1024 * 1000));
+    let mut peer = connect(&mock).await;
+    peer.get_pieces().await.unwrap();
+    peer.extension_handshake().await.unwrap();
+
+    let info = peer.extension_metadata().await.unwrap();
+    assert_eq!(info.info_hash().unwrap(), mock.info_hash());
+    assert_eq!(info.pieces().len(), 1000);
+}
+
+#[tokio::test]
+async fn metadata_requires_peer_support() {
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, sample_data(1000)).without_metadata();
+    let mut peer = connect(&mock).await;
+    peer.get_pieces().await.unwrap();
+    peer.extension_handshake().await.unwrap();
+    assert!(matches!(
+        peer.extension_metadata().await,
+        Err(Error::Metadata(_))
+    ));
+}
@@ -0,0 +1,41 @@
+use bittorrent_starter_rust::{ratelimit::RateLimiter, testing::sample_data};
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn waits_out_what_the_bucket_lacks() {
+    let limiter = RateLimiter::new(100_000);
+    let started = Instant::now();
+    // A full bucket lets a second's worth through at once.
+    limiter.acquire(100_000).await;
+    assert!(started.elapsed() < Duration::from_millis(100));
+    limiter.acquire(50_000).await;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+
+    limiter.set_rate(None);
+    assert_eq!(limiter.rate(), None);
+    let started = Instant::now();
+    limiter.acquire(u64::MAX).await;
+    assert!(started.elapsed() < Duration::from_millis(100));
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn caps_a_download_at_its_limit() {
+    use bittorrent_starter_rust::testing::{MockPeer, MockTracker};
+
+    let data = sample_data(16 * 1024 * 32);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![seeder.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let started = Instant::now();
+    let handle = torrent.download();
+    handle.set_download_limit(Some(256 * 1024));
+    assert_eq!(handle.join().await.unwrap(), data);
+    // Half the data fits the first second's bucket, the rest waits.
+    assert!(started.elapsed() >= Duration::from_millis(800));
+}
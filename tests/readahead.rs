@@ -0,0 +1,31 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::testing::{sample_data, MockPeer, MockTracker};
+use tokio_stream::StreamExt;
+
+const PIECE_LENGTH: usize = 16 * 1024;
+
+#[tokio::test]
+async fn streams_from_the_playback_position() {
+    let data = sample_data(PIECE_LENGTH * 8);
+    let mock = MockPeer::seeding("movie.mkv", PIECE_LENGTH as u64, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let mut stream = torrent.piece_stream();
+    stream.set_readahead(2 * PIECE_LENGTH as u64);
+    stream.seek(5 * PIECE_LENGTH as u64 + 100);
+    assert_eq!(stream.readahead().position, 5 * PIECE_LENGTH as u64 + 100);
+
+    let mut order = Vec::new();
+    while let Some(piece) = stream.next().await {
+        let (index, bytes) = piece.unwrap();
+        assert_eq!(
+            bytes,
+            data[index * PIECE_LENGTH..(index + 1) * PIECE_LENGTH]
+        );
+        order.push(index);
+    }
+    // Skipped pieces still arrive, after the end of the file.
+    assert_eq!(order, vec![5, 6, 7, 0, 1, 2, 3, 4]);
+}
@@ -0,0 +1,27 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::DownloadEvent,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn picks_up_peers_from_later_announces() {
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let partial = seeder.clone().with_pieces([0, 1]);
+    let partial_address = partial.listen().await.unwrap();
+    let seeder_address = seeder.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![partial_address]).await.unwrap();
+    tracker.set_interval(1);
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let mut events = handle.events();
+    while !matches!(events.next().await, Some(DownloadEvent::Announced { .. })) {}
+    // Only the second announce finds someone with the last two pieces.
+    tracker.set_peers(vec![partial_address, seeder_address]);
+
+    assert_eq!(handle.join().await.unwrap(), data);
+    assert!(tracker.requests().len() >= 2);
+}
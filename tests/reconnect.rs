@@ -0,0 +1,30 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::DownloadEvent,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn reconnects_to_dropped_peers() {
+    let data = sample_data(16 * 1024 * 8);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone()).dropping_after(3);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let events = handle.events();
+    assert_eq!(handle.join().await.unwrap(), data);
+    assert_eq!(mock.connections(), 2);
+
+    let events: Vec<_> = events.collect().await;
+    let connects = events
+        .iter()
+        .filter(|event| matches!(event, DownloadEvent::PeerConnected(peer) if *peer == address))
+        .count();
+    assert_eq!(connects, 2);
+    assert!(!events
+        .iter()
+        .any(|event| matches!(event, DownloadEvent::PeerDisconnected(_))));
+}
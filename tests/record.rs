@@ -0,0 +1,39 @@
+use bittorrent_starter_rust::{
+    peer::Peer,
+    record::{self, Direction, ReplayStream},
+    testing::{sample_data, MockPeer},
+};
+
+#[tokio::test]
+async fn recorded_session_replays_through_peer() {
+    let data = sample_data(40_000);
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, data.clone()).with_pieces([0, 2]);
+    let address = "127.0.0.1:6881".parse().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("session.log");
+
+    record::start(&log).unwrap();
+    let mut peer = Peer::connect_stream(mock.connect(), address, mock.info_hash())
+        .await
+        .unwrap();
+    let pieces = peer.get_pieces().await.unwrap();
+    peer.prepare_download().await.unwrap();
+    let piece = peer.load_piece(2, mock.info().piece_len(2)).await.unwrap();
+    record::stop().unwrap();
+    assert_eq!(piece, data[32 * 1024..]);
+
+    let records = record::read_log(&log).unwrap();
+    assert_eq!(record::peers(&records), vec![address]);
+    assert_eq!(records[0].direction, Direction::Sent);
+    assert_eq!(records[0].describe(), "handshake");
+    assert_eq!(records[1].info_hash(), Some(mock.info_hash()));
+
+    let stream = ReplayStream::new(&records, address);
+    let mut replayed = Peer::connect_stream(stream, address, mock.info_hash())
+        .await
+        .unwrap();
+    assert_eq!(replayed.id, mock.peer_id());
+    assert_eq!(replayed.get_pieces().await.unwrap(), pieces);
+    replayed.prepare_download().await.unwrap();
+    assert_eq!(replayed.replay().await.unwrap(), 1);
+}
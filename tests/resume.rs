@@ -0,0 +1,121 @@
+use bittorrent_starter_rust::{resume::ResumeFile, testing::sample_data, torrent::Info};
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+#[test]
+fn round_trips_resume_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let info = Info::single_file(
+        "sample.bin",
+        PIECE_LENGTH,
+        &sample_data(PIECE_LENGTH as usize * 3 + 100),
+    );
+    let mut resume = ResumeFile::new(&info).unwrap();
+    resume.set_have([0, 2], 4);
+    resume.add_piece_from("127.0.0.1:6881".parse().unwrap());
+    resume.add_piece_from("127.0.0.1:6881".parse().unwrap());
+    resume.downloaded = 12_345;
+
+    let path = ResumeFile::path_for(&dir.path().join("sample.bin"));
+    resume.write(&path).unwrap();
+    let read = ResumeFile::read(&path).unwrap();
+    assert_eq!(read, resume);
+    assert_eq!(read.have(), vec![0, 2]);
+    assert_eq!(read.peers[0].pieces, 2);
+    assert!(read.matches(&info));
+    let other = Info::single_file(
+        "other.bin",
+        PIECE_LENGTH,
+        &sample_data(PIECE_LENGTH as usize * 3 + 100),
+    );
+    assert!(!read.matches(&other));
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn trusts_pieces_the_resume_file_lists() {
+    use bittorrent_starter_rust::testing::{MockPeer, MockTracker};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone()).with_pieces([1, 3]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    // Pieces 0 and 2 are listed, so they are not hash-checked: the damage to
+    // piece 0 goes unnoticed.
+    let mut on_disk = data.clone();
+    on_disk[7] ^= 0xff;
+    on_disk[PIECE_LENGTH as usize..PIECE_LENGTH as usize * 2].fill(0);
+    on_disk[PIECE_LENGTH as usize * 3..].fill(0);
+    std::fs::write(&path, &on_disk).unwrap();
+    let resume_path = ResumeFile::path_for(&path);
+    let mut resume = ResumeFile::new(&torrent.info).unwrap();
+    resume.set_have([0, 2], 4);
+    resume.downloaded = 1000;
+    resume.write(&resume_path).unwrap();
+
+    torrent
+        .download_with_resume(path.clone())
+        .join()
+        .await
+        .unwrap();
+    let downloaded = std::fs::read(&path).unwrap();
+    assert_eq!(downloaded[7], data[7] ^ 0xff);
+    assert_eq!(
+        downloaded[PIECE_LENGTH as usize..],
+        data[PIECE_LENGTH as usize..]
+    );
+
+    let resume = ResumeFile::read(&resume_path).unwrap();
+    assert_eq!(resume.have(), vec![0, 1, 2, 3]);
+    assert_eq!(resume.downloaded, 1000 + PIECE_LENGTH + 100);
+    assert_eq!(resume.peers.len(), 1);
+    assert_eq!(resume.peers[0].pieces, 2);
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn saves_progress_when_cancelled() {
+    use bittorrent_starter_rust::{
+        download::DownloadEvent,
+        testing::{MockPeer, MockTracker},
+    };
+    use tokio_stream::StreamExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    // Nobody has pieces 2 and 3, so the download never finishes.
+    let mock = MockPeer::seeding(
+        "sample.bin",
+        PIECE_LENGTH,
+        sample_data(PIECE_LENGTH as usize * 3 + 100),
+    )
+    .with_pieces([0, 1]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let handle = torrent.download_with_resume(path.clone());
+    let verified = handle
+        .events()
+        .filter(|event| matches!(event, DownloadEvent::PieceVerified { .. }))
+        .take(2)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(verified.len(), 2);
+    handle.cancel();
+    assert!(matches!(
+        handle.join().await,
+        Err(bittorrent_starter_rust::Error::Cancelled)
+    ));
+
+    let resume = ResumeFile::read(&ResumeFile::path_for(&path)).unwrap();
+    assert_eq!(resume.have(), vec![0, 1]);
+    assert!(tracker
+        .requests()
+        .last()
+        .is_some_and(|query| query.contains("event=stopped")));
+}
@@ -0,0 +1,78 @@
+#![cfg(feature = "rss")]
+
+use bittorrent_starter_rust::rss::{parse_feed, FeedConfig, FeedItem};
+use regex::Regex;
+
+const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0">
+  <channel>
+    <title>Releases</title>
+    <item>
+      <title>Show S01E01 1080p</title>
+      <link>https://example.com/show-s01e01</link>
+      <enclosure url="https://example.com/show-s01e01.torrent" type="application/x-bittorrent"/>
+    </item>
+    <item>
+      <title>Other Show S02E05 720p</title>
+      <link>magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f</link>
+    </item>
+  </channel>
+</rss>"#;
+
+const ATOM: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Releases</title>
+  <entry>
+    <title>Album (FLAC)</title>
+    <link href="https://example.com/album"/>
+    <link rel="enclosure" href="https://example.com/album.torrent"/>
+  </entry>
+</feed>"#;
+
+#[test]
+fn parses_rss_items_preferring_enclosures() {
+    assert_eq!(
+        parse_feed(RSS).unwrap(),
+        vec![
+            FeedItem {
+                title: "Show S01E01 1080p".to_string(),
+                link: "https://example.com/show-s01e01.torrent".to_string(),
+            },
+            FeedItem {
+                title: "Other Show S02E05 720p".to_string(),
+                link: "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f".to_string(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn parses_atom_entries() {
+    assert_eq!(
+        parse_feed(ATOM).unwrap(),
+        vec![FeedItem {
+            title: "Album (FLAC)".to_string(),
+            link: "https://example.com/album.torrent".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn rejects_unknown_documents() {
+    assert!(parse_feed("<html></html>").is_err());
+    assert!(parse_feed("not xml").is_err());
+}
+
+#[test]
+fn filters_match_titles() {
+    let mut feed = FeedConfig {
+        url: "https://example.com/feed".parse().unwrap(),
+        filters: vec![],
+        download_dir: "downloads".into(),
+    };
+    assert!(feed.matches("anything"));
+
+    feed.filters = vec![Regex::new(r"^Show S\d+E\d+ 1080p$").unwrap()];
+    assert!(feed.matches("Show S01E01 1080p"));
+    assert!(!feed.matches("Other Show S02E05 720p"));
+}
@@ -0,0 +1,42 @@
+use bittorrent_starter_rust::selection::{choose, Rating};
+
+fn rating(rate: Option<u64>, assigned: usize, timeouts: u32) -> Rating {
+    Rating {
+        rate,
+        assigned,
+        timeouts,
+    }
+}
+
+#[test]
+fn prefers_the_fastest_source() {
+    let ratings = [rating(Some(100), 0, 0), rating(Some(5000), 0, 0)];
+    assert_eq!(choose(&ratings), Some(1));
+}
+
+#[test]
+fn shares_a_fast_source_among_its_pieces() {
+    // Half the rate of the first, but it has nothing else to do.
+    let ratings = [rating(Some(1000), 3, 0), rating(Some(500), 0, 0)];
+    assert_eq!(choose(&ratings), Some(1));
+}
+
+#[test]
+fn demotes_sources_that_time_out() {
+    let ratings = [rating(Some(1000), 0, 2), rating(Some(400), 0, 0)];
+    assert_eq!(choose(&ratings), Some(1));
+}
+
+#[test]
+fn tries_sources_not_measured_yet() {
+    // Rated like the best measured one, and less busy.
+    let ratings = [rating(Some(1000), 1, 0), rating(None, 0, 0)];
+    assert_eq!(choose(&ratings), Some(1));
+}
+
+#[test]
+fn spreads_pieces_before_anything_is_measured() {
+    let ratings = [rating(None, 2, 0), rating(None, 0, 0), rating(None, 1, 0)];
+    assert_eq!(choose(&ratings), Some(1));
+    assert_eq!(choose(&[]), None);
+}
@@ -0,0 +1,39 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::{DownloadEvent, PieceOrder, Sequential},
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::collections::{BTreeSet, HashSet};
+use tokio_stream::StreamExt;
+
+const PIECE_LENGTH: usize = 16 * 1024;
+
+#[test]
+fn sequential_order_fills_its_window_from_the_lowest_piece() {
+    let order = Sequential { window: 3 };
+    let pending: BTreeSet<usize> = [2, 4, 5, 7, 9].into();
+    assert_eq!(order.next(&pending, &HashSet::new()), vec![2, 4, 5]);
+    assert_eq!(order.next(&pending, &[0, 1].into()), vec![2]);
+    assert!(order.next(&pending, &[0, 1, 3].into()).is_empty());
+}
+
+#[tokio::test]
+async fn downloads_pieces_in_index_order() {
+    let data = sample_data(PIECE_LENGTH * 8);
+    let mock = MockPeer::seeding("movie.mkv", PIECE_LENGTH as u64, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let handle = torrent.download_ordered(Sequential { window: 1 });
+    let events = handle.events();
+    assert_eq!(handle.join().await.unwrap(), data);
+    let verified: Vec<usize> = events
+        .filter_map(|event| match event {
+            DownloadEvent::PieceVerified { index, .. } => Some(index),
+            _ => None,
+        })
+        .collect()
+        .await;
+    assert_eq!(verified, (0..8).collect::<Vec<_>>());
+}
@@ -0,0 +1,173 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    session::{Session, SessionConfig},
+    source::Source,
+    testing::{sample_data, MockPeer, MockTracker},
+};
+
+#[tokio::test]
+async fn runs_several_torrents_with_one_peer_id() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionConfig {
+        max_active: Some(1),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    let mut swarms = Vec::new();
+    for name in ["first.bin", "second.bin"] {
+        let data = sample_data(16 * 1024 * 3 + 100);
+        let seeder = MockPeer::seeding(name, 16 * 1024, data.clone());
+        let tracker = MockTracker::start(vec![seeder.listen().await.unwrap()])
+            .await
+            .unwrap();
+        let torrent = seeder.torrent(&tracker.announce_url());
+        let handle = session
+            .add_torrent(&torrent, dir.path().join(name))
+            .unwrap();
+        assert!(session
+            .add_torrent(&torrent, dir.path().join(name))
+            .is_err());
+        swarms.push((name, data, tracker, handle));
+    }
+    assert_eq!(session.torrents().len(), 2);
+
+    for (name, data, tracker, handle) in swarms {
+        handle.join().await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join(name)).unwrap(), data);
+        let peer_id = format!("peer_id={}", session.peer_id());
+        assert!(tracker
+            .requests()
+            .iter()
+            .all(|query| query.contains(&peer_id)));
+    }
+}
+
+#[tokio::test]
+async fn removing_a_queued_torrent_cancels_it() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionConfig {
+        max_active: Some(1),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    // Never answers block requests, so it holds the only slot.
+    let stalled = MockPeer::seeding("stalled.bin", 16 * 1024, sample_data(16 * 1024 * 3 + 100))
+        .unresponsive();
+    let tracker = MockTracker::start(vec![stalled.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let first = session
+        .add_torrent(
+            &stalled.torrent(&tracker.announce_url()),
+            dir.path().join("a"),
+        )
+        .unwrap();
+    let queued = MockPeer::seeding("queued.bin", 16 * 1024, sample_data(16 * 1024 * 3 + 100));
+    let torrent = queued.torrent(&tracker.announce_url());
+    let second = session.add_torrent(&torrent, dir.path().join("b")).unwrap();
+
+    assert!(session.remove(&torrent.info_hash().unwrap()));
+    assert!(matches!(
+        second.join().await,
+        Err(bittorrent_starter_rust::Error::Cancelled)
+    ));
+    assert_eq!(queued.connections(), 0);
+    session.shutdown();
+    assert!(matches!(
+        first.join().await,
+        Err(bittorrent_starter_rust::Error::Cancelled)
+    ));
+    assert!(session.torrents().is_empty());
+}
+
+#[tokio::test]
+async fn closing_waits_for_trackers_to_hear_we_stopped() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionConfig::default()).await.unwrap();
+    let stalled = MockPeer::seeding("stalled.bin", 16 * 1024, sample_data(16 * 1024 * 3 + 100))
+        .unresponsive();
+    let tracker = MockTracker::start(vec![stalled.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let handle = session
+        .add_torrent(
+            &stalled.torrent(&tracker.announce_url()),
+            dir.path().join("a"),
+        )
+        .unwrap();
+    while stalled.connections() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    session.close().await;
+    assert!(handle.is_finished());
+    assert!(tracker
+        .requests()
+        .last()
+        .is_some_and(|query| query.contains("event=stopped")));
+}
+
+#[tokio::test]
+async fn sessions_side_by_side_keep_to_themselves() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut sessions = Vec::new();
+    let mut handles = Vec::new();
+    for name in ["left.bin", "right.bin"] {
+        let session = Session::new(SessionConfig {
+            listen: Some("127.0.0.1:0".parse().unwrap()),
+            max_peers: Some(2),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let data = sample_data(16 * 1024 * 3 + 100);
+        let seeder = MockPeer::seeding(name, 16 * 1024, data.clone());
+        let tracker = MockTracker::start(vec![seeder.listen().await.unwrap()])
+            .await
+            .unwrap();
+        let handle = session
+            .add_torrent(
+                &seeder.torrent(&tracker.announce_url()),
+                dir.path().join(name),
+            )
+            .unwrap();
+        sessions.push((session, seeder, tracker));
+        handles.push(handle);
+    }
+    assert_ne!(sessions[0].0.peer_id(), sessions[1].0.peer_id());
+
+    for ((session, seeder, tracker), handle) in sessions.iter().zip(handles) {
+        handle.join().await.unwrap();
+        // Each session introduces itself, and announces its own listener.
+        let peer_id: [u8; 20] = session.peer_id().as_bytes().try_into().unwrap();
+        assert_eq!(seeder.client_ids(), vec![peer_id]);
+        let port = format!("port={}", session.listen_addr().unwrap().port());
+        assert!(tracker.requests().iter().all(|query| query.contains(&port)));
+    }
+
+    // Shutting one session down leaves the other listening.
+    sessions[0].0.shutdown();
+    let right = sessions[1].0.listen_addr().unwrap();
+    assert!(tokio::net::TcpStream::connect(right).await.is_ok());
+}
+
+#[tokio::test]
+async fn adds_sources_to_a_given_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let session = Session::new(SessionConfig::default()).await.unwrap();
+    let data = sample_data(16 * 1024 * 3 + 100);
+    let seeder = MockPeer::seeding("episode.bin", 16 * 1024, data.clone());
+    let tracker = MockTracker::start(vec![seeder.listen().await.unwrap()])
+        .await
+        .unwrap();
+    let magnet = seeder.torrent(&tracker.announce_url()).magnet().unwrap();
+    let source = Source::MagnetUri(magnet.to_url());
+
+    let (info_hash, handle) = session.add_source_in(&source, dir.path()).await.unwrap();
+    assert_eq!(info_hash, seeder.info_hash());
+    handle.join().await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join("episode.bin")).unwrap(), data);
+}
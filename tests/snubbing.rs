@@ -0,0 +1,36 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    download::{self, DownloadEvent},
+    testing::{sample_data, MockPeer, MockTracker},
+};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+#[tokio::test]
+async fn reassigns_pieces_from_snubbing_peers() {
+    download::set_snub_timeout(Duration::from_millis(300));
+    let data = sample_data(16 * 1024 * 16);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let stalled = seeder.clone().unresponsive();
+    let seeder_address = seeder.listen().await.unwrap();
+    let stalled_address = stalled.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![stalled_address, seeder_address])
+        .await
+        .unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    let handle = torrent.download();
+    let events = handle.events();
+    assert_eq!(handle.join().await.unwrap(), data);
+
+    let events: Vec<_> = events.collect().await;
+    let snubbed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            DownloadEvent::PeerSnubbed(peer) => Some(*peer),
+            _ => None,
+        })
+        .collect();
+    assert!(!snubbed.is_empty());
+    assert!(snubbed.iter().all(|peer| *peer == stalled_address));
+}
@@ -0,0 +1,143 @@
+use bittorrent_starter_rust::{
+    error::Error,
+    peer::Peer,
+    storage::{PieceReader, PieceStore, PieceWriter},
+    testing::{sample_data, MockPeer},
+    torrent::Info,
+};
+use bytes::Bytes;
+use std::net::SocketAddr;
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+#[tokio::test]
+async fn refuses_pieces_corrupted_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    let info = Info::single_file("sample.bin", PIECE_LENGTH, &data);
+    let mut on_disk = data.clone();
+    on_disk[PIECE_LENGTH as usize + 5] ^= 0xff;
+    std::fs::write(&path, &on_disk).unwrap();
+
+    let reader = PieceReader::new(info.clone(), path.clone());
+    assert_eq!(
+        reader.read_block(0, 1024, 1024).await.unwrap(),
+        &data[1024..2048]
+    );
+    assert!(reader.read_block(1, 0, 1024).await.is_err());
+    // The failure is remembered, even for blocks that look fine on their own.
+    assert!(reader.read_block(1, 8192, 1024).await.is_err());
+    assert!(reader.read_block(3, 0, 101).await.is_err());
+
+    let unverified = PieceReader::new(info, path).verify(false);
+    assert_eq!(
+        unverified.read_block(1, 0, 16).await.unwrap(),
+        &on_disk[PIECE_LENGTH as usize..PIECE_LENGTH as usize + 16]
+    );
+}
+
+#[tokio::test]
+async fn seeds_intact_pieces_from_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    std::fs::write(&path, &data).unwrap();
+    let info = Info::single_file("sample.bin", PIECE_LENGTH, &data);
+
+    let mock = MockPeer::from_storage(PieceReader::new(info, path));
+    let address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+    let mut peer = Peer::connect_stream(mock.connect(), address, mock.info_hash())
+        .await
+        .unwrap();
+    peer.get_pieces().await.unwrap();
+    peer.prepare_download().await.unwrap();
+    let piece = peer.load_piece(3, 100).await.unwrap();
+    assert_eq!(piece, &data[PIECE_LENGTH as usize * 3..]);
+}
+
+#[tokio::test]
+async fn writes_pieces_in_place_and_serves_them_from_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    let info = Info::single_file("sample.bin", PIECE_LENGTH, &data);
+    let store = PieceStore::default();
+    let mut writer = PieceWriter::create(path.clone(), &info, store.clone())
+        .await
+        .unwrap();
+
+    // Out of order, the way pieces finish.
+    for index in [3, 1] {
+        let start = index * PIECE_LENGTH as usize;
+        let piece = &data[start..(start + PIECE_LENGTH as usize).min(data.len())];
+        store.insert(index, Bytes::copy_from_slice(piece));
+        writer.write_piece(index, piece).await.unwrap();
+    }
+    assert_eq!(store.pieces(), vec![1, 3]);
+    assert_eq!(
+        store.read_block(1, 16, 16).await.unwrap(),
+        &data[PIECE_LENGTH as usize + 16..PIECE_LENGTH as usize + 32]
+    );
+    assert!(store.read_block(0, 0, 16).await.is_err());
+    writer.finish().await.unwrap();
+
+    let on_disk = std::fs::read(&path).unwrap();
+    assert_eq!(on_disk.len(), data.len());
+    assert_eq!(
+        on_disk[PIECE_LENGTH as usize * 3..],
+        data[PIECE_LENGTH as usize * 3..]
+    );
+}
+
+#[tokio::test]
+async fn reports_disk_failures_as_storage_errors() {
+    let dir = tempfile::tempdir().unwrap();
+    let data = sample_data(PIECE_LENGTH as usize * 2);
+    let info = Info::single_file("sample.bin", PIECE_LENGTH, &data);
+    // A file where a directory should be.
+    let blocked = dir.path().join("file").join("sample.bin");
+    std::fs::write(dir.path().join("file"), b"").unwrap();
+    assert!(matches!(
+        PieceWriter::create(blocked, &info, PieceStore::default()).await,
+        Err(Error::Storage(_))
+    ));
+
+    let path = dir.path().join("sample.bin");
+    let store = PieceStore::default();
+    let mut writer = PieceWriter::create(path.clone(), &info, store.clone())
+        .await
+        .unwrap();
+    writer
+        .write_piece(0, &data[..PIECE_LENGTH as usize])
+        .await
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(
+        store.read_block(0, 0, 16).await,
+        Err(Error::Storage(_))
+    ));
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn resumes_by_fetching_only_missing_and_corrupt_pieces() {
+    use bittorrent_starter_rust::testing::MockTracker;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 100);
+    // The peer can't supply the pieces that survived on disk.
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone()).with_pieces([1, 3]);
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    // Interrupted partway through the last piece, with the second corrupt.
+    let mut partial = data[..PIECE_LENGTH as usize * 3 + 50].to_vec();
+    partial[PIECE_LENGTH as usize + 5] ^= 0xff;
+    std::fs::write(&path, partial).unwrap();
+
+    torrent.download_to(path.clone()).join().await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
@@ -0,0 +1,102 @@
+use bittorrent_starter_rust::{
+    peer::Peer,
+    testing::{sample_data, MockPeer},
+    tor,
+    tracker::{self, TrackerRequest},
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// The bare minimum of SOCKS5 with username/password auth (RFC 1928/1929),
+// recording each connection's username and target before relaying it.
+async fn socks_proxy() -> (SocketAddr, Arc<Mutex<Vec<(String, SocketAddr)>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let log = seen.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut client, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            client.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            client.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&2));
+            client.write_all(&[5, 2]).await.unwrap();
+
+            let mut auth = [0u8; 2];
+            client.read_exact(&mut auth).await.unwrap();
+            let mut user = vec![0u8; auth[1] as usize];
+            client.read_exact(&mut user).await.unwrap();
+            let mut password = vec![0u8; client.read_u8().await.unwrap() as usize];
+            client.read_exact(&mut password).await.unwrap();
+            client.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0u8; 4];
+            client.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3], 1, "expected an IPv4 target");
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip).await.unwrap();
+            let target = SocketAddr::from((ip, client.read_u16().await.unwrap()));
+            client
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            log.lock()
+                .unwrap()
+                .push((String::from_utf8(user).unwrap(), target));
+
+            let mut upstream = TcpStream::connect(target).await.unwrap();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            });
+        }
+    });
+    (address, seen)
+}
+
+#[tokio::test]
+async fn peers_connect_through_isolated_circuits() {
+    let (proxy, seen) = socks_proxy().await;
+    let data = sample_data(40_000);
+    let first = MockPeer::seeding("sample.bin", 16 * 1024, data.clone());
+    let second = MockPeer::seeding("sample.bin", 16 * 1024, data);
+    let addresses = [
+        first.listen().await.unwrap(),
+        second.listen().await.unwrap(),
+    ];
+
+    tor::enable(proxy);
+    for address in addresses {
+        let peer = Peer::new(address, first.info_hash()).await.unwrap();
+        assert_eq!(peer.id, first.peer_id());
+    }
+    let udp = tracker::announce(
+        "udp://tracker.example:6969",
+        &[0; 20],
+        &TrackerRequest::builder().build(),
+    )
+    .await;
+    // WebRTC's UDP can't go through the proxy, so it's refused outright.
+    #[cfg(feature = "webrtc")]
+    let webrtc = bittorrent_starter_rust::webtorrent::PendingOffer::new(&Default::default()).await;
+    tor::disable();
+
+    assert!(udp.is_err());
+    #[cfg(feature = "webrtc")]
+    assert!(webrtc.is_err());
+    let seen = seen.lock().unwrap().clone();
+    assert_eq!(
+        seen,
+        addresses
+            .iter()
+            .map(|address| (address.to_string(), *address))
+            .collect::<Vec<_>>()
+    );
+}
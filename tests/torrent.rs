@@ -0,0 +1,44 @@
+use bittorrent_starter_rust::{error::Error, torrent::Torrent};
+
+fn metainfo(length: u64, piece_length: u64) -> Vec<u8> {
+    let pieces = length.div_ceil(piece_length) as usize * 20;
+    let mut bytes = format!(
+        "d8:announce17:http://tracker/an4:infod6:lengthi{}e4:name7:big.iso12:piece lengthi{}e6:pieces{}:",
+        length, piece_length, pieces
+    )
+    .into_bytes();
+    bytes.extend(vec![0u8; pieces]);
+    bytes.extend(b"ee");
+    bytes
+}
+
+#[test]
+fn parses_torrents_larger_than_4_gib() {
+    let length = 5 * 1024 * 1024 * 1024 + 1000;
+    let torrent = Torrent::from_bytes(&metainfo(length, 4 * 1024 * 1024)).unwrap();
+    assert_eq!(torrent.len(), length);
+    assert_eq!(torrent.pieces().len(), 1281);
+    let (last, _, last_len) = torrent.piece_infos().last().unwrap();
+    assert_eq!((last, last_len), (1280, 1000));
+}
+
+#[test]
+fn rejects_pieces_larger_than_4_gib() {
+    let piece_length = 5 * 1024 * 1024 * 1024;
+    assert!(matches!(
+        Torrent::from_bytes(&metainfo(piece_length, piece_length)),
+        Err(Error::Metadata(_))
+    ));
+}
+
+#[test]
+fn reads_optional_metainfo_fields() {
+    let mut bytes = b"d8:announce17:http://tracker/an7:comment5:hello10:created by8:tool/1.013:creation datei1700000000e8:encoding5:UTF-8".to_vec();
+    bytes.extend(&metainfo(1000, 16384)[b"d8:announce17:http://tracker/an".len()..]);
+    let torrent = Torrent::from_bytes(&bytes).unwrap();
+    assert_eq!(torrent.comment.as_deref(), Some("hello"));
+    assert_eq!(torrent.created_by.as_deref(), Some("tool/1.0"));
+    assert_eq!(torrent.creation_date, Some(1_700_000_000));
+    assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+    assert!(!torrent.info.is_private());
+}
@@ -0,0 +1,235 @@
+#![cfg(feature = "http")]
+
+use bittorrent_starter_rust::testing::{sample_data, MockPeer, MockTracker};
+
+#[tokio::test]
+async fn announce_returns_tracked_peers() {
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(40_000));
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.get_peer_addrs().await.unwrap(), vec![address]);
+
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].contains("info_hash="));
+    assert!(requests[0].contains("left=40000"));
+}
+
+#[tokio::test]
+async fn downloads_torrent_from_mock_swarm() {
+    let data = sample_data(100_000);
+    let mock = MockPeer::seeding("sample.bin", 32 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    assert_eq!(
+        torrent.download_piece(1).await.unwrap(),
+        data[32 * 1024..64 * 1024]
+    );
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn announces_both_address_families() {
+    use bittorrent_starter_rust::tracker::{self, TrackerRequest};
+
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(1_000));
+    let tracker = MockTracker::start(vec![]).await.unwrap();
+    let request = TrackerRequest::builder()
+        .ipv4("203.0.113.7".parse().unwrap())
+        .ipv6("2001:db8::1".parse().unwrap())
+        .build();
+    tracker::announce(&tracker.announce_url(), &mock.info_hash(), &request)
+        .await
+        .unwrap();
+
+    let requests = tracker.requests();
+    assert!(requests[0].contains("ipv4=203.0.113.7"));
+    assert!(requests[0].contains("ipv6=2001%3Adb8%3A%3A1"));
+}
+
+#[test]
+fn merges_ipv4_and_ipv6_peers() {
+    use bittorrent_starter_rust::{bencode, tracker::TrackerResponse};
+    use std::net::SocketAddr;
+
+    let mut peers6 = "2001:db8::2"
+        .parse::<std::net::Ipv6Addr>()
+        .unwrap()
+        .octets()
+        .to_vec();
+    peers6.extend(6881u16.to_be_bytes());
+    let body = [
+        b"d5:peers6:".as_slice(),
+        &[10, 0, 0, 1, 0x1a, 0xe1],
+        b"6:peers618:",
+        &peers6,
+        b"e",
+    ]
+    .concat();
+    let response = bencode::from_bytes::<TrackerResponse>(&body).unwrap();
+    let expected: Vec<SocketAddr> = vec![
+        "10.0.0.1:6881".parse().unwrap(),
+        "[2001:db8::2]:6881".parse().unwrap(),
+    ];
+    assert_eq!(response.peers().unwrap(), expected);
+}
+
+#[tokio::test]
+async fn falls_back_through_announce_tiers() {
+    use bittorrent_starter_rust::torrent::Torrent;
+
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(40_000));
+    let first = mock.listen().await.unwrap();
+    let second = mock.clone().listen().await.unwrap();
+    let tracker = MockTracker::start(vec![first]).await.unwrap();
+    let backup = MockTracker::start(vec![first, second]).await.unwrap();
+    let unreachable = "http://127.0.0.1:1/announce".to_string();
+
+    let torrent = mock.torrent(&unreachable);
+    let encoded = serde_bencode::to_bytes(&Torrent {
+        announce_list: vec![
+            vec![unreachable.clone(), tracker.announce_url()],
+            vec![backup.announce_url(), unreachable],
+        ],
+        ..torrent
+    })
+    .unwrap();
+    let torrent = Torrent::from_bytes(&encoded).unwrap();
+
+    assert_eq!(torrent.get_peer_addrs().await.unwrap(), vec![first, second]);
+    assert_eq!(tracker.requests().len(), 1);
+    assert_eq!(backup.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn skips_udp_trackers_in_a_tier() {
+    use bittorrent_starter_rust::torrent::Torrent;
+
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(40_000));
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let udp = "udp://127.0.0.1:6969/announce".to_string();
+
+    let torrent = mock.torrent(&udp);
+    let encoded = serde_bencode::to_bytes(&Torrent {
+        announce_list: vec![vec![udp, tracker.announce_url()]],
+        ..torrent
+    })
+    .unwrap();
+    let torrent = Torrent::from_bytes(&encoded).unwrap();
+
+    assert_eq!(torrent.get_peer_addrs().await.unwrap(), vec![address]);
+    assert_eq!(tracker.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn scrapes_swarm_statistics() {
+    use bittorrent_starter_rust::tracker::{self, ScrapeStats};
+
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(1_000));
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+
+    let url = tracker::scrape_url(&tracker.announce_url()).unwrap();
+    assert_eq!(url.path(), "/scrape");
+    assert!(tracker::scrape_url("http://example.com/tracker").is_err());
+
+    let info_hash = mock.info_hash();
+    let stats = tracker::scrape(&tracker.announce_url(), &[info_hash])
+        .await
+        .unwrap();
+    let expected = ScrapeStats {
+        seeders: 1,
+        leechers: 0,
+        completed: 0,
+    };
+    assert_eq!(stats.get(&info_hash), Some(&expected));
+    assert!(tracker.requests().is_empty());
+
+    let torrent = mock.torrent(&tracker.announce_url());
+    assert_eq!(torrent.scrape().await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn reports_download_stats_and_events() {
+    let data = sample_data(100_000);
+    let mock = MockPeer::seeding("sample.bin", 32 * 1024, data.clone());
+    let address = mock.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+
+    let requests = tracker.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("event=started"));
+    assert!(requests[0].contains("downloaded=0"));
+    assert!(requests[0].contains("left=100000"));
+    assert!(requests[1].contains("event=completed"));
+    assert!(requests[1].contains("downloaded=100000"));
+    assert!(requests[1].contains("left=0"));
+    let peer_id = |request: &str| {
+        request
+            .split('&')
+            .find(|pair| pair.starts_with("peer_id="))
+            .map(str::to_string)
+    };
+    assert_eq!(peer_id(&requests[0]), peer_id(&requests[1]));
+}
+
+#[tokio::test]
+async fn surfaces_tracker_failure_reasons() {
+    use bittorrent_starter_rust::error::Error;
+
+    let mock = MockPeer::seeding("sample.bin", 16 * 1024, sample_data(40_000));
+    let tracker = MockTracker::start(vec![]).await.unwrap();
+    tracker.set_failure(Some("torrent not registered"));
+    let torrent = mock.torrent(&tracker.announce_url());
+
+    let error = torrent.get_peer_addrs().await.unwrap_err();
+    assert!(matches!(&error, Error::Tracker(reason) if reason == "torrent not registered"));
+    // A refusal is final; only unreachable trackers are retried.
+    assert_eq!(tracker.requests().len(), 1);
+}
+
+#[test]
+fn parses_dictionary_peer_lists() {
+    use bittorrent_starter_rust::{
+        bencode::{self, Value},
+        tracker::TrackerResponse,
+    };
+    use std::collections::BTreeMap;
+
+    let entry = |ip: &str, port: i64| {
+        Value::Dict(BTreeMap::from([
+            (b"ip".to_vec(), Value::Bytes(ip.as_bytes().to_vec())),
+            (b"peer id".to_vec(), Value::Bytes(vec![b'x'; 20])),
+            (b"port".to_vec(), Value::Int(port)),
+        ]))
+    };
+    let response = Value::Dict(BTreeMap::from([
+        (b"interval".to_vec(), Value::Int(1800)),
+        (
+            b"peers".to_vec(),
+            Value::List(vec![
+                entry("10.0.0.1", 6881),
+                entry("2001:db8::1", 6882),
+                entry("peer.example.com", 6883),
+            ]),
+        ),
+    ]));
+    let response: TrackerResponse =
+        bencode::from_bytes(&bencode::encode(&response).unwrap()).unwrap();
+
+    assert_eq!(
+        response.peers().unwrap(),
+        vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:6882".parse().unwrap(),
+        ]
+    );
+}
@@ -0,0 +1,91 @@
+use bittorrent_starter_rust::{
+    error::Error, peer::Peer, storage::PieceStore, testing::sample_data,
+};
+use bytes::Bytes;
+use std::net::SocketAddr;
+
+const PIECE_LENGTH: usize = 16 * 1024;
+const INFO_HASH: [u8; 20] = [7; 20];
+
+// Connects a leecher to a peer seeding `pieces` of `data` from a store.
+async fn leech_from(data: &[u8], pieces: &[usize]) -> (Peer, PieceStore) {
+    let store = PieceStore::default();
+    for &piece in pieces {
+        let start = piece * PIECE_LENGTH;
+        store.insert(
+            piece,
+            Bytes::copy_from_slice(&data[start..start + PIECE_LENGTH]),
+        );
+    }
+    let address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+    let (ours, theirs) = tokio::io::duplex(1 << 20);
+    let uploads = store.clone();
+    tokio::spawn(async move {
+        let mut seeder = Peer::accept_stream(ours, address, &[INFO_HASH])
+            .await
+            .unwrap()
+            .with_piece_count(3)
+            .with_uploads(uploads);
+        seeder.seed().await
+    });
+    let leecher = Peer::connect_stream(theirs, address, INFO_HASH)
+        .await
+        .unwrap()
+        .with_piece_count(3);
+    (leecher, store)
+}
+
+#[tokio::test]
+async fn serves_requests_once_interested() {
+    let data = sample_data(PIECE_LENGTH * 3);
+    let (mut leecher, store) = leech_from(&data, &[0, 1, 2]).await;
+
+    assert_eq!(leecher.get_pieces().await.unwrap(), vec![0, 1, 2]);
+    leecher.prepare_download().await.unwrap();
+    let piece = leecher.load_piece(1, PIECE_LENGTH as u32).await.unwrap();
+    assert_eq!(piece, data[PIECE_LENGTH..2 * PIECE_LENGTH]);
+    assert_eq!(store.uploaded(), PIECE_LENGTH as u64);
+}
+
+#[tokio::test]
+async fn rejects_requests_for_missing_pieces() {
+    let data = sample_data(PIECE_LENGTH * 3);
+    let (mut leecher, store) = leech_from(&data, &[0]).await;
+
+    assert_eq!(leecher.get_pieces().await.unwrap(), vec![0]);
+    leecher.prepare_download().await.unwrap();
+    assert!(matches!(
+        leecher.load_piece(2, PIECE_LENGTH as u32).await,
+        Err(Error::Rejected(2))
+    ));
+    assert_eq!(store.uploaded(), 0);
+}
+
+#[tokio::test]
+async fn serves_metadata_to_peers_that_ask() {
+    use bittorrent_starter_rust::torrent::Info;
+
+    // Small pieces make for metadata longer than one ut_metadata piece.
+    let info = Info::single_file("sample.bin", 16, &sample_data(PIECE_LENGTH * 3));
+    let info_hash = info.info_hash().unwrap();
+    let metadata = Bytes::from(serde_bencode::to_bytes(&info).unwrap());
+    let address: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+    let (ours, theirs) = tokio::io::duplex(1 << 20);
+    tokio::spawn(async move {
+        let mut seeder = Peer::accept_stream(ours, address, &[info_hash])
+            .await
+            .unwrap()
+            .with_piece_count(info.pieces().len())
+            .with_uploads(PieceStore::default())
+            .with_metadata(metadata);
+        seeder.seed().await
+    });
+    let mut leecher = Peer::connect_stream(theirs, address, info_hash)
+        .await
+        .unwrap();
+    leecher.get_pieces().await.unwrap();
+    leecher.extension_handshake().await.unwrap();
+
+    let fetched = leecher.extension_metadata().await.unwrap();
+    assert_eq!(fetched.info_hash().unwrap(), info_hash);
+}
@@ -0,0 +1,64 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    peer::{Peer, Transport},
+    testing::{sample_data, MockPeer, MockTracker},
+    utp::{self, UtpSocket},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+#[tokio::test]
+async fn streams_bytes_both_ways() {
+    let socket = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = socket.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received.reverse();
+        stream.write_all(&received).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let data = sample_data(300_000);
+    let mut stream = utp::connect(address).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).await.unwrap();
+    echoed.reverse();
+    assert_eq!(echoed, data);
+}
+
+#[tokio::test]
+async fn downloads_a_piece_over_utp() {
+    let data = sample_data(16 * 1024 * 2);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let address = mock.listen_utp().await.unwrap();
+
+    let mut peer = Peer::dial(address, mock.info_hash(), Transport::Utp)
+        .await
+        .unwrap();
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![0, 1]);
+    peer.prepare_download().await.unwrap();
+    let piece = peer.load_piece(1, 16 * 1024).await.unwrap();
+    assert_eq!(piece, data[16 * 1024..]);
+}
+
+#[tokio::test]
+async fn dials_exchanged_peers_over_utp_when_flagged() {
+    let data = sample_data(16 * 1024 * 4);
+    let seeder = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    // Only reachable over uTP.
+    let seeder_address = seeder.listen_utp().await.unwrap();
+    let partial = seeder
+        .clone()
+        .with_pieces([0, 1])
+        .exchanging_over(vec![(seeder_address, Transport::Utp)]);
+    let partial_address = partial.listen().await.unwrap();
+    let tracker = MockTracker::start(vec![partial_address]).await.unwrap();
+    let torrent = seeder.torrent(&tracker.announce_url());
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+}
@@ -0,0 +1,148 @@
+use bittorrent_starter_rust::{
+    bencode::{self, Value},
+    error::Error,
+    testing::{sample_data, MockPeer, MockTracker},
+    torrent::Torrent,
+    v2,
+};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn dict<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Dict(
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.as_bytes().to_vec(), value))
+            .collect(),
+    )
+}
+
+fn file(length: usize, root: [u8; 32]) -> Value {
+    dict([(
+        "",
+        dict([
+            ("length", Value::Int(length as i64)),
+            ("pieces root", Value::Bytes(root.to_vec())),
+        ]),
+    )])
+}
+
+// A v2-only torrent of a three-piece file and a small one, and its info
+// dict's bytes.
+fn metainfo(big: &[u8], small: &[u8], layer: Vec<u8>) -> (Vec<u8>, Vec<u8>) {
+    let blocks: Vec<[u8; 32]> = big.chunks(16 * 1024).map(|b| sha256(&[b])).collect();
+    let big_root = sha256(&[
+        &sha256(&[&blocks[0], &blocks[1]]),
+        &sha256(&[&blocks[2], &[0; 32]]),
+    ]);
+    let info = dict([
+        (
+            "file tree",
+            dict([
+                ("big.bin", file(big.len(), big_root)),
+                ("small.txt", file(small.len(), sha256(&[small]))),
+            ]),
+        ),
+        ("meta version", Value::Int(2)),
+        ("name", Value::Bytes(b"album".to_vec())),
+        ("piece length", Value::Int(PIECE_LENGTH as i64)),
+    ]);
+    let info_bytes = bencode::encode(&info).unwrap();
+    let torrent = Value::Dict(BTreeMap::from([
+        (
+            b"announce".to_vec(),
+            Value::Bytes(b"http://tracker/an".to_vec()),
+        ),
+        (b"info".to_vec(), info),
+        (
+            b"piece layers".to_vec(),
+            Value::Dict(BTreeMap::from([(big_root.to_vec(), Value::Bytes(layer))])),
+        ),
+    ]));
+    (bencode::encode(&torrent).unwrap(), info_bytes)
+}
+
+#[test]
+fn parses_v2_torrents_and_checks_pieces() {
+    let (big, small) = (sample_data(40_000), sample_data(100));
+    let layer = big.chunks(16 * 1024).flat_map(|b| sha256(&[b])).collect();
+    let (bytes, info_bytes) = metainfo(&big, &small, layer);
+    let torrent = Torrent::from_bytes(&bytes).unwrap();
+
+    assert!(torrent.info.is_v2_only());
+    assert_eq!(torrent.info.info_hash_v2().unwrap(), sha256(&[&info_bytes]));
+    assert_eq!(
+        torrent.info.files(),
+        vec![
+            (vec!["big.bin".to_string()], 40_000),
+            (vec!["small.txt".to_string()], 100)
+        ]
+    );
+    assert_eq!(torrent.len(), 40_100);
+    assert!(torrent.verify_v2_piece(0, 2, &big[32 * 1024..]).unwrap());
+    assert!(!torrent.verify_v2_piece(0, 1, &big[32 * 1024..]).unwrap());
+    assert!(torrent.verify_v2_piece(1, 0, &small).unwrap());
+    assert!(!torrent.verify_v2_piece(1, 0, &small[1..]).unwrap());
+
+    // Pieces are numbered across files, each file starting on a new one.
+    let info = &torrent.info;
+    assert_eq!(info.piece_count(), 4);
+    assert_eq!((info.piece_offset(3), info.piece_len(3)), (40_000, 100));
+    assert_eq!(info.piece_len(2), 40_000 - 32 * 1024);
+    assert!(info.verify_piece(2, &big[32 * 1024..]));
+    assert!(info.verify_piece(3, &small));
+    assert!(!info.verify_piece(3, &big[1..101]));
+}
+
+#[test]
+fn rejects_piece_layers_that_miss_the_root() {
+    let (big, small) = (sample_data(40_000), sample_data(100));
+    let mut layer: Vec<u8> = big.chunks(16 * 1024).flat_map(|b| sha256(&[b])).collect();
+    layer[40] ^= 1;
+    let (bytes, _) = metainfo(&big, &small, layer);
+    assert!(matches!(
+        Torrent::from_bytes(&bytes),
+        Err(Error::Metadata(_))
+    ));
+}
+
+#[test]
+fn verifies_merkle_proofs() {
+    let data = sample_data(4 * 16 * 1024);
+    let leaves = v2::block_hashes(&data);
+    let root = v2::pieces_root(&data, 4 * PIECE_LENGTH);
+    let proof = [leaves[3], sha256(&[&leaves[0], &leaves[1]])];
+    assert!(v2::verify_proof(leaves[2], 2, &proof, &root));
+    assert!(!v2::verify_proof(leaves[2], 3, &proof, &root));
+    assert!(!v2::verify_proof(leaves[1], 2, &proof, &root));
+}
+
+#[tokio::test]
+async fn downloads_v2_only_torrents() {
+    let (big, small) = (sample_data(40_000), sample_data(100));
+    let layer = big.chunks(16 * 1024).flat_map(|b| sha256(&[b])).collect();
+    let (bytes, _) = metainfo(&big, &small, layer);
+    let mut torrent = Torrent::from_bytes(&bytes).unwrap();
+
+    let mock = MockPeer::new(torrent.info.clone(), [big.clone(), small.clone()].concat());
+    let tracker = MockTracker::start(vec![mock.listen().await.unwrap()])
+        .await
+        .unwrap();
+    torrent.announce = tracker.announce_url();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("album");
+    torrent.download_to(path.clone()).join().await.unwrap();
+    assert_eq!(std::fs::read(path.join("big.bin")).unwrap(), big);
+    assert_eq!(std::fs::read(path.join("small.txt")).unwrap(), small);
+}
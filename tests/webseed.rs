@@ -0,0 +1,71 @@
+#![cfg(feature = "http")]
+use bittorrent_starter_rust::{
+    bencode::{self, Value},
+    testing::{sample_data, MockPeer, MockTracker, MockWebSeed},
+    torrent::Torrent,
+};
+
+#[tokio::test]
+async fn downloads_from_web_seed_without_peers() {
+    let data = sample_data(16 * 1024 * 3 + 100);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let seed = MockWebSeed::start("file.bin", data.clone()).await.unwrap();
+    let tracker = MockTracker::start(vec![]).await.unwrap();
+    let torrent = Torrent {
+        url_list: vec![seed.url()],
+        ..mock.torrent(&tracker.announce_url())
+    };
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    assert!(seed.requests() >= 4);
+}
+
+#[tokio::test]
+async fn downloads_from_web_seeds_alongside_peers() {
+    let data = sample_data(16 * 1024 * 4);
+    let partial = MockPeer::seeding("file.bin", 16 * 1024, data.clone()).with_pieces([0, 1]);
+    let address = partial.clone().listen().await.unwrap();
+    let seed = MockWebSeed::start("file.bin", data.clone()).await.unwrap();
+    let tracker = MockTracker::start(vec![address]).await.unwrap();
+    let torrent = Torrent {
+        url_list: vec![seed.url()],
+        ..partial.torrent(&tracker.announce_url())
+    };
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    // Nobody else has the last two pieces.
+    assert!(seed.requests() >= 2);
+}
+
+#[tokio::test]
+async fn downloads_from_http_seed() {
+    let data = sample_data(16 * 1024 * 3 + 100);
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, data.clone());
+    let seed = MockWebSeed::http_seed(16 * 1024, data.clone())
+        .await
+        .unwrap();
+    let tracker = MockTracker::start(vec![]).await.unwrap();
+    let torrent = Torrent {
+        http_seeds: vec![seed.url()],
+        ..mock.torrent(&tracker.announce_url())
+    };
+
+    assert_eq!(torrent.download().join().await.unwrap(), data);
+    assert!(seed.requests() >= 4);
+}
+
+#[tokio::test]
+async fn parses_a_single_web_seed_url() {
+    let mock = MockPeer::seeding("file.bin", 16 * 1024, vec![7; 100]);
+    let encoded = serde_bencode::to_bytes(&mock.torrent("http://tracker/announce")).unwrap();
+    let Value::Dict(mut metainfo) = bencode::decode(&encoded).unwrap() else {
+        panic!("metainfo is not a dictionary");
+    };
+    metainfo.insert(
+        b"url-list".to_vec(),
+        Value::Bytes(b"http://mirror/file.bin".to_vec()),
+    );
+
+    let torrent = Torrent::from_bytes(&bencode::encode(&Value::Dict(metainfo)).unwrap()).unwrap();
+    assert_eq!(torrent.url_list, vec!["http://mirror/file.bin".to_string()]);
+}
@@ -0,0 +1,200 @@
+#![cfg(feature = "webrtc")]
+
+use bittorrent_starter_rust::{
+    testing::{sample_data, MockPeer},
+    webtorrent::{self, PendingAnswer, PendingOffer, WebRtcConfig},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+const PIECE_LENGTH: u64 = 16 * 1024;
+
+fn config() -> WebRtcConfig {
+    // Host candidates only; the test never leaves the machine.
+    WebRtcConfig {
+        ice_servers: vec![],
+        offers: 1,
+        window: Duration::from_secs(20),
+    }
+}
+
+// A WebSocket tracker with one "browser" peer behind it that answers the
+// first offer it sees and then seeds `mock` over the data channel.
+async fn tracker_with_browser_peer(mock: MockPeer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let Some(Ok(Message::Text(text))) = socket.next().await else {
+            panic!("expected an announce");
+        };
+        let announce: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(announce["action"], "announce");
+        assert_eq!(announce["event"], "started");
+        let offer = &announce["offers"][0];
+
+        let answer = PendingAnswer::new(&config(), offer["offer"]["sdp"].as_str().unwrap().into())
+            .await
+            .unwrap();
+        let reply = json!({
+            "action": "announce",
+            "info_hash": announce["info_hash"],
+            "peer_id": webtorrent::binary_string(&[7; 20]),
+            "answer": { "type": "answer", "sdp": answer.sdp },
+            "offer_id": offer["offer_id"],
+        });
+        socket.send(Message::Text(reply.to_string())).await.unwrap();
+        let stream = answer.open().await.unwrap();
+        let _ = mock.serve(stream).await;
+    });
+    format!("ws://{}", address)
+}
+
+// A WebSocket tracker relaying an offer from a "browser" peer, which seeds
+// `mock` once our answer comes back.
+async fn tracker_relaying_an_offer(mock: MockPeer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let Some(Ok(Message::Text(text))) = socket.next().await else {
+            panic!("expected an announce");
+        };
+        let announce: Value = serde_json::from_str(&text).unwrap();
+
+        let offer = PendingOffer::new(&config()).await.unwrap();
+        let relayed = json!({
+            "action": "announce",
+            "info_hash": announce["info_hash"],
+            "peer_id": webtorrent::binary_string(&[7; 20]),
+            "offer": { "type": "offer", "sdp": offer.sdp },
+            "offer_id": "relayed",
+        });
+        socket
+            .send(Message::Text(relayed.to_string()))
+            .await
+            .unwrap();
+        let Some(Ok(Message::Text(text))) = socket.next().await else {
+            panic!("expected an answer");
+        };
+        let reply: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(reply["offer_id"], "relayed");
+        assert_eq!(reply["to_peer_id"], webtorrent::binary_string(&[7; 20]));
+        let sdp = reply["answer"]["sdp"].as_str().unwrap().to_string();
+        let stream = offer.accept(sdp).await.unwrap();
+        let _ = mock.serve(stream).await;
+    });
+    format!("ws://{}", address)
+}
+
+// A WebSocket tracker with two "browser" peers behind it: the first seeds
+// `mock`, the second handshakes and then sends a frame too long to be a
+// message.
+async fn tracker_with_a_broken_peer(mock: MockPeer) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let Some(Ok(Message::Text(text))) = socket.next().await else {
+            panic!("expected an announce");
+        };
+        let announce: Value = serde_json::from_str(&text).unwrap();
+        let mut answers = Vec::new();
+        for (index, offer) in announce["offers"].as_array().unwrap().iter().enumerate() {
+            let sdp = offer["offer"]["sdp"].as_str().unwrap().into();
+            let answer = PendingAnswer::new(&config(), sdp).await.unwrap();
+            let reply = json!({
+                "action": "announce",
+                "info_hash": announce["info_hash"],
+                "peer_id": webtorrent::binary_string(&[index as u8; 20]),
+                "answer": { "type": "answer", "sdp": answer.sdp },
+                "offer_id": offer["offer_id"],
+            });
+            socket.send(Message::Text(reply.to_string())).await.unwrap();
+            answers.push(answer);
+        }
+        let broken = answers.pop().unwrap();
+        let seeding = answers.pop().unwrap();
+        tokio::spawn(async move {
+            let _ = mock.serve(seeding.open().await.unwrap()).await;
+        });
+        let mut stream = broken.open().await.unwrap();
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        stream.write_all(&handshake).await.unwrap();
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        // Stays open, so only the bad frame can end the connection.
+        std::future::pending::<()>().await;
+    });
+    format!("ws://{}", address)
+}
+
+#[tokio::test]
+async fn downloads_from_webrtc_peer() {
+    let data = sample_data(PIECE_LENGTH as usize * 3 + 10);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let torrent = mock.torrent("ws://unused");
+    let torrent = bittorrent_starter_rust::torrent::Torrent {
+        announce: tracker_with_browser_peer(mock).await,
+        ..torrent
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    webtorrent::download_to(&torrent, config(), path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
+
+#[tokio::test]
+async fn answers_offers_relayed_by_the_tracker() {
+    let data = sample_data(PIECE_LENGTH as usize * 2 + 10);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let torrent = mock.torrent("ws://unused");
+    let torrent = bittorrent_starter_rust::torrent::Torrent {
+        announce: tracker_relaying_an_offer(mock).await,
+        ..torrent
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    webtorrent::download_to(&torrent, config(), path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
+
+#[tokio::test]
+async fn keeps_going_when_one_peer_fails() {
+    let data = sample_data(PIECE_LENGTH as usize * 3);
+    let mock = MockPeer::seeding("sample.bin", PIECE_LENGTH, data.clone());
+    let torrent = mock.torrent("ws://unused");
+    let torrent = bittorrent_starter_rust::torrent::Torrent {
+        announce: tracker_with_a_broken_peer(mock).await,
+        ..torrent
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sample.bin");
+    let config = WebRtcConfig {
+        offers: 2,
+        ..config()
+    };
+    webtorrent::download_to(&torrent, config, path.clone())
+        .join()
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+}
@@ -0,0 +1,98 @@
+use bittorrent_starter_rust::{
+    dht,
+    peer::{self, Handshake, Peer},
+};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    time,
+};
+
+const INFO_HASH: [u8; 20] = [5; 20];
+
+async fn connected() -> (Peer, DuplexStream) {
+    let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+    let remote = tokio::spawn(async move {
+        let mut handshake = [0u8; 68];
+        theirs.read_exact(&mut handshake).await.unwrap();
+        let mut reply = Handshake::new(INFO_HASH, *b"-MK0001-remote000000");
+        reply.reserved = [0; 8];
+        theirs.write_all(&reply.to_bytes().unwrap()).await.unwrap();
+        theirs
+    });
+    let peer = Peer::connect_stream(ours, "127.0.0.1:6881".parse().unwrap(), INFO_HASH)
+        .await
+        .unwrap();
+    (peer, remote.await.unwrap())
+}
+
+#[tokio::test]
+async fn keeps_quiet_connections_alive() {
+    peer::set_keep_alive_interval(Duration::from_millis(100));
+    let (mut peer, mut theirs) = connected().await;
+    // Sent even while a read is waiting on the connection.
+    let waiting = tokio::spawn(async move { peer.get_pieces().await });
+
+    for _ in 0..2 {
+        let mut frame = [0xffu8; 4];
+        time::timeout(Duration::from_secs(2), theirs.read_exact(&mut frame))
+            .await
+            .expect("no keep-alive")
+            .unwrap();
+        assert_eq!(frame, [0; 4]);
+    }
+    waiting.abort();
+}
+
+#[tokio::test]
+async fn skips_keep_alives_from_the_peer() {
+    let (mut peer, mut theirs) = connected().await;
+    theirs.write_all(&[0, 0, 0, 0]).await.unwrap();
+    theirs
+        .write_all(&[0, 0, 0, 2, 5, 0b1010_0000])
+        .await
+        .unwrap();
+    theirs.write_all(&[0, 0, 0, 0]).await.unwrap();
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![0, 2]);
+}
+
+#[tokio::test]
+async fn skips_messages_it_does_not_know() {
+    let (mut peer, mut theirs) = connected().await;
+    theirs.write_all(&[0, 0, 0, 3, 99, 1, 2]).await.unwrap();
+    theirs
+        .write_all(&[0, 0, 0, 2, 5, 0b0100_0000])
+        .await
+        .unwrap();
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![1]);
+}
+
+#[tokio::test]
+async fn adds_dht_nodes_peers_announce() {
+    dht::enable(Vec::new());
+    let (mut peer, mut theirs) = connected().await;
+    theirs
+        .write_all(&[0, 0, 0, 3, 9, 0x1b, 0x58])
+        .await
+        .unwrap();
+    theirs
+        .write_all(&[0, 0, 0, 2, 5, 0b1000_0000])
+        .await
+        .unwrap();
+    assert_eq!(peer.get_pieces().await.unwrap(), vec![0]);
+    assert_eq!(dht::bootstrap_nodes(), vec!["127.0.0.1:7000".to_string()]);
+    dht::disable();
+}
+
+#[tokio::test]
+async fn closes_the_connection_once_every_clone_is_dropped() {
+    let (peer, mut theirs) = connected().await;
+    let clone = peer.clone();
+    drop(peer);
+    drop(clone);
+    let mut rest = Vec::new();
+    time::timeout(Duration::from_secs(2), theirs.read_to_end(&mut rest))
+        .await
+        .expect("connection left open")
+        .unwrap();
+}